@@ -0,0 +1,199 @@
+use reqwest::Client;
+use user::{record_audit, set_auto_elevation, Account, AuditAction};
+use crate::error::LoginError;
+use crate::providers::{Provider, ProviderUser, Tokens};
+use crate::ElevationConfig;
+
+// Who the audit log says made the change
+const ACTOR: &str = "github allowlist";
+
+/*
+Whether the GitHub user is on the allowlist, None when an organization couldn't be checked.
+Logins are checked first so most logins don't need another request.
+ */
+async fn allowed(client: &Client, provider: &dyn Provider, tokens: &Tokens, user: &ProviderUser, config: &ElevationConfig) -> Option<bool> {
+    if config.ids.contains(&user.subject) {
+        return Some(true);
+    }
+    if let Some(login) = &user.login {
+        if config.logins.contains(&login.to_lowercase()) {
+            return Some(true);
+        }
+    }
+
+    let mut unknown = false;
+    for organization in &config.organizations {
+        match provider.member_of(client, tokens, organization).await {
+            Ok(true) => return Some(true),
+            Ok(false) => {},
+            Err(err) => {
+                eprintln!("Error checking membership of {} in {}: {:?}", user.subject, organization, err);
+                unknown = true;
+            }
+        }
+    }
+    if unknown { None } else { Some(false) }
+}
+
+/*
+What elevated should become, None to leave the account as it is.
+Only elevation the allowlist gave is ever taken away, an admin's stays.
+ */
+fn elevation_change(account: &Account, allowed: Option<bool>) -> Option<bool> {
+    match allowed {
+        Some(true) if !account.elevated => Some(true),
+        Some(false) if account.auto_elevated => Some(false),
+        // Nothing to change, or GitHub couldn't say and the account keeps what it has
+        _ => None,
+    }
+}
+
+/*
+Elevates GitHub accounts on the allowlist at each login, and takes it away again from accounts
+it elevated that no longer match. Accounts elevated by an admin are left alone either way.
+Returns the account as it is now.
+ */
+pub async fn apply_allowlist(mongo: &mongodb::Client, client: &Client, provider: &dyn Provider, tokens: &Tokens, user: &ProviderUser, account: Account, config: &ElevationConfig) -> Result<Account, LoginError> {
+    if provider.name() != "github" || (config.is_empty() && !account.auto_elevated) {
+        return Ok(account);
+    }
+
+    let elevated = match elevation_change(&account, allowed(client, provider, tokens, user, config).await) {
+        Some(elevated) => elevated,
+        None => return Ok(account),
+    };
+
+    set_auto_elevation(mongo, &account.uuid, elevated).await
+        .map_err(LoginError::Database)?;
+    record_audit(mongo, ACTOR, &account.uuid, AuditAction::SetElevated { elevated }).await
+        .map_err(LoginError::Database)?;
+    println!("Set elevated of account {} to {} from the GitHub allowlist", account.uuid, elevated);
+
+    Ok(Account { elevated, auto_elevated: elevated, ..account })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use crate::providers::ProviderError;
+    use user::AccountStatus;
+
+    // Answers membership checks from a list, organizations missing from it fail like GitHub being down
+    struct StubProvider {
+        memberships: Vec<(&'static str, bool)>,
+    }
+
+    #[async_trait]
+    impl Provider for StubProvider {
+        fn name(&self) -> &str {
+            "github"
+        }
+
+        fn authorize_url(&self, _state: &str, _nonce: &str, _code_challenge: &str, _redirect_uri: &str) -> String {
+            String::new()
+        }
+
+        async fn exchange(&self, _client: &Client, _code: &str, _code_verifier: &str, _redirect_uri: &str) -> Result<Tokens, ProviderError> {
+            Err(ProviderError::TokenError("stub".to_string()))
+        }
+
+        async fn user(&self, _client: &Client, _tokens: &Tokens, _nonce: &str) -> Result<ProviderUser, ProviderError> {
+            Err(ProviderError::TokenError("stub".to_string()))
+        }
+
+        async fn member_of(&self, _client: &Client, _tokens: &Tokens, organization: &str) -> Result<bool, ProviderError> {
+            match self.memberships.iter().find(|(name, _)| *name == organization) {
+                Some((_, member)) => Ok(*member),
+                None => Err(ProviderError::TokenError("unavailable".to_string())),
+            }
+        }
+    }
+
+    fn config(logins: &[&str], ids: &[&str], organizations: &[&str]) -> ElevationConfig {
+        let list = |items: &[&str]| items.iter().map(|item| item.to_string()).collect();
+        ElevationConfig { logins: list(logins), ids: list(ids), organizations: list(organizations) }
+    }
+
+    fn user(subject: &str, login: &str) -> ProviderUser {
+        ProviderUser {
+            subject: subject.to_string(),
+            login: Some(login.to_string()),
+            name: None,
+            email: None,
+            avatar_url: None,
+            url: None,
+            bio: None,
+        }
+    }
+
+    fn account(elevated: bool, auto_elevated: bool) -> Account {
+        Account {
+            name: None,
+            uuid: "uuid".to_string(),
+            provider: "github".to_string(),
+            subject: "1".to_string(),
+            email: None,
+            elevated,
+            auto_elevated,
+            sessions: vec![],
+            passkeys: vec![],
+            admin: false,
+            status: AccountStatus::Active,
+            suspended_until: None,
+            status_reason: None,
+            profile: None,
+        }
+    }
+
+    async fn check(memberships: Vec<(&'static str, bool)>, user: ProviderUser, config: ElevationConfig) -> Option<bool> {
+        let tokens = Tokens { access_token: String::new(), id_token: None };
+        allowed(&Client::new(), &StubProvider { memberships }, &tokens, &user, &config).await
+    }
+
+    #[actix_web::test]
+    async fn logins_are_compared_in_lowercase() {
+        assert_eq!(check(vec![], user("1", "Ann"), config(&["ann"], &[], &[])).await, Some(true));
+        assert_eq!(check(vec![], user("1", "Anne"), config(&["ann"], &[], &[])).await, Some(false));
+    }
+
+    #[actix_web::test]
+    async fn matches_ids() {
+        assert_eq!(check(vec![], user("42", "ann"), config(&[], &["42"], &[])).await, Some(true));
+    }
+
+    #[actix_web::test]
+    async fn checks_organizations() {
+        let memberships = vec![("staff", false), ("writers", true)];
+        assert_eq!(check(memberships.clone(), user("1", "ann"), config(&[], &[], &["staff", "writers"])).await, Some(true));
+        assert_eq!(check(memberships, user("1", "ann"), config(&[], &[], &["staff"])).await, Some(false));
+    }
+
+    #[actix_web::test]
+    async fn failed_membership_check_is_unknown() {
+        assert_eq!(check(vec![("staff", false)], user("1", "ann"), config(&[], &[], &["staff", "down"])).await, None);
+        // Any match still counts
+        assert_eq!(check(vec![("staff", true)], user("1", "ann"), config(&[], &[], &["down", "staff"])).await, Some(true));
+    }
+
+    #[test]
+    fn unknown_keeps_the_current_state() {
+        assert_eq!(elevation_change(&account(true, true), None), None);
+        assert_eq!(elevation_change(&account(false, false), None), None);
+    }
+
+    #[test]
+    fn elevates_accounts_on_the_allowlist() {
+        assert_eq!(elevation_change(&account(false, false), Some(true)), Some(true));
+        assert_eq!(elevation_change(&account(true, true), Some(true)), None);
+        assert_eq!(elevation_change(&account(true, false), Some(true)), None);
+    }
+
+    #[test]
+    fn only_revokes_its_own_elevation() {
+        assert_eq!(elevation_change(&account(true, true), Some(false)), Some(false));
+        // Elevated by an admin
+        assert_eq!(elevation_change(&account(true, false), Some(false)), None);
+        assert_eq!(elevation_change(&account(false, false), Some(false)), None);
+    }
+}
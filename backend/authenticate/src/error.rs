@@ -0,0 +1,226 @@
+use std::fmt;
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use mongodb::bson::DateTime;
+use serde::Serialize;
+use user::{AccountStatus, PasswordError, ProfileError, TotpError};
+use crate::providers::ProviderError;
+
+// Everything that can stop a login, each with its own status so a frontend can tell them apart
+#[derive(Debug)]
+pub enum LoginError {
+    UnknownProvider(String),
+    // No login was started from this session, or its state was already used
+    MissingState,
+    ExpiredState,
+    StateMismatch,
+    // The user said no on the provider's page
+    Denied(String),
+    Provider(ProviderError),
+    Session(String),
+    Database(mongodb::error::Error),
+    // Local accounts
+    LocalDisabled,
+    RegistrationClosed,
+    InvalidInput(String),
+    UsernameTaken,
+    InvalidCredentials,
+    LockedOut(DateTime),
+    NoPassword,
+    NotLoggedIn,
+    Hash(String),
+    // Passkeys
+    PasskeysDisabled,
+    PasskeyRejected(String),
+    UnknownPasskey,
+    PasskeyNotFound,
+    // Two-factor
+    InvalidCode,
+    TotpAlreadyEnabled,
+    TotpNotEnrolled,
+    SecondFactorRequired,
+    Secret(String),
+    TokenNotFound,
+    SessionNotFound,
+    // Suspended or banned
+    Blocked(AccountStatus, Option<DateTime>),
+    // Admin
+    NotAdmin,
+    AccountNotFound,
+    // Profiles
+    HandleTaken,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: &'static str,
+    message: String,
+}
+
+impl LoginError {
+    fn code(&self) -> &'static str {
+        match self {
+            LoginError::UnknownProvider(_) => "unknown_provider",
+            LoginError::MissingState => "missing_state",
+            LoginError::ExpiredState => "expired_state",
+            LoginError::StateMismatch => "state_mismatch",
+            LoginError::Denied(_) => "access_denied",
+            LoginError::Provider(_) => "provider_error",
+            LoginError::Session(_) => "session_error",
+            LoginError::Database(_) => "database_error",
+            LoginError::LocalDisabled => "local_accounts_disabled",
+            LoginError::RegistrationClosed => "registration_closed",
+            LoginError::InvalidInput(_) => "invalid_input",
+            LoginError::UsernameTaken => "username_taken",
+            LoginError::InvalidCredentials => "invalid_credentials",
+            LoginError::LockedOut(_) => "locked_out",
+            LoginError::NoPassword => "no_password",
+            LoginError::NotLoggedIn => "not_logged_in",
+            LoginError::Hash(_) => "hash_error",
+            LoginError::PasskeysDisabled => "passkeys_disabled",
+            LoginError::PasskeyRejected(_) => "passkey_rejected",
+            LoginError::UnknownPasskey => "unknown_passkey",
+            LoginError::PasskeyNotFound => "passkey_not_found",
+            LoginError::InvalidCode => "invalid_code",
+            LoginError::TotpAlreadyEnabled => "totp_already_enabled",
+            LoginError::TotpNotEnrolled => "totp_not_enrolled",
+            LoginError::SecondFactorRequired => "second_factor_required",
+            LoginError::Secret(_) => "secret_error",
+            LoginError::TokenNotFound => "token_not_found",
+            LoginError::SessionNotFound => "session_not_found",
+            LoginError::Blocked(AccountStatus::Banned, _) => "account_banned",
+            LoginError::Blocked(_, _) => "account_suspended",
+            LoginError::NotAdmin => "not_admin",
+            LoginError::AccountNotFound => "account_not_found",
+            LoginError::HandleTaken => "handle_taken",
+        }
+    }
+}
+
+// Only says as much as the user needs, the details are logged instead
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoginError::UnknownProvider(name) => write!(f, "Unknown provider {}", name),
+            LoginError::MissingState => write!(f, "No login in progress, start again"),
+            LoginError::ExpiredState => write!(f, "The login took too long, start again"),
+            LoginError::StateMismatch => write!(f, "The login does not match this session"),
+            LoginError::Denied(reason) => write!(f, "The provider did not allow the login: {}", reason),
+            LoginError::Provider(ProviderError::TokenError(reason)) => write!(f, "The provider refused the login: {}", reason),
+            LoginError::Provider(ProviderError::RequestError(_)) => write!(f, "Could not reach the provider"),
+            LoginError::Provider(ProviderError::DeserializationError(_)) => write!(f, "Unexpected response from the provider"),
+            LoginError::Session(_) => write!(f, "Could not use the session"),
+            LoginError::Database(_) => write!(f, "Could not read or write the account"),
+            LoginError::LocalDisabled => write!(f, "Local accounts are turned off"),
+            LoginError::RegistrationClosed => write!(f, "Registration is closed"),
+            LoginError::InvalidInput(reason) => write!(f, "{}", reason),
+            LoginError::UsernameTaken => write!(f, "That username is taken"),
+            LoginError::InvalidCredentials => write!(f, "Wrong username or password"),
+            LoginError::LockedOut(until) => write!(f, "Too many failed logins, try again after {}", until),
+            LoginError::NoPassword => write!(f, "This account logs in through a provider and has no password"),
+            LoginError::NotLoggedIn => write!(f, "Not logged in"),
+            LoginError::Hash(_) => write!(f, "Could not hash the password"),
+            LoginError::PasskeysDisabled => write!(f, "Passkeys are turned off"),
+            LoginError::PasskeyRejected(reason) => write!(f, "The passkey was not accepted: {}", reason),
+            LoginError::UnknownPasskey => write!(f, "That passkey is not registered here"),
+            LoginError::PasskeyNotFound => write!(f, "No such passkey"),
+            LoginError::InvalidCode => write!(f, "Wrong code"),
+            LoginError::TotpAlreadyEnabled => write!(f, "An authenticator app is already set up"),
+            LoginError::TotpNotEnrolled => write!(f, "No authenticator app is set up"),
+            LoginError::SecondFactorRequired => write!(f, "Elevated accounts have to keep two-factor authentication on"),
+            LoginError::Secret(_) => write!(f, "Could not set up the authenticator app"),
+            LoginError::TokenNotFound => write!(f, "No such token"),
+            LoginError::SessionNotFound => write!(f, "No such session"),
+            LoginError::Blocked(AccountStatus::Banned, _) => write!(f, "This account has been banned"),
+            LoginError::Blocked(_, Some(until)) => write!(f, "This account is suspended until {}", until),
+            LoginError::Blocked(_, None) => write!(f, "This account is suspended"),
+            LoginError::NotAdmin => write!(f, "Only admins can do that"),
+            LoginError::AccountNotFound => write!(f, "No such account"),
+            LoginError::HandleTaken => write!(f, "That handle is taken"),
+        }
+    }
+}
+
+impl ResponseError for LoginError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            LoginError::UnknownProvider(_) => StatusCode::NOT_FOUND,
+            LoginError::MissingState | LoginError::ExpiredState | LoginError::StateMismatch => StatusCode::BAD_REQUEST,
+            LoginError::Denied(_) => StatusCode::FORBIDDEN,
+            LoginError::Provider(ProviderError::TokenError(_)) => StatusCode::UNAUTHORIZED,
+            LoginError::Provider(_) => StatusCode::BAD_GATEWAY,
+            LoginError::LocalDisabled | LoginError::PasskeysDisabled | LoginError::PasskeyNotFound | LoginError::TokenNotFound | LoginError::SessionNotFound | LoginError::AccountNotFound => StatusCode::NOT_FOUND,
+            LoginError::Blocked(_, _) | LoginError::NotAdmin => StatusCode::FORBIDDEN,
+            LoginError::PasskeyRejected(_) => StatusCode::BAD_REQUEST,
+            LoginError::UnknownPasskey | LoginError::InvalidCode => StatusCode::UNAUTHORIZED,
+            LoginError::TotpAlreadyEnabled => StatusCode::CONFLICT,
+            LoginError::TotpNotEnrolled => StatusCode::BAD_REQUEST,
+            LoginError::SecondFactorRequired => StatusCode::FORBIDDEN,
+            LoginError::RegistrationClosed => StatusCode::FORBIDDEN,
+            LoginError::InvalidInput(_) | LoginError::NoPassword => StatusCode::BAD_REQUEST,
+            LoginError::UsernameTaken | LoginError::HandleTaken => StatusCode::CONFLICT,
+            LoginError::InvalidCredentials | LoginError::NotLoggedIn => StatusCode::UNAUTHORIZED,
+            LoginError::LockedOut(_) => StatusCode::TOO_MANY_REQUESTS,
+            LoginError::Session(_) | LoginError::Database(_) | LoginError::Hash(_) | LoginError::Secret(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            LoginError::Provider(err) => eprintln!("Login failed, provider error: {:?}", err),
+            LoginError::Session(details) => eprintln!("Login failed, session error: {}", details),
+            LoginError::Database(err) => eprintln!("Login failed, database error: {}", err),
+            LoginError::Hash(details) => eprintln!("Login failed, hash error: {}", details),
+            LoginError::Secret(details) => eprintln!("Login failed, TOTP secret error: {}", details),
+            _ => {},
+        }
+
+        let mut response = HttpResponse::build(self.status_code());
+        if let LoginError::LockedOut(until) = self {
+            let seconds = (until.timestamp_millis() - DateTime::now().timestamp_millis()).max(0) / 1000;
+            response.insert_header((RETRY_AFTER, seconds.to_string()));
+        }
+        response.json(ErrorBody {
+            error: self.code(),
+            message: self.to_string(),
+        })
+    }
+}
+
+impl From<PasswordError> for LoginError {
+    fn from(err: PasswordError) -> LoginError {
+        match err {
+            PasswordError::InvalidUsername(reason) | PasswordError::WeakPassword(reason) => LoginError::InvalidInput(reason),
+            PasswordError::UsernameTaken => LoginError::UsernameTaken,
+            PasswordError::InvalidCredentials => LoginError::InvalidCredentials,
+            PasswordError::LockedOut(until) => LoginError::LockedOut(until),
+            PasswordError::NoPassword => LoginError::NoPassword,
+            PasswordError::HashError(details) => LoginError::Hash(details),
+            PasswordError::MongoError(err) => LoginError::Database(err),
+        }
+    }
+}
+
+impl From<TotpError> for LoginError {
+    fn from(err: TotpError) -> LoginError {
+        match err {
+            TotpError::AlreadyEnabled => LoginError::TotpAlreadyEnabled,
+            TotpError::NotEnrolled => LoginError::TotpNotEnrolled,
+            TotpError::InvalidCode => LoginError::InvalidCode,
+            TotpError::LockedOut(until) => LoginError::LockedOut(until),
+            TotpError::SecretError(details) => LoginError::Secret(details),
+            TotpError::MongoError(err) => LoginError::Database(err),
+        }
+    }
+}
+
+impl From<ProfileError> for LoginError {
+    fn from(err: ProfileError) -> LoginError {
+        match err {
+            ProfileError::InvalidInput(reason) => LoginError::InvalidInput(reason),
+            ProfileError::HandleTaken => LoginError::HandleTaken,
+            ProfileError::MongoError(err) => LoginError::Database(err),
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use actix_session::Session;
+use actix_web::http::header::USER_AGENT;
+use actix_web::{HttpRequest, HttpResponse};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use pwhash::bcrypt;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use user::{create_session, get_account_from_session, set_account_session, totp_enabled, Account, SessionOrigin, SessionPolicy};
+use crate::error::LoginError;
+use crate::SecondFactorConfig;
+
+const SESSION_KEY: &str = "oauth";
+const PENDING_KEY: &str = "second_factor";
+
+fn random_string(length: usize) -> String {
+    thread_rng().sample_iter(&Alphanumeric).take(length).map(char::from).collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+/*
+A login between /request and /callback, kept in the session.
+It is taken out of the session as soon as the callback sees it, so each state only works once.
+ */
+#[derive(Deserialize, Serialize)]
+pub struct LoginState {
+    // Hash it so even the session doesn't know what the state is
+    state_hash: String,
+    pub provider: String,
+    // PKCE (RFC 7636), only the challenge derived from this is sent with the authorize request
+    pub code_verifier: String,
+    // Seconds since the epoch
+    issued: u64,
+}
+
+impl LoginState {
+    // Returns the state to send to the provider, along with the login to keep
+    pub fn start(provider: &str) -> Result<(String, LoginState), LoginError> {
+        let state = random_string(30);
+        let state_hash = match bcrypt::hash(&state) {
+            Ok(state_hash) => state_hash,
+            Err(err) => return Err(LoginError::Session(format!("Could not hash state: {}", err))),
+        };
+
+        Ok((state, LoginState {
+            state_hash,
+            provider: provider.to_string(),
+            // 43 to 128 characters from the unreserved set
+            code_verifier: random_string(64),
+            issued: now(),
+        }))
+    }
+
+    pub fn save(&self, session: &Session) -> Result<(), LoginError> {
+        session.insert(SESSION_KEY, self)
+            .map_err(|err| LoginError::Session(err.to_string()))
+    }
+
+    pub fn take(session: &Session) -> Result<LoginState, LoginError> {
+        match session.remove_as::<LoginState>(SESSION_KEY) {
+            Some(Ok(login)) => Ok(login),
+            Some(Err(_)) | None => Err(LoginError::MissingState),
+        }
+    }
+
+    pub fn check(&self, state: &str, lifetime: Duration) -> Result<(), LoginError> {
+        if now().saturating_sub(self.issued) > lifetime.as_secs() {
+            return Err(LoginError::ExpiredState);
+        }
+        if !bcrypt::verify(state, &self.state_hash) {
+            return Err(LoginError::StateMismatch);
+        }
+        Ok(())
+    }
+
+    // The S256 method, plain is never used
+    pub fn code_challenge(&self) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(self.code_verifier.as_bytes()))
+    }
+}
+
+pub async fn logged_in(mongo: &mongodb::Client, session: &Session) -> Result<Account, LoginError> {
+    match get_account_from_session(mongo, session).await {
+        Ok(account) => Ok(account),
+        Err(_) => Err(LoginError::NotLoggedIn),
+    }
+}
+
+fn session_origin(req: &HttpRequest) -> SessionOrigin {
+    SessionOrigin {
+        user_agent: req.headers().get(USER_AGENT)
+            .and_then(|agent| agent.to_str().ok())
+            .map(|agent| agent.chars().take(256).collect()),
+        // The service only listens on localhost, so this comes from the proxy in front of it
+        ip: req.connection_info().realip_remote_addr().map(|ip| ip.to_string()),
+    }
+}
+
+// Gives the browser a session for the account, however it logged in
+pub async fn start_session(mongo: &mongodb::Client, req: &HttpRequest, session: &Session, account: &Account, policy: &SessionPolicy) -> Result<(), LoginError> {
+    if account.blocked() {
+        return Err(LoginError::Blocked(account.status, account.suspended_until));
+    }
+    let session_id: String = create_session(mongo, &account.uuid, policy, session_origin(req)).await
+        .map_err(LoginError::Database)?;
+
+    // A new session key on login, so one planted before it is no use afterwards
+    session.renew();
+    session.remove(PENDING_KEY);
+    set_account_session(&session_id, &account.uuid, session).await
+        .map_err(|err| LoginError::Session(format!("{:?}", err)))
+}
+
+/*
+A login that got past its first factor and is waiting on the second.
+Nothing in it counts as being logged in, get_account_from_session only looks at the real session.
+ */
+#[derive(Deserialize, Serialize)]
+pub struct PendingLogin {
+    pub uuid: String,
+    // The account has no second factor yet but has to have one, so it sets one up instead
+    pub enroll: bool,
+    // Seconds since the epoch
+    issued: u64,
+}
+
+#[derive(Serialize)]
+struct SecondFactorRequired {
+    // "totp", or "enroll" when one has to be set up first
+    second_factor: &'static str,
+}
+
+impl PendingLogin {
+    pub fn get(session: &Session, config: &SecondFactorConfig) -> Result<PendingLogin, LoginError> {
+        let pending = match session.get::<PendingLogin>(PENDING_KEY) {
+            Ok(Some(pending)) => pending,
+            Ok(None) | Err(_) => return Err(LoginError::MissingState),
+        };
+        if now().saturating_sub(pending.issued) > config.lifetime.as_secs() {
+            session.remove(PENDING_KEY);
+            return Err(LoginError::ExpiredState);
+        }
+        Ok(pending)
+    }
+}
+
+/*
+Finishes the first factor of a login: straight to a session when the account has no second factor,
+otherwise a pending login and a 202 telling the frontend to ask for a code.
+ */
+pub async fn log_in(mongo: &mongodb::Client, req: &HttpRequest, session: &Session, account: &Account, policy: &SessionPolicy, config: &SecondFactorConfig) -> Result<HttpResponse, LoginError> {
+    // Before asking for a code that wouldn't get them in anyway
+    if account.blocked() {
+        return Err(LoginError::Blocked(account.status, account.suspended_until));
+    }
+    let enabled = totp_enabled(mongo, &account.uuid).await
+        .map_err(LoginError::Database)?;
+    let enroll = !enabled && account.elevated && config.required_for_elevated;
+
+    if !enabled && !enroll {
+        start_session(mongo, req, session, account, policy).await?;
+        return Ok(HttpResponse::Ok().body("Successfully authenticated."));
+    }
+
+    // Whoever was logged in before is logged out, the new login isn't finished yet
+    session.clear();
+    session.renew();
+    session.insert(PENDING_KEY, PendingLogin {
+        uuid: account.uuid.clone(),
+        enroll,
+        issued: now(),
+    }).map_err(|err| LoginError::Session(err.to_string()))?;
+
+    Ok(HttpResponse::Accepted().json(SecondFactorRequired {
+        second_factor: if enroll { "enroll" } else { "totp" },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_session::SessionExt;
+    use actix_web::test::TestRequest;
+
+    const LIFETIME: Duration = Duration::from_secs(600);
+
+    #[test]
+    fn code_challenge_is_s256() {
+        // RFC 7636 appendix B
+        let (_, mut login) = LoginState::start("github").unwrap();
+        login.code_verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".to_string();
+        assert_eq!(login.code_challenge(), "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM");
+    }
+
+    #[test]
+    fn verifier_is_long_enough() {
+        let (state, login) = LoginState::start("github").unwrap();
+        assert!((43..=128).contains(&login.code_verifier.len()));
+        assert_ne!(login.state_hash, state);
+    }
+
+    #[test]
+    fn accepts_its_own_state() {
+        let (state, login) = LoginState::start("github").unwrap();
+        assert!(login.check(&state, LIFETIME).is_ok());
+    }
+
+    #[test]
+    fn rejects_expired_state() {
+        let (state, mut login) = LoginState::start("github").unwrap();
+        login.issued = now() - LIFETIME.as_secs() - 1;
+        assert!(matches!(login.check(&state, LIFETIME), Err(LoginError::ExpiredState)));
+    }
+
+    #[test]
+    fn rejects_wrong_state() {
+        let (_, login) = LoginState::start("github").unwrap();
+        let (other, _) = LoginState::start("github").unwrap();
+        assert!(matches!(login.check(&other, LIFETIME), Err(LoginError::StateMismatch)));
+        assert!(matches!(login.check("", LIFETIME), Err(LoginError::StateMismatch)));
+    }
+
+    #[test]
+    fn state_can_only_be_taken_once() {
+        let session = TestRequest::default().to_http_request().get_session();
+        let (_, login) = LoginState::start("github").unwrap();
+        login.save(&session).unwrap();
+
+        assert_eq!(LoginState::take(&session).unwrap().provider, "github");
+        assert!(matches!(LoginState::take(&session), Err(LoginError::MissingState)));
+    }
+}
@@ -4,26 +4,198 @@ use actix_web::cookie::Key;
 use actix_web::{web, App, HttpServer};
 use env_logger;
 use mongodb::Client;
+use std::collections::HashMap;
 use std::env;
+use std::sync::Arc;
+use std::time::Duration;
 
+mod elevation;
+mod error;
+mod login;
+mod providers;
 mod routes;
+mod webauthn;
 
+use providers::{GitHub, Gitea, GitLab, Oidc, Provider};
+use user::{create_account_indexes, create_local_indexes, create_profile_indexes, create_token_indexes, create_totp_indexes, find_account_by_uuid, migrate_accounts, record_audit, set_privileges, AuditAction, LockoutPolicy, SessionPolicy, LOCAL_PROVIDER};
+use webauthn::RelyingParty;
 
 #[derive(Clone)]
 pub struct OAuthConfig {
-    pub client_id: String,
-    pub client_secret: String,
     pub redirect_uri: String,
-    pub authorise: String
+    // Keyed by Provider::name, which is also what accounts are keyed by
+    pub providers: HashMap<String, Arc<dyn Provider>>,
+    // Used by /request when no provider is asked for
+    pub default_provider: String,
+    // How long the user has to finish logging in with the provider
+    pub state_lifetime: Duration,
+}
+
+// Username and password accounts, off unless BLOG_LOCAL_ACCOUNTS is "true"
+#[derive(Clone)]
+pub struct LocalConfig {
+    pub enabled: bool,
+    pub registration: bool,
+    pub lockout: LockoutPolicy,
+}
+
+// TOTP, for any account that sets it up, and optionally a must for elevated ones
+#[derive(Clone)]
+pub struct SecondFactorConfig {
+    // Shown next to the code in authenticator apps
+    pub issuer: String,
+    pub required_for_elevated: bool,
+    // How long the user has to enter a code after the first factor
+    pub lifetime: Duration,
+    pub lockout: LockoutPolicy,
+}
+
+/*
+GitHub accounts that become elevated when they log in, by login, user id or organization.
+Lists are comma separated, logins and organizations are matched without case.
+ */
+#[derive(Clone)]
+pub struct ElevationConfig {
+    pub logins: Vec<String>,
+    pub ids: Vec<String>,
+    pub organizations: Vec<String>,
+}
+
+impl ElevationConfig {
+    pub fn is_empty(&self) -> bool {
+        self.logins.is_empty() && self.ids.is_empty() && self.organizations.is_empty()
+    }
+}
+
+fn env_list(name: &str) -> Vec<String> {
+    env::var(name)
+        .map(|list| list.split(',')
+            .map(|item| item.trim().to_lowercase())
+            .filter(|item| !item.is_empty())
+            .collect())
+        .unwrap_or_default()
+}
+
+fn required_env(name: &str) -> String {
+    env::var(name)
+        .map_err(|err| {
+            eprintln!("Error fetching {} from env variables: {}", name, err);
+            std::process::exit(1);
+        })
+        .unwrap()
 }
 
 #[actix_web::main]
 pub async fn main() -> std::io::Result<()> {
+    let elevation_config = ElevationConfig {
+        logins: env_list("BLOG_ELEVATE_GITHUB_LOGINS"),
+        ids: env_list("BLOG_ELEVATE_GITHUB_IDS"),
+        organizations: env_list("BLOG_ELEVATE_GITHUB_ORGS"),
+    };
+
+    // A provider is turned on by setting its client id
+    let mut providers: Vec<Arc<dyn Provider>> = Vec::new();
+    if let Ok(client_id) = env::var("BLOG_CLIENT_ID") {
+        providers.push(Arc::new(GitHub::new(client_id, required_env("BLOG_CLIENT_SECRET"), !elevation_config.organizations.is_empty())));
+    }
+    if let Ok(client_id) = env::var("BLOG_GITLAB_CLIENT_ID") {
+        providers.push(Arc::new(GitLab::new(
+            env::var("BLOG_GITLAB_URL").unwrap_or_else(|_| "https://gitlab.com".into()),
+            client_id,
+            required_env("BLOG_GITLAB_CLIENT_SECRET"),
+        )));
+    }
+    if let Ok(client_id) = env::var("BLOG_GITEA_CLIENT_ID") {
+        providers.push(Arc::new(Gitea::new(required_env("BLOG_GITEA_URL"), client_id, required_env("BLOG_GITEA_CLIENT_SECRET"))));
+    }
+    if let Ok(issuer) = env::var("BLOG_OIDC_ISSUER") {
+        let name = env::var("BLOG_OIDC_NAME").unwrap_or_else(|_| "oidc".into());
+        // Local accounts are keyed under that name already
+        if name == LOCAL_PROVIDER {
+            eprintln!("BLOG_OIDC_NAME can't be {}", LOCAL_PROVIDER);
+            std::process::exit(1);
+        }
+        let oidc = Oidc::discover(
+            &reqwest::Client::new(),
+            name,
+            &issuer,
+            required_env("BLOG_OIDC_CLIENT_ID"),
+            required_env("BLOG_OIDC_CLIENT_SECRET"),
+        ).await
+            .map_err(|err| {
+                eprintln!("Error discovering OpenID Connect provider {}: {:?}", issuer, err);
+                std::process::exit(1);
+            })
+            .unwrap();
+        providers.push(Arc::new(oidc));
+    }
+
+    // Shared by passwords and TOTP codes
+    let lockout = LockoutPolicy {
+        max_attempts: env::var("BLOG_LOGIN_MAX_ATTEMPTS").ok().and_then(|attempts| attempts.parse().ok()).unwrap_or(5),
+        lockout: Duration::from_secs(env::var("BLOG_LOGIN_LOCKOUT_MINUTES").ok()
+            .and_then(|minutes| minutes.parse::<u64>().ok())
+            .unwrap_or(15) * 60),
+    };
+
+    let elevation_config_data = web::Data::new(elevation_config);
+
+    let local_config_data = web::Data::new(LocalConfig {
+        enabled: env::var("BLOG_LOCAL_ACCOUNTS").map(|enabled| enabled == "true").unwrap_or(false),
+        registration: env::var("BLOG_LOCAL_REGISTRATION").map(|enabled| enabled == "true").unwrap_or(false),
+        lockout: lockout.clone(),
+    });
+
+    let second_factor_config_data = web::Data::new(SecondFactorConfig {
+        issuer: env::var("BLOG_TOTP_ISSUER").unwrap_or_else(|_| "Blog".into()),
+        required_for_elevated: env::var("BLOG_2FA_REQUIRED_FOR_ELEVATED").map(|required| required == "true").unwrap_or(false),
+        lifetime: Duration::from_secs(env::var("BLOG_2FA_TIMEOUT_SECONDS").ok()
+            .and_then(|seconds| seconds.parse().ok())
+            .unwrap_or(5 * 60)),
+        lockout,
+    });
+
+    if providers.is_empty() && !local_config_data.enabled {
+        eprintln!("No way to log in configured, set BLOG_CLIENT_ID for GitHub or BLOG_LOCAL_ACCOUNTS=true");
+        std::process::exit(1);
+    }
+
+    // Passkeys only work on the origin they were made for, so they are off until it is set
+    let relying_party_data = web::Data::new(env::var("BLOG_WEBAUTHN_ORIGIN").ok().map(|origin| {
+        RelyingParty::new(
+            origin,
+            env::var("BLOG_WEBAUTHN_RP_ID").ok(),
+            env::var("BLOG_WEBAUTHN_RP_NAME").unwrap_or_else(|_| "Blog".into()),
+            Duration::from_secs(env::var("BLOG_WEBAUTHN_TIMEOUT_SECONDS").ok()
+                .and_then(|seconds| seconds.parse().ok())
+                .unwrap_or(5 * 60)),
+        )
+            .map_err(|err| {
+                eprintln!("Error configuring passkeys: {}", err);
+                std::process::exit(1);
+            })
+            .unwrap()
+    }));
+
+    // Fixed on each session as it is made, the blog and cdn enforce them without needing the config
+    let session_policy_data = web::Data::new(SessionPolicy {
+        idle: Duration::from_secs(env::var("BLOG_SESSION_IDLE_HOURS").ok()
+            .and_then(|hours| hours.parse::<u64>().ok())
+            .unwrap_or(7 * 24) * 60 * 60),
+        absolute: Duration::from_secs(env::var("BLOG_SESSION_MAX_DAYS").ok()
+            .and_then(|days| days.parse::<u64>().ok())
+            .unwrap_or(30) * 24 * 60 * 60),
+    });
+
     let oauth_config_data = web::Data::new(OAuthConfig {
-        client_id: env::var("BLOG_CLIENT_ID").unwrap().to_string(),
-        client_secret: env::var("BLOG_CLIENT_SECRET").unwrap().to_string(),
         redirect_uri: env::var("BLOG_REDIRECT_URI").unwrap_or_else(|_| "http://127.0.0.1:3001/callback".into()).to_string(),
-        authorise: "https://github.com/login/oauth/authorize?client_id={client_id}&state={state}&redirect_uri={redirect_uri}".to_string()
+        default_provider: env::var("BLOG_DEFAULT_PROVIDER").unwrap_or_else(|_| providers.first().map(|provider| provider.name().to_string()).unwrap_or_default()),
+        state_lifetime: Duration::from_secs(env::var("BLOG_OAUTH_STATE_LIFETIME_SECONDS").ok()
+            .and_then(|seconds| seconds.parse().ok())
+            .unwrap_or(10 * 60)),
+        providers: providers.into_iter()
+            .map(|provider| (provider.name().to_string(), provider))
+            .collect(),
     });
 
     let secret_key = Key::from(env::var("BLOG_SECRET_KEY")
@@ -42,6 +214,64 @@ pub async fn main() -> std::io::Result<()> {
     let uri = env::var("BLOG_MONGODB_URI").unwrap_or_else(|_| "mongodb://localhost:27017".into());
     let client = Client::with_uri_str(uri).await.expect("failed to connect");
 
+    // Before the unique index, which legacy accounts without a provider would all collide on
+    match migrate_accounts(&client).await {
+        Ok(0) => {},
+        Ok(migrated) => println!("Migrated {} GitHub accounts to provider and subject", migrated),
+        Err(err) => {
+            eprintln!("Error migrating accounts: {}", err);
+            std::process::exit(1);
+        }
+    }
+
+    if let Err(err) = create_account_indexes(&client).await {
+        eprintln!("Error creating account indexes: {}", err);
+        std::process::exit(1);
+    }
+
+    if local_config_data.enabled {
+        if let Err(err) = create_local_indexes(&client).await {
+            eprintln!("Error creating local account indexes: {}", err);
+            std::process::exit(1);
+        }
+    }
+
+    if let Err(err) = create_token_indexes(&client).await {
+        eprintln!("Error creating API token indexes: {}", err);
+        std::process::exit(1);
+    }
+
+    if let Err(err) = create_profile_indexes(&client).await {
+        eprintln!("Error creating profile indexes: {}", err);
+        std::process::exit(1);
+    }
+
+    if let Err(err) = create_totp_indexes(&client).await {
+        eprintln!("Error creating TOTP indexes: {}", err);
+        std::process::exit(1);
+    }
+
+    // The first admins can't be made through the API, so they come from a comma separated list of account uuids
+    if let Ok(admins) = env::var("BLOG_ADMIN_ACCOUNTS") {
+        for uuid in admins.split(',').map(str::trim).filter(|uuid| !uuid.is_empty()) {
+            match find_account_by_uuid(&client, uuid).await {
+                Ok(Some(account)) if account.admin => {},
+                Ok(Some(_)) => {
+                    if let Err(err) = set_privileges(&client, uuid, None, Some(true)).await {
+                        eprintln!("Error making account {} an admin: {}", uuid, err);
+                        continue;
+                    }
+                    if let Err(err) = record_audit(&client, "BLOG_ADMIN_ACCOUNTS", uuid, AuditAction::SetAdmin { admin: true }).await {
+                        eprintln!("Error recording admin of account {}: {}", uuid, err);
+                    }
+                    println!("Made account {} an admin", uuid);
+                }
+                Ok(None) => eprintln!("Admin account {} not found", uuid),
+                Err(err) => eprintln!("Error finding admin account {}: {}", uuid, err),
+            }
+        }
+    }
+
 	HttpServer::new(move || {
         App::new()
             .wrap(
@@ -52,6 +282,11 @@ pub async fn main() -> std::io::Result<()> {
             )
             .app_data(web::Data::new(client.clone()))
             .app_data(oauth_config_data.clone())
+            .app_data(local_config_data.clone())
+            .app_data(relying_party_data.clone())
+            .app_data(second_factor_config_data.clone())
+            .app_data(session_policy_data.clone())
+            .app_data(elevation_config_data.clone())
             .configure(routes::init)
     })
     .bind(("127.0.0.1", 3001))?
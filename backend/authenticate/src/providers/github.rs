@@ -0,0 +1,90 @@
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use super::{exchange_code, get_json, non_empty, url_with_params, Provider, ProviderError, ProviderUser, Tokens};
+
+pub struct GitHub {
+    client_id: String,
+    client_secret: String,
+    // Private organization memberships can only be seen with the read:org scope
+    read_org: bool,
+}
+
+impl GitHub {
+    pub fn new(client_id: String, client_secret: String, read_org: bool) -> GitHub {
+        GitHub { client_id, client_secret, read_org }
+    }
+}
+
+// Option<String> is used to represent that it can be either string or null
+#[derive(Deserialize)]
+struct UserResponse {
+    login: Option<String>,
+    id: u64,
+    avatar_url: Option<String>,
+    html_url: Option<String>,
+    name: Option<String>,
+    email: Option<String>,
+    bio: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MembershipResponse {
+    // "pending" until the user accepts the invitation
+    state: String,
+}
+
+#[async_trait]
+impl Provider for GitHub {
+    fn name(&self) -> &str {
+        "github"
+    }
+
+    fn authorize_url(&self, state: &str, _nonce: &str, code_challenge: &str, redirect_uri: &str) -> String {
+        let mut params = vec![
+            ("client_id", self.client_id.as_str()),
+            ("state", state),
+            ("code_challenge", code_challenge),
+            ("code_challenge_method", "S256"),
+            ("redirect_uri", redirect_uri),
+        ];
+        if self.read_org {
+            params.push(("scope", "read:org"));
+        }
+        url_with_params("https://github.com/login/oauth/authorize", &params)
+    }
+
+    async fn exchange(&self, client: &Client, code: &str, code_verifier: &str, redirect_uri: &str) -> Result<Tokens, ProviderError> {
+        exchange_code(client, "https://github.com/login/oauth/access_token", &[
+            ("client_id", &self.client_id),
+            ("client_secret", &self.client_secret),
+            ("code", code),
+            ("code_verifier", code_verifier),
+            ("redirect_uri", redirect_uri),
+        ]).await
+    }
+
+    async fn user(&self, client: &Client, tokens: &Tokens, _nonce: &str) -> Result<ProviderUser, ProviderError> {
+        let user: UserResponse = get_json(client, "https://api.github.com/user", Some(&tokens.access_token)).await?;
+
+        Ok(ProviderUser {
+            subject: user.id.to_string(),
+            login: user.login,
+            name: non_empty(user.name),
+            email: non_empty(user.email),
+            avatar_url: non_empty(user.avatar_url),
+            url: non_empty(user.html_url),
+            bio: non_empty(user.bio),
+        })
+    }
+
+    async fn member_of(&self, client: &Client, tokens: &Tokens, organization: &str) -> Result<bool, ProviderError> {
+        let url = format!("https://api.github.com/user/memberships/orgs/{}", organization);
+        match get_json::<MembershipResponse>(client, &url, Some(&tokens.access_token)).await {
+            Ok(membership) => Ok(membership.state == "active"),
+            // Not a member. A 403 (missing scope, or an organization that restricts apps) says nothing either way, so it stays an error
+            Err(ProviderError::RequestError(err)) if err.status() == Some(StatusCode::NOT_FOUND) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::Deserialize;
+use super::{exchange_code, get_json, non_empty, url_with_params, Provider, ProviderError, ProviderUser, Tokens};
+
+// gitlab.com or a self-hosted instance
+pub struct GitLab {
+    base_url: String,
+    client_id: String,
+    client_secret: String,
+}
+
+impl GitLab {
+    pub fn new(base_url: String, client_id: String, client_secret: String) -> GitLab {
+        GitLab { base_url: base_url.trim_end_matches('/').to_string(), client_id, client_secret }
+    }
+}
+
+#[derive(Deserialize)]
+struct UserResponse {
+    id: u64,
+    username: Option<String>,
+    name: Option<String>,
+    email: Option<String>,
+    avatar_url: Option<String>,
+    web_url: Option<String>,
+    bio: Option<String>,
+}
+
+#[async_trait]
+impl Provider for GitLab {
+    fn name(&self) -> &str {
+        "gitlab"
+    }
+
+    fn authorize_url(&self, state: &str, _nonce: &str, code_challenge: &str, redirect_uri: &str) -> String {
+        url_with_params(&format!("{}/oauth/authorize", self.base_url), &[
+            ("client_id", &self.client_id),
+            ("redirect_uri", redirect_uri),
+            ("response_type", "code"),
+            ("scope", "read_user"),
+            ("state", state),
+            ("code_challenge", code_challenge),
+            ("code_challenge_method", "S256"),
+        ])
+    }
+
+    async fn exchange(&self, client: &Client, code: &str, code_verifier: &str, redirect_uri: &str) -> Result<Tokens, ProviderError> {
+        exchange_code(client, &format!("{}/oauth/token", self.base_url), &[
+            ("client_id", &self.client_id),
+            ("client_secret", &self.client_secret),
+            ("code", code),
+            ("code_verifier", code_verifier),
+            ("grant_type", "authorization_code"),
+            ("redirect_uri", redirect_uri),
+        ]).await
+    }
+
+    async fn user(&self, client: &Client, tokens: &Tokens, _nonce: &str) -> Result<ProviderUser, ProviderError> {
+        let user: UserResponse = get_json(client, &format!("{}/api/v4/user", self.base_url), Some(&tokens.access_token)).await?;
+
+        Ok(ProviderUser {
+            subject: user.id.to_string(),
+            login: user.username,
+            name: non_empty(user.name),
+            email: non_empty(user.email),
+            avatar_url: non_empty(user.avatar_url),
+            url: non_empty(user.web_url),
+            bio: non_empty(user.bio),
+        })
+    }
+}
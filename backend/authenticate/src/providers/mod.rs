@@ -0,0 +1,150 @@
+use async_trait::async_trait;
+use reqwest::{Client, Url};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::from_str;
+
+mod github;
+mod gitea;
+mod gitlab;
+mod oidc;
+
+pub use github::GitHub;
+pub use gitea::Gitea;
+pub use gitlab::GitLab;
+pub use oidc::Oidc;
+
+// Some providers (GitHub) refuse API requests without one
+const USER_AGENT: &str = "Danielle's Blog API";
+
+#[derive(Debug)]
+pub enum ProviderError {
+    RequestError(reqwest::Error),
+    DeserializationError(serde_json::Error),
+    // The provider refused the code, or sent back a token that doesn't check out
+    TokenError(String),
+}
+
+pub struct Tokens {
+    pub access_token: String,
+    // Only sent by OpenID Connect providers
+    pub id_token: Option<String>,
+}
+
+// What every provider can tell us about a user, whatever shape its API returns it in
+#[derive(Debug)]
+pub struct ProviderUser {
+    // The id the provider knows the user by, unlike the login this never changes
+    pub subject: String,
+    pub login: Option<String>,
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub avatar_url: Option<String>,
+    pub url: Option<String>,
+    pub bio: Option<String>,
+}
+
+/*
+An OAuth provider accounts can log in through. The flow is the same for all of them:
+send the user to authorize_url, exchange the code they come back with, then fetch who they are.
+ */
+#[async_trait]
+pub trait Provider: Send + Sync {
+    // Accounts are keyed by this, so it must not change once accounts exist
+    fn name(&self) -> &str;
+
+    // The nonce is only used by OpenID Connect, to tie the ID token to this login
+    fn authorize_url(&self, state: &str, nonce: &str, code_challenge: &str, redirect_uri: &str) -> String;
+
+    // The verifier proves this is the same client that asked for the code (PKCE)
+    async fn exchange(&self, client: &Client, code: &str, code_verifier: &str, redirect_uri: &str) -> Result<Tokens, ProviderError>;
+
+    async fn user(&self, client: &Client, tokens: &Tokens, nonce: &str) -> Result<ProviderUser, ProviderError>;
+
+    // Only GitHub has organizations we check, everyone else is in none
+    async fn member_of(&self, _client: &Client, _tokens: &Tokens, _organization: &str) -> Result<bool, ProviderError> {
+        Ok(false)
+    }
+}
+
+pub fn url_with_params(base: &str, params: &[(&str, &str)]) -> String {
+    match Url::parse_with_params(base, params) {
+        Ok(url) => url.to_string(),
+        Err(err) => {
+            eprintln!("Invalid authorize URL {}: {}", base, err);
+            base.to_string()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+    id_token: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/*
+The standard authorization code exchange, with the client credentials in the body rather than the URL.
+GitHub answers errors with a 200, so the body is checked instead of the status.
+ */
+pub async fn exchange_code(client: &Client, token_url: &str, params: &[(&str, &str)]) -> Result<Tokens, ProviderError> {
+    let res = match client.post(token_url)
+        .header("Accept", "application/json")
+        .form(params)
+        .send()
+        .await {
+        Ok(res) => res,
+        Err(err) => return Err(ProviderError::RequestError(err)),
+    };
+
+    let text = match res.text().await {
+        Ok(text) => text,
+        Err(err) => return Err(ProviderError::RequestError(err)),
+    };
+
+    let token: TokenResponse = match from_str(&text) {
+        Ok(token) => token,
+        Err(err) => return Err(ProviderError::DeserializationError(err)),
+    };
+
+    match token.access_token {
+        Some(access_token) => Ok(Tokens { access_token, id_token: token.id_token }),
+        None => Err(ProviderError::TokenError(token.error_description
+            .or(token.error)
+            .unwrap_or_else(|| "No access token in response".to_string()))),
+    }
+}
+
+pub async fn get_json<T: DeserializeOwned>(client: &Client, url: &str, access_token: Option<&str>) -> Result<T, ProviderError> {
+    let mut req = client.get(url)
+        .header("User-Agent", USER_AGENT)
+        .header("Accept", "application/json");
+    if let Some(access_token) = access_token {
+        req = req.bearer_auth(access_token);
+    }
+
+    let res = match req.send().await.and_then(|res| res.error_for_status()) {
+        Ok(res) => res,
+        Err(err) => return Err(ProviderError::RequestError(err)),
+    };
+
+    let text = match res.text().await {
+        Ok(text) => text,
+        Err(err) => return Err(ProviderError::RequestError(err)),
+    };
+
+    match from_str(&text) {
+        Ok(value) => Ok(value),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            Err(ProviderError::DeserializationError(err))
+        }
+    }
+}
+
+// Empty strings mean "not set" for most providers, which is the same as leaving the field out
+fn non_empty(value: Option<String>) -> Option<String> {
+    value.filter(|value| !value.is_empty())
+}
@@ -0,0 +1,327 @@
+use async_trait::async_trait;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use reqwest::Client;
+use serde::Deserialize;
+use super::{exchange_code, get_json, non_empty, url_with_params, Provider, ProviderError, ProviderUser, Tokens};
+
+// Any OpenID Connect provider, set up from its discovery document
+pub struct Oidc {
+    name: String,
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+    jwks_uri: String,
+}
+
+// The standard claims, both in the ID token and from the userinfo endpoint
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    nonce: Option<String>,
+    preferred_username: Option<String>,
+    name: Option<String>,
+    email: Option<String>,
+    picture: Option<String>,
+    profile: Option<String>,
+}
+
+impl Oidc {
+    pub async fn discover(client: &Client, name: String, issuer: &str, client_id: String, client_secret: String) -> Result<Oidc, ProviderError> {
+        let issuer = issuer.trim_end_matches('/');
+        let discovery: Discovery = get_json(client, &format!("{}/.well-known/openid-configuration", issuer), None).await?;
+
+        // Otherwise one provider could hand out tokens in the name of another
+        if discovery.issuer.trim_end_matches('/') != issuer {
+            return Err(ProviderError::TokenError(format!("Discovery document is for issuer {}", discovery.issuer)));
+        }
+
+        Ok(Oidc {
+            name,
+            issuer: discovery.issuer,
+            client_id,
+            client_secret,
+            authorization_endpoint: discovery.authorization_endpoint,
+            token_endpoint: discovery.token_endpoint,
+            userinfo_endpoint: discovery.userinfo_endpoint,
+            jwks_uri: discovery.jwks_uri,
+        })
+    }
+
+    // Checks the ID token against the provider's published keys
+    async fn validate(&self, client: &Client, id_token: &str, nonce: &str) -> Result<Claims, ProviderError> {
+        // Fetched every time so rotated keys are picked up, logins are rare enough for it not to matter
+        let jwks: JwkSet = get_json(client, &self.jwks_uri, None).await?;
+        self.validate_with(&jwks, id_token, nonce)
+    }
+
+    // Checks the signature, then the issuer, audience, expiry and nonce
+    fn validate_with(&self, jwks: &JwkSet, id_token: &str, nonce: &str) -> Result<Claims, ProviderError> {
+        let header = match decode_header(id_token) {
+            Ok(header) => header,
+            Err(err) => return Err(ProviderError::TokenError(format!("Invalid ID token: {}", err))),
+        };
+
+        // Those would be signed with our own client secret, which anyone holding it could forge
+        if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            return Err(ProviderError::TokenError("ID tokens signed with the client secret are not accepted".to_string()));
+        }
+
+        let jwk = match &header.kid {
+            Some(kid) => jwks.find(kid),
+            None if jwks.keys.len() == 1 => jwks.keys.first(),
+            None => None,
+        };
+        let key = match jwk.map(DecodingKey::from_jwk) {
+            Some(Ok(key)) => key,
+            Some(Err(err)) => return Err(ProviderError::TokenError(format!("Unusable signing key: {}", err))),
+            None => return Err(ProviderError::TokenError("No signing key found for ID token".to_string())),
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&self.client_id]);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        let claims: Claims = match decode(id_token, &key, &validation) {
+            Ok(token) => token.claims,
+            Err(err) => return Err(ProviderError::TokenError(format!("Invalid ID token: {}", err))),
+        };
+
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(ProviderError::TokenError("ID token nonce does not match".to_string()));
+        }
+        Ok(claims)
+    }
+}
+
+// The ID token may only carry the subject, the rest of the profile is filled in from userinfo
+fn with_userinfo(claims: Claims, userinfo: Claims) -> Result<Claims, ProviderError> {
+    if userinfo.sub != claims.sub {
+        return Err(ProviderError::TokenError("Userinfo is for a different subject".to_string()));
+    }
+    Ok(Claims {
+        sub: claims.sub,
+        nonce: claims.nonce,
+        preferred_username: userinfo.preferred_username.or(claims.preferred_username),
+        name: userinfo.name.or(claims.name),
+        email: userinfo.email.or(claims.email),
+        picture: userinfo.picture.or(claims.picture),
+        profile: userinfo.profile.or(claims.profile),
+    })
+}
+
+#[async_trait]
+impl Provider for Oidc {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn authorize_url(&self, state: &str, nonce: &str, code_challenge: &str, redirect_uri: &str) -> String {
+        url_with_params(&self.authorization_endpoint, &[
+            ("client_id", &self.client_id),
+            ("redirect_uri", redirect_uri),
+            ("response_type", "code"),
+            ("scope", "openid profile email"),
+            ("state", state),
+            ("code_challenge", code_challenge),
+            ("code_challenge_method", "S256"),
+            ("nonce", nonce),
+        ])
+    }
+
+    async fn exchange(&self, client: &Client, code: &str, code_verifier: &str, redirect_uri: &str) -> Result<Tokens, ProviderError> {
+        exchange_code(client, &self.token_endpoint, &[
+            ("client_id", &self.client_id),
+            ("client_secret", &self.client_secret),
+            ("code", code),
+            ("code_verifier", code_verifier),
+            ("grant_type", "authorization_code"),
+            ("redirect_uri", redirect_uri),
+        ]).await
+    }
+
+    async fn user(&self, client: &Client, tokens: &Tokens, nonce: &str) -> Result<ProviderUser, ProviderError> {
+        let id_token = match &tokens.id_token {
+            Some(id_token) => id_token,
+            None => return Err(ProviderError::TokenError("No ID token in response".to_string())),
+        };
+        let mut claims = self.validate(client, id_token, nonce).await?;
+
+        if let Some(userinfo_endpoint) = &self.userinfo_endpoint {
+            let userinfo: Claims = get_json(client, userinfo_endpoint, Some(&tokens.access_token)).await?;
+            claims = with_userinfo(claims, userinfo)?;
+        }
+
+        Ok(ProviderUser {
+            subject: claims.sub,
+            login: non_empty(claims.preferred_username),
+            name: non_empty(claims.name),
+            email: non_empty(claims.email),
+            avatar_url: non_empty(claims.picture),
+            url: non_empty(claims.profile),
+            bio: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use serde_json::{json, Value};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const ISSUER: &str = "https://id.example.com";
+    const CLIENT_ID: &str = "blog";
+    const NONCE: &str = "nonce";
+
+    fn provider() -> Oidc {
+        Oidc {
+            name: "example".to_string(),
+            issuer: ISSUER.to_string(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: "secret".to_string(),
+            authorization_endpoint: format!("{}/authorize", ISSUER),
+            token_endpoint: format!("{}/token", ISSUER),
+            userinfo_endpoint: None,
+            jwks_uri: format!("{}/jwks", ISSUER),
+        }
+    }
+
+    // A freshly made P-256 key, and the key set publishing it
+    fn signing_key() -> (EncodingKey, JwkSet) {
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new()).unwrap();
+        let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &SystemRandom::new()).unwrap();
+        // Uncompressed point, 0x04 || x || y
+        let point = key_pair.public_key().as_ref();
+        let jwks = json!({"keys": [{
+            "kty": "EC",
+            "crv": "P-256",
+            "kid": "key",
+            "alg": "ES256",
+            "use": "sig",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+        }]});
+        (EncodingKey::from_ec_der(pkcs8.as_ref()), serde_json::from_value(jwks).unwrap())
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn claims() -> Value {
+        json!({"iss": ISSUER, "aud": CLIENT_ID, "sub": "1234", "exp": now() + 300, "iat": now(), "nonce": NONCE})
+    }
+
+    fn sign(key: &EncodingKey, claims: &Value) -> String {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some("key".to_string());
+        encode(&header, claims, key).unwrap()
+    }
+
+    fn validate(changes: Value) -> Result<Claims, ProviderError> {
+        let (key, jwks) = signing_key();
+        let mut claims = claims();
+        for (name, value) in changes.as_object().unwrap() {
+            match value {
+                Value::Null => { claims.as_object_mut().unwrap().remove(name); }
+                value => { claims[name] = value.clone(); }
+            }
+        }
+        provider().validate_with(&jwks, &sign(&key, &claims), NONCE)
+    }
+
+    fn error(result: Result<Claims, ProviderError>) -> String {
+        match result {
+            Err(ProviderError::TokenError(error)) => error,
+            Err(_) => "wrong error".to_string(),
+            Ok(_) => "accepted".to_string(),
+        }
+    }
+
+    #[test]
+    fn accepts_valid_token() {
+        assert_eq!(validate(json!({})).unwrap().sub, "1234");
+    }
+
+    #[test]
+    fn rejects_wrong_audience() {
+        assert!(error(validate(json!({"aud": "another client"}))).contains("InvalidAudience"));
+    }
+
+    #[test]
+    fn rejects_wrong_issuer() {
+        assert!(error(validate(json!({"iss": "https://evil.example.com"}))).contains("InvalidIssuer"));
+    }
+
+    #[test]
+    fn rejects_expired_token() {
+        // Past the default minute of leeway
+        assert!(error(validate(json!({"exp": now() - 120}))).contains("ExpiredSignature"));
+    }
+
+    #[test]
+    fn rejects_wrong_or_missing_nonce() {
+        assert_eq!(error(validate(json!({"nonce": "another nonce"}))), "ID token nonce does not match");
+        assert_eq!(error(validate(json!({"nonce": null}))), "ID token nonce does not match");
+    }
+
+    #[test]
+    fn rejects_client_secret_tokens() {
+        let (_, jwks) = signing_key();
+        let token = encode(&Header::new(Algorithm::HS256), &claims(), &EncodingKey::from_secret(b"secret")).unwrap();
+        assert_eq!(error(provider().validate_with(&jwks, &token, NONCE)), "ID tokens signed with the client secret are not accepted");
+    }
+
+    #[test]
+    fn rejects_other_keys() {
+        let (key, _) = signing_key();
+        let (_, jwks) = signing_key();
+        assert!(error(provider().validate_with(&jwks, &sign(&key, &claims()), NONCE)).contains("InvalidSignature"));
+    }
+
+    fn userinfo(sub: &str) -> Claims {
+        Claims {
+            sub: sub.to_string(),
+            nonce: None,
+            preferred_username: Some("ann".to_string()),
+            name: None,
+            email: Some("ann@example.com".to_string()),
+            picture: None,
+            profile: None,
+        }
+    }
+
+    #[test]
+    fn fills_in_from_userinfo() {
+        let claims = validate(json!({"name": "Ann"})).unwrap();
+        let merged = with_userinfo(claims, userinfo("1234")).unwrap();
+        assert_eq!(merged.preferred_username.as_deref(), Some("ann"));
+        assert_eq!(merged.name.as_deref(), Some("Ann"));
+        assert_eq!(merged.email.as_deref(), Some("ann@example.com"));
+    }
+
+    #[test]
+    fn rejects_userinfo_for_another_subject() {
+        let claims = validate(json!({})).unwrap();
+        assert!(matches!(with_userinfo(claims, userinfo("5678")), Err(ProviderError::TokenError(_))));
+    }
+}
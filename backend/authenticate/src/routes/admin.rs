@@ -0,0 +1,274 @@
+use actix_session::Session;
+use actix_web::{web::{self}, HttpResponse};
+use mongodb::bson::{doc, DateTime, Document};
+use serde::{Deserialize, Serialize};
+use user::{active_sessions, find_account_by_uuid, find_audit_entries, record_audit, search_accounts, set_privileges, set_status, Account, AccountStatus, AuditAction};
+use crate::error::LoginError;
+use crate::login::logged_in;
+use crate::routes::sessions::SessionInfo;
+
+/*
+Account management for admins. Every change is written to the audit log
+with the admin who made it, and an admin can't change their own account here.
+ */
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 200;
+
+#[derive(Deserialize)]
+pub struct AccountSearch {
+    query: Option<String>,
+    page: Option<u64>,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct AuditSearch {
+    target: Option<String>,
+    page: Option<u64>,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct Privileges {
+    elevated: Option<bool>,
+    admin: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct Suspension {
+    // Until lifted by hand when left out
+    days: Option<u32>,
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Ban {
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct AccountSummary {
+    uuid: String,
+    name: Option<String>,
+    provider: String,
+    subject: String,
+    email: Option<String>,
+    elevated: bool,
+    admin: bool,
+    status: AccountStatus,
+    suspended_until: Option<DateTime>,
+    status_reason: Option<String>,
+    active_sessions: usize,
+    posts: u64,
+}
+
+#[derive(Serialize)]
+pub struct AccountDetails {
+    account: AccountSummary,
+    sessions: Vec<SessionInfo>,
+}
+
+async fn admin_account(mongo: &mongodb::Client, session: &Session) -> Result<Account, LoginError> {
+    let account = logged_in(mongo, session).await?;
+    if !account.admin {
+        return Err(LoginError::NotAdmin);
+    }
+    Ok(account)
+}
+
+// Someone else's account, so no admin can lock themselves out
+fn check_target(admin: &str, target: &str) -> Result<(), LoginError> {
+    if admin == target {
+        return Err(LoginError::InvalidInput("Admins can't change their own account".to_string()));
+    }
+    Ok(())
+}
+
+async fn target_account(mongo: &mongodb::Client, admin: &Account, uuid: &str) -> Result<Account, LoginError> {
+    check_target(&admin.uuid, uuid)?;
+    match find_account_by_uuid(mongo, uuid).await {
+        Ok(Some(account)) => Ok(account),
+        Ok(None) => Err(LoginError::AccountNotFound),
+        Err(err) => Err(LoginError::Database(err)),
+    }
+}
+
+// Posts belong to the blog service, this only counts them
+async fn post_count(mongo: &mongodb::Client, uuid: &str) -> Result<u64, LoginError> {
+    mongo.database("blog").collection::<Document>("posts")
+        .count_documents(doc! {"creator": uuid})
+        .await
+        .map_err(LoginError::Database)
+}
+
+async fn summary(mongo: &mongodb::Client, account: &Account) -> Result<AccountSummary, LoginError> {
+    Ok(AccountSummary {
+        uuid: account.uuid.clone(),
+        name: account.name.clone(),
+        provider: account.provider.clone(),
+        subject: account.subject.clone(),
+        email: account.email.clone(),
+        elevated: account.elevated,
+        admin: account.admin,
+        status: account.status,
+        suspended_until: account.suspended_until,
+        status_reason: account.status_reason.clone(),
+        active_sessions: active_sessions(account).len(),
+        posts: post_count(mongo, &account.uuid).await?,
+    })
+}
+
+// Saturating, as a page far past the end should just come back empty
+fn page(page: Option<u64>, limit: Option<i64>) -> (u64, i64) {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    (page.unwrap_or(0).saturating_mul(limit as u64), limit)
+}
+
+// The new value of a privilege if it is really changing, so only changes go in the log
+fn changed(requested: Option<bool>, current: bool) -> Option<bool> {
+    requested.filter(|requested| *requested != current)
+}
+
+pub async fn list(session: Session, mongo: web::Data<mongodb::Client>, query: web::Query<AccountSearch>) -> Result<HttpResponse, LoginError> {
+    admin_account(mongo.get_ref(), &session).await?;
+
+    let (skip, limit) = page(query.page, query.limit);
+    let accounts = search_accounts(mongo.get_ref(), query.query.as_deref(), skip, limit).await
+        .map_err(LoginError::Database)?;
+
+    let mut summaries = Vec::with_capacity(accounts.len());
+    for account in &accounts {
+        summaries.push(summary(mongo.get_ref(), account).await?);
+    }
+    Ok(HttpResponse::Ok().json(summaries))
+}
+
+pub async fn get(session: Session, mongo: web::Data<mongodb::Client>, path: web::Path<String>) -> Result<HttpResponse, LoginError> {
+    admin_account(mongo.get_ref(), &session).await?;
+
+    let account = match find_account_by_uuid(mongo.get_ref(), &path.into_inner()).await {
+        Ok(Some(account)) => account,
+        Ok(None) => return Err(LoginError::AccountNotFound),
+        Err(err) => return Err(LoginError::Database(err)),
+    };
+
+    Ok(HttpResponse::Ok().json(AccountDetails {
+        account: summary(mongo.get_ref(), &account).await?,
+        sessions: active_sessions(&account).into_iter()
+            .map(|stored| SessionInfo::new(stored, false))
+            .collect(),
+    }))
+}
+
+pub async fn privileges(session: Session, mongo: web::Data<mongodb::Client>, path: web::Path<String>, body: web::Json<Privileges>) -> Result<HttpResponse, LoginError> {
+    let admin = admin_account(mongo.get_ref(), &session).await?;
+    let target = target_account(mongo.get_ref(), &admin, &path.into_inner()).await?;
+
+    set_privileges(mongo.get_ref(), &target.uuid, body.elevated, body.admin).await
+        .map_err(LoginError::Database)?;
+
+    if let Some(elevated) = changed(body.elevated, target.elevated) {
+        record_audit(mongo.get_ref(), &admin.uuid, &target.uuid, AuditAction::SetElevated { elevated }).await
+            .map_err(LoginError::Database)?;
+        println!("Admin {} set elevated of account {} to {}", admin.uuid, target.uuid, elevated);
+    }
+    if let Some(is_admin) = changed(body.admin, target.admin) {
+        record_audit(mongo.get_ref(), &admin.uuid, &target.uuid, AuditAction::SetAdmin { admin: is_admin }).await
+            .map_err(LoginError::Database)?;
+        println!("Admin {} set admin of account {} to {}", admin.uuid, target.uuid, is_admin);
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn suspend(session: Session, mongo: web::Data<mongodb::Client>, path: web::Path<String>, body: web::Json<Suspension>) -> Result<HttpResponse, LoginError> {
+    let admin = admin_account(mongo.get_ref(), &session).await?;
+    let target = target_account(mongo.get_ref(), &admin, &path.into_inner()).await?;
+    let body = body.into_inner();
+
+    let until = match body.days {
+        Some(0) => return Err(LoginError::InvalidInput("A suspension lasts at least a day".to_string())),
+        Some(days) => Some(DateTime::from_millis(DateTime::now().timestamp_millis() + days as i64 * 24 * 60 * 60 * 1000)),
+        None => None,
+    };
+
+    set_status(mongo.get_ref(), &target.uuid, AccountStatus::Suspended, until, body.reason.clone()).await
+        .map_err(LoginError::Database)?;
+    record_audit(mongo.get_ref(), &admin.uuid, &target.uuid, AuditAction::Suspend { until, reason: body.reason }).await
+        .map_err(LoginError::Database)?;
+    println!("Admin {} suspended account {}", admin.uuid, target.uuid);
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn ban(session: Session, mongo: web::Data<mongodb::Client>, path: web::Path<String>, body: web::Json<Ban>) -> Result<HttpResponse, LoginError> {
+    let admin = admin_account(mongo.get_ref(), &session).await?;
+    let target = target_account(mongo.get_ref(), &admin, &path.into_inner()).await?;
+    let body = body.into_inner();
+
+    set_status(mongo.get_ref(), &target.uuid, AccountStatus::Banned, None, body.reason.clone()).await
+        .map_err(LoginError::Database)?;
+    record_audit(mongo.get_ref(), &admin.uuid, &target.uuid, AuditAction::Ban { reason: body.reason }).await
+        .map_err(LoginError::Database)?;
+    println!("Admin {} banned account {}", admin.uuid, target.uuid);
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn reinstate(session: Session, mongo: web::Data<mongodb::Client>, path: web::Path<String>) -> Result<HttpResponse, LoginError> {
+    let admin = admin_account(mongo.get_ref(), &session).await?;
+    let target = target_account(mongo.get_ref(), &admin, &path.into_inner()).await?;
+
+    set_status(mongo.get_ref(), &target.uuid, AccountStatus::Active, None, None).await
+        .map_err(LoginError::Database)?;
+    record_audit(mongo.get_ref(), &admin.uuid, &target.uuid, AuditAction::Reinstate).await
+        .map_err(LoginError::Database)?;
+    println!("Admin {} reinstated account {}", admin.uuid, target.uuid);
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn audit(session: Session, mongo: web::Data<mongodb::Client>, query: web::Query<AuditSearch>) -> Result<HttpResponse, LoginError> {
+    admin_account(mongo.get_ref(), &session).await?;
+
+    let (skip, limit) = page(query.page, query.limit);
+    let entries = find_audit_entries(mongo.get_ref(), query.target.as_deref(), skip, limit).await
+        .map_err(LoginError::Database)?;
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages() {
+        assert_eq!(page(None, None), (0, DEFAULT_LIMIT));
+        assert_eq!(page(Some(2), Some(10)), (20, 10));
+        assert_eq!(page(Some(1), Some(0)), (1, 1));
+        assert_eq!(page(Some(1), Some(-5)), (1, 1));
+        assert_eq!(page(Some(1), Some(10_000)), (MAX_LIMIT as u64, MAX_LIMIT));
+    }
+
+    #[test]
+    fn huge_pages_saturate() {
+        assert_eq!(page(Some(u64::MAX), Some(MAX_LIMIT)), (u64::MAX, MAX_LIMIT));
+        assert_eq!(page(Some(u64::MAX / 2), None), (u64::MAX, DEFAULT_LIMIT));
+    }
+
+    #[test]
+    fn admins_cant_change_themselves() {
+        assert!(matches!(check_target("a", "a"), Err(LoginError::InvalidInput(_))));
+        assert!(check_target("a", "b").is_ok());
+    }
+
+    #[test]
+    fn only_changes_are_audited() {
+        assert_eq!(changed(None, true), None);
+        assert_eq!(changed(Some(true), true), None);
+        assert_eq!(changed(Some(false), false), None);
+        assert_eq!(changed(Some(true), false), Some(true));
+        assert_eq!(changed(Some(false), true), Some(false));
+    }
+}
@@ -0,0 +1,82 @@
+use actix_session::Session;
+use actix_web::{web::{self}, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use user::{change_password, get_account_from_session, refresh_profile, register_local_account, verify_local_login, Account, ProfileUpdate, SessionPolicy};
+use crate::error::LoginError;
+use crate::login::{log_in, start_session};
+use crate::{LocalConfig, SecondFactorConfig};
+
+/*
+Username and password accounts, for setups that can't reach an OAuth provider
+and people without an account at one. They only work when turned on in the config.
+ */
+
+#[derive(Deserialize)]
+pub struct Register {
+    username: String,
+    password: String,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Login {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePassword {
+    current_password: String,
+    new_password: String,
+}
+
+pub async fn register(req: HttpRequest, session: Session, mongo: web::Data<mongodb::Client>, body: web::Json<Register>, local: web::Data<LocalConfig>, policy: web::Data<SessionPolicy>) -> Result<HttpResponse, LoginError> {
+    if !local.enabled {
+        return Err(LoginError::LocalDisabled);
+    }
+    if !local.registration {
+        return Err(LoginError::RegistrationClosed);
+    }
+
+    let body = body.into_inner();
+    let account: Account = register_local_account(mongo.get_ref(), &body.username, &body.password, body.name).await?;
+    println!("Registered local account {} ({})", account.subject, account.uuid);
+
+    // There is no provider to fill it in later, so the username starts out as the handle
+    if let Err(err) = refresh_profile(mongo.get_ref(), &account, ProfileUpdate {
+        handle: Some(account.subject.clone()),
+        display_name: account.name.clone(),
+        ..ProfileUpdate::default()
+    }).await {
+        eprintln!("Error creating profile of account {}: {:?}", account.uuid, err);
+    }
+
+    start_session(mongo.get_ref(), &req, &session, &account, &policy).await?;
+    Ok(HttpResponse::Created().body("Successfully registered."))
+}
+
+pub async fn login(req: HttpRequest, session: Session, mongo: web::Data<mongodb::Client>, body: web::Json<Login>, local: web::Data<LocalConfig>, second_factor: web::Data<SecondFactorConfig>, policy: web::Data<SessionPolicy>) -> Result<HttpResponse, LoginError> {
+    if !local.enabled {
+        return Err(LoginError::LocalDisabled);
+    }
+
+    let account: Account = verify_local_login(mongo.get_ref(), &body.username, &body.password, &local.lockout).await?;
+
+    log_in(mongo.get_ref(), &req, &session, &account, &policy, &second_factor).await
+}
+
+pub async fn password(session: Session, mongo: web::Data<mongodb::Client>, body: web::Json<ChangePassword>, local: web::Data<LocalConfig>) -> Result<HttpResponse, LoginError> {
+    if !local.enabled {
+        return Err(LoginError::LocalDisabled);
+    }
+
+    let account: Account = match get_account_from_session(mongo.get_ref(), &session).await {
+        Ok(account) => account,
+        Err(_) => return Err(LoginError::NotLoggedIn),
+    };
+
+    change_password(mongo.get_ref(), &account.uuid, &body.current_password, &body.new_password, &local.lockout).await?;
+    println!("Changed password of local account {} ({})", account.subject, account.uuid);
+
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -1,7 +1,14 @@
 use actix_web::web;
 
+pub mod admin;
 pub mod request;
 pub mod callback;
+pub mod local;
+pub mod passkeys;
+pub mod profile;
+pub mod second_factor;
+pub mod sessions;
+pub mod tokens;
 mod protected;
 
 pub fn init(cfg: &mut web::ServiceConfig) {
@@ -13,5 +20,40 @@ pub fn init(cfg: &mut web::ServiceConfig) {
         web::resource("/callback")
             .route(web::get().to(callback::callback))
     )
+        .service(web::resource("/providers").route(web::get().to(request::providers)))
+        .service(web::resource("/register").route(web::post().to(local::register)))
+        .service(web::resource("/login").route(web::post().to(local::login)))
+        .service(web::resource("/password").route(web::post().to(local::password)))
+        .service(web::resource("/passkeys").route(web::get().to(passkeys::list)))
+        .service(web::resource("/passkeys/register/start").route(web::post().to(passkeys::register_start)))
+        .service(web::resource("/passkeys/register/finish").route(web::post().to(passkeys::register_finish)))
+        .service(web::resource("/passkeys/login/start").route(web::post().to(passkeys::login_start)))
+        .service(web::resource("/passkeys/login/finish").route(web::post().to(passkeys::login_finish)))
+        .service(web::resource("/passkeys/{id}").route(web::delete().to(passkeys::delete)))
+        .service(web::resource("/2fa/verify").route(web::post().to(second_factor::verify)))
+        .service(web::resource("/2fa/totp")
+            .route(web::post().to(second_factor::enroll))
+            .route(web::delete().to(second_factor::disable)))
+        .service(web::resource("/2fa/totp/confirm").route(web::post().to(second_factor::confirm)))
+        .service(web::resource("/2fa/recovery-codes").route(web::post().to(second_factor::recovery_codes)))
+        .service(web::resource("/tokens")
+            .route(web::get().to(tokens::list))
+            .route(web::post().to(tokens::create)))
+        .service(web::resource("/tokens/{id}").route(web::delete().to(tokens::revoke)))
+        .service(web::resource("/logout").route(web::post().to(sessions::logout)))
+        .service(web::resource("/sessions")
+            .route(web::get().to(sessions::list))
+            .route(web::delete().to(sessions::revoke_all)))
+        .service(web::resource("/sessions/{id}").route(web::delete().to(sessions::revoke)))
+        .service(web::resource("/profile")
+            .route(web::get().to(profile::get))
+            .route(web::patch().to(profile::update)))
+        .service(web::resource("/admin/accounts").route(web::get().to(admin::list)))
+        .service(web::resource("/admin/accounts/{uuid}").route(web::get().to(admin::get)))
+        .service(web::resource("/admin/accounts/{uuid}/privileges").route(web::post().to(admin::privileges)))
+        .service(web::resource("/admin/accounts/{uuid}/suspend").route(web::post().to(admin::suspend)))
+        .service(web::resource("/admin/accounts/{uuid}/ban").route(web::post().to(admin::ban)))
+        .service(web::resource("/admin/accounts/{uuid}/reinstate").route(web::post().to(admin::reinstate)))
+        .service(web::resource("/admin/audit").route(web::get().to(admin::audit)))
         .service(web::resource("/protected").route(web::get().to(protected::protected)));
 }
\ No newline at end of file
@@ -0,0 +1,167 @@
+use actix_session::Session;
+use actix_web::{web::{self}, HttpRequest, HttpResponse};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+use user::{add_passkey, find_account_by_passkey, record_passkey_use, remove_passkey, Account, Passkey, SessionPolicy};
+use crate::error::LoginError;
+use crate::login::{logged_in, start_session};
+use crate::webauthn::{creation_options, decode, request_options, verify_assertion, verify_registration, AssertionResponse, AttestationResponse, Ceremony, CeremonyKind, Credential, RelyingParty};
+
+/*
+Passkeys, registered from an existing session and then usable to log in on their own.
+Each ceremony is a start request giving the options for the browser's WebAuthn call,
+and a finish request with what the browser returned.
+ */
+
+const MAX_NAME_LENGTH: usize = 64;
+
+#[derive(Deserialize)]
+pub struct StartRegistration {
+    name: String,
+}
+
+// Everything but the key itself
+#[derive(Serialize)]
+pub struct PasskeyInfo {
+    id: String,
+    name: String,
+    created: DateTime,
+    last_used: Option<DateTime>,
+}
+
+fn relying_party(rp: &Option<RelyingParty>) -> Result<&RelyingParty, LoginError> {
+    match rp {
+        Some(rp) => Ok(rp),
+        None => Err(LoginError::PasskeysDisabled),
+    }
+}
+
+pub async fn register_start(session: Session, mongo: web::Data<mongodb::Client>, body: web::Json<StartRegistration>, rp: web::Data<Option<RelyingParty>>) -> Result<HttpResponse, LoginError> {
+    let rp = relying_party(rp.get_ref())?;
+    let account = logged_in(mongo.get_ref(), &session).await?;
+
+    let name = body.name.trim().to_string();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(LoginError::InvalidInput(format!("Passkey names are 1 to {} characters long", MAX_NAME_LENGTH)));
+    }
+
+    Ok(HttpResponse::Ok().json(creation_options(rp, &session, &account, name)?))
+}
+
+pub async fn register_finish(session: Session, mongo: web::Data<mongodb::Client>, body: web::Json<Credential<AttestationResponse>>, rp: web::Data<Option<RelyingParty>>) -> Result<HttpResponse, LoginError> {
+    let rp = relying_party(rp.get_ref())?;
+    let account = logged_in(mongo.get_ref(), &session).await?;
+    let ceremony = Ceremony::take(&session, CeremonyKind::Register, rp)?;
+
+    // The session could have changed hands between start and finish
+    if ceremony.uuid.as_deref() != Some(account.uuid.as_str()) {
+        return Err(LoginError::StateMismatch);
+    }
+    if body.kind != "public-key" {
+        return Err(LoginError::PasskeyRejected(format!("Unexpected credential type {}", body.kind)));
+    }
+
+    let credential = verify_registration(rp, &ceremony, &body.response)
+        .map_err(LoginError::PasskeyRejected)?;
+    let credential_id = URL_SAFE_NO_PAD.encode(&credential.credential_id);
+
+    // Credential ids are random, one already in use means the same authenticator again
+    match find_account_by_passkey(mongo.get_ref(), &credential_id).await {
+        Ok(Some(_)) => return Err(LoginError::PasskeyRejected("That passkey is already registered".to_string())),
+        Ok(None) => {},
+        Err(err) => return Err(LoginError::Database(err)),
+    }
+
+    let passkey = Passkey {
+        credential_id,
+        public_key: URL_SAFE_NO_PAD.encode(&credential.public_key),
+        sign_count: credential.sign_count,
+        name: ceremony.name.unwrap_or_default(),
+        created: DateTime::now(),
+        last_used: None,
+    };
+    add_passkey(mongo.get_ref(), &account.uuid, &passkey).await
+        .map_err(LoginError::Database)?;
+    println!("Registered passkey {} for account {}", passkey.name, account.uuid);
+
+    Ok(HttpResponse::Created().json(PasskeyInfo {
+        id: passkey.credential_id,
+        name: passkey.name,
+        created: passkey.created,
+        last_used: passkey.last_used,
+    }))
+}
+
+pub async fn login_start(session: Session, rp: web::Data<Option<RelyingParty>>) -> Result<HttpResponse, LoginError> {
+    let rp = relying_party(rp.get_ref())?;
+    Ok(HttpResponse::Ok().json(request_options(rp, &session)?))
+}
+
+pub async fn login_finish(req: HttpRequest, session: Session, mongo: web::Data<mongodb::Client>, body: web::Json<Credential<AssertionResponse>>, rp: web::Data<Option<RelyingParty>>, policy: web::Data<SessionPolicy>) -> Result<HttpResponse, LoginError> {
+    let rp = relying_party(rp.get_ref())?;
+    let ceremony = Ceremony::take(&session, CeremonyKind::Login, rp)?;
+
+    // Normalised the same way as when it was stored
+    let credential_id = URL_SAFE_NO_PAD.encode(decode("id", &body.id).map_err(LoginError::PasskeyRejected)?);
+    let account: Account = match find_account_by_passkey(mongo.get_ref(), &credential_id).await {
+        Ok(Some(account)) => account,
+        Ok(None) => return Err(LoginError::UnknownPasskey),
+        Err(err) => return Err(LoginError::Database(err)),
+    };
+    let passkey = match account.passkeys.iter().find(|passkey| passkey.credential_id == credential_id) {
+        Some(passkey) => passkey,
+        None => return Err(LoginError::UnknownPasskey),
+    };
+
+    // The user handle is the account's uuid, set when the passkey was created
+    if let Some(user_handle) = &body.response.user_handle {
+        if decode("userHandle", user_handle).map_err(LoginError::PasskeyRejected)? != account.uuid.as_bytes() {
+            return Err(LoginError::PasskeyRejected("The passkey belongs to a different account".to_string()));
+        }
+    }
+
+    let public_key = decode("public_key", &passkey.public_key).map_err(LoginError::Session)?;
+    let sign_count = match verify_assertion(rp, &ceremony, &body.response, &public_key, passkey.sign_count) {
+        Ok(sign_count) => sign_count,
+        Err(reason) => {
+            eprintln!("Passkey login to account {} with {} refused: {}", account.uuid, passkey.name, reason);
+            return Err(LoginError::PasskeyRejected(reason));
+        }
+    };
+
+    record_passkey_use(mongo.get_ref(), &credential_id, sign_count).await
+        .map_err(LoginError::Database)?;
+
+    // No TOTP on top, the passkey was already something the user has plus a PIN or biometric
+    start_session(mongo.get_ref(), &req, &session, &account, &policy).await?;
+    Ok(HttpResponse::Ok().body("Successfully authenticated."))
+}
+
+pub async fn list(session: Session, mongo: web::Data<mongodb::Client>) -> Result<HttpResponse, LoginError> {
+    let account = logged_in(mongo.get_ref(), &session).await?;
+    let passkeys: Vec<PasskeyInfo> = account.passkeys.into_iter()
+        .map(|passkey| PasskeyInfo {
+            id: passkey.credential_id,
+            name: passkey.name,
+            created: passkey.created,
+            last_used: passkey.last_used,
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(passkeys))
+}
+
+pub async fn delete(session: Session, mongo: web::Data<mongodb::Client>, path: web::Path<String>) -> Result<HttpResponse, LoginError> {
+    let account = logged_in(mongo.get_ref(), &session).await?;
+    let credential_id = path.into_inner();
+
+    let result = remove_passkey(mongo.get_ref(), &account.uuid, &credential_id).await
+        .map_err(LoginError::Database)?;
+    if result.modified_count == 0 {
+        return Err(LoginError::PasskeyNotFound);
+    }
+    println!("Removed passkey {} from account {}", credential_id, account.uuid);
+
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,27 @@
+use actix_session::Session;
+use actix_web::{web::{self}, HttpResponse};
+use user::{update_profile, ProfileUpdate};
+use crate::error::LoginError;
+use crate::login::logged_in;
+
+/*
+The account's own public profile. Everyone can see it on the blog's author page,
+only the account can change it here.
+ */
+
+pub async fn get(session: Session, mongo: web::Data<mongodb::Client>) -> Result<HttpResponse, LoginError> {
+    let account = logged_in(mongo.get_ref(), &session).await?;
+    let profile = match account.profile {
+        Some(ref profile) => profile.clone(),
+        // Made on first use for accounts from before profiles
+        None => update_profile(mongo.get_ref(), &account, ProfileUpdate::default()).await?,
+    };
+    Ok(HttpResponse::Ok().json(profile))
+}
+
+pub async fn update(session: Session, mongo: web::Data<mongodb::Client>, body: web::Json<ProfileUpdate>) -> Result<HttpResponse, LoginError> {
+    let account = logged_in(mongo.get_ref(), &session).await?;
+    let profile = update_profile(mongo.get_ref(), &account, body.into_inner()).await?;
+    println!("Updated profile of account {}", account.uuid);
+    Ok(HttpResponse::Ok().json(profile))
+}
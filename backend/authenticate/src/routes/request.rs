@@ -1,29 +1,38 @@
 use std::iter;
 use actix_session::Session;
-use actix_web::{web::{self, Redirect}, App, HttpServer, Responder};
+use actix_web::{web::{self, Redirect}, App, HttpResponse, HttpServer};
 use mongodb::Client;
 // https://www.linkedin.com/pulse/using-redis-rust-amit-nadiger/
 
 use crate::OAuthConfig;
 
-use rand::{thread_rng, Rng};
-use rand::distributions::Alphanumeric;
+use serde::Deserialize;
+use crate::error::LoginError;
+use crate::login::LoginState;
 
-use pwhash::bcrypt;
+#[derive(Debug, Deserialize)]
+pub struct RequestQuery {
+    provider: Option<String>,
+}
 
-pub async fn request(session: Session, data: web::Data<OAuthConfig>) -> impl Responder {
-    let s = String::from_utf8(
-     thread_rng()
-         .sample_iter(&Alphanumeric)
-         .take(30)
-        .collect::<Vec<_>>(),
-        ).unwrap();
+pub async fn request(session: Session, query: web::Query<RequestQuery>, data: web::Data<OAuthConfig>) -> Result<Redirect, LoginError> {
+    let name = query.provider.as_deref().unwrap_or(&data.default_provider);
+    let provider = match data.providers.get(name) {
+        Some(provider) => provider,
+        None => return Err(LoginError::UnknownProvider(name.to_string())),
+    };
 
-    // Hash it so even the session doesn't know what it is
-    session.insert("auth", bcrypt::hash(&s).unwrap()).expect("TODO: panic message");
+    // Starting again replaces any login already in progress
+    let (state, login) = LoginState::start(provider.name())?;
+    login.save(&session)?;
 
-	Redirect::to(data.authorise.clone()
-    .replace("{client_id}", &data.client_id)
-    .replace("{state}", &s)
-    .replace("{redirect_uri}", &data.redirect_uri))
-}
\ No newline at end of file
+    // The state doubles as the OpenID Connect nonce, it is just as random and already checked by the callback
+	Ok(Redirect::to(provider.authorize_url(&state, &state, &login.code_challenge(), &data.redirect_uri)))
+}
+
+// Names of the providers that can be passed to /request, for a login page to list
+pub async fn providers(data: web::Data<OAuthConfig>) -> HttpResponse {
+    let mut names: Vec<&String> = data.providers.keys().collect();
+    names.sort();
+    HttpResponse::Ok().json(names)
+}
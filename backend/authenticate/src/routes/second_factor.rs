@@ -0,0 +1,118 @@
+use actix_session::Session;
+use actix_web::{web::{self}, HttpRequest, HttpResponse};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use serde::{Deserialize, Serialize};
+use user::{confirm_totp_enrollment, disable_totp, find_account_by_uuid, get_account_from_session, regenerate_recovery_codes, start_totp_enrollment, verify_second_factor, Account, SessionPolicy};
+use crate::error::LoginError;
+use crate::login::{logged_in, start_session, PendingLogin};
+use crate::SecondFactorConfig;
+
+/*
+TOTP as a second factor. A login that needs one is left pending by login::log_in,
+and only becomes a session once /2fa/verify gets a code from the authenticator app or a recovery code.
+ */
+
+#[derive(Deserialize)]
+pub struct Code {
+    code: String,
+}
+
+#[derive(Serialize)]
+pub struct Enrollment {
+    secret: String,
+    uri: String,
+    // An SVG of the URI, for the app to scan
+    qr: String,
+}
+
+// Shown once, only their hashes are kept
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+async fn pending_account(mongo: &mongodb::Client, pending: &PendingLogin) -> Result<Account, LoginError> {
+    match find_account_by_uuid(mongo, &pending.uuid).await {
+        Ok(Some(account)) => Ok(account),
+        Ok(None) => Err(LoginError::MissingState),
+        Err(err) => Err(LoginError::Database(err)),
+    }
+}
+
+// A logged in account adding TOTP, or a login that can't finish until it has it
+async fn enrolling_account(mongo: &mongodb::Client, session: &Session, config: &SecondFactorConfig) -> Result<(Account, bool), LoginError> {
+    if let Ok(account) = get_account_from_session(mongo, session).await {
+        return Ok((account, false));
+    }
+    let pending = match PendingLogin::get(session, config) {
+        Ok(pending) if pending.enroll => pending,
+        Ok(_) | Err(LoginError::MissingState) => return Err(LoginError::NotLoggedIn),
+        Err(err) => return Err(err),
+    };
+    Ok((pending_account(mongo, &pending).await?, true))
+}
+
+pub async fn verify(req: HttpRequest, session: Session, mongo: web::Data<mongodb::Client>, body: web::Json<Code>, config: web::Data<SecondFactorConfig>, policy: web::Data<SessionPolicy>) -> Result<HttpResponse, LoginError> {
+    let pending = PendingLogin::get(&session, &config)?;
+    if pending.enroll {
+        return Err(LoginError::TotpNotEnrolled);
+    }
+
+    verify_second_factor(mongo.get_ref(), &pending.uuid, &body.code, &config.lockout).await?;
+    let account = pending_account(mongo.get_ref(), &pending).await?;
+
+    start_session(mongo.get_ref(), &req, &session, &account, &policy).await?;
+    Ok(HttpResponse::Ok().body("Successfully authenticated."))
+}
+
+pub async fn enroll(session: Session, mongo: web::Data<mongodb::Client>, config: web::Data<SecondFactorConfig>) -> Result<HttpResponse, LoginError> {
+    let (account, _) = enrolling_account(mongo.get_ref(), &session, &config).await?;
+
+    let account_name = account.name.clone().unwrap_or_else(|| account.subject.clone());
+    let enrollment = start_totp_enrollment(mongo.get_ref(), &account.uuid, &config.issuer, &account_name).await?;
+    let qr = match QrCode::new(enrollment.uri.as_bytes()) {
+        Ok(code) => code.render::<svg::Color>().min_dimensions(200, 200).build(),
+        Err(err) => return Err(LoginError::Secret(err.to_string())),
+    };
+
+    Ok(HttpResponse::Ok().json(Enrollment {
+        secret: enrollment.secret,
+        uri: enrollment.uri,
+        qr,
+    }))
+}
+
+pub async fn confirm(req: HttpRequest, session: Session, mongo: web::Data<mongodb::Client>, body: web::Json<Code>, config: web::Data<SecondFactorConfig>, policy: web::Data<SessionPolicy>) -> Result<HttpResponse, LoginError> {
+    let (account, pending) = enrolling_account(mongo.get_ref(), &session, &config).await?;
+
+    let recovery_codes = confirm_totp_enrollment(mongo.get_ref(), &account.uuid, &body.code).await?;
+    println!("Turned on TOTP for account {}", account.uuid);
+
+    // Setting it up was the second factor for a pending login
+    if pending {
+        start_session(mongo.get_ref(), &req, &session, &account, &policy).await?;
+    }
+    Ok(HttpResponse::Ok().json(RecoveryCodes { recovery_codes }))
+}
+
+pub async fn disable(session: Session, mongo: web::Data<mongodb::Client>, body: web::Json<Code>, config: web::Data<SecondFactorConfig>) -> Result<HttpResponse, LoginError> {
+    let account = logged_in(mongo.get_ref(), &session).await?;
+    if account.elevated && config.required_for_elevated {
+        return Err(LoginError::SecondFactorRequired);
+    }
+
+    disable_totp(mongo.get_ref(), &account.uuid, &body.code, &config.lockout).await?;
+    println!("Turned off TOTP for account {}", account.uuid);
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn recovery_codes(session: Session, mongo: web::Data<mongodb::Client>, body: web::Json<Code>, config: web::Data<SecondFactorConfig>) -> Result<HttpResponse, LoginError> {
+    let account = logged_in(mongo.get_ref(), &session).await?;
+
+    let recovery_codes = regenerate_recovery_codes(mongo.get_ref(), &account.uuid, &body.code, &config.lockout).await?;
+    println!("Replaced recovery codes for account {}", account.uuid);
+
+    Ok(HttpResponse::Ok().json(RecoveryCodes { recovery_codes }))
+}
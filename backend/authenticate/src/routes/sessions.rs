@@ -0,0 +1,79 @@
+use actix_session::Session;
+use actix_web::{web::{self}, HttpResponse};
+use mongodb::bson::DateTime;
+use serde::Serialize;
+use user::{active_sessions, current_session_id, delete_session, revoke_all_sessions, revoke_session, Session as StoredSession};
+use crate::error::LoginError;
+use crate::login::logged_in;
+
+// Everything but the session_id, which is as good as the login itself
+#[derive(Serialize)]
+pub struct SessionInfo {
+    id: String,
+    created: Option<DateTime>,
+    last_seen: Option<DateTime>,
+    expires: Option<DateTime>,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    // The session making this request
+    current: bool,
+}
+
+impl SessionInfo {
+    pub fn new(stored: &StoredSession, current: bool) -> SessionInfo {
+        SessionInfo {
+            id: stored.id.clone(),
+            created: stored.created,
+            last_seen: stored.last_seen,
+            expires: stored.expires,
+            user_agent: stored.user_agent.clone(),
+            ip: stored.ip.clone(),
+            current,
+        }
+    }
+}
+
+pub async fn logout(session: Session, mongo: web::Data<mongodb::Client>) -> Result<HttpResponse, LoginError> {
+    if let Some(session_id) = current_session_id(&session) {
+        delete_session(mongo.get_ref(), &session_id).await
+            .map_err(LoginError::Database)?;
+    }
+    session.purge();
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn list(session: Session, mongo: web::Data<mongodb::Client>) -> Result<HttpResponse, LoginError> {
+    let account = logged_in(mongo.get_ref(), &session).await?;
+    let current = current_session_id(&session);
+
+    let sessions: Vec<SessionInfo> = active_sessions(&account).into_iter()
+        .map(|stored| SessionInfo::new(stored, current.as_deref() == Some(stored.session_id.as_str())))
+        .collect();
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+pub async fn revoke(session: Session, mongo: web::Data<mongodb::Client>, path: web::Path<String>) -> Result<HttpResponse, LoginError> {
+    let account = logged_in(mongo.get_ref(), &session).await?;
+    let id = path.into_inner();
+
+    let revoked = revoke_session(mongo.get_ref(), &account.uuid, &id).await
+        .map_err(LoginError::Database)?;
+    if !revoked {
+        return Err(LoginError::SessionNotFound);
+    }
+    println!("Revoked session {} of account {}", id, account.uuid);
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Logs out everywhere, this browser included
+pub async fn revoke_all(session: Session, mongo: web::Data<mongodb::Client>) -> Result<HttpResponse, LoginError> {
+    let account = logged_in(mongo.get_ref(), &session).await?;
+
+    revoke_all_sessions(mongo.get_ref(), &account.uuid).await
+        .map_err(LoginError::Database)?;
+    session.purge();
+    println!("Revoked all sessions of account {}", account.uuid);
+
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,121 @@
+use actix_session::Session;
+use actix_web::{web::{self}, HttpResponse};
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+use user::{create_api_token, list_api_tokens, revoke_api_token, ApiToken, Scope};
+use crate::error::LoginError;
+use crate::login::logged_in;
+
+/*
+Personal API tokens for the blog and cdn, sent as "Authorization: Bearer <token>".
+They can only be managed from a logged in session, a token can't make more tokens.
+ */
+
+const MAX_NAME_LENGTH: usize = 64;
+const MAX_EXPIRY_DAYS: u32 = 365;
+
+#[derive(Deserialize)]
+pub struct NewToken {
+    name: String,
+    scopes: Vec<Scope>,
+    // Never expires when left out
+    expires_in_days: Option<u32>,
+}
+
+// Everything but the hash
+#[derive(Serialize)]
+pub struct TokenInfo {
+    id: String,
+    name: String,
+    scopes: Vec<Scope>,
+    created: DateTime,
+    expires: Option<DateTime>,
+    last_used: Option<DateTime>,
+}
+
+impl From<ApiToken> for TokenInfo {
+    fn from(token: ApiToken) -> TokenInfo {
+        TokenInfo {
+            id: token.id,
+            name: token.name,
+            scopes: token.scopes,
+            created: token.created,
+            expires: token.expires,
+            last_used: token.last_used,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct CreatedToken {
+    #[serde(flatten)]
+    info: TokenInfo,
+    // The only time it is shown
+    token: String,
+}
+
+pub async fn create(session: Session, mongo: web::Data<mongodb::Client>, body: web::Json<NewToken>) -> Result<HttpResponse, LoginError> {
+    let account = logged_in(mongo.get_ref(), &session).await?;
+    let body = body.into_inner();
+
+    let name = body.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(LoginError::InvalidInput(format!("Token names are 1 to {} characters long", MAX_NAME_LENGTH)));
+    }
+    if body.scopes.is_empty() {
+        return Err(LoginError::InvalidInput("A token needs at least one scope".to_string()));
+    }
+    // A token can't do more than its account, so only admins can make tokens for the admin routes
+    if body.scopes.contains(&Scope::Admin) && !account.admin {
+        return Err(LoginError::InvalidInput("Only admins can make admin tokens".to_string()));
+    }
+
+    let mut scopes: Vec<Scope> = Vec::new();
+    for scope in body.scopes {
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+
+    let expires = match body.expires_in_days {
+        Some(days) if days == 0 || days > MAX_EXPIRY_DAYS => {
+            return Err(LoginError::InvalidInput(format!("Tokens can last 1 to {} days", MAX_EXPIRY_DAYS)));
+        }
+        Some(days) => Some(DateTime::from_millis(DateTime::now().timestamp_millis() + days as i64 * 24 * 60 * 60 * 1000)),
+        None => None,
+    };
+
+    let (api_token, token) = create_api_token(mongo.get_ref(), &account.uuid, name, scopes, expires).await
+        .map_err(LoginError::Database)?;
+    println!("Created API token {} ({}) for account {}", api_token.name, api_token.id, account.uuid);
+
+    Ok(HttpResponse::Created().json(CreatedToken {
+        info: api_token.into(),
+        token,
+    }))
+}
+
+pub async fn list(session: Session, mongo: web::Data<mongodb::Client>) -> Result<HttpResponse, LoginError> {
+    let account = logged_in(mongo.get_ref(), &session).await?;
+
+    let tokens: Vec<TokenInfo> = list_api_tokens(mongo.get_ref(), &account.uuid).await
+        .map_err(LoginError::Database)?
+        .into_iter()
+        .map(TokenInfo::from)
+        .collect();
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+pub async fn revoke(session: Session, mongo: web::Data<mongodb::Client>, path: web::Path<String>) -> Result<HttpResponse, LoginError> {
+    let account = logged_in(mongo.get_ref(), &session).await?;
+    let id = path.into_inner();
+
+    let revoked = revoke_api_token(mongo.get_ref(), &account.uuid, &id).await
+        .map_err(LoginError::Database)?;
+    if !revoked {
+        return Err(LoginError::TokenNotFound);
+    }
+    println!("Revoked API token {} of account {}", id, account.uuid);
+
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,582 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use actix_session::Session;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ciborium::value::Value;
+use rand::{thread_rng, RngCore};
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use user::Account;
+use crate::error::LoginError;
+
+/*
+WebAuthn (https://www.w3.org/TR/webauthn-3/) registration and assertion ceremonies.
+Only what passkeys need is checked: no attestation is asked for, so the authenticator's
+make and model are never verified, only that it holds the key and the user was there.
+ */
+
+const SESSION_KEY: &str = "webauthn";
+
+// COSE algorithm identifiers, in order of preference
+const ES256: i64 = -7;
+const EDDSA: i64 = -8;
+const RS256: i64 = -257;
+
+// Authenticator data flags
+const USER_PRESENT: u8 = 0x01;
+const USER_VERIFIED: u8 = 0x04;
+const ATTESTED_CREDENTIAL: u8 = 0x40;
+
+#[derive(Clone)]
+pub struct RelyingParty {
+    // The domain passkeys are bound to, the origin has to be it or a subdomain of it
+    pub id: String,
+    pub name: String,
+    // Exactly what the browser puts in clientDataJSON, e.g. https://blog.example.com
+    pub origin: String,
+    // How long the user has to answer their authenticator
+    pub timeout: Duration,
+}
+
+impl RelyingParty {
+    // Takes the id from the origin's host unless one is given
+    pub fn new(origin: String, id: Option<String>, name: String, timeout: Duration) -> Result<RelyingParty, String> {
+        let origin = origin.trim_end_matches('/').to_string();
+        let host = match reqwest::Url::parse(&origin) {
+            Ok(url) => match url.host_str() {
+                Some(host) => host.to_string(),
+                None => return Err(format!("{} has no host", origin)),
+            },
+            Err(err) => return Err(format!("{} is not a URL: {}", origin, err)),
+        };
+        let id = id.unwrap_or_else(|| host.clone());
+        if host != id && !host.ends_with(&format!(".{}", id)) {
+            return Err(format!("{} is not on {}", origin, id));
+        }
+        Ok(RelyingParty { id, name, origin, timeout })
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq)]
+pub enum CeremonyKind {
+    Register,
+    Login,
+}
+
+/*
+A ceremony between its start and finish requests, kept in the session like an OAuth login.
+It is taken out when finishing, so each challenge can only be answered once.
+ */
+#[derive(Deserialize, Serialize)]
+pub struct Ceremony {
+    challenge: String,
+    kind: CeremonyKind,
+    // Registration only: whose passkey it will be and what to call it
+    pub uuid: Option<String>,
+    pub name: Option<String>,
+    // Seconds since the epoch
+    issued: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+impl Ceremony {
+    fn start(kind: CeremonyKind, uuid: Option<String>, name: Option<String>) -> Ceremony {
+        let mut challenge = [0u8; 32];
+        thread_rng().fill_bytes(&mut challenge);
+        Ceremony {
+            challenge: URL_SAFE_NO_PAD.encode(challenge),
+            kind,
+            uuid,
+            name,
+            issued: now(),
+        }
+    }
+
+    fn save(&self, session: &Session) -> Result<(), LoginError> {
+        session.insert(SESSION_KEY, self)
+            .map_err(|err| LoginError::Session(err.to_string()))
+    }
+
+    pub fn take(session: &Session, kind: CeremonyKind, rp: &RelyingParty) -> Result<Ceremony, LoginError> {
+        let ceremony = match session.remove_as::<Ceremony>(SESSION_KEY) {
+            Some(Ok(ceremony)) => ceremony,
+            Some(Err(_)) | None => return Err(LoginError::MissingState),
+        };
+        if ceremony.kind != kind {
+            return Err(LoginError::MissingState);
+        }
+        if now().saturating_sub(ceremony.issued) > rp.timeout.as_secs() {
+            return Err(LoginError::ExpiredState);
+        }
+        Ok(ceremony)
+    }
+}
+
+// PublicKeyCredentialCreationOptions for navigator.credentials.create, binary fields as base64url
+pub fn creation_options(rp: &RelyingParty, session: &Session, account: &Account, name: String) -> Result<serde_json::Value, LoginError> {
+    let ceremony = Ceremony::start(CeremonyKind::Register, Some(account.uuid.clone()), Some(name));
+    ceremony.save(session)?;
+
+    // So the same authenticator isn't registered twice
+    let exclude: Vec<serde_json::Value> = account.passkeys.iter()
+        .map(|passkey| json!({"type": "public-key", "id": passkey.credential_id}))
+        .collect();
+    let display_name = account.name.clone().unwrap_or_else(|| account.subject.clone());
+
+    Ok(json!({
+        "rp": {"id": rp.id, "name": rp.name},
+        "user": {
+            "id": URL_SAFE_NO_PAD.encode(account.uuid.as_bytes()),
+            "name": account.subject,
+            "displayName": display_name,
+        },
+        "challenge": ceremony.challenge,
+        "pubKeyCredParams": [
+            {"type": "public-key", "alg": ES256},
+            {"type": "public-key", "alg": EDDSA},
+            {"type": "public-key", "alg": RS256},
+        ],
+        "timeout": rp.timeout.as_millis() as u64,
+        "excludeCredentials": exclude,
+        "authenticatorSelection": {
+            "residentKey": "required",
+            "requireResidentKey": true,
+            "userVerification": "required",
+        },
+        "attestation": "none",
+    }))
+}
+
+// PublicKeyCredentialRequestOptions for navigator.credentials.get, no credentials are listed so the authenticator offers its own
+pub fn request_options(rp: &RelyingParty, session: &Session) -> Result<serde_json::Value, LoginError> {
+    let ceremony = Ceremony::start(CeremonyKind::Login, None, None);
+    ceremony.save(session)?;
+
+    Ok(json!({
+        "rpId": rp.id,
+        "challenge": ceremony.challenge,
+        "timeout": rp.timeout.as_millis() as u64,
+        "userVerification": "required",
+    }))
+}
+
+// What a browser's PublicKeyCredential.toJSON() gives, for either ceremony
+#[derive(Deserialize)]
+pub struct Credential<R> {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub response: R,
+}
+
+#[derive(Deserialize)]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String,
+}
+
+#[derive(Deserialize)]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    pub signature: String,
+    #[serde(rename = "userHandle")]
+    pub user_handle: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+    #[serde(rename = "crossOrigin", default)]
+    cross_origin: bool,
+}
+
+struct AuthenticatorData {
+    rp_id_hash: Vec<u8>,
+    flags: u8,
+    sign_count: u32,
+    // Only there when registering
+    credential: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+// A registered credential, ready to be stored as a Passkey
+pub struct NewCredential {
+    pub credential_id: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+pub fn decode(field: &str, value: &str) -> Result<Vec<u8>, String> {
+    // Some libraries pad their base64url, which the standard says not to
+    URL_SAFE_NO_PAD.decode(value.trim_end_matches('='))
+        .map_err(|_| format!("{} is not base64url", field))
+}
+
+fn check_client_data(rp: &RelyingParty, ceremony: &Ceremony, client_data_json: &[u8], kind: &str) -> Result<(), String> {
+    let client_data: ClientData = match serde_json::from_slice(client_data_json) {
+        Ok(client_data) => client_data,
+        Err(err) => return Err(format!("clientDataJSON is not valid: {}", err)),
+    };
+    if client_data.kind != kind {
+        return Err(format!("Expected a {} ceremony, got {}", kind, client_data.kind));
+    }
+    if client_data.challenge != ceremony.challenge {
+        return Err("The challenge does not match".to_string());
+    }
+    // What makes passkeys phishing resistant, the browser reports the page it was really asked from
+    if client_data.origin != rp.origin {
+        return Err(format!("Unexpected origin {}", client_data.origin));
+    }
+    if client_data.cross_origin {
+        return Err("Passkeys can't be used from an embedded page".to_string());
+    }
+    Ok(())
+}
+
+fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData, String> {
+    if data.len() < 37 {
+        return Err("Authenticator data is too short".to_string());
+    }
+    let flags = data[32];
+    let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+    let mut credential = None;
+    if flags & ATTESTED_CREDENTIAL != 0 {
+        // 16 bytes of AAGUID, then the credential id's length
+        if data.len() < 55 {
+            return Err("Attested credential data is too short".to_string());
+        }
+        let id_length = u16::from_be_bytes([data[53], data[54]]) as usize;
+        if data.len() < 55 + id_length {
+            return Err("Credential id is cut off".to_string());
+        }
+        let credential_id = data[55..55 + id_length].to_vec();
+
+        // The key is CBOR with nothing saying how long it is, so read one item and see how far that got
+        let rest = &data[55 + id_length..];
+        let mut reader = rest;
+        if ciborium::de::from_reader::<Value, _>(&mut reader).is_err() {
+            return Err("Credential public key is not valid CBOR".to_string());
+        }
+        let public_key = rest[..rest.len() - reader.len()].to_vec();
+        credential = Some((credential_id, public_key));
+    }
+
+    Ok(AuthenticatorData {
+        rp_id_hash: data[..32].to_vec(),
+        flags,
+        sign_count,
+        credential,
+    })
+}
+
+fn check_authenticator_data(rp: &RelyingParty, data: &AuthenticatorData) -> Result<(), String> {
+    if data.rp_id_hash != Sha256::digest(rp.id.as_bytes())[..] {
+        return Err("The passkey is for a different site".to_string());
+    }
+    if data.flags & USER_PRESENT == 0 {
+        return Err("The user was not present".to_string());
+    }
+    // A PIN or biometric, so a stolen device alone isn't enough
+    if data.flags & USER_VERIFIED == 0 {
+        return Err("The user was not verified".to_string());
+    }
+    Ok(())
+}
+
+pub fn verify_registration(rp: &RelyingParty, ceremony: &Ceremony, response: &AttestationResponse) -> Result<NewCredential, String> {
+    let client_data_json = decode("clientDataJSON", &response.client_data_json)?;
+    check_client_data(rp, ceremony, &client_data_json, "webauthn.create")?;
+
+    // {"fmt", "attStmt", "authData"}, only authData matters without attestation
+    let attestation_object = decode("attestationObject", &response.attestation_object)?;
+    let attestation: Value = match ciborium::de::from_reader(attestation_object.as_slice()) {
+        Ok(attestation) => attestation,
+        Err(err) => return Err(format!("attestationObject is not valid CBOR: {}", err)),
+    };
+    let auth_data = match map_get(&attestation, Value::Text("authData".to_string())) {
+        Some(Value::Bytes(auth_data)) => auth_data,
+        _ => return Err("attestationObject has no authData".to_string()),
+    };
+
+    let data = parse_authenticator_data(auth_data)?;
+    check_authenticator_data(rp, &data)?;
+    let (credential_id, public_key) = match data.credential {
+        Some(credential) => credential,
+        None => return Err("No credential was created".to_string()),
+    };
+    if credential_id.len() > 1023 {
+        return Err("Credential id is too long".to_string());
+    }
+    // Refuse keys that could never be checked now, rather than at the first login
+    CoseKey::parse(&public_key)?;
+
+    Ok(NewCredential {
+        credential_id,
+        public_key,
+        sign_count: data.sign_count,
+    })
+}
+
+// Returns the new signature counter to store
+pub fn verify_assertion(rp: &RelyingParty, ceremony: &Ceremony, response: &AssertionResponse, public_key: &[u8], stored_count: u32) -> Result<u32, String> {
+    let client_data_json = decode("clientDataJSON", &response.client_data_json)?;
+    check_client_data(rp, ceremony, &client_data_json, "webauthn.get")?;
+
+    let authenticator_data = decode("authenticatorData", &response.authenticator_data)?;
+    let data = parse_authenticator_data(&authenticator_data)?;
+    check_authenticator_data(rp, &data)?;
+
+    // The authenticator signs its data followed by a hash of what the browser saw
+    let mut signed = authenticator_data.clone();
+    signed.extend_from_slice(&Sha256::digest(&client_data_json));
+    let signature = decode("signature", &response.signature)?;
+    CoseKey::parse(public_key)?.verify(&signed, &signature)?;
+
+    // Counters of 0 mean the authenticator doesn't keep one, as synced passkeys don't
+    if (data.sign_count != 0 || stored_count != 0) && data.sign_count <= stored_count {
+        return Err("The signature counter went backwards, the passkey may have been cloned".to_string());
+    }
+    Ok(data.sign_count)
+}
+
+fn map_get(map: &Value, key: Value) -> Option<&Value> {
+    match map {
+        Value::Map(entries) => entries.iter().find(|(k, _)| *k == key).map(|(_, v)| v),
+        _ => None,
+    }
+}
+
+fn cose_bytes(map: &Value, label: i64) -> Result<&[u8], String> {
+    match map_get(map, Value::Integer(label.into())) {
+        Some(Value::Bytes(bytes)) => Ok(bytes),
+        _ => Err(format!("COSE key is missing parameter {}", label)),
+    }
+}
+
+fn cose_int(map: &Value, label: i64) -> Result<i128, String> {
+    match map_get(map, Value::Integer(label.into())) {
+        Some(Value::Integer(value)) => Ok(i128::from(*value)),
+        _ => Err(format!("COSE key is missing parameter {}", label)),
+    }
+}
+
+// The public keys from RFC 9053 that we ask authenticators for
+enum CoseKey {
+    // Uncompressed point, 0x04 || x || y
+    P256(Vec<u8>),
+    Ed25519(Vec<u8>),
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+}
+
+impl CoseKey {
+    fn parse(bytes: &[u8]) -> Result<CoseKey, String> {
+        let key: Value = match ciborium::de::from_reader(bytes) {
+            Ok(key) => key,
+            Err(err) => return Err(format!("COSE key is not valid CBOR: {}", err)),
+        };
+        // 1 is kty, 3 is alg, negative labels depend on the key type
+        let kty = cose_int(&key, 1)?;
+        let alg = cose_int(&key, 3)?;
+        match (kty, alg as i64) {
+            (2, ES256) => {
+                if cose_int(&key, -1)? != 1 {
+                    return Err("Only P-256 is supported for ES256".to_string());
+                }
+                let x = cose_bytes(&key, -2)?;
+                let y = cose_bytes(&key, -3)?;
+                if x.len() != 32 || y.len() != 32 {
+                    return Err("P-256 coordinates must be 32 bytes".to_string());
+                }
+                let mut point = vec![0x04];
+                point.extend_from_slice(x);
+                point.extend_from_slice(y);
+                Ok(CoseKey::P256(point))
+            }
+            (1, EDDSA) => {
+                if cose_int(&key, -1)? != 6 {
+                    return Err("Only Ed25519 is supported for EdDSA".to_string());
+                }
+                Ok(CoseKey::Ed25519(cose_bytes(&key, -2)?.to_vec()))
+            }
+            (3, RS256) => Ok(CoseKey::Rsa {
+                n: cose_bytes(&key, -1)?.to_vec(),
+                e: cose_bytes(&key, -2)?.to_vec(),
+            }),
+            (kty, alg) => Err(format!("Unsupported key type {} with algorithm {}", kty, alg)),
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), String> {
+        let result = match self {
+            CoseKey::P256(point) => UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_ASN1, point).verify(message, signature),
+            CoseKey::Ed25519(key) => UnparsedPublicKey::new(&signature::ED25519, key).verify(message, signature),
+            CoseKey::Rsa { n, e } => RsaPublicKeyComponents { n, e }.verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, signature),
+        };
+        result.map_err(|_| "The signature is not valid".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn rp() -> RelyingParty {
+        RelyingParty::new("https://blog.example.com".to_string(), Some("example.com".to_string()), "Blog".to_string(), Duration::from_secs(60)).unwrap()
+    }
+
+    fn ceremony(kind: CeremonyKind) -> Ceremony {
+        Ceremony {
+            challenge: "challenge".to_string(),
+            kind,
+            uuid: None,
+            name: None,
+            issued: now(),
+        }
+    }
+
+    fn generate_key() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    fn cose_key(key_pair: &Ed25519KeyPair) -> Vec<u8> {
+        let key = Value::Map(vec![
+            (Value::Integer(1.into()), Value::Integer(1.into())),
+            (Value::Integer(3.into()), Value::Integer(EDDSA.into())),
+            (Value::Integer((-1).into()), Value::Integer(6.into())),
+            (Value::Integer((-2).into()), Value::Bytes(key_pair.public_key().as_ref().to_vec())),
+        ]);
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(&key, &mut bytes).unwrap();
+        bytes
+    }
+
+    fn authenticator_data(rp_id: &str, flags: u8, sign_count: u32) -> Vec<u8> {
+        let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+        data.push(flags);
+        data.extend_from_slice(&sign_count.to_be_bytes());
+        data
+    }
+
+    fn client_data(kind: &str, challenge: &str, origin: &str) -> Vec<u8> {
+        serde_json::to_vec(&json!({"type": kind, "challenge": challenge, "origin": origin})).unwrap()
+    }
+
+    fn assertion(key_pair: &Ed25519KeyPair, authenticator_data: &[u8], client_data_json: &[u8]) -> AssertionResponse {
+        let mut signed = authenticator_data.to_vec();
+        signed.extend_from_slice(&Sha256::digest(client_data_json));
+        AssertionResponse {
+            client_data_json: URL_SAFE_NO_PAD.encode(client_data_json),
+            authenticator_data: URL_SAFE_NO_PAD.encode(authenticator_data),
+            signature: URL_SAFE_NO_PAD.encode(key_pair.sign(&signed)),
+            user_handle: None,
+        }
+    }
+
+    fn login(rp_id: &str, flags: u8, sign_count: u32, stored_count: u32) -> Result<u32, String> {
+        let key_pair = generate_key();
+        let data = authenticator_data(rp_id, flags, sign_count);
+        let client_data = client_data("webauthn.get", "challenge", "https://blog.example.com");
+        verify_assertion(&rp(), &ceremony(CeremonyKind::Login), &assertion(&key_pair, &data, &client_data), &cose_key(&key_pair), stored_count)
+    }
+
+    #[test]
+    fn accepts_assertion() {
+        assert_eq!(login("example.com", USER_PRESENT | USER_VERIFIED, 5, 4), Ok(5));
+    }
+
+    #[test]
+    fn rejects_rp_id_mismatch() {
+        assert_eq!(login("blog.example.com", USER_PRESENT | USER_VERIFIED, 5, 4), Err("The passkey is for a different site".to_string()));
+        assert_eq!(login("example.org", USER_PRESENT | USER_VERIFIED, 5, 4), Err("The passkey is for a different site".to_string()));
+    }
+
+    #[test]
+    fn rejects_missing_flags() {
+        assert_eq!(login("example.com", USER_VERIFIED, 5, 4), Err("The user was not present".to_string()));
+        assert_eq!(login("example.com", USER_PRESENT, 5, 4), Err("The user was not verified".to_string()));
+    }
+
+    #[test]
+    fn rejects_counter_going_backwards() {
+        let error = Err("The signature counter went backwards, the passkey may have been cloned".to_string());
+        assert_eq!(login("example.com", USER_PRESENT | USER_VERIFIED, 3, 4), error);
+        assert_eq!(login("example.com", USER_PRESENT | USER_VERIFIED, 4, 4), error);
+        assert_eq!(login("example.com", USER_PRESENT | USER_VERIFIED, 0, 4), error);
+        // Authenticators without a counter always send 0
+        assert_eq!(login("example.com", USER_PRESENT | USER_VERIFIED, 0, 0), Ok(0));
+    }
+
+    #[test]
+    fn rejects_wrong_client_data() {
+        let key_pair = generate_key();
+        let data = authenticator_data("example.com", USER_PRESENT | USER_VERIFIED, 0);
+        for client_data in [
+            client_data("webauthn.create", "challenge", "https://blog.example.com"),
+            client_data("webauthn.get", "another challenge", "https://blog.example.com"),
+            client_data("webauthn.get", "challenge", "https://evil.example.com"),
+        ] {
+            let response = assertion(&key_pair, &data, &client_data);
+            assert!(verify_assertion(&rp(), &ceremony(CeremonyKind::Login), &response, &cose_key(&key_pair), 0).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_bad_signature() {
+        let key_pair = generate_key();
+        let data = authenticator_data("example.com", USER_PRESENT | USER_VERIFIED, 0);
+        let client_data = client_data("webauthn.get", "challenge", "https://blog.example.com");
+        let response = assertion(&key_pair, &data, &client_data);
+        let result = verify_assertion(&rp(), &ceremony(CeremonyKind::Login), &response, &cose_key(&generate_key()), 0);
+        assert_eq!(result, Err("The signature is not valid".to_string()));
+    }
+
+    #[test]
+    fn registers_credential() {
+        let key_pair = generate_key();
+        let public_key = cose_key(&key_pair);
+        let mut data = authenticator_data("example.com", USER_PRESENT | USER_VERIFIED | ATTESTED_CREDENTIAL, 0);
+        data.extend_from_slice(&[0; 16]);
+        data.extend_from_slice(&4u16.to_be_bytes());
+        data.extend_from_slice(&[1, 2, 3, 4]);
+        data.extend_from_slice(&public_key);
+
+        let attestation = Value::Map(vec![
+            (Value::Text("fmt".to_string()), Value::Text("none".to_string())),
+            (Value::Text("attStmt".to_string()), Value::Map(vec![])),
+            (Value::Text("authData".to_string()), Value::Bytes(data)),
+        ]);
+        let mut attestation_object = Vec::new();
+        ciborium::ser::into_writer(&attestation, &mut attestation_object).unwrap();
+
+        let response = AttestationResponse {
+            client_data_json: URL_SAFE_NO_PAD.encode(client_data("webauthn.create", "challenge", "https://blog.example.com")),
+            attestation_object: URL_SAFE_NO_PAD.encode(attestation_object),
+        };
+        let credential = verify_registration(&rp(), &ceremony(CeremonyKind::Register), &response).unwrap();
+        assert_eq!(credential.credential_id, vec![1, 2, 3, 4]);
+        assert_eq!(credential.public_key, public_key);
+    }
+
+    #[test]
+    fn origin_has_to_be_on_rp_id() {
+        assert!(RelyingParty::new("https://blog.example.com".to_string(), Some("example.org".to_string()), "Blog".to_string(), Duration::from_secs(60)).is_err());
+        assert!(RelyingParty::new("https://notexample.com".to_string(), Some("example.com".to_string()), "Blog".to_string(), Duration::from_secs(60)).is_err());
+        assert_eq!(RelyingParty::new("https://blog.example.com/".to_string(), None, "Blog".to_string(), Duration::from_secs(60)).unwrap().id, "blog.example.com");
+    }
+}
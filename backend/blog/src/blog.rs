@@ -1,4 +1,5 @@
 use futures::stream::{StreamExt, TryStreamExt};
+use media::{LinkedImage, MediaConfig, MediaLink};
 use mongodb::bson::{doc, DateTime};
 use mongodb::error::Error;
 use mongodb::results::{DeleteResult, InsertOneResult, UpdateResult};
@@ -21,6 +22,14 @@ pub struct Post {
     pub last_edit: Option<DateTime>,
 }
 
+// A post as it's sent to readers, with placeholders for the images it links to so the page doesn't jump around
+#[derive(Debug, Serialize)]
+pub struct PostView {
+    #[serde(flatten)]
+    pub post: Post,
+    pub images: Vec<LinkedImage>,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct PostUpload {
     pub title: Option<String>,
@@ -38,6 +47,13 @@ pub struct Pagination {
     pub limit: i64,
 }
 
+impl Pagination {
+    // Pages start at 1, and one far past the end just skips everything
+    pub fn skip(&self) -> u64 {
+        (self.page.max(1) - 1).saturating_mul(self.limit.max(0)) as u64
+    }
+}
+
 
 pub async fn insert_post(client: &Client, post: &Post) -> mongodb::error::Result<InsertOneResult> {
     let collection: Collection<Post> = client.database("blog").collection("posts");
@@ -81,7 +97,7 @@ pub async fn get_posts(client: &Client, creator: Option<String>, drafts: bool, h
     }
 
     let skip = match pagination.as_ref() {
-        Some(pagination) => (pagination.page - 1) * pagination.limit,
+        Some(pagination) => pagination.skip(),
         None => 0, // Default to starting from the beginning
     };
 
@@ -91,13 +107,71 @@ pub async fn get_posts(client: &Client, creator: Option<String>, drafts: bool, h
     };
 
     let cursor: Cursor<Post> = collection.find(filter)
-        .skip(skip as u64)
+        .skip(skip)
         .limit(limit)
         .await.expect("Failed to find posts");
 
     cursor.try_collect().await
 }
 
+// An author's published posts, newest first
+pub async fn get_author_posts(client: &Client, creator: &str, pagination: Pagination) -> Result<Vec<Post>, Error> {
+    let collection: Collection<Post> = client.database("blog").collection("posts");
+    let cursor: Cursor<Post> = collection.find(doc! {"creator": creator, "draft": false, "hidden": false})
+        .sort(doc! {"published": -1})
+        .skip(pagination.skip())
+        .limit(pagination.limit)
+        .await?;
+
+    cursor.try_collect().await
+}
+
+/*
+Posts as they're sent to readers. Everything the posts link to is looked up in one query,
+then each post gets the images it links to itself.
+ */
+pub async fn post_views(client: &Client, posts: Vec<Post>, media_config: &MediaConfig) -> Vec<PostView> {
+    let links: Vec<Vec<MediaLink>> = posts.iter()
+        .map(|post| media::find_links(&post.body, &media_config.public_url))
+        .collect();
+
+    let mut all_links: Vec<MediaLink> = Vec::new();
+    for link in links.iter().flatten() {
+        if !all_links.contains(link) {
+            all_links.push(link.clone());
+        }
+    }
+
+    let images = match media::find_images(client, &all_links).await {
+        Ok(images) => images,
+        Err(err) => {
+            // The posts are still readable without them
+            eprintln!("Error getting image placeholders for {} posts: {}", posts.len(), err);
+            Vec::new()
+        }
+    };
+
+    attach_images(posts, &links, &images)
+}
+
+fn attach_images(posts: Vec<Post>, links: &[Vec<MediaLink>], images: &[LinkedImage]) -> Vec<PostView> {
+    posts.into_iter().zip(links).map(|(post, links)| {
+        let images = images.iter()
+            .filter(|image| links.iter().any(|link| link.bucket == image.bucket && link.name == image.name && link.hash == image.hash))
+            .cloned()
+            .collect();
+        PostView { post, images }
+    }).collect()
+}
+
+// Keeps the media index in step with the CDN links in a post, so objects know which posts use them
+pub async fn update_media_references(client: &Client, post: &Post, media_config: &MediaConfig) {
+    let links = media::find_links(&post.body, &media_config.public_url);
+    if let Err(err) = media::set_post_references(client, &post.id, &links).await {
+        eprintln!("Error updating media references for post {}: {}", post.id, err);
+    }
+}
+
 // THANKS https://www.reddit.com/r/learnrust/comments/lnewid/create_a_random_fixed_digitlength_i32_in_which/
 pub async fn generate_id(client: &Client) -> String {
     let id: String = String::from_utf8(
@@ -112,4 +186,69 @@ pub async fn generate_id(client: &Client) -> String {
         return Box::pin(generate_id(client)).await;
     }
     id // return id
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_whole_pages() {
+        assert_eq!(Pagination { page: 1, limit: 10 }.skip(), 0);
+        assert_eq!(Pagination { page: 3, limit: 10 }.skip(), 20);
+        assert_eq!(Pagination { page: 0, limit: 10 }.skip(), 0);
+    }
+
+    #[test]
+    fn huge_pages_saturate() {
+        assert_eq!(Pagination { page: i64::MAX, limit: 50 }.skip(), i64::MAX as u64);
+    }
+
+    fn post(id: &str) -> Post {
+        Post {
+            creator: "author".to_string(),
+            id: id.to_string(),
+            title: id.to_string(),
+            body: String::new(),
+            draft: false,
+            hidden: false,
+            created: None,
+            published: None,
+            last_edit: None,
+        }
+    }
+
+    fn link(name: &str) -> MediaLink {
+        MediaLink { bucket: "images".to_string(), name: name.to_string(), hash: format!("{}-hash", name) }
+    }
+
+    fn image(name: &str) -> LinkedImage {
+        LinkedImage {
+            bucket: "images".to_string(),
+            name: name.to_string(),
+            hash: format!("{}-hash", name),
+            image: media::ImageInfo { width: 4, height: 3, color: "#000000".to_string(), blurhash: "L00000".to_string() },
+        }
+    }
+
+    #[test]
+    fn posts_get_their_own_images() {
+        let posts = vec![post("first"), post("second"), post("third")];
+        let links = vec![vec![link("a"), link("b")], vec![link("b"), link("c")], vec![]];
+        let views = attach_images(posts, &links, &[image("a"), image("b"), image("c")]);
+
+        let names: Vec<Vec<&str>> = views.iter()
+            .map(|view| view.images.iter().map(|image| image.name.as_str()).collect())
+            .collect();
+        assert_eq!(names, vec![vec!["a", "b"], vec!["b", "c"], vec![]]);
+        assert_eq!(views[1].post.id, "second");
+    }
+
+    #[test]
+    fn images_must_match_the_linked_content() {
+        let mut replaced = image("a");
+        replaced.hash = "other-hash".to_string();
+        let views = attach_images(vec![post("first")], &[vec![link("a")]], &[replaced]);
+        assert!(views[0].images.is_empty());
+    }
+}
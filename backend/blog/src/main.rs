@@ -25,6 +25,13 @@ pub async fn main() -> std::io::Result<()> {
         .await
         .unwrap();
 
+    // Used to find media in posts, and to hand out expiring links for it in drafts and hidden posts
+    let media_data = web::Data::new(media::MediaConfig {
+        public_url: env::var("BLOG_CDN_PUBLIC_URL").unwrap_or_else(|_| "http://127.0.0.1:8080".into()),
+        signing_key: env::var("BLOG_CDN_SIGNING_KEY").ok().map(|key| key.into_bytes()),
+        signed_ttl: env::var("BLOG_CDN_SIGNED_URL_TTL").ok().and_then(|ttl| ttl.parse().ok()).unwrap_or(60 * 60),
+    });
+
     HttpServer::new(move || {
         App::new()
             .wrap(
@@ -34,6 +41,7 @@ pub async fn main() -> std::io::Result<()> {
                 )
             )
             .app_data(web::Data::new(client.clone()))
+            .app_data(media_data.clone())
             .configure(routes::init)
     })
         .bind(("127.0.0.1", 3002))?
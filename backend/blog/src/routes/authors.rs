@@ -0,0 +1,127 @@
+use crate::blog::{get_author_posts, post_views, Pagination, PostView};
+use actix_web::{web, HttpResponse};
+use media::MediaConfig;
+use mongodb::Client;
+use serde::{Deserialize, Serialize};
+use user::{find_account_by_handle, Account, Profile};
+
+#[derive(Deserialize)]
+pub struct AuthorQuery {
+    page: Option<i64>,
+    limit: Option<i64>,
+}
+
+// The public half of an account, nothing that isn't in its profile
+#[derive(Serialize)]
+pub struct Author {
+    handle: String,
+    display_name: Option<String>,
+    avatar_url: Option<String>,
+    bio: Option<String>,
+    links: Vec<String>,
+    posts: Vec<PostView>,
+}
+
+// Suspended and banned authors don't get a page, nor do accounts that never got a profile
+fn public_profile(account: &Account) -> Option<Profile> {
+    match account.profile {
+        Some(ref profile) if !account.blocked() => Some(profile.clone()),
+        _ => None,
+    }
+}
+
+pub async fn author(client: web::Data<Client>, media_config: web::Data<MediaConfig>, path: web::Path<String>, query: web::Query<AuthorQuery>) -> HttpResponse {
+    let handle = path.into_inner();
+    let mongo: &Client = client.get_ref();
+
+    let account = match find_account_by_handle(mongo, &handle).await {
+        Ok(Some(account)) => account,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(err) => {
+            eprintln!("Error finding author {}: {}", handle, err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let profile = match public_profile(&account) {
+        Some(profile) => profile,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    // Same bounds as the post list
+    let pagination = Pagination {
+        page: query.page.unwrap_or(1).max(1),
+        limit: query.limit.unwrap_or(10).clamp(10, 50),
+    };
+    let posts = match get_author_posts(mongo, &account.uuid, pagination).await {
+        Ok(posts) => posts,
+        Err(err) => {
+            eprintln!("Error getting posts of author {}: {}", handle, err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let posts = post_views(mongo, posts, &media_config).await;
+
+    HttpResponse::Ok().json(Author {
+        handle: profile.handle,
+        display_name: profile.display_name,
+        avatar_url: profile.avatar_url,
+        bio: profile.bio,
+        links: profile.links,
+        posts,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::DateTime;
+    use user::AccountStatus;
+
+    fn account(status: AccountStatus, suspended_until: Option<DateTime>) -> Account {
+        Account {
+            name: None,
+            uuid: "author".to_string(),
+            provider: "github".to_string(),
+            subject: "1".to_string(),
+            email: None,
+            elevated: true,
+            auto_elevated: false,
+            sessions: vec![],
+            passkeys: vec![],
+            admin: false,
+            status,
+            suspended_until,
+            status_reason: None,
+            profile: Some(Profile { handle: "ann".to_string(), ..Profile::default() }),
+        }
+    }
+
+    fn days(days: i64) -> DateTime {
+        DateTime::from_millis(DateTime::now().timestamp_millis() + days * 24 * 60 * 60 * 1000)
+    }
+
+    #[test]
+    fn active_authors_are_shown() {
+        let profile = public_profile(&account(AccountStatus::Active, None)).unwrap();
+        assert_eq!(profile.handle, "ann");
+    }
+
+    #[test]
+    fn blocked_authors_are_hidden() {
+        assert!(public_profile(&account(AccountStatus::Banned, None)).is_none());
+        assert!(public_profile(&account(AccountStatus::Suspended, None)).is_none());
+        assert!(public_profile(&account(AccountStatus::Suspended, Some(days(3)))).is_none());
+    }
+
+    #[test]
+    fn lapsed_suspensions_are_shown() {
+        assert!(public_profile(&account(AccountStatus::Suspended, Some(days(-1)))).is_some());
+    }
+
+    #[test]
+    fn accounts_without_profiles_are_hidden() {
+        let mut account = account(AccountStatus::Active, None);
+        account.profile = None;
+        assert!(public_profile(&account).is_none());
+    }
+}
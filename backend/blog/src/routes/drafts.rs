@@ -1,20 +1,29 @@
-use crate::blog::get_posts;
+use crate::blog::{get_posts, post_views};
 use actix_session::Session;
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
+use media::{sign_urls, MediaConfig};
 use mongodb::Client;
-use user::{get_account_from_session, Account};
+use user::{get_account, Account, Scope};
 
-pub async fn drafts(session: Session, client: web::Data<Client>) -> HttpResponse {
+pub async fn drafts(req: HttpRequest, session: Session, client: web::Data<Client>, media_config: web::Data<MediaConfig>) -> HttpResponse {
     let mongo: &Client = client.get_ref();
 
-    let account : Account = match get_account_from_session(mongo, &session).await {
+    let account : Account = match get_account(mongo, &req, &session, Scope::PostsRead).await {
         Ok(account) => account,
         Err(e) => {
             return HttpResponse::InternalServerError().json(format!("{:?}", e));
         }
     };
 
-    let posts = get_posts(mongo, Some(account.uuid), true, false, None).await;
-    let json_posts = serde_json::to_string(&posts.unwrap()).unwrap();
+    let mut posts = get_posts(mongo, Some(account.uuid), true, false, None).await.unwrap();
+
+    // Drafts aren't public, so their media only gets links that run out
+    for post in posts.iter_mut() {
+        post.body = sign_urls(&post.body, &media_config, &format!("post:{}", post.id));
+    }
+
+    let posts = post_views(mongo, posts, &media_config).await;
+
+    let json_posts = serde_json::to_string(&posts).unwrap();
     HttpResponse::Ok().body(json_posts)
 }
@@ -1,17 +1,18 @@
 use crate::blog;
 use crate::blog::{get_post, Criteria, Post, PostUpload};
 use actix_session::Session;
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
+use media::MediaConfig;
 use mongodb::{bson, Client};
-use user::{get_account_from_session, Account};
+use user::{get_account, Account, Scope};
 
 // post_id
-pub async fn edit(session: Session, path: web::Path<(String)>, info: web::Json<PostUpload>, client: web::Data<Client>) -> HttpResponse {
+pub async fn edit(req: HttpRequest, session: Session, path: web::Path<(String)>, info: web::Json<PostUpload>, client: web::Data<Client>, media_config: web::Data<MediaConfig>) -> HttpResponse {
     let mongo: &Client = client.get_ref();
 
     let (post_id) = path.into_inner();
 
-    let account : Account = match get_account_from_session(mongo, &session).await {
+    let account : Account = match get_account(mongo, &req, &session, Scope::PostsWrite).await {
         Ok(account) => account,
         Err(e) => {
             return HttpResponse::InternalServerError()
@@ -51,8 +52,11 @@ pub async fn edit(session: Session, path: web::Path<(String)>, info: web::Json<P
     }
     post.last_edit = Option::from(bson::DateTime::now());
 
-    match blog::update_post(mongo, post).await {
-        Ok(_) => HttpResponse::Ok().finish(),
+    match blog::update_post(mongo, post.clone()).await {
+        Ok(_) => {
+            blog::update_media_references(mongo, &post, &media_config).await;
+            HttpResponse::Ok().finish()
+        },
         Err(e) => {
             HttpResponse::InternalServerError().body("Failed to update")
         }
@@ -1,10 +1,11 @@
-use crate::blog::{get_post, Criteria};
+use crate::blog::{get_post, Criteria, PostView};
 use actix_session::Session;
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
+use media::{sign_urls, MediaConfig};
 use mongodb::Client;
-use user::Account;
+use user::{Account, Scope};
 
-pub async fn get(session: Session, client: web::Data<Client>, path: web::Path<(String)>) -> HttpResponse {
+pub async fn get(req: HttpRequest, session: Session, client: web::Data<Client>, media_config: web::Data<MediaConfig>, path: web::Path<(String)>) -> HttpResponse {
     let (id) = path.into_inner();
 
     let mongo: &Client = client.get_ref();
@@ -19,10 +20,10 @@ pub async fn get(session: Session, client: web::Data<Client>, path: web::Path<(S
         return HttpResponse::NotFound().finish();
     }
 
-    let post = post.unwrap();
+    let mut post = post.unwrap();
 
     if post.draft || post.hidden {
-        let account : Account = match user::get_account_from_session(&client, &session).await {
+        let account : Account = match user::get_account(&client, &req, &session, Scope::PostsRead).await {
             Ok(account) => account,
             Err(_) => {
                 return HttpResponse::InternalServerError().body("You need to be the post creator to see this post")
@@ -32,7 +33,21 @@ pub async fn get(session: Session, client: web::Data<Client>, path: web::Path<(S
         if !account.elevated || !account.uuid.eq(&post.creator) {
             return HttpResponse::Forbidden().finish();
         }
+
+        // Media in posts that aren't public only gets links that run out
+        post.body = sign_urls(&post.body, &media_config, &format!("post:{}", post.id));
     }
 
-    HttpResponse::Ok().body(serde_json::to_string(&post).unwrap())
+    // Only once the post may be seen, so a hidden post's images don't give it away
+    let links = media::find_links(&post.body, &media_config.public_url);
+    let images = match media::find_images(mongo, &links).await {
+        Ok(images) => images,
+        Err(err) => {
+            // The post is still readable without them
+            eprintln!("Error getting image placeholders for post {}: {}", post.id, err);
+            Vec::new()
+        }
+    };
+
+    HttpResponse::Ok().body(serde_json::to_string(&PostView { post, images }).unwrap())
 }
@@ -1,17 +1,17 @@
 use crate::blog;
 use crate::blog::{get_post, Criteria, Post};
 use actix_session::Session;
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 use mongodb::Client;
-use user::{get_account_from_session, Account};
+use user::{get_account, Account, Scope};
 
 // post_id, boolean hide
-pub async fn hide(session: Session,path: web::Path<(String, bool)>, client: web::Data<Client>) -> HttpResponse {
+pub async fn hide(req: HttpRequest, session: Session,path: web::Path<(String, bool)>, client: web::Data<Client>) -> HttpResponse {
     let mongo: &Client = client.get_ref();
 
     let (post_id, hide) = path.into_inner();
 
-    let account : Account = match get_account_from_session(mongo, &session).await {
+    let account : Account = match get_account(mongo, &req, &session, Scope::PostsWrite).await {
         Ok(account) => account,
         Err(e) => {
             return HttpResponse::InternalServerError()
@@ -1,8 +1,9 @@
-use crate::blog::{get_posts, Pagination};
+use crate::blog::{get_posts, post_views, Pagination};
 use actix_web::{web, HttpResponse};
+use media::MediaConfig;
 use mongodb::Client;
 
-pub async fn list(client: web::Data<Client>, path: web::Path<(i64, i64)>) -> HttpResponse {
+pub async fn list(client: web::Data<Client>, media_config: web::Data<MediaConfig>, path: web::Path<(i64, i64)>) -> HttpResponse {
     let (mut page, mut limit) = path.into_inner();
 
     if limit > 50 {
@@ -22,6 +23,8 @@ pub async fn list(client: web::Data<Client>, path: web::Path<(i64, i64)>) -> Htt
                               limit,
                           })).await;
 
-    let json_posts = serde_json::to_string(&posts.unwrap()).unwrap();
+    let posts = post_views(mongo, posts.unwrap(), &media_config).await;
+
+    let json_posts = serde_json::to_string(&posts).unwrap();
     HttpResponse::Ok().body(json_posts)
 }
@@ -1,6 +1,7 @@
 use actix_web::web;
 
 pub mod list;
+mod authors;
 mod upload;
 mod drafts;
 mod draft;
@@ -20,5 +21,8 @@ pub fn init(cfg: &mut web::ServiceConfig) {
     ).service(
         web::resource("/upload")
             .route(web::post().to(upload::upload))
+    ).service(
+        web::resource("/authors/{handle}")
+            .route(web::get().to(authors::author))
     );
 }
\ No newline at end of file
@@ -1,12 +1,13 @@
 use crate::blog;
 use crate::blog::{Post, PostUpload};
 use actix_session::Session;
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
+use media::MediaConfig;
 use mongodb::bson;
-use user::Account;
+use user::{Account, Scope};
 
-pub async fn upload(session: Session, info: web::Json<PostUpload>, client: web::Data<mongodb::Client>) -> HttpResponse {
-    let account : Account = match user::get_account_from_session(&client, &session).await {
+pub async fn upload(req: HttpRequest, session: Session, info: web::Json<PostUpload>, client: web::Data<mongodb::Client>, media_config: web::Data<MediaConfig>) -> HttpResponse {
+    let account : Account = match user::get_account(&client, &req, &session, Scope::PostsWrite).await {
         Ok(account) => account,
         Err(_) => {
             return HttpResponse::InternalServerError().body("No account found")
@@ -32,6 +33,7 @@ pub async fn upload(session: Session, info: web::Json<PostUpload>, client: web::
     };
 
     blog::insert_post(&client, &post).await.expect("TODO: panic message");
+    blog::update_media_references(&client, &post, &media_config).await;
     
     HttpResponse::Ok().content_type("text/json").body(
         serde_json::to_string(&post).unwrap()
@@ -0,0 +1,110 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use actix_web::web::Bytes;
+use mini_moka::sync::Cache;
+use serde::Serialize;
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct CacheKey {
+    pub bucket: String,
+    pub name: String,
+    pub hash: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct CachedObject {
+    pub bytes: Bytes,
+    pub content_type: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: u64,
+    pub size: u64,
+    pub max_size: u64,
+    pub max_object_size: usize,
+}
+
+/*
+Keeps popular objects in memory so they don't have to come from MinIO every time.
+Bounded by the total size of the cached bytes and a TTL, objects bigger than max_object_size are always streamed.
+Entries are keyed by the content hash, so only verified bytes are ever stored in here.
+ */
+pub struct HotCache {
+    cache: Cache<CacheKey, CachedObject>,
+    max_size: u64,
+    max_object_size: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl HotCache {
+    pub fn new(max_size: u64, max_object_size: usize, ttl: Duration) -> HotCache {
+        let cache = Cache::builder()
+            .weigher(|_key: &CacheKey, object: &CachedObject| object.bytes.len().try_into().unwrap_or(u32::MAX))
+            .max_capacity(max_size)
+            .time_to_live(ttl)
+            .build();
+
+        HotCache {
+            cache,
+            max_size,
+            max_object_size,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<CachedObject> {
+        let object = self.cache.get(key);
+        match object {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        object
+    }
+
+    pub fn fits(&self, size: usize) -> bool {
+        size <= self.max_object_size
+    }
+
+    pub fn insert(&self, key: CacheKey, object: CachedObject) {
+        if self.fits(object.bytes.len()) {
+            self.cache.insert(key, object);
+        }
+    }
+
+    // Purges everything in a bucket, or just the entries for one object (including its variants)
+    pub fn purge(&self, bucket: Option<&str>, name: Option<&str>) -> usize {
+        if bucket.is_none() {
+            let entries = self.cache.entry_count() as usize;
+            self.cache.invalidate_all();
+            return entries;
+        }
+
+        let keys: Vec<CacheKey> = self.cache.iter()
+            .map(|entry| entry.key().clone())
+            .filter(|key| Some(key.bucket.as_str()) == bucket)
+            .filter(|key| match name {
+                Some(name) => key.name == name || key.name.starts_with(&format!("variants/{}/", name)),
+                None => true,
+            })
+            .collect();
+
+        keys.iter().for_each(|key| self.cache.invalidate(key));
+        keys.len()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.cache.entry_count(),
+            size: self.cache.weighted_size(),
+            max_size: self.max_size,
+            max_object_size: self.max_object_size,
+        }
+    }
+}
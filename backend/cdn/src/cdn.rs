@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use actix_web::web::Bytes;
+use blake2::{Blake2b512, Digest};
+use media::ImageInfo;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use crate::placeholder;
+use crate::storage::Storage;
+
+// Object metadata key the Blake2b-512 hex digest is stored under (sent to MinIO as x-amz-meta-blake2b)
+pub const HASH_METADATA: &str = "blake2b";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StoredObject {
+    pub bucket: String,
+    pub name: String,
+    pub hash: String,
+    pub size: usize,
+    pub content_type: String,
+    pub url: String,
+    pub image: Option<ImageInfo>,
+}
+
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    let mut hasher = Blake2b512::new();
+    hasher.update(bytes);
+    format!("{:x}", hasher.finalize())
+}
+
+// Stores the object along with its hash so it never has to be hashed again when served, and its placeholder if it's an image
+pub async fn put_object(db: &dyn Storage, bucket: &str, name: &str, content_type: &str, bytes: Bytes, hash: &str, image: Option<&ImageInfo>) -> Result<(), String> {
+    let mut metadata = HashMap::new();
+    metadata.insert(HASH_METADATA.to_string(), hash.to_string());
+    if let Some(image) = image {
+        placeholder::to_metadata(image, &mut metadata);
+    }
+
+    db.put(bucket, name, content_type, metadata, bytes).await
+}
+
+// Removes an object along with every resized variant generated from it
+pub async fn remove_object(db: &dyn Storage, bucket: &str, name: &str) -> Result<(), String> {
+    for variant in db.list(bucket, &format!("variants/{}/", name)).await? {
+        db.delete(bucket, &variant).await?;
+    }
+    db.delete(bucket, name).await
+}
+
+pub fn object_url(public_url: &str, bucket: &str, name: &str, hash: &str) -> String {
+    format!("{}/get/{}/{}/{}", public_url.trim_end_matches('/'), bucket, name, hash)
+}
+
+pub fn generate_name(content_type: &str) -> String {
+    let id = String::from_utf8(
+        thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .collect::<Vec<_>>(),
+    ).unwrap();
+
+    match extension(content_type) {
+        Some(ext) => format!("{}.{}", id, ext),
+        None => id,
+    }
+}
+
+// Content addressed objects are named after their hash, so the same bytes always end up under the same key
+pub fn content_name(hash: &str, content_type: &str) -> String {
+    match extension(content_type) {
+        Some(ext) => format!("{}.{}", hash, ext),
+        None => hash.to_string(),
+    }
+}
+
+pub fn extension(content_type: &str) -> Option<&'static str> {
+    match content_type {
+        "image/jpeg" => Some("jpg"),
+        "image/png" => Some("png"),
+        "image/gif" => Some("gif"),
+        "image/webp" => Some("webp"),
+        "image/avif" => Some("avif"),
+        "image/svg+xml" => Some("svg"),
+        "video/mp4" => Some("mp4"),
+        "video/webm" => Some("webm"),
+        "audio/mpeg" => Some("mp3"),
+        "audio/ogg" => Some("ogg"),
+        "application/pdf" => Some("pdf"),
+        _ => None,
+    }
+}
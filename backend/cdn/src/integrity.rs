@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use mongodb::bson::DateTime;
+use serde::Serialize;
+use crate::cdn::{hash_bytes, HASH_METADATA};
+use crate::storage::Storage;
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct BackfillReport {
+    pub bucket: String,
+    pub hashed: Vec<String>,
+    pub already_hashed: usize,
+    pub failed: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Corruption {
+    pub bucket: String,
+    pub name: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ScrubReport {
+    pub finished: Option<DateTime>,
+    pub checked: usize,
+    pub corrupted: Vec<Corruption>,
+    pub failed: Vec<String>,
+}
+
+// Last scrub result, shared with the admin routes
+#[derive(Default)]
+pub struct ScrubState {
+    pub last: Mutex<Option<ScrubReport>>,
+}
+
+// Lists every original object in a bucket, generated variants are left out as they are never served by hash
+pub async fn list_originals(db: &dyn Storage, bucket: &str) -> Result<Vec<String>, String> {
+    let mut names = db.list(bucket, "").await?;
+    names.retain(|name| !name.starts_with("variants/"));
+    Ok(names)
+}
+
+// Hashes an object that has no stored hash and adds the hash to the metadata it already has
+pub async fn backfill_object(db: &dyn Storage, bucket: &str, name: &str, mut metadata: HashMap<String, String>) -> Result<String, String> {
+    let bytes = db.get_bytes(bucket, name).await?;
+
+    let hash = hash_bytes(&bytes);
+    metadata.insert(HASH_METADATA.to_string(), hash.clone());
+    db.set_metadata(bucket, name, metadata).await?;
+
+    Ok(hash)
+}
+
+pub async fn backfill_bucket(db: &dyn Storage, bucket: &str) -> Result<BackfillReport, String> {
+    let names = list_originals(db, bucket).await?;
+
+    let mut report = BackfillReport {
+        bucket: bucket.to_string(),
+        ..Default::default()
+    };
+
+    for name in names {
+        let stat = match db.head(bucket, &name).await {
+            Ok(Some(stat)) => stat,
+            _ => {
+                report.failed.push(name);
+                continue;
+            }
+        };
+
+        if stat.metadata.contains_key(HASH_METADATA) {
+            report.already_hashed += 1;
+            continue;
+        }
+
+        match backfill_object(db, bucket, &name, stat.metadata).await {
+            Ok(_) => report.hashed.push(name),
+            Err(error) => {
+                eprintln!("Error backfilling hash of {}/{}: {}", bucket, name, error);
+                report.failed.push(name);
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+// Re-reads every hashed object and compares it against the stored hash
+pub async fn scrub(db: &dyn Storage, buckets: &[String]) -> ScrubReport {
+    let mut report = ScrubReport::default();
+
+    for bucket in buckets {
+        let names = match list_originals(db, bucket).await {
+            Ok(names) => names,
+            Err(error) => {
+                eprintln!("Error listing {} for scrub: {}", bucket, error);
+                report.failed.push(bucket.clone());
+                continue;
+            }
+        };
+
+        for name in names {
+            let expected = match db.head(bucket, &name).await {
+                Ok(Some(stat)) => match stat.metadata.get(HASH_METADATA) {
+                    Some(expected) => expected.clone(),
+                    None => continue, // Nothing to compare against until it has been backfilled
+                },
+                _ => {
+                    report.failed.push(format!("{}/{}", bucket, name));
+                    continue;
+                }
+            };
+
+            let bytes = match db.get_bytes(bucket, &name).await {
+                Ok(bytes) => bytes,
+                Err(_) => {
+                    report.failed.push(format!("{}/{}", bucket, name));
+                    continue;
+                }
+            };
+
+            report.checked += 1;
+            let actual = hash_bytes(&bytes);
+            if actual != expected {
+                eprintln!("Corrupted object {}/{}: expected {} got {}", bucket, name, expected, actual);
+                report.corrupted.push(Corruption {
+                    bucket: bucket.clone(),
+                    name,
+                    expected,
+                    actual,
+                });
+            }
+        }
+    }
+
+    report.finished = Some(DateTime::now());
+    report
+}
+
+pub fn spawn_scrub(db: Arc<dyn Storage>, buckets: Vec<String>, every: Duration, state: actix_web::web::Data<ScrubState>) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(every);
+        loop {
+            interval.tick().await;
+            let report = scrub(db.as_ref(), &buckets).await;
+            println!("Integrity scrub checked {} objects, {} corrupted", report.checked, report.corrupted.len());
+            *state.last.lock().unwrap() = Some(report);
+        }
+    });
+}
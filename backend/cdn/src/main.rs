@@ -1,35 +1,186 @@
 use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use actix_session::SessionMiddleware;
+use actix_session::storage::RedisSessionStore;
 use actix_web::{web, App, HttpServer};
+use actix_web::cookie::Key;
 use minio_rsc::Minio;
 use minio_rsc::provider::StaticProvider;
+use mongodb::Client;
+use storage::{LocalStorage, MinioStorage, Storage};
 
 mod routes;
+mod cdn;
+mod resize;
+mod integrity;
+mod cache;
+mod mime;
+mod metadata;
+mod svg;
+mod policy;
+mod orphans;
+mod placeholder;
+mod storage;
+mod tus;
+
+#[derive(Clone)]
+pub struct CdnConfig {
+    pub public_url: String,
+    pub max_upload_size: usize,
+    pub allowed_types: Vec<String>,
+    pub allowed_sizes: Vec<(u32, u32)>,
+    pub scrub_buckets: Vec<String>,
+    pub buckets: policy::BucketPolicies,
+    pub signing_key: Option<Vec<u8>>,
+    pub max_resumable_size: u64,
+    // How long a resumable upload can go untouched before it's thrown away
+    pub resumable_expiry: Duration,
+    // Unfinished resumable uploads one account can have at once
+    pub max_resumable_uploads: u64,
+}
 
 #[actix_web::main]
 pub async fn main() -> std::io::Result<()> {
-    let minio = Minio::builder()
-        .endpoint(env::var("BLOG_MINIO_ENDPOINT").unwrap_or_else(|_| "127.0.0.1:9000".to_owned()))
-        .provider(StaticProvider::new(
-            env::var("BLOG_MINIO_ACCESS_KEY").map_err(|err| {
-                eprintln!("Error fetching secret key from env variables: {}", err);
-                std::process::exit(1);
-            }).unwrap(),
-            env::var("BLOG_MINIO_SECRET_KEY").map_err(|err| {
-                eprintln!("Error fetching secret key from env variables: {}", err);
-                std::process::exit(1);
-            }).unwrap(),
-            None
-        ))
-        .secure(false) // TODO PRODUCTION MAKE THIS SECURE
-        .build()
+    // MinIO unless told otherwise, a local directory saves running MinIO in development
+    let storage: Arc<dyn Storage> = match env::var("BLOG_CDN_STORAGE").as_deref() {
+        Ok("local") => Arc::new(LocalStorage::new(env::var("BLOG_CDN_STORAGE_DIR").unwrap_or_else(|_| "storage".to_owned()))),
+        Ok("minio") | Err(_) => {
+            let minio = Minio::builder()
+                .endpoint(env::var("BLOG_MINIO_ENDPOINT").unwrap_or_else(|_| "127.0.0.1:9000".to_owned()))
+                .provider(StaticProvider::new(
+                    env::var("BLOG_MINIO_ACCESS_KEY").map_err(|err| {
+                        eprintln!("Error fetching secret key from env variables: {}", err);
+                        std::process::exit(1);
+                    }).unwrap(),
+                    env::var("BLOG_MINIO_SECRET_KEY").map_err(|err| {
+                        eprintln!("Error fetching secret key from env variables: {}", err);
+                        std::process::exit(1);
+                    }).unwrap(),
+                    None
+                ))
+                .secure(false) // TODO PRODUCTION MAKE THIS SECURE
+                .build()
+                .unwrap();
+            Arc::new(MinioStorage::new(minio))
+        }
+        Ok(other) => {
+            eprintln!("Unknown storage backend {}", other);
+            std::process::exit(1);
+        }
+    };
+
+    // Move the storage into the closure
+    let storage_data: web::Data<dyn Storage> = web::Data::from(storage);
+
+    // Buckets that aren't listed are never served or written to
+    let buckets = policy::BucketPolicies::parse(&env::var("BLOG_CDN_BUCKETS").unwrap_or_default())
+        .map_err(|err| {
+            eprintln!("Error parsing bucket policies: {}", err);
+            std::process::exit(1);
+        })
         .unwrap();
 
-    // Move the minio instance into the closure
-    let minio_data = web::Data::new(minio);
+    let cdn_config_data = web::Data::new(CdnConfig {
+        public_url: env::var("BLOG_CDN_PUBLIC_URL").unwrap_or_else(|_| "http://127.0.0.1:8080".into()),
+        max_upload_size: env::var("BLOG_CDN_MAX_UPLOAD_SIZE").ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(10 * 1024 * 1024),
+        allowed_types: env::var("BLOG_CDN_ALLOWED_TYPES")
+            .unwrap_or_else(|_| "image/jpeg,image/png,image/gif,image/webp".into())
+            .split(',')
+            .map(|content_type| content_type.trim().to_string())
+            .filter(|content_type| !content_type.is_empty())
+            .collect(),
+        allowed_sizes: resize::parse_sizes(&env::var("BLOG_CDN_ALLOWED_SIZES")
+            .unwrap_or_else(|_| "320x0,640x0,1280x0,1920x0".into())),
+        scrub_buckets: match env::var("BLOG_CDN_SCRUB_BUCKETS") {
+            Ok(scrub_buckets) => scrub_buckets
+                .split(',')
+                .map(|bucket| bucket.trim().to_string())
+                .filter(|bucket| !bucket.is_empty())
+                .collect(),
+            Err(_) => buckets.buckets(),
+        },
+        buckets,
+        // Without a key signed URLs are refused, so signed buckets can't be read at all
+        signing_key: env::var("BLOG_CDN_SIGNING_KEY").ok().map(|key| key.into_bytes()),
+        max_resumable_size: env::var("BLOG_CDN_MAX_RESUMABLE_SIZE").ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(4 * 1024 * 1024 * 1024),
+        resumable_expiry: Duration::from_secs(env::var("BLOG_CDN_RESUMABLE_EXPIRY_HOURS").ok()
+            .and_then(|hours| hours.parse::<u64>().ok())
+            .unwrap_or(24) * 60 * 60),
+        max_resumable_uploads: env::var("BLOG_CDN_MAX_RESUMABLE_UPLOADS").ok()
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(5),
+    });
+
+    let scrub_state = web::Data::new(integrity::ScrubState::default());
+
+    let hot_cache = web::Data::new(cache::HotCache::new(
+        env::var("BLOG_CDN_CACHE_SIZE").ok().and_then(|size| size.parse().ok()).unwrap_or(256 * 1024 * 1024),
+        env::var("BLOG_CDN_CACHE_MAX_OBJECT_SIZE").ok().and_then(|size| size.parse().ok()).unwrap_or(4 * 1024 * 1024),
+        Duration::from_secs(env::var("BLOG_CDN_CACHE_TTL_SECONDS").ok().and_then(|ttl| ttl.parse().ok()).unwrap_or(60 * 60)),
+    ));
+
+    // The scrub is optional as it reads every object back from MinIO
+    if let Some(hours) = env::var("BLOG_CDN_SCRUB_INTERVAL_HOURS").ok().and_then(|hours| hours.parse::<u64>().ok()) {
+        integrity::spawn_scrub(storage_data.clone().into_inner(), cdn_config_data.scrub_buckets.clone(),
+                               Duration::from_secs(hours * 60 * 60), scrub_state.clone());
+    }
+
+    let secret_key = Key::from(env::var("BLOG_SECRET_KEY")
+        .map_err(|err| {
+            eprintln!("Error fetching secret key: {}", err);
+            std::process::exit(1);
+        })
+        .unwrap().as_bytes());
+
+    let redis_uri = env::var("BLOG_REDIS_URI").unwrap_or_else(|_| "redis://127.0.0.1:6379".into());
+    let redis_store = RedisSessionStore::new(redis_uri)
+        .await
+        .unwrap();
+
+    // mongo
+    let uri = env::var("BLOG_MONGODB_URI").unwrap_or_else(|_| "mongodb://localhost:27017".into());
+    let client = Client::with_uri_str(uri).await.expect("failed to connect");
+
+    if let Err(err) = media::create_media_indexes(&client).await {
+        eprintln!("Error creating media indexes: {}", err);
+        std::process::exit(1);
+    }
+    if let Err(err) = tus::create_upload_indexes(&client).await {
+        eprintln!("Error creating upload indexes: {}", err);
+        std::process::exit(1);
+    }
+    // Multipart uploads nothing knows about are given as long as an upload has before it expires
+    tus::spawn_expiry(storage_data.clone().into_inner(), client.clone(), cdn_config_data.buckets.buckets(),
+                      cdn_config_data.resumable_expiry, Duration::from_secs(60 * 60));
+
+    // Orphans are only reported unless BLOG_CDN_ORPHAN_MODE is "delete"
+    let orphan_state = web::Data::new(orphans::OrphanState::default());
+    if let Some(hours) = env::var("BLOG_CDN_ORPHAN_SWEEP_HOURS").ok().and_then(|hours| hours.parse::<u64>().ok()) {
+        let grace = env::var("BLOG_CDN_ORPHAN_GRACE_HOURS").ok().and_then(|hours| hours.parse::<u64>().ok()).unwrap_or(72);
+        let delete = env::var("BLOG_CDN_ORPHAN_MODE").map(|mode| mode == "delete").unwrap_or(false);
+        orphans::spawn_sweep(storage_data.clone().into_inner(), client.clone(), hot_cache.clone(),
+                             Duration::from_secs(hours * 60 * 60), Duration::from_secs(grace * 60 * 60), delete, orphan_state.clone());
+    }
 
     HttpServer::new(move || {
         App::new()
-            .app_data(minio_data.clone())
+            .wrap(
+                SessionMiddleware::new(
+                    redis_store.clone(),
+                    secret_key.clone(),
+                )
+            )
+            .app_data(storage_data.clone())
+            .app_data(cdn_config_data.clone())
+            .app_data(scrub_state.clone())
+            .app_data(hot_cache.clone())
+            .app_data(orphan_state.clone())
+            .app_data(web::Data::new(client.clone()))
             .configure(routes::init)
     })
     .bind(("127.0.0.1", 8080))?
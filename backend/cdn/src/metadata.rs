@@ -0,0 +1,817 @@
+use std::io::Cursor;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageEncoder, ImageFormat, ImageReader};
+
+// EXIF tags an author can ask to keep, everything else (GPS, camera, timestamps...) is dropped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tag {
+    Artist,
+    Copyright,
+}
+
+impl Tag {
+    fn parse(tag: &str) -> Option<Tag> {
+        match tag {
+            "artist" => Some(Tag::Artist),
+            "copyright" => Some(Tag::Copyright),
+            _ => None,
+        }
+    }
+
+    fn id(&self) -> u16 {
+        match self {
+            Tag::Artist => 0x013B,
+            Tag::Copyright => 0x8298,
+        }
+    }
+}
+
+// Parses the upload's keep list, e.g. "copyright,artist"
+pub fn parse_keep(keep: &str) -> Result<Vec<Tag>, String> {
+    keep.split(',')
+        .map(|tag| tag.trim())
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| Tag::parse(tag).ok_or_else(|| format!("Unknown metadata tag {}", tag)))
+        .collect()
+}
+
+/*
+Removes EXIF, XMP and IPTC metadata from an uploaded image. Phones put GPS coordinates in there.
+The pixels are left alone unless the EXIF orientation says they need rotating, then the image is
+re-encoded with the orientation applied, as dropping the tag alone would show it sideways.
+GIFs can't carry EXIF, so there is nothing to keep or rotate, only XMP and comments to drop.
+AVIFs with metadata are refused, as taking it out means rewriting where every item is stored.
+Types that aren't images are returned untouched.
+ */
+pub fn strip(bytes: &[u8], content_type: &str, keep: &[Tag]) -> Result<Vec<u8>, String> {
+    let (format, (stripped, exif)) = match content_type {
+        "image/jpeg" => (ImageFormat::Jpeg, strip_jpeg(bytes)?),
+        "image/png" => (ImageFormat::Png, strip_png(bytes)?),
+        "image/webp" => (ImageFormat::WebP, strip_webp(bytes)?),
+        "image/gif" => return strip_gif(bytes),
+        "image/avif" => return match avif_metadata(bytes)? {
+            Some(kind) => Err(format!("AVIF carries {} metadata, which can't be removed here, export it without", kind)),
+            None => Ok(bytes.to_vec()),
+        },
+        _ => return Ok(bytes.to_vec()),
+    };
+
+    let kept = exif.as_deref().and_then(|exif| kept_exif(exif, keep));
+    let orientation = exif.as_deref().and_then(Orientation::from_exif_chunk);
+
+    match orientation {
+        Some(orientation) if orientation != Orientation::NoTransforms => {
+            reencode(&stripped, format, orientation, kept).map_err(|error| format!("Error rotating image: {}", error))
+        }
+        _ => match kept {
+            Some(kept) => match format {
+                ImageFormat::Jpeg => insert_jpeg_exif(&stripped, &kept),
+                ImageFormat::Png => insert_png_exif(&stripped, &kept),
+                _ => insert_webp_exif(&stripped, &kept),
+            },
+            None => Ok(stripped),
+        },
+    }
+}
+
+fn reencode(bytes: &[u8], format: ImageFormat, orientation: Orientation, exif: Option<Vec<u8>>) -> image::ImageResult<Vec<u8>> {
+    let mut decoder = ImageReader::with_format(Cursor::new(bytes), format).into_decoder()?;
+    let icc_profile = decoder.icc_profile()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    let mut output = Vec::new();
+    match format {
+        ImageFormat::Jpeg => {
+            let mut encoder = JpegEncoder::new_with_quality(&mut output, 90);
+            set_metadata(&mut encoder, icc_profile, exif);
+            DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(encoder)?;
+        }
+        ImageFormat::Png => {
+            let mut encoder = PngEncoder::new(&mut output);
+            set_metadata(&mut encoder, icc_profile, exif);
+            image.write_with_encoder(encoder)?;
+        }
+        _ => {
+            let mut encoder = WebPEncoder::new_lossless(&mut output);
+            set_metadata(&mut encoder, icc_profile, exif);
+            DynamicImage::ImageRgba8(image.to_rgba8()).write_with_encoder(encoder)?;
+        }
+    }
+    Ok(output)
+}
+
+fn set_metadata(encoder: &mut impl ImageEncoder, icc_profile: Option<Vec<u8>>, exif: Option<Vec<u8>>) {
+    // Encoders that can't carry these just lose them, the image itself is still fine
+    if let Some(icc_profile) = icc_profile {
+        let _ = encoder.set_icc_profile(icc_profile);
+    }
+    if let Some(exif) = exif {
+        let _ = encoder.set_exif_metadata(exif);
+    }
+}
+
+/*
+JPEG metadata lives in APPn segments: EXIF and XMP in APP1, IPTC in APP13.
+Everything up to the start of scan is walked, the compressed image data after it is copied as is.
+ */
+fn strip_jpeg(bytes: &[u8]) -> Result<(Vec<u8>, Option<Vec<u8>>), String> {
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        return Err("Not a JPEG".into());
+    }
+
+    let mut output = vec![0xFF, 0xD8];
+    let mut exif = None;
+    let mut offset = 2;
+
+    while offset + 4 <= bytes.len() {
+        if bytes[offset] != 0xFF {
+            return Err("Malformed JPEG segment".into());
+        }
+        let marker = bytes[offset + 1];
+        // Fill bytes before a marker
+        if marker == 0xFF {
+            offset += 1;
+            continue;
+        }
+
+        let length = u16::from_be_bytes([bytes[offset + 2], bytes[offset + 3]]) as usize;
+        let end = offset + 2 + length;
+        if length < 2 || end > bytes.len() {
+            return Err("Truncated JPEG segment".into());
+        }
+        let data = &bytes[offset + 4..end];
+
+        match marker {
+            // Start of scan, the rest is image data
+            0xDA => {
+                output.extend_from_slice(&bytes[offset..]);
+                return Ok((output, exif));
+            }
+            0xE1 => {
+                if exif.is_none() && data.starts_with(b"Exif\0\0") {
+                    exif = Some(data[6..].to_vec());
+                }
+            }
+            0xED => {}
+            _ => output.extend_from_slice(&bytes[offset..end]),
+        }
+        offset = end;
+    }
+
+    Err("JPEG has no image data".into())
+}
+
+fn insert_jpeg_exif(bytes: &[u8], exif: &[u8]) -> Result<Vec<u8>, String> {
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        return Err("Not a JPEG".into());
+    }
+    // The segment length has to fit in 16 bits
+    if exif.len() + 8 > u16::MAX as usize {
+        return Err("EXIF too large for a JPEG segment".into());
+    }
+
+    let mut output = vec![0xFF, 0xD8, 0xFF, 0xE1];
+    output.extend_from_slice(&((exif.len() + 8) as u16).to_be_bytes());
+    output.extend_from_slice(b"Exif\0\0");
+    output.extend_from_slice(exif);
+    output.extend_from_slice(&bytes[2..]);
+    Ok(output)
+}
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+// PNG keeps EXIF in eXIf chunks and XMP and free text in the text chunks
+fn strip_png(bytes: &[u8]) -> Result<(Vec<u8>, Option<Vec<u8>>), String> {
+    if !bytes.starts_with(&PNG_SIGNATURE) {
+        return Err("Not a PNG".into());
+    }
+
+    let mut output = PNG_SIGNATURE.to_vec();
+    let mut exif = None;
+    let mut offset = PNG_SIGNATURE.len();
+
+    while offset + 12 <= bytes.len() {
+        let length = u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+        let end = offset + 12 + length;
+        if end > bytes.len() {
+            return Err("Truncated PNG chunk".into());
+        }
+        let kind = &bytes[offset + 4..offset + 8];
+
+        match kind {
+            b"eXIf" => exif = Some(bytes[offset + 8..offset + 8 + length].to_vec()),
+            b"tEXt" | b"zTXt" | b"iTXt" => {}
+            _ => output.extend_from_slice(&bytes[offset..end]),
+        }
+        if kind == b"IEND" {
+            return Ok((output, exif));
+        }
+        offset = end;
+    }
+
+    Err("PNG has no end chunk".into())
+}
+
+fn insert_png_exif(bytes: &[u8], exif: &[u8]) -> Result<Vec<u8>, String> {
+    if !bytes.starts_with(&PNG_SIGNATURE) || bytes.len() < PNG_SIGNATURE.len() + 12 || &bytes[12..16] != b"IHDR" {
+        return Err("PNG doesn't start with IHDR".into());
+    }
+    // eXIf has to come before the image data, right after IHDR is always fine
+    let ihdr_end = PNG_SIGNATURE.len() + 12 + u32::from_be_bytes(bytes[8..12].try_into().unwrap()) as usize;
+    if ihdr_end > bytes.len() {
+        return Err("Truncated PNG chunk".into());
+    }
+
+    let mut chunk = b"eXIf".to_vec();
+    chunk.extend_from_slice(exif);
+
+    let mut output = bytes[..ihdr_end].to_vec();
+    output.extend_from_slice(&(exif.len() as u32).to_be_bytes());
+    output.extend_from_slice(&chunk);
+    output.extend_from_slice(&crc32fast::hash(&chunk).to_be_bytes());
+    output.extend_from_slice(&bytes[ihdr_end..]);
+    Ok(output)
+}
+
+// Flags in the VP8X header saying EXIF and XMP chunks are present
+const WEBP_EXIF_FLAG: u8 = 0x08;
+const WEBP_XMP_FLAG: u8 = 0x04;
+// Flags, reserved bytes, then canvas width and height
+const VP8X_LENGTH: usize = 10;
+
+// WebP only carries metadata in the extended (VP8X) format, as EXIF and "XMP " chunks
+fn strip_webp(bytes: &[u8]) -> Result<(Vec<u8>, Option<Vec<u8>>), String> {
+    if bytes.len() < 12 || !bytes.starts_with(b"RIFF") || &bytes[8..12] != b"WEBP" {
+        return Err("Not a WebP".into());
+    }
+
+    let mut output = b"RIFF\0\0\0\0WEBP".to_vec();
+    let mut exif = None;
+    let mut offset = 12;
+
+    while offset + 8 <= bytes.len() {
+        let length = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        // Chunks are padded to an even length
+        let end = (offset + 8 + length + (length & 1)).min(bytes.len());
+        if offset + 8 + length > bytes.len() {
+            return Err("Truncated WebP chunk".into());
+        }
+
+        match &bytes[offset..offset + 4] {
+            b"EXIF" => exif = Some(bytes[offset + 8..offset + 8 + length].to_vec()),
+            b"XMP " => {}
+            b"VP8X" => {
+                if length < VP8X_LENGTH {
+                    return Err("Truncated VP8X chunk".into());
+                }
+                let start = output.len();
+                output.extend_from_slice(&bytes[offset..end]);
+                output[start + 8] &= !(WEBP_EXIF_FLAG | WEBP_XMP_FLAG);
+            }
+            _ => output.extend_from_slice(&bytes[offset..end]),
+        }
+        offset = end;
+    }
+
+    set_riff_size(&mut output);
+    Ok((output, exif))
+}
+
+fn insert_webp_exif(bytes: &[u8], exif: &[u8]) -> Result<Vec<u8>, String> {
+    if bytes.len() < 20 || !bytes.starts_with(b"RIFF") || &bytes[8..12] != b"WEBP" {
+        return Err("Not a WebP".into());
+    }
+    // A simple (non VP8X) WebP has nowhere to put EXIF, so it goes without
+    if &bytes[12..16] != b"VP8X" {
+        return Ok(bytes.to_vec());
+    }
+    if bytes.len() < 20 + VP8X_LENGTH {
+        return Err("Truncated VP8X chunk".into());
+    }
+
+    let mut output = bytes.to_vec();
+    output[20] |= WEBP_EXIF_FLAG;
+    output.extend_from_slice(b"EXIF");
+    output.extend_from_slice(&(exif.len() as u32).to_le_bytes());
+    output.extend_from_slice(exif);
+    if exif.len() % 2 == 1 {
+        output.push(0);
+    }
+    set_riff_size(&mut output);
+    Ok(output)
+}
+
+fn set_riff_size(output: &mut [u8]) {
+    let size = (output.len() - 8) as u32;
+    output[4..8].copy_from_slice(&size.to_le_bytes());
+}
+
+// Application extensions that only say how to loop an animation, every other one (XMP...) is dropped
+const GIF_LOOP_EXTENSIONS: [&[u8]; 2] = [b"NETSCAPE2.0", b"ANIMEXTS1.0"];
+
+/*
+GIF keeps XMP in an application extension and free text in comment extensions.
+Both are sequences of sub-blocks like the image data, each a length byte then that many bytes, ending with an empty one.
+ */
+fn strip_gif(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    if bytes.len() < 13 || !(bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a")) {
+        return Err("Not a GIF".into());
+    }
+
+    // Header and logical screen descriptor, then the global colour table if the flag says there is one
+    let packed = bytes[10];
+    let mut offset = 13;
+    if packed & 0x80 != 0 {
+        offset += 3 << ((packed & 0x07) + 1);
+    }
+    if offset > bytes.len() {
+        return Err("Truncated GIF colour table".into());
+    }
+    let mut output = bytes[..offset].to_vec();
+
+    loop {
+        match bytes.get(offset) {
+            Some(0x3B) => {
+                output.push(0x3B);
+                return Ok(output);
+            }
+            Some(0x21) => {
+                let label = *bytes.get(offset + 1).ok_or("Truncated GIF extension")?;
+                let end = gif_sub_blocks(bytes, offset + 2)?;
+                let drop = match label {
+                    0xFE => true,
+                    // The first sub-block of an application extension is its 11 byte identifier
+                    0xFF => !GIF_LOOP_EXTENSIONS.iter().any(|id| bytes.get(offset + 3..offset + 14) == Some(id)),
+                    _ => false,
+                };
+                if !drop {
+                    output.extend_from_slice(&bytes[offset..end]);
+                }
+                offset = end;
+            }
+            Some(0x2C) => {
+                let packed = *bytes.get(offset + 9).ok_or("Truncated GIF image descriptor")?;
+                let mut data = offset + 10;
+                if packed & 0x80 != 0 {
+                    data += 3 << ((packed & 0x07) + 1);
+                }
+                // The LZW minimum code size comes before the image data
+                let end = gif_sub_blocks(bytes, data + 1)?;
+                output.extend_from_slice(&bytes[offset..end]);
+                offset = end;
+            }
+            Some(_) => return Err("Malformed GIF block".into()),
+            None => return Err("GIF has no trailer".into()),
+        }
+    }
+}
+
+// Where the sub-blocks starting at offset end, after their empty terminator
+fn gif_sub_blocks(bytes: &[u8], mut offset: usize) -> Result<usize, String> {
+    loop {
+        let length = *bytes.get(offset).ok_or("Truncated GIF sub-block")? as usize;
+        offset += 1 + length;
+        if length == 0 {
+            return Ok(offset);
+        }
+        if offset > bytes.len() {
+            return Err("Truncated GIF sub-block".into());
+        }
+    }
+}
+
+// A box's type and its contents
+type BmffBox<'a> = (&'a [u8], &'a [u8]);
+
+// ISO-BMFF boxes: a 32 bit size (1 means a 64 bit one follows, 0 means to the end), then the type
+fn bmff_boxes(bytes: &[u8]) -> Result<Vec<BmffBox<'_>>, String> {
+    let mut boxes = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= bytes.len() {
+        let size = u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap()) as u64;
+        let kind = &bytes[offset + 4..offset + 8];
+        let (header, size) = match size {
+            0 => (8, (bytes.len() - offset) as u64),
+            1 => match bytes.get(offset + 8..offset + 16) {
+                Some(large) => (16, u64::from_be_bytes(large.try_into().unwrap())),
+                None => return Err("Truncated AVIF box".into()),
+            },
+            size => (8, size),
+        };
+        if size < header as u64 || size > (bytes.len() - offset) as u64 {
+            return Err("Truncated AVIF box".into());
+        }
+        let end = offset + size as usize;
+        boxes.push((kind, &bytes[offset + header..end]));
+        offset = end;
+    }
+    Ok(boxes)
+}
+
+/*
+AVIF keeps EXIF and XMP as items of their own, listed in the meta box's item info.
+Returns which kind was found, if any.
+ */
+fn avif_metadata(bytes: &[u8]) -> Result<Option<&'static str>, String> {
+    let meta = match bmff_boxes(bytes)?.into_iter().find(|(kind, _)| *kind == b"meta") {
+        Some((_, meta)) => meta,
+        None => return Ok(None),
+    };
+    // meta, iinf and infe are full boxes, with a version byte and three bytes of flags first
+    let iinf = match bmff_boxes(meta.get(4..).ok_or("Truncated AVIF meta box")?)?.into_iter().find(|(kind, _)| *kind == b"iinf") {
+        Some((_, iinf)) => iinf,
+        None => return Ok(None),
+    };
+    let entries = match iinf.first() {
+        Some(0) => iinf.get(6..),
+        Some(_) => iinf.get(8..),
+        None => None,
+    }.ok_or("Truncated AVIF item info")?;
+
+    for (kind, infe) in bmff_boxes(entries)? {
+        if kind != b"infe" {
+            continue;
+        }
+        // Versions 2 and 3 have a 16 or 32 bit item id, then the protection index, then the type
+        let item_type = match infe.first() {
+            Some(2) => infe.get(8..12),
+            Some(3) => infe.get(10..14),
+            _ => None,
+        };
+        match item_type {
+            Some(b"Exif") => return Ok(Some("EXIF")),
+            Some(b"mime") => return Ok(Some("XMP")),
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/*
+Builds a new EXIF block (a small TIFF) holding only the tags the author asked to keep.
+Only IFD0 is read, which is where artist and copyright live. None if none of them were set.
+ */
+fn kept_exif(exif: &[u8], keep: &[Tag]) -> Option<Vec<u8>> {
+    if keep.is_empty() || exif.len() < 8 {
+        return None;
+    }
+
+    let little_endian = match &exif[..4] {
+        b"II*\0" => true,
+        b"MM\0*" => false,
+        _ => return None,
+    };
+    let read_u16 = |at: usize| exif.get(at..at + 2).map(|bytes| {
+        let bytes = [bytes[0], bytes[1]];
+        if little_endian { u16::from_le_bytes(bytes) } else { u16::from_be_bytes(bytes) }
+    });
+    let read_u32 = |at: usize| exif.get(at..at + 4).map(|bytes| {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if little_endian { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) }
+    });
+
+    let ifd = read_u32(4)? as usize;
+    let count = read_u16(ifd)? as usize;
+    let mut tags: Vec<(u16, Vec<u8>)> = Vec::new();
+
+    for index in 0..count {
+        let entry = ifd + 2 + index * 12;
+        let id = read_u16(entry)?;
+        // Only ASCII values, which is what artist and copyright are
+        if !keep.iter().any(|tag| tag.id() == id) || read_u16(entry + 2)? != 2 {
+            continue;
+        }
+
+        let length = read_u32(entry + 4)? as usize;
+        let start = if length <= 4 { entry + 8 } else { read_u32(entry + 8)? as usize };
+        if let Some(value) = exif.get(start..start + length) {
+            tags.push((id, value.to_vec()));
+        }
+    }
+
+    if tags.is_empty() {
+        return None;
+    }
+    tags.sort_by_key(|(id, _)| *id);
+
+    // Big endian TIFF: header, one IFD, then the values that don't fit inline
+    let mut output = b"MM\0*".to_vec();
+    output.extend_from_slice(&8u32.to_be_bytes());
+    output.extend_from_slice(&(tags.len() as u16).to_be_bytes());
+
+    let mut data_offset = 8 + 2 + tags.len() * 12 + 4;
+    let mut data = Vec::new();
+    for (id, value) in &tags {
+        output.extend_from_slice(&id.to_be_bytes());
+        output.extend_from_slice(&2u16.to_be_bytes());
+        output.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        if value.len() <= 4 {
+            let mut inline = value.clone();
+            inline.resize(4, 0);
+            output.extend_from_slice(&inline);
+        } else {
+            output.extend_from_slice(&(data_offset as u32).to_be_bytes());
+            data.extend_from_slice(value);
+            if value.len() % 2 == 1 {
+                data.push(0);
+            }
+            data_offset = 8 + 2 + tags.len() * 12 + 4 + data.len();
+        }
+    }
+    output.extend_from_slice(&0u32.to_be_bytes());
+    output.extend_from_slice(&data);
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jpeg_segment(marker: u8, data: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&((data.len() + 2) as u16).to_be_bytes());
+        segment.extend_from_slice(data);
+        segment
+    }
+
+    fn png_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(kind);
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(&crc32fast::hash(&chunk[4..]).to_be_bytes());
+        chunk
+    }
+
+    fn webp_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = kind.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn webp(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = b"RIFF\0\0\0\0WEBP".to_vec();
+        for chunk in chunks {
+            bytes.extend_from_slice(chunk);
+        }
+        set_riff_size(&mut bytes);
+        bytes
+    }
+
+    // A big endian TIFF with an artist of "Ann" and nothing else
+    fn artist_exif() -> Vec<u8> {
+        let mut exif = b"MM\0*".to_vec();
+        exif.extend_from_slice(&8u32.to_be_bytes());
+        exif.extend_from_slice(&1u16.to_be_bytes());
+        exif.extend_from_slice(&0x013Bu16.to_be_bytes());
+        exif.extend_from_slice(&2u16.to_be_bytes());
+        exif.extend_from_slice(&4u32.to_be_bytes());
+        exif.extend_from_slice(b"Ann\0");
+        exif.extend_from_slice(&0u32.to_be_bytes());
+        exif
+    }
+
+    fn contains(bytes: &[u8], needle: &[u8]) -> bool {
+        bytes.windows(needle.len()).any(|window| window == needle)
+    }
+
+    fn jpeg_with_metadata() -> Vec<u8> {
+        let mut exif = b"Exif\0\0".to_vec();
+        exif.extend_from_slice(&artist_exif());
+
+        let mut bytes = vec![0xFF, 0xD8];
+        bytes.extend(jpeg_segment(0xE0, b"JFIF\0\x01\x01"));
+        bytes.extend(jpeg_segment(0xE1, &exif));
+        bytes.extend(jpeg_segment(0xE1, b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta/>"));
+        bytes.extend(jpeg_segment(0xED, b"Photoshop 3.0\0"));
+        bytes.extend(jpeg_segment(0xDB, &[0; 65]));
+        bytes.extend(jpeg_segment(0xDA, &[1, 2, 3]));
+        bytes.extend_from_slice(&[0x12, 0x34, 0xFF, 0xD9]);
+        bytes
+    }
+
+    #[test]
+    fn strips_jpeg_metadata() {
+        let (stripped, exif) = strip_jpeg(&jpeg_with_metadata()).unwrap();
+
+        let mut expected = vec![0xFF, 0xD8];
+        expected.extend(jpeg_segment(0xE0, b"JFIF\0\x01\x01"));
+        expected.extend(jpeg_segment(0xDB, &[0; 65]));
+        expected.extend(jpeg_segment(0xDA, &[1, 2, 3]));
+        expected.extend_from_slice(&[0x12, 0x34, 0xFF, 0xD9]);
+        assert_eq!(stripped, expected);
+        assert_eq!(exif, Some(artist_exif()));
+    }
+
+    #[test]
+    fn keeps_asked_for_jpeg_tags() {
+        let bytes = jpeg_with_metadata();
+        assert!(!contains(&strip(&bytes, "image/jpeg", &[]).unwrap(), b"Ann"));
+        assert!(!contains(&strip(&bytes, "image/jpeg", &[Tag::Copyright]).unwrap(), b"Ann"));
+
+        let kept = strip(&bytes, "image/jpeg", &[Tag::Artist]).unwrap();
+        assert!(contains(&kept, b"Exif\0\0MM\0*"));
+        assert!(contains(&kept, b"Ann"));
+        assert!(!contains(&kept, b"xmpmeta"));
+    }
+
+    #[test]
+    fn rejects_malformed_jpegs() {
+        let bytes = jpeg_with_metadata();
+        assert!(strip_jpeg(b"").is_err());
+        assert!(strip_jpeg(&[0xFF, 0xD8]).is_err());
+        assert!(strip_jpeg(b"\x89PNG").is_err());
+        // Cut off inside a segment, and before the start of scan
+        assert!(strip_jpeg(&bytes[..30]).is_err());
+        assert!(strip_jpeg(&bytes[..bytes.len() - 12]).is_err());
+        // A segment length shorter than the length field itself
+        assert!(strip_jpeg(&[0xFF, 0xD8, 0xFF, 0xE1, 0x00, 0x01, 0x00, 0x00]).is_err());
+        // Garbage where a marker should be
+        assert!(strip_jpeg(&[0xFF, 0xD8, 0x00, 0xE1, 0x00, 0x04, 0x00, 0x00]).is_err());
+        assert!(insert_jpeg_exif(b"", b"").is_err());
+    }
+
+    fn png_with_metadata() -> Vec<u8> {
+        let mut bytes = PNG_SIGNATURE.to_vec();
+        bytes.extend(png_chunk(b"IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 0, 0, 0]));
+        bytes.extend(png_chunk(b"tEXt", b"Comment\0taken at home"));
+        bytes.extend(png_chunk(b"eXIf", &artist_exif()));
+        bytes.extend(png_chunk(b"iTXt", b"XML:com.adobe.xmp\0\0\0\0\0<x:xmpmeta/>"));
+        bytes.extend(png_chunk(b"IDAT", &[1, 2, 3]));
+        bytes.extend(png_chunk(b"IEND", &[]));
+        bytes
+    }
+
+    #[test]
+    fn strips_png_metadata() {
+        let (stripped, exif) = strip_png(&png_with_metadata()).unwrap();
+
+        let mut expected = PNG_SIGNATURE.to_vec();
+        expected.extend(png_chunk(b"IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 0, 0, 0]));
+        expected.extend(png_chunk(b"IDAT", &[1, 2, 3]));
+        expected.extend(png_chunk(b"IEND", &[]));
+        assert_eq!(stripped, expected);
+        assert_eq!(exif, Some(artist_exif()));
+
+        let kept = strip(&png_with_metadata(), "image/png", &[Tag::Artist]).unwrap();
+        assert!(contains(&kept, b"eXIf"));
+        assert!(contains(&kept, b"Ann"));
+        assert!(!contains(&kept, b"taken at home"));
+    }
+
+    #[test]
+    fn rejects_malformed_pngs() {
+        let bytes = png_with_metadata();
+        assert!(strip_png(b"").is_err());
+        assert!(strip_png(&PNG_SIGNATURE).is_err());
+        assert!(strip_png(&bytes[..bytes.len() - 12]).is_err());
+        assert!(strip_png(&bytes[..40]).is_err());
+
+        // A chunk claiming to be far longer than the file
+        let mut long = PNG_SIGNATURE.to_vec();
+        long.extend_from_slice(&u32::MAX.to_be_bytes());
+        long.extend_from_slice(b"IHDR\0\0\0\0");
+        assert!(strip_png(&long).is_err());
+
+        assert!(insert_png_exif(&PNG_SIGNATURE, b"exif").is_err());
+        assert!(insert_png_exif(&bytes[..20], b"exif").is_err());
+    }
+
+    fn webp_with_metadata() -> Vec<u8> {
+        webp(&[
+            webp_chunk(b"VP8X", &[WEBP_EXIF_FLAG | WEBP_XMP_FLAG | 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            webp_chunk(b"VP8L", &[0x2F, 0, 0, 0, 0]),
+            webp_chunk(b"EXIF", &artist_exif()),
+            webp_chunk(b"XMP ", b"<x:xmpmeta/>"),
+        ])
+    }
+
+    #[test]
+    fn strips_webp_metadata() {
+        let (stripped, exif) = strip_webp(&webp_with_metadata()).unwrap();
+
+        let expected = webp(&[
+            webp_chunk(b"VP8X", &[0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            webp_chunk(b"VP8L", &[0x2F, 0, 0, 0, 0]),
+        ]);
+        assert_eq!(stripped, expected);
+        assert_eq!(exif, Some(artist_exif()));
+
+        let kept = strip(&webp_with_metadata(), "image/webp", &[Tag::Artist]).unwrap();
+        assert_eq!(kept[20] & WEBP_EXIF_FLAG, WEBP_EXIF_FLAG);
+        assert_eq!(kept[20] & WEBP_XMP_FLAG, 0);
+        assert!(contains(&kept, b"Ann"));
+        assert_eq!(u32::from_le_bytes(kept[4..8].try_into().unwrap()) as usize, kept.len() - 8);
+    }
+
+    #[test]
+    fn rejects_malformed_webps() {
+        let bytes = webp_with_metadata();
+        assert!(strip_webp(b"").is_err());
+        assert!(strip_webp(b"RIFF\0\0\0\0WEBM").is_err());
+        assert!(strip_webp(&bytes[..bytes.len() - 4]).is_err());
+        // VP8X chunks too short to hold their flags
+        assert!(strip_webp(&webp(&[webp_chunk(b"VP8X", &[])])).is_err());
+        assert!(strip_webp(&webp(&[webp_chunk(b"VP8X", &[WEBP_EXIF_FLAG])])).is_err());
+
+        assert!(insert_webp_exif(b"RIFF\0\0\0\0WEBP", b"exif").is_err());
+        assert!(insert_webp_exif(b"RIFF\0\0\0\0WEBPVP8X\0\0\0\0", b"exif").is_err());
+        // Simple WebPs go without
+        let simple = webp(&[webp_chunk(b"VP8L", &[0x2F, 0, 0, 0, 0])]);
+        assert_eq!(insert_webp_exif(&simple, b"exif").unwrap(), simple);
+    }
+
+    // A 1x1 GIF, with whatever extensions go before its image
+    fn gif(extensions: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\x00\x00\x00\xFF\xFF\xFF".to_vec();
+        for extension in extensions {
+            bytes.extend_from_slice(extension);
+        }
+        bytes.extend_from_slice(b"\x21\xF9\x04\x01\x00\x00\x00\x00");
+        bytes.extend_from_slice(b"\x2C\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02\x44\x01\x00\x3B");
+        bytes
+    }
+
+    const GIF_XMP: &[u8] = b"\x21\xFF\x0BXMP DataXMP\x0C<x:xmpmeta/>\x00";
+    const GIF_COMMENT: &[u8] = b"\x21\xFE\x0Dtaken at home\x00";
+    const GIF_LOOP: &[u8] = b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00";
+
+    #[test]
+    fn strips_gif_metadata() {
+        let stripped = strip(&gif(&[GIF_LOOP, GIF_XMP, GIF_COMMENT]), "image/gif", &[]).unwrap();
+        assert_eq!(stripped, gif(&[GIF_LOOP]));
+        assert!(!contains(&stripped, b"xmpmeta"));
+        assert!(!contains(&stripped, b"at home"));
+        assert!(image::load_from_memory(&stripped).is_ok());
+
+        assert_eq!(strip(&gif(&[]), "image/gif", &[]).unwrap(), gif(&[]));
+    }
+
+    #[test]
+    fn rejects_malformed_gifs() {
+        let bytes = gif(&[GIF_XMP]);
+        assert!(strip_gif(b"GIF89a").is_err());
+        assert!(strip_gif(&bytes[..bytes.len() - 1]).is_err());
+        assert!(strip_gif(&bytes[..20]).is_err());
+        // A colour table running past the end
+        assert!(strip_gif(b"GIF89a\x01\x00\x01\x00\x87\x00\x00").is_err());
+        let mut garbage = gif(&[]);
+        garbage.insert(19, 0x99);
+        assert!(strip_gif(&garbage).is_err());
+    }
+
+    fn bmff_box(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut bytes = ((data.len() + 8) as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(kind);
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    // An AVIF with an image item and whatever other item types are given
+    fn avif(item_types: &[&[u8]]) -> Vec<u8> {
+        let mut entries = Vec::new();
+        for (id, item_type) in [b"av01".as_slice()].iter().chain(item_types).enumerate() {
+            let mut infe = vec![2, 0, 0, 0];
+            infe.extend_from_slice(&(id as u16 + 1).to_be_bytes());
+            infe.extend_from_slice(&[0, 0]);
+            infe.extend_from_slice(item_type);
+            infe.push(0);
+            entries.extend(bmff_box(b"infe", &infe));
+        }
+        let mut iinf = vec![0, 0, 0, 0];
+        iinf.extend_from_slice(&(item_types.len() as u16 + 1).to_be_bytes());
+        iinf.extend(entries);
+
+        let mut meta = vec![0, 0, 0, 0];
+        meta.extend(bmff_box(b"hdlr", &[0; 24]));
+        meta.extend(bmff_box(b"iinf", &iinf));
+
+        let mut bytes = bmff_box(b"ftyp", b"avif\0\0\0\0avifmif1");
+        bytes.extend(bmff_box(b"meta", &meta));
+        bytes.extend(bmff_box(b"mdat", &[1, 2, 3]));
+        bytes
+    }
+
+    #[test]
+    fn refuses_avif_metadata() {
+        assert_eq!(strip(&avif(&[]), "image/avif", &[]).unwrap(), avif(&[]));
+        assert!(strip(&avif(&[b"Exif"]), "image/avif", &[]).unwrap_err().contains("EXIF"));
+        assert!(strip(&avif(&[b"mime"]), "image/avif", &[]).unwrap_err().contains("XMP"));
+        // A box claiming to be longer than the file
+        let mut truncated = avif(&[]);
+        truncated.truncate(40);
+        assert!(strip(&truncated, "image/avif", &[]).is_err());
+    }
+
+    #[test]
+    fn leaves_other_types_alone() {
+        assert_eq!(strip(b"%PDF-1.7", "application/pdf", &[]).unwrap(), b"%PDF-1.7");
+    }
+}
@@ -0,0 +1,225 @@
+// How many bytes sniff needs to see to recognise every type below
+pub const SNIFF_LENGTH: usize = 512;
+
+/*
+Works out the MIME type from the file signature (magic bytes).
+Used when an object was stored without a content type, as browsers refuse to show some files without one.
+ */
+pub fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        return Some("image/jpeg");
+    }
+    if bytes.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+        return Some("image/png");
+    }
+    if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        return Some("image/gif");
+    }
+    if bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+    // ISO-BMFF is shared by many formats (HEIC, QuickTime, ...), the major brand says which this is
+    if bytes.len() >= 12 && &bytes[4..8] == b"ftyp" {
+        return match &bytes[8..12] {
+            b"avif" | b"avis" => Some("image/avif"),
+            b"isom" | b"iso2" | b"iso4" | b"iso5" | b"iso6" | b"mp41" | b"mp42" | b"avc1" | b"dash" | b"M4V " => Some("video/mp4"),
+            _ => None,
+        };
+    }
+    if bytes.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        return Some("video/webm");
+    }
+    if bytes.starts_with(b"ID3") || bytes.starts_with(&[0xFF, 0xFB]) || bytes.starts_with(&[0xFF, 0xF3]) {
+        return Some("audio/mpeg");
+    }
+    if bytes.starts_with(b"OggS") {
+        return Some("audio/ogg");
+    }
+    if bytes.starts_with(b"%PDF-") {
+        return Some("application/pdf");
+    }
+    if is_svg(bytes) {
+        return Some("image/svg+xml");
+    }
+    None
+}
+
+// Types sniff can recognise, an upload claiming one of these has to have the right signature
+fn recognised(content_type: &str) -> bool {
+    matches!(content_type, "image/jpeg" | "image/png" | "image/gif" | "image/webp" | "image/avif" | "video/mp4"
+        | "video/webm" | "audio/mpeg" | "audio/ogg" | "application/pdf" | "image/svg+xml")
+}
+
+/*
+Checks an upload really is what it claims to be, so a browser can't be talked into treating it as something else.
+Types we can't sniff are let through, as there is nothing to check them against.
+ */
+pub fn validate(bytes: &[u8], content_type: &str) -> Result<(), String> {
+    validate_parts(bytes, bytes, content_type)
+}
+
+/*
+The same checks for a file that was never whole in memory, given its first POLYGLOT_LENGTH
+and last ZIP_TAIL_LENGTH bytes (which may overlap for small files).
+ */
+pub fn validate_parts(start: &[u8], tail: &[u8], content_type: &str) -> Result<(), String> {
+    check_signature(start, content_type)?;
+
+    match polyglot(start, tail, content_type) {
+        Some(format) => Err(format!("File also contains {}", format)),
+        None => Ok(()),
+    }
+}
+
+// Only needs the first SNIFF_LENGTH bytes, so uploads that arrive in pieces can be checked early
+pub fn check_signature(start: &[u8], content_type: &str) -> Result<(), String> {
+    match sniff(start) {
+        Some(sniffed) if sniffed == content_type => Ok(()),
+        Some(sniffed) => Err(format!("File looks like {} but was uploaded as {}", sniffed, content_type)),
+        None if recognised(content_type) => Err(format!("File does not look like {}", content_type)),
+        None => Ok(()),
+    }
+}
+
+// How far in browsers and PDF readers look for their own signatures
+pub const POLYGLOT_LENGTH: usize = 1024;
+
+// The ZIP end of central directory record sits within its maximum comment length of the end
+pub const ZIP_TAIL_LENGTH: usize = 22 + 65535;
+
+/*
+Looks for a second format hidden in a file that is valid as its declared type,
+e.g. a GIF that is also an HTML page, or a JPEG with a ZIP (or JAR) appended.
+ */
+fn polyglot(start: &[u8], tail: &[u8], content_type: &str) -> Option<&'static str> {
+    let start = start[..start.len().min(POLYGLOT_LENGTH)].to_ascii_lowercase();
+    let contains = |haystack: &[u8], needle: &[u8]| haystack.windows(needle.len()).any(|window| window == needle);
+
+    // SVGs are markup anyway and get sanitized instead
+    if content_type != "image/svg+xml" {
+        let markup: [&[u8]; 6] = [b"<html", b"<head", b"<body", b"<script", b"<iframe", b"<!doctype html"];
+        if markup.iter().any(|tag| contains(&start, tag)) {
+            return Some("HTML");
+        }
+    }
+
+    if content_type != "application/pdf" && contains(&start, b"%pdf-") {
+        return Some("PDF");
+    }
+
+    if contains(&tail[tail.len().saturating_sub(ZIP_TAIL_LENGTH)..], b"PK\x05\x06") {
+        return Some("ZIP");
+    }
+    None
+}
+
+fn is_svg(bytes: &[u8]) -> bool {
+    let start = &bytes[..bytes.len().min(SNIFF_LENGTH)];
+    let text = match std::str::from_utf8(start) {
+        Ok(text) => text,
+        // The cut off may have landed in the middle of a character
+        Err(error) => std::str::from_utf8(&start[..error.valid_up_to()]).unwrap_or_default(),
+    };
+    let text = text.trim_start_matches('\u{feff}').trim_start();
+    (text.starts_with("<?xml") || text.starts_with("<svg") || text.starts_with("<!DOCTYPE svg") || text.starts_with("<!--"))
+        && text.contains("<svg")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0, 0, 0, 0x0D, b'I', b'H', b'D', b'R'];
+
+    fn ftyp(brand: &[u8; 4]) -> Vec<u8> {
+        let mut bytes = vec![0, 0, 0, 0x18];
+        bytes.extend_from_slice(b"ftyp");
+        bytes.extend_from_slice(brand);
+        bytes.extend_from_slice(&[0; 12]);
+        bytes
+    }
+
+    #[test]
+    fn sniffs_signatures() {
+        assert_eq!(sniff(&[0xFF, 0xD8, 0xFF, 0xE0]), Some("image/jpeg"));
+        assert_eq!(sniff(PNG), Some("image/png"));
+        assert_eq!(sniff(b"GIF89a\x01\x00"), Some("image/gif"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(b"%PDF-1.7"), Some("application/pdf"));
+        assert_eq!(sniff(b"<?xml version=\"1.0\"?><svg xmlns=\"http://www.w3.org/2000/svg\"/>"), Some("image/svg+xml"));
+        assert_eq!(sniff(b"plain text"), None);
+    }
+
+    #[test]
+    fn sniffs_iso_bmff_brands() {
+        assert_eq!(sniff(&ftyp(b"avif")), Some("image/avif"));
+        assert_eq!(sniff(&ftyp(b"isom")), Some("video/mp4"));
+        assert_eq!(sniff(&ftyp(b"mp42")), Some("video/mp4"));
+        assert_eq!(sniff(&ftyp(b"heic")), None);
+        assert_eq!(sniff(&ftyp(b"qt  ")), None);
+    }
+
+    #[test]
+    fn heic_and_quicktime_are_not_mistaken_for_mp4() {
+        assert_eq!(validate(&ftyp(b"heic"), "image/heic"), Ok(()));
+        assert_eq!(validate(&ftyp(b"qt  "), "video/quicktime"), Ok(()));
+        assert!(validate(&ftyp(b"heic"), "video/mp4").is_err());
+    }
+
+    #[test]
+    fn rejects_type_mismatch() {
+        assert_eq!(validate(PNG, "image/png"), Ok(()));
+        assert_eq!(validate(PNG, "image/jpeg"), Err("File looks like image/png but was uploaded as image/jpeg".to_string()));
+        assert_eq!(validate(b"plain text", "image/png"), Err("File does not look like image/png".to_string()));
+    }
+
+    #[test]
+    fn lets_unknown_types_through() {
+        assert_eq!(validate(b"plain text", "text/plain"), Ok(()));
+    }
+
+    #[test]
+    fn rejects_html_polyglot() {
+        let mut gif = b"GIF89a".to_vec();
+        gif.extend_from_slice(b"/*<html><script>alert(1)</script>*/");
+        assert_eq!(validate(&gif, "image/gif"), Err("File also contains HTML".to_string()));
+
+        // Case doesn't matter to browsers either
+        let mut gif = b"GIF89a".to_vec();
+        gif.extend_from_slice(b"<SCRIPT>alert(1)</SCRIPT>");
+        assert!(validate(&gif, "image/gif").is_err());
+    }
+
+    #[test]
+    fn rejects_pdf_polyglot() {
+        let mut png = PNG.to_vec();
+        png.extend_from_slice(b"%PDF-1.4");
+        assert_eq!(validate(&png, "image/png"), Err("File also contains PDF".to_string()));
+        assert_eq!(validate(b"%PDF-1.4\n%%EOF", "application/pdf"), Ok(()));
+    }
+
+    #[test]
+    fn rejects_appended_zip() {
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0];
+        jpeg.extend_from_slice(&[0; 2048]);
+        jpeg.extend_from_slice(b"PK\x05\x06");
+        jpeg.extend_from_slice(&[0; 18]);
+        assert_eq!(validate(&jpeg, "image/jpeg"), Err("File also contains ZIP".to_string()));
+    }
+
+    #[test]
+    fn checks_parts() {
+        let mut tail = vec![0; 64];
+        tail.extend_from_slice(b"PK\x05\x06");
+        assert_eq!(validate_parts(PNG, &[0; 64], "image/png"), Ok(()));
+        assert_eq!(validate_parts(PNG, &tail, "image/png"), Err("File also contains ZIP".to_string()));
+    }
+
+    #[test]
+    fn markup_only_counts_near_the_start() {
+        let mut png = PNG.to_vec();
+        png.extend_from_slice(&[0; POLYGLOT_LENGTH]);
+        png.extend_from_slice(b"<html>");
+        assert_eq!(validate(&png, "image/png"), Ok(()));
+    }
+}
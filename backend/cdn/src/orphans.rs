@@ -0,0 +1,76 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use actix_web::web;
+use mongodb::bson::DateTime;
+use serde::Serialize;
+use crate::cache::HotCache;
+use crate::routes::library::delete_media;
+use crate::storage::Storage;
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct OrphanReport {
+    pub finished: Option<DateTime>,
+    pub orphans: Vec<media::MediaObject>,
+    pub deleted: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+// Last sweep result, shared with the admin routes
+#[derive(Default)]
+pub struct OrphanState {
+    pub last: Mutex<Option<OrphanReport>>,
+}
+
+/*
+Finds media no post links to anymore. The grace period counts from when the last link went,
+which leaves time to use something that has only just been uploaded, or that was taken out of
+a post by mistake. Orphans are only deleted when asked to, otherwise they are just reported.
+ */
+pub async fn sweep(db: &dyn Storage, client: &mongodb::Client, cache: &HotCache, grace: Duration, delete: bool) -> Result<OrphanReport, String> {
+    let cutoff = DateTime::from_millis(DateTime::now().timestamp_millis() - grace.as_millis() as i64);
+    let orphans = media::find_orphans(client, cutoff).await
+        .map_err(|error| format!("Error finding orphans: {}", error))?;
+
+    let mut report = OrphanReport::default();
+    if delete {
+        for orphan in &orphans {
+            // A post may have linked it since it was found
+            match media::delete_orphan(client, &orphan.bucket, &orphan.name, cutoff).await {
+                Ok(true) => {},
+                Ok(false) => continue,
+                Err(error) => {
+                    eprintln!("Error removing orphan {}/{} from the index: {}", orphan.bucket, orphan.name, error);
+                    report.failed.push(format!("{}/{}", orphan.bucket, orphan.name));
+                    continue;
+                }
+            }
+            match delete_media(db, client, cache, orphan).await {
+                Ok(_) => report.deleted.push(format!("{}/{}", orphan.bucket, orphan.name)),
+                Err(error) => {
+                    eprintln!("Error deleting orphan {}/{}: {}", orphan.bucket, orphan.name, error);
+                    report.failed.push(format!("{}/{}", orphan.bucket, orphan.name));
+                }
+            }
+        }
+    }
+
+    report.orphans = orphans;
+    report.finished = Some(DateTime::now());
+    Ok(report)
+}
+
+pub fn spawn_sweep(db: Arc<dyn Storage>, client: mongodb::Client, cache: web::Data<HotCache>, every: Duration, grace: Duration, delete: bool, state: web::Data<OrphanState>) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(every);
+        loop {
+            interval.tick().await;
+            match sweep(db.as_ref(), &client, &cache, grace, delete).await {
+                Ok(report) => {
+                    println!("Orphan sweep found {} orphans, deleted {}", report.orphans.len(), report.deleted.len());
+                    *state.last.lock().unwrap() = Some(report);
+                }
+                Err(error) => eprintln!("{}", error),
+            }
+        }
+    });
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use image::{ImageResult, RgbImage};
+use media::ImageInfo;
+
+// Object metadata keys the placeholder is stored under, next to the hash
+const WIDTH_METADATA: &str = "width";
+const HEIGHT_METADATA: &str = "height";
+const COLOR_METADATA: &str = "color";
+const BLURHASH_METADATA: &str = "blurhash";
+
+// Types the image crate can decode, anything else gets no placeholder
+pub fn supported(content_type: &str) -> bool {
+    matches!(content_type, "image/jpeg" | "image/png" | "image/gif" | "image/webp")
+}
+
+// The image is shrunk to this first, a placeholder has no use for more detail and it keeps the maths cheap
+const SAMPLE_SIZE: u32 = 32;
+
+pub fn compute(bytes: &[u8]) -> ImageResult<ImageInfo> {
+    let image = image::load_from_memory(bytes)?;
+    let sample = image.thumbnail(SAMPLE_SIZE, SAMPLE_SIZE).to_rgb8();
+
+    // More components along the longer side, so the blur follows the image's shape
+    let (x_components, y_components) = if image.width() >= image.height() { (4, 3) } else { (3, 4) };
+
+    Ok(ImageInfo {
+        width: image.width(),
+        height: image.height(),
+        color: dominant_color(&sample),
+        blurhash: blurhash(&sample, x_components, y_components),
+    })
+}
+
+pub fn to_metadata(image: &ImageInfo, metadata: &mut HashMap<String, String>) {
+    metadata.insert(WIDTH_METADATA.to_string(), image.width.to_string());
+    metadata.insert(HEIGHT_METADATA.to_string(), image.height.to_string());
+    metadata.insert(COLOR_METADATA.to_string(), image.color.clone());
+    metadata.insert(BLURHASH_METADATA.to_string(), image.blurhash.clone());
+}
+
+pub fn from_metadata(metadata: &HashMap<String, String>) -> Option<ImageInfo> {
+    Some(ImageInfo {
+        width: metadata.get(WIDTH_METADATA)?.parse().ok()?,
+        height: metadata.get(HEIGHT_METADATA)?.parse().ok()?,
+        color: metadata.get(COLOR_METADATA)?.clone(),
+        blurhash: metadata.get(BLURHASH_METADATA)?.clone(),
+    })
+}
+
+/*
+The most common colour, rather than the average which turns most photos grey-brown.
+Pixels are grouped by their top 4 bits per channel and the biggest group is averaged.
+ */
+fn dominant_color(image: &RgbImage) -> String {
+    let mut groups: HashMap<(u8, u8, u8), (u32, [u32; 3])> = HashMap::new();
+    for pixel in image.pixels() {
+        let [r, g, b] = pixel.0;
+        let group = groups.entry((r >> 4, g >> 4, b >> 4)).or_insert((0, [0; 3]));
+        group.0 += 1;
+        group.1[0] += r as u32;
+        group.1[1] += g as u32;
+        group.1[2] += b as u32;
+    }
+
+    match groups.values().max_by_key(|(count, _)| *count) {
+        Some((count, [r, g, b])) => format!("#{:02x}{:02x}{:02x}", r / count, g / count, b / count),
+        None => "#000000".to_string(),
+    }
+}
+
+const BASE83: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+fn base83(value: u32, length: u32, output: &mut String) {
+    for digit in 1..=length {
+        output.push(BASE83[(value / 83u32.pow(length - digit) % 83) as usize] as char);
+    }
+}
+
+fn to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.0;
+    if value <= 0.04045 { value / 12.92 } else { ((value + 0.055) / 1.055).powf(2.4) }
+}
+
+fn to_srgb(value: f32) -> u32 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.0031308 {
+        (value * 12.92 * 255.0 + 0.5) as u32
+    } else {
+        ((1.055 * value.powf(1.0 / 2.4) - 0.055) * 255.0 + 0.5) as u32
+    }
+}
+
+fn sign_pow(value: f32, exponent: f32) -> f32 {
+    value.abs().powf(exponent).copysign(value)
+}
+
+// Encodes the image as described in https://github.com/woltapp/blurhash/blob/master/Algorithm.md
+fn blurhash(image: &RgbImage, x_components: u32, y_components: u32) -> String {
+    let (width, height) = image.dimensions();
+    let mut factors: Vec<[f32; 3]> = Vec::new();
+
+    for j in 0..y_components {
+        for i in 0..x_components {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut factor = [0.0; 3];
+            for (x, y, pixel) in image.enumerate_pixels() {
+                let basis = normalisation
+                    * (PI * i as f32 * x as f32 / width as f32).cos()
+                    * (PI * j as f32 * y as f32 / height as f32).cos();
+                for (value, channel) in factor.iter_mut().zip(pixel.0) {
+                    *value += basis * to_linear(channel);
+                }
+            }
+            let scale = 1.0 / (width * height) as f32;
+            factors.push(factor.map(|value| value * scale));
+        }
+    }
+
+    let mut hash = String::new();
+    base83((x_components - 1) + (y_components - 1) * 9, 1, &mut hash);
+
+    let (dc, ac) = factors.split_first().unwrap();
+    let maximum = if ac.is_empty() {
+        base83(0, 1, &mut hash);
+        1.0
+    } else {
+        let actual = ac.iter().flatten().fold(0.0f32, |max, value| max.max(value.abs()));
+        let quantised = ((actual * 166.0 - 0.5).floor() as i32).clamp(0, 82) as u32;
+        base83(quantised, 1, &mut hash);
+        (quantised + 1) as f32 / 166.0
+    };
+
+    base83((to_srgb(dc[0]) << 16) + (to_srgb(dc[1]) << 8) + to_srgb(dc[2]), 4, &mut hash);
+
+    for factor in ac {
+        let quantise = |value: f32| ((sign_pow(value / maximum, 0.5) * 9.0 + 9.5).floor() as i32).clamp(0, 18) as u32;
+        base83(quantise(factor[0]) * 19 * 19 + quantise(factor[1]) * 19 + quantise(factor[2]), 2, &mut hash);
+    }
+
+    hash
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    // Anyone with the hash URL
+    Public,
+    // Only callers with a logged in session (or a signed URL)
+    Session,
+    // Only signed, unexpired URLs
+    Signed,
+    // Stored but never served
+    Deny,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BucketPolicies {
+    buckets: HashMap<String, Access>,
+}
+
+impl Access {
+    fn parse(access: &str) -> Option<Access> {
+        match access {
+            "public" => Some(Access::Public),
+            "session" => Some(Access::Session),
+            "signed" => Some(Access::Signed),
+            "deny" => Some(Access::Deny),
+            _ => None,
+        }
+    }
+}
+
+impl BucketPolicies {
+    // Parses BLOG_CDN_BUCKETS style lists, e.g. "images:public,members:session,drafts:signed,backups:deny"
+    pub fn parse(buckets: &str) -> Result<BucketPolicies, String> {
+        let mut policies = BucketPolicies::default();
+
+        for entry in buckets.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+            let (bucket, access) = match entry.split_once(':') {
+                Some(entry) => entry,
+                None => return Err(format!("Bucket policy {} is missing an access level", entry)),
+            };
+
+            let bucket = bucket.trim();
+            if bucket.is_empty() {
+                return Err(format!("Bucket policy {} is missing a bucket name", entry));
+            }
+
+            let access = match Access::parse(access.trim()) {
+                Some(access) => access,
+                None => return Err(format!("Unknown access level {} for bucket {}", access.trim(), bucket)),
+            };
+            // Two policies for one bucket is a typo, and which one should win isn't clear
+            if policies.buckets.insert(bucket.to_string(), access).is_some() {
+                return Err(format!("Bucket {} has more than one policy", bucket));
+            }
+        }
+
+        Ok(policies)
+    }
+
+    // Buckets without a policy get None and must be treated as not existing
+    pub fn access(&self, bucket: &str) -> Option<Access> {
+        self.buckets.get(bucket).copied()
+    }
+
+    pub fn buckets(&self) -> Vec<String> {
+        self.buckets.keys().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_policies() {
+        let policies = BucketPolicies::parse("images:public,members:session,drafts:signed,backups:deny").unwrap();
+        assert_eq!(policies.access("images"), Some(Access::Public));
+        assert_eq!(policies.access("members"), Some(Access::Session));
+        assert_eq!(policies.access("drafts"), Some(Access::Signed));
+        assert_eq!(policies.access("backups"), Some(Access::Deny));
+        assert_eq!(policies.buckets().len(), 4);
+    }
+
+    #[test]
+    fn unlisted_buckets_have_no_access() {
+        let policies = BucketPolicies::parse("images:public").unwrap();
+        assert_eq!(policies.access("drafts"), None);
+        assert_eq!(policies.access("Images"), None);
+        assert_eq!(BucketPolicies::parse("").unwrap().access("images"), None);
+    }
+
+    #[test]
+    fn ignores_stray_whitespace_and_empty_entries() {
+        let policies = BucketPolicies::parse(" images : public ,, drafts:signed, ,").unwrap();
+        assert_eq!(policies.access("images"), Some(Access::Public));
+        assert_eq!(policies.access("drafts"), Some(Access::Signed));
+        assert_eq!(policies.buckets().len(), 2);
+    }
+
+    #[test]
+    fn rejects_unknown_access() {
+        for buckets in ["images:private", "images:Public", "images:", "images"] {
+            assert!(BucketPolicies::parse(buckets).is_err(), "{}", buckets);
+        }
+    }
+
+    #[test]
+    fn rejects_duplicate_and_unnamed_buckets() {
+        assert!(BucketPolicies::parse("images:public,images:signed").is_err());
+        assert!(BucketPolicies::parse("images:public, images :public").is_err());
+        assert!(BucketPolicies::parse(":public").is_err());
+    }
+}
@@ -0,0 +1,279 @@
+use std::io::Cursor;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, ImageResult};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct TransformQuery {
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+    pub fit: Option<String>,
+    pub format: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fit {
+    // Scale down to fit inside the box, keeping the aspect ratio
+    Contain,
+    // Scale and crop so the box is completely covered
+    Cover,
+    // Stretch to exactly the requested size
+    Fill,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    WebP,
+    Avif,
+    Jpeg,
+    Png,
+}
+
+#[derive(Clone, Debug)]
+pub struct Transform {
+    pub width: u32,
+    pub height: u32,
+    pub fit: Fit,
+    pub format: OutputFormat,
+}
+
+impl Fit {
+    fn parse(fit: &str) -> Option<Fit> {
+        match fit {
+            "contain" => Some(Fit::Contain),
+            "cover" => Some(Fit::Cover),
+            "fill" => Some(Fit::Fill),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+            Fit::Fill => "fill",
+        }
+    }
+}
+
+impl OutputFormat {
+    fn parse(format: &str) -> Option<OutputFormat> {
+        match format {
+            "webp" => Some(OutputFormat::WebP),
+            "avif" => Some(OutputFormat::Avif),
+            "jpeg" | "jpg" => Some(OutputFormat::Jpeg),
+            "png" => Some(OutputFormat::Png),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::WebP => "webp",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::Avif => "image/avif",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+        }
+    }
+}
+
+/*
+Turns the query string into a transform. Returns Ok(None) when no transform was asked for,
+so the original object is served untouched.
+Only sizes in the allowlist are accepted, otherwise anyone could make us render and store every size imaginable.
+ */
+pub fn parse(query: &TransformQuery, allowed_sizes: &[(u32, u32)]) -> Result<Option<Transform>, String> {
+    if query.w.is_none() && query.h.is_none() && query.fit.is_none() && query.format.is_none() {
+        return Ok(None);
+    }
+
+    let width = query.w.unwrap_or(0);
+    let height = query.h.unwrap_or(0);
+
+    if (width != 0 || height != 0) && !allowed_sizes.contains(&(width, height)) {
+        return Err(format!("Size {}x{} is not allowed", width, height));
+    }
+
+    let fit = match &query.fit {
+        Some(fit) => Fit::parse(fit).ok_or_else(|| format!("Unknown fit {}", fit))?,
+        None => Fit::Contain,
+    };
+
+    let format = match &query.format {
+        Some(format) => OutputFormat::parse(format).ok_or_else(|| format!("Unknown format {}", format))?,
+        None => OutputFormat::WebP,
+    };
+
+    Ok(Some(Transform {
+        width,
+        height,
+        fit,
+        format,
+    }))
+}
+
+// Parses BLOG_CDN_ALLOWED_SIZES style lists, e.g. "320x0,640x0,1280x0,200x200" (0 keeps the aspect ratio)
+pub fn parse_sizes(sizes: &str) -> Vec<(u32, u32)> {
+    sizes.split(',')
+        .filter_map(|size| {
+            let (width, height) = size.trim().split_once('x')?;
+            Some((width.parse().ok()?, height.parse().ok()?))
+        })
+        .collect()
+}
+
+// Variants are stored next to the original, keyed by name and hash so a hit means the source was verified
+pub fn variant_name(name: &str, hash: &str, transform: &Transform) -> String {
+    format!("variants/{}/{}/{}", name, hash, variant_tag(transform))
+}
+
+pub fn variant_tag(transform: &Transform) -> String {
+    format!("{}x{}-{}.{}", transform.width, transform.height, transform.fit.as_str(), transform.format.extension())
+}
+
+pub fn apply(bytes: &[u8], transform: &Transform) -> ImageResult<Vec<u8>> {
+    let image = image::load_from_memory(bytes)?;
+    let resized = resize(image, transform);
+
+    let mut output = Cursor::new(Vec::new());
+    match transform.format {
+        OutputFormat::Jpeg => {
+            let encoder = JpegEncoder::new_with_quality(&mut output, 85);
+            DynamicImage::ImageRgb8(resized.to_rgb8()).write_with_encoder(encoder)?;
+        }
+        OutputFormat::WebP => {
+            DynamicImage::ImageRgba8(resized.to_rgba8()).write_to(&mut output, ImageFormat::WebP)?;
+        }
+        OutputFormat::Avif => {
+            DynamicImage::ImageRgba8(resized.to_rgba8()).write_to(&mut output, ImageFormat::Avif)?;
+        }
+        OutputFormat::Png => {
+            resized.write_to(&mut output, ImageFormat::Png)?;
+        }
+    }
+
+    Ok(output.into_inner())
+}
+
+fn resize(image: DynamicImage, transform: &Transform) -> DynamicImage {
+    if transform.width == 0 && transform.height == 0 {
+        return image;
+    }
+
+    // A zero dimension means "whatever keeps the aspect ratio"
+    let (width, height) = match (transform.width, transform.height) {
+        (0, height) => ((image.width() as u64 * height as u64 / image.height().max(1) as u64) as u32, height),
+        (width, 0) => (width, (image.height() as u64 * width as u64 / image.width().max(1) as u64) as u32),
+        size => size,
+    };
+    let (width, height) = (width.max(1), height.max(1));
+
+    match transform.fit {
+        Fit::Contain => image.resize(width, height, FilterType::Lanczos3),
+        Fit::Cover => image.resize_to_fill(width, height, FilterType::Lanczos3),
+        Fit::Fill => image.resize_exact(width, height, FilterType::Lanczos3),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZES: [(u32, u32); 3] = [(320, 0), (640, 0), (200, 200)];
+
+    fn query(w: Option<u32>, h: Option<u32>, fit: Option<&str>, format: Option<&str>) -> TransformQuery {
+        TransformQuery { w, h, fit: fit.map(str::to_string), format: format.map(str::to_string) }
+    }
+
+    fn transform(w: Option<u32>, h: Option<u32>, fit: Option<&str>, format: Option<&str>) -> Transform {
+        parse(&query(w, h, fit, format), &SIZES).unwrap().unwrap()
+    }
+
+    #[test]
+    fn no_query_is_no_transform() {
+        assert!(parse(&query(None, None, None, None), &SIZES).unwrap().is_none());
+    }
+
+    #[test]
+    fn parses_allowed_sizes() {
+        let parsed = transform(Some(320), None, None, None);
+        assert_eq!((parsed.width, parsed.height), (320, 0));
+        assert_eq!(parsed.fit, Fit::Contain);
+        assert_eq!(parsed.format, OutputFormat::WebP);
+
+        let parsed = transform(Some(200), Some(200), Some("cover"), Some("png"));
+        assert_eq!((parsed.width, parsed.height, parsed.fit, parsed.format), (200, 200, Fit::Cover, OutputFormat::Png));
+
+        // Only converting keeps the original size
+        let parsed = transform(None, None, None, Some("avif"));
+        assert_eq!((parsed.width, parsed.height, parsed.format), (0, 0, OutputFormat::Avif));
+    }
+
+    #[test]
+    fn rejects_sizes_outside_the_allowlist() {
+        for (w, h) in [(Some(321), None), (Some(320), Some(1)), (None, Some(320)), (Some(200), None), (Some(u32::MAX), Some(u32::MAX))] {
+            assert!(parse(&query(w, h, None, None), &SIZES).is_err(), "{:?}x{:?}", w, h);
+        }
+        assert!(parse(&query(Some(320), None, None, None), &[]).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_fits_and_formats() {
+        assert!(parse(&query(Some(320), None, Some("stretch"), None), &SIZES).is_err());
+        assert!(parse(&query(Some(320), None, None, Some("gif")), &SIZES).is_err());
+        assert!(parse(&query(Some(320), None, None, Some("WEBP")), &SIZES).is_err());
+        assert!(parse(&query(None, None, None, Some("")), &SIZES).is_err());
+    }
+
+    #[test]
+    fn parses_size_lists() {
+        assert_eq!(parse_sizes("320x0, 640x0,200x200"), vec![(320, 0), (640, 0), (200, 200)]);
+        // Malformed entries are skipped rather than failing the whole list
+        assert_eq!(parse_sizes("320,x200,axb,-1x5,100x100,"), vec![(100, 100)]);
+        assert!(parse_sizes("").is_empty());
+    }
+
+    #[test]
+    fn variant_keys_are_stable() {
+        let first = transform(Some(200), Some(200), Some("cover"), Some("jpg"));
+        let second = transform(Some(200), Some(200), Some("cover"), Some("jpeg"));
+        assert_eq!(variant_tag(&first), "200x200-cover.jpg");
+        assert_eq!(variant_tag(&first), variant_tag(&second));
+        assert_eq!(variant_name("photo.png", "abc", &first), "variants/photo.png/abc/200x200-cover.jpg");
+
+        // Defaults are spelled out, so leaving them off finds the same variant
+        assert_eq!(variant_tag(&transform(Some(320), None, None, None)), variant_tag(&transform(Some(320), Some(0), Some("contain"), Some("webp"))));
+    }
+
+    #[test]
+    fn variant_keys_differ_with_the_transform() {
+        let tags: Vec<String> = [
+            transform(Some(200), Some(200), Some("cover"), None),
+            transform(Some(200), Some(200), Some("fill"), None),
+            transform(Some(200), Some(200), Some("cover"), Some("png")),
+            transform(Some(320), None, None, None),
+        ].iter().map(variant_tag).collect();
+        for (i, tag) in tags.iter().enumerate() {
+            assert!(!tags[i + 1..].contains(tag), "{}", tag);
+        }
+    }
+
+    #[test]
+    fn keeps_the_aspect_ratio_for_zero_dimensions() {
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(8, 4).write_to(&mut png, ImageFormat::Png).unwrap();
+        let resized = apply(png.get_ref(), &Transform { width: 4, height: 0, fit: Fit::Contain, format: OutputFormat::Png }).unwrap();
+        let resized = image::load_from_memory(&resized).unwrap();
+        assert_eq!((resized.width(), resized.height()), (4, 2));
+    }
+}
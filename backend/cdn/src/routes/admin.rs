@@ -0,0 +1,64 @@
+use actix_session::Session;
+use actix_web::{web, HttpRequest, HttpResponse};
+use user::{get_account, Account, Scope};
+use crate::integrity::{self, ScrubState};
+use crate::orphans::OrphanState;
+use crate::storage::Storage;
+use crate::CdnConfig;
+
+// Elevated accounts can upload, only admins can run the maintenance jobs
+pub async fn admin_account(client: &mongodb::Client, req: &HttpRequest, session: &Session) -> Result<Account, HttpResponse> {
+    let account : Account = match get_account(client, req, session, Scope::Admin).await {
+        Ok(account) => account,
+        Err(_) => return Err(HttpResponse::Unauthorized().body("No account found")),
+    };
+
+    if !account.admin {
+        return Err(HttpResponse::Forbidden().finish());
+    }
+    Ok(account)
+}
+
+// bucket
+pub async fn backfill(req: HttpRequest, session: Session, path: web::Path<String>, db: web::Data<dyn Storage>, client: web::Data<mongodb::Client>) -> HttpResponse {
+    if let Err(response) = admin_account(client.get_ref(), &req, &session).await {
+        return response;
+    }
+
+    match integrity::backfill_bucket(db.get_ref(), &path.into_inner()).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(error) => HttpResponse::InternalServerError().body(error),
+    }
+}
+
+pub async fn scrub_report(req: HttpRequest, session: Session, client: web::Data<mongodb::Client>, state: web::Data<ScrubState>) -> HttpResponse {
+    if let Err(response) = admin_account(client.get_ref(), &req, &session).await {
+        return response;
+    }
+
+    match state.last.lock().unwrap().clone() {
+        Some(report) => HttpResponse::Ok().json(report),
+        None => HttpResponse::NotFound().body("No scrub has run yet"),
+    }
+}
+
+pub async fn scrub(req: HttpRequest, session: Session, db: web::Data<dyn Storage>, client: web::Data<mongodb::Client>, config: web::Data<CdnConfig>, state: web::Data<ScrubState>) -> HttpResponse {
+    if let Err(response) = admin_account(client.get_ref(), &req, &session).await {
+        return response;
+    }
+
+    let report = integrity::scrub(db.get_ref(), &config.scrub_buckets).await;
+    *state.last.lock().unwrap() = Some(report.clone());
+    HttpResponse::Ok().json(report)
+}
+
+pub async fn orphan_report(req: HttpRequest, session: Session, client: web::Data<mongodb::Client>, state: web::Data<OrphanState>) -> HttpResponse {
+    if let Err(response) = admin_account(client.get_ref(), &req, &session).await {
+        return response;
+    }
+
+    match state.last.lock().unwrap().clone() {
+        Some(report) => HttpResponse::Ok().json(report),
+        None => HttpResponse::NotFound().body("No orphan sweep has run yet"),
+    }
+}
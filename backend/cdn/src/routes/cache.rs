@@ -0,0 +1,39 @@
+use actix_session::Session;
+use actix_web::{web, HttpRequest, HttpResponse};
+use crate::cache::HotCache;
+use crate::routes::admin::admin_account;
+
+pub async fn stats(req: HttpRequest, session: Session, client: web::Data<mongodb::Client>, cache: web::Data<HotCache>) -> HttpResponse {
+    if let Err(response) = admin_account(client.get_ref(), &req, &session).await {
+        return response;
+    }
+
+    HttpResponse::Ok().json(cache.stats())
+}
+
+pub async fn purge_all(req: HttpRequest, session: Session, client: web::Data<mongodb::Client>, cache: web::Data<HotCache>) -> HttpResponse {
+    if let Err(response) = admin_account(client.get_ref(), &req, &session).await {
+        return response;
+    }
+
+    HttpResponse::Ok().json(cache.purge(None, None))
+}
+
+// bucket
+pub async fn purge_bucket(req: HttpRequest, session: Session, path: web::Path<String>, client: web::Data<mongodb::Client>, cache: web::Data<HotCache>) -> HttpResponse {
+    if let Err(response) = admin_account(client.get_ref(), &req, &session).await {
+        return response;
+    }
+
+    HttpResponse::Ok().json(cache.purge(Some(&path.into_inner()), None))
+}
+
+// bucket, name
+pub async fn purge_object(req: HttpRequest, session: Session, path: web::Path<(String, String)>, client: web::Data<mongodb::Client>, cache: web::Data<HotCache>) -> HttpResponse {
+    if let Err(response) = admin_account(client.get_ref(), &req, &session).await {
+        return response;
+    }
+
+    let (bucket, name) = path.into_inner();
+    HttpResponse::Ok().json(cache.purge(Some(&bucket), Some(&name)))
+}
@@ -1,38 +1,535 @@
-use actix_web::{get, web, HttpResponse};
-use minio_rsc::Minio;
-use reqwest::Response;
-use blake2::{Blake2b512, Digest};
+use std::collections::HashMap;
+use actix_session::Session;
+use actix_web::{web, HttpRequest, HttpResponse, HttpResponseBuilder};
+use actix_web::body::SizedStream;
+use actix_web::http::header::{self, CacheControl, CacheDirective, ContentRangeSpec, EntityTag, Header, IfNoneMatch, IfRange, Range};
+use actix_web::web::Bytes;
+use futures::TryStreamExt;
+use crate::cache::{CacheKey, CachedObject, HotCache};
+use crate::cdn::{hash_bytes, HASH_METADATA};
+use crate::mime::{self, SNIFF_LENGTH};
+use crate::policy::Access;
+use crate::resize::{self, Transform, TransformQuery};
+use crate::routes::Services;
+use crate::storage::Storage;
+use crate::CdnConfig;
+use media::SignatureError;
+use serde::Deserialize;
 
-pub async fn get(db: web::Data<Minio>, param: web::Path<(String, String, String)>) -> HttpResponse {
+#[derive(Debug, Deserialize)]
+pub struct SignatureQuery {
+    pub expires: Option<u64>,
+    pub scope: Option<String>,
+    pub sig: Option<String>,
+}
+
+// The object a link points at
+#[derive(Clone, Copy)]
+pub struct ObjectRef<'a> {
+    pub bucket: &'a str,
+    pub name: &'a str,
+    pub hash: &'a str,
+}
+
+// How long the browser (and anything in between) may keep a response
+#[derive(Clone, Copy)]
+struct Caching {
+    public: bool,
+    max_age: u32,
+}
+
+// Everything the steps of serving one object share
+struct Serving<'a> {
+    req: &'a HttpRequest,
+    db: &'a dyn Storage,
+    cache: &'a HotCache,
+    caching: Caching,
+    object: ObjectRef<'a>,
+}
+
+pub async fn get(req: HttpRequest, session: Session, param: web::Path<(String, String, String)>, query: web::Query<TransformQuery>, signature: web::Query<SignatureQuery>, services: Services) -> HttpResponse {
     let bucket_str: String = param.0.clone();
     let name_str: String = param.1.clone();
     let hash_str: String = param.2.clone();
+    let Services { db, client, config, cache } = services;
+    let object = ObjectRef { bucket: &bucket_str, name: &name_str, hash: &hash_str };
+
+    let (access, signed_until) = match authorize(&req, &session, client.get_ref(), &config, object, &signature).await {
+        Ok(authorized) => authorized,
+        Err(response) => return response,
+    };
+
+    // Signed responses must not outlive their link, anything that needs a session is kept out of shared caches
+    let caching = match signed_until {
+        Some(expires) => Caching {
+            public: false,
+            max_age: expires.saturating_sub(media::now()).min(31_536_000) as u32,
+        },
+        None => Caching {
+            public: access == Access::Public,
+            max_age: 31_536_000,
+        },
+    };
+
+    let transform: Option<Transform> = match resize::parse(&query, &config.allowed_sizes) {
+        Ok(transform) => transform,
+        Err(error) => return HttpResponse::BadRequest()
+            .content_type("text/html; charset=utf-8")
+            .body(error),
+    };
+
+    // The URL already names the content, so the hash (plus the variant) is all the ETag needs to be
+    let etag = match &transform {
+        Some(transform) => format!("{}-{}", hash_str, resize::variant_tag(transform)),
+        None => hash_str.clone(),
+    };
+
+    if not_modified(&req, &etag) {
+        let mut response = HttpResponse::NotModified();
+        cache_headers(&mut response, &etag, caching);
+        return response.finish();
+    }
+
+    let cache_key = CacheKey {
+        bucket: bucket_str.clone(),
+        name: match &transform {
+            Some(transform) => resize::variant_name(&name_str, &hash_str, transform),
+            None => name_str.clone(),
+        },
+        hash: hash_str.clone(),
+    };
+
+    if let Some(cached) = cache.get(&cache_key) {
+        return bytes_response(&req, caching, &etag, cached.bytes, cached.content_type);
+    }
+
+    // Serve an already generated variant without touching the original
+    if let Some(transform) = &transform {
+        if let Ok(variant_bytes) = db.get_bytes(&bucket_str, &cache_key.name).await {
+            cache.insert(cache_key, CachedObject {
+                bytes: variant_bytes.clone(),
+                content_type: Some(transform.format.content_type().to_string()),
+            });
+            return bytes_response(&req, caching, &etag, variant_bytes, Some(transform.format.content_type().to_string()));
+        }
+    }
+
+    let stat = match db.head(&bucket_str, &name_str).await {
+        Ok(Some(stat)) => stat,
+        Ok(None) => return HttpResponse::NotFound()
+            .content_type("text/html; charset=utf-8")
+            .body("Object not found"),
+        Err(error) => return HttpResponse::InternalServerError()
+            .content_type("text/html; charset=utf-8")
+            .body(error),
+    };
+
+    // Objects uploaded before hashes were stored in metadata are hashed on every read, until the admin backfill stores one
+    let stored_hash = match stat.metadata.get(HASH_METADATA) {
+        Some(stored_hash) => stored_hash.clone(),
+        None => return get_unhashed(&Serving { req: &req, db: db.get_ref(), cache: &cache, caching, object }, &stat.content_type, transform).await,
+    };
+
+    if stored_hash != hash_str {
+        return HttpResponse::BadRequest()
+            .content_type("text/html; charset=utf-8")
+            .body("Invalid hash");
+    }
+
+    if let Some(transform) = transform {
+        return match fetch_bytes(db.get_ref(), &bucket_str, &name_str).await {
+            Ok(source) => variant(&Serving { req: &req, db: db.get_ref(), cache: &cache, caching, object }, &transform, source).await,
+            Err(response) => response,
+        };
+    }
+
+    // Small objects are read whole so they can be kept in memory for next time
+    if cache.fits(stat.size as usize) {
+        return match fetch_bytes(db.get_ref(), &bucket_str, &name_str).await {
+            Ok(bytes) => {
+                let content_type = content_type(&stat.content_type, &bytes);
+                cache.insert(cache_key, CachedObject {
+                    bytes: bytes.clone(),
+                    content_type: content_type.clone(),
+                });
+                bytes_response(&req, caching, &etag, bytes, content_type)
+            }
+            Err(response) => response,
+        };
+    }
+
+    let size = stat.size;
+    let range = match requested_range(&req, &etag, size) {
+        Ok(range) => range,
+        Err(_) => return HttpResponse::RangeNotSatisfiable()
+            .insert_header(header::ContentRange(ContentRangeSpec::Bytes {
+                range: None,
+                instance_length: Some(size),
+            }))
+            .finish(),
+    };
+
+    // Only sniff when storage doesn't know the type, which costs one small ranged read
+    let content_type = match content_type(&stat.content_type, &[]) {
+        Some(content_type) => Some(content_type),
+        None => match db.get(&bucket_str, &name_str, Some((0, SNIFF_LENGTH as u64 - 1))).await {
+            Ok(stream) => match stream.try_collect::<Vec<Bytes>>().await {
+                Ok(start) => content_type("", &start.concat()),
+                Err(_) => None,
+            },
+            Err(_) => None,
+        },
+    };
+
+    let stream = match db.get(&bucket_str, &name_str, range).await {
+        Ok(stream) => stream,
+        Err(error) => return HttpResponse::InternalServerError()
+            .content_type("text/html; charset=utf-8")
+            .body(error),
+    };
+
+    let stream = stream.map_err(move |error| {
+        eprintln!("Error streaming {}/{}: {}", bucket_str, name_str, error);
+        error
+    });
+
+    match range {
+        Some((start, end)) => {
+            let mut response = HttpResponse::PartialContent();
+            response.insert_header(header::ContentRange(ContentRangeSpec::Bytes {
+                range: Some((start, end)),
+                instance_length: Some(size),
+            }));
+            content_headers(&mut response, &etag, caching, content_type);
+            response.body(SizedStream::new(end - start + 1, stream))
+        }
+        None => {
+            let mut response = HttpResponse::Ok();
+            content_headers(&mut response, &etag, caching, content_type);
+            response.body(SizedStream::new(size, stream))
+        }
+    }
+}
+
+/*
+Applies the bucket's access policy, checking the signature if the link has one.
+Ok holds the access level, and when the link runs out if it was signed.
+ */
+pub async fn authorize(req: &HttpRequest, session: &Session, client: &mongodb::Client, config: &CdnConfig, object: ObjectRef<'_>, signature: &SignatureQuery) -> Result<(Access, Option<u64>), HttpResponse> {
+    // Checked before anything else so unknown buckets never reach storage
+    let access = match config.buckets.access(object.bucket) {
+        Some(Access::Deny) | None => return Err(HttpResponse::NotFound()
+            .content_type("text/html; charset=utf-8")
+            .body("Object not found")),
+        Some(access) => access,
+    };
 
-    match db.get_object(bucket_str, name_str).await {
-        Ok(response) => {
-            match response.bytes().await {
-                Ok(response_bytes) => {
-                    let mut hasher = Blake2b512::new();
-                    hasher.update(response_bytes.clone());
-                    let res = hasher.finalize();
-
-                    let hex_res = format!("{:x}", res);
-                    if hex_res != hash_str {
-                    return HttpResponse::BadRequest()
-                            .content_type("text/html; charset=utf-8")
-                            .body("Invalid hash");
-                    }
-
-                    HttpResponse::Ok()
-                        .body(response_bytes)
+    let signed_until = match &signature.sig {
+        Some(sig) => {
+            let key = match &config.signing_key {
+                Some(key) => key,
+                None => return Err(HttpResponse::Forbidden()
+                    .content_type("text/html; charset=utf-8")
+                    .body("Signed links are not enabled")),
+            };
+            let expires = signature.expires.unwrap_or(0);
+            let scope = signature.scope.clone().unwrap_or_default();
+            match media::verify(key, object.bucket, object.name, object.hash, expires, &scope, sig) {
+                Ok(_) => {
+                    check_scope(client, object, &scope).await?;
+                    Some(expires)
                 }
-                Err(error) => HttpResponse::InternalServerError()
+                Err(SignatureError::Expired) => return Err(HttpResponse::Forbidden()
                     .content_type("text/html; charset=utf-8")
-                    .body(format!("Error fetching response body: {}", error)),
+                    .body("Link has expired")),
+                Err(SignatureError::Invalid) => return Err(HttpResponse::Forbidden()
+                    .content_type("text/html; charset=utf-8")
+                    .body("Invalid signature")),
             }
         }
-        Err(error) => HttpResponse::InternalServerError()
+        None => None,
+    };
+
+    match access {
+        Access::Signed if signed_until.is_none() => return Err(HttpResponse::Forbidden()
+            .content_type("text/html; charset=utf-8")
+            .body("This link needs a signature")),
+        Access::Session if signed_until.is_none() && user::get_account(client, req, session, user::Scope::MediaRead).await.is_err() => {
+            return Err(HttpResponse::Unauthorized()
+                .content_type("text/html; charset=utf-8")
+                .body("You need to be logged in to see this"));
+        }
+        _ => {}
+    }
+
+    Ok((access, signed_until))
+}
+
+/*
+A link signed for a post only works while the post still links the object,
+so taking an image out of a draft takes its links with it.
+ */
+async fn check_scope(client: &mongodb::Client, object: ObjectRef<'_>, scope: &str) -> Result<(), HttpResponse> {
+    if scope.is_empty() {
+        return Ok(());
+    }
+    let post = match scope.split_once(':') {
+        Some(("post", post)) if !post.is_empty() => post,
+        _ => return Err(HttpResponse::Forbidden()
+            .content_type("text/html; charset=utf-8")
+            .body("Unknown link scope")),
+    };
+
+    match media::find_object(client, object.bucket, object.name).await {
+        Ok(Some(indexed)) if indexed.posts.iter().any(|id| id == post) => Ok(()),
+        Ok(_) => Err(HttpResponse::Forbidden()
+            .content_type("text/html; charset=utf-8")
+            .body("Link is not for this object")),
+        Err(error) => {
+            eprintln!("Error checking scope of {}/{}: {}", object.bucket, object.name, error);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+fn not_modified(req: &HttpRequest, etag: &str) -> bool {
+    match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(tags)) => {
+            let etag = EntityTag::new_strong(etag.to_string());
+            tags.iter().any(|tag| tag.weak_eq(&etag))
+        }
+        Err(_) => false,
+    }
+}
+
+// The stored content type wins, unless it's missing or the generic binary type
+fn content_type(stored: &str, bytes: &[u8]) -> Option<String> {
+    if !stored.is_empty() && stored != "application/octet-stream" {
+        return Some(stored.to_string());
+    }
+    mime::sniff(bytes).map(|content_type| content_type.to_string())
+}
+
+// Content at a hash URL can never change, so it can be cached for as long as the access rules allow
+fn cache_headers(response: &mut HttpResponseBuilder, etag: &str, caching: Caching) {
+    response.insert_header(CacheControl(vec![
+        if caching.public { CacheDirective::Public } else { CacheDirective::Private },
+        CacheDirective::MaxAge(caching.max_age),
+        CacheDirective::Extension("immutable".to_string(), None),
+    ]));
+    response.insert_header(header::ETag(EntityTag::new_strong(etag.to_string())));
+}
+
+fn content_headers(response: &mut HttpResponseBuilder, etag: &str, caching: Caching, content_type: Option<String>) {
+    cache_headers(response, etag, caching);
+    response.insert_header((header::ACCEPT_RANGES, "bytes"));
+    response.insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"));
+    response.content_type(content_type.unwrap_or_else(|| "application/octet-stream".to_string()));
+}
+
+/*
+Works out which single byte range to send, Ok(None) means the whole object.
+Multiple ranges are answered with the whole object, which RFC 9110 allows.
+If-Range only holds when it matches the ETag, otherwise the object may have changed so send all of it.
+ */
+fn requested_range(req: &HttpRequest, etag: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let range = match Range::parse(req) {
+        Ok(range) => range,
+        Err(_) => return Ok(None),
+    };
+
+    if let Ok(if_range) = IfRange::parse(req) {
+        let matches = match if_range {
+            IfRange::EntityTag(tag) => tag.strong_eq(&EntityTag::new_strong(etag.to_string())),
+            IfRange::Date(_) => false,
+        };
+        if !matches {
+            return Ok(None);
+        }
+    }
+
+    match range {
+        Range::Bytes(specs) if specs.len() == 1 => match specs[0].to_satisfiable_range(size) {
+            Some(range) => Ok(Some(range)),
+            None => Err(()),
+        },
+        _ => Ok(None),
+    }
+}
+
+fn bytes_response(req: &HttpRequest, caching: Caching, etag: &str, bytes: Bytes, content_type: Option<String>) -> HttpResponse {
+    let size = bytes.len() as u64;
+    match requested_range(req, etag, size) {
+        Ok(Some((start, end))) => {
+            let mut response = HttpResponse::PartialContent();
+            response.insert_header(header::ContentRange(ContentRangeSpec::Bytes {
+                range: Some((start, end)),
+                instance_length: Some(size),
+            }));
+            content_headers(&mut response, etag, caching, content_type);
+            response.body(bytes.slice(start as usize..=end as usize))
+        }
+        Ok(None) => {
+            let mut response = HttpResponse::Ok();
+            content_headers(&mut response, etag, caching, content_type);
+            response.body(bytes)
+        }
+        Err(_) => HttpResponse::RangeNotSatisfiable()
+            .insert_header(header::ContentRange(ContentRangeSpec::Bytes {
+                range: None,
+                instance_length: Some(size),
+            }))
+            .finish(),
+    }
+}
+
+async fn fetch_bytes(db: &dyn Storage, bucket: &str, name: &str) -> Result<Bytes, HttpResponse> {
+    db.get_bytes(bucket, name).await.map_err(|error| HttpResponse::InternalServerError()
+        .content_type("text/html; charset=utf-8")
+        .body(error))
+}
+
+async fn get_unhashed(serving: &Serving<'_>, stored_type: &str, transform: Option<Transform>) -> HttpResponse {
+    let ObjectRef { bucket, name, hash } = serving.object;
+    let response_bytes = match fetch_bytes(serving.db, bucket, name).await {
+        Ok(response_bytes) => response_bytes,
+        Err(response) => return response,
+    };
+
+    // Nothing is written back here, a read shouldn't change the object. The admin backfill does that
+    if hash_bytes(&response_bytes) != hash {
+        return HttpResponse::BadRequest()
             .content_type("text/html; charset=utf-8")
-            .body(format!("Error getting object: {}", error)),
+            .body("Invalid hash");
+    }
+
+    let content_type = content_type(stored_type, &response_bytes);
+    match transform {
+        Some(transform) => variant(serving, &transform, response_bytes).await,
+        None => bytes_response(serving.req, serving.caching, hash, response_bytes, content_type),
+    }
+}
+
+async fn variant(serving: &Serving<'_>, transform: &Transform, source: Bytes) -> HttpResponse {
+    let ObjectRef { bucket, name, hash } = serving.object;
+    let owned_transform = transform.clone();
+    // Resizing is CPU heavy so keep it off the async workers
+    let rendered = match web::block(move || resize::apply(&source, &owned_transform)).await {
+        Ok(Ok(rendered)) => Bytes::from(rendered),
+        Ok(Err(error)) => return HttpResponse::UnprocessableEntity()
+            .content_type("text/html; charset=utf-8")
+            .body(format!("Could not transform image: {}", error)),
+        Err(error) => return HttpResponse::InternalServerError()
+            .content_type("text/html; charset=utf-8")
+            .body(format!("Error transforming image: {}", error)),
+    };
+
+    let variant_name = resize::variant_name(name, hash, transform);
+    if let Err(error) = serving.db.put(bucket, &variant_name, transform.format.content_type(), HashMap::new(), rendered.clone()).await {
+        // Still serve it, we just have to render it again next time
+        eprintln!("Error caching variant of {}/{}: {}", bucket, name, error);
+    }
+
+    serving.cache.insert(CacheKey {
+        bucket: bucket.to_string(),
+        name: variant_name,
+        hash: hash.to_string(),
+    }, CachedObject {
+        bytes: rendered.clone(),
+        content_type: Some(transform.format.content_type().to_string()),
+    });
+
+    let etag = format!("{}-{}", hash, resize::variant_tag(transform));
+    bytes_response(serving.req, serving.caching, &etag, rendered, Some(transform.format.content_type().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    const ETAG: &str = "abc123";
+
+    fn request(headers: &[(&str, &str)]) -> HttpRequest {
+        let mut request = TestRequest::default();
+        for (name, value) in headers {
+            request = request.insert_header((*name, *value));
+        }
+        request.to_http_request()
+    }
+
+    fn range(headers: &[(&str, &str)]) -> Result<Option<(u64, u64)>, ()> {
+        requested_range(&request(headers), ETAG, 100)
+    }
+
+    #[test]
+    fn no_range_is_the_whole_object() {
+        assert_eq!(range(&[]), Ok(None));
+        assert_eq!(range(&[("Range", "lines=1-2")]), Ok(None));
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(range(&[("Range", "bytes=0-9")]), Ok(Some((0, 9))));
+        // Past the end is cut down to the object
+        assert_eq!(range(&[("Range", "bytes=90-500")]), Ok(Some((90, 99))));
+    }
+
+    #[test]
+    fn parses_suffix_and_open_ranges() {
+        assert_eq!(range(&[("Range", "bytes=-10")]), Ok(Some((90, 99))));
+        assert_eq!(range(&[("Range", "bytes=-500")]), Ok(Some((0, 99))));
+        assert_eq!(range(&[("Range", "bytes=40-")]), Ok(Some((40, 99))));
+    }
+
+    #[test]
+    fn starting_past_the_end_is_unsatisfiable() {
+        assert_eq!(range(&[("Range", "bytes=100-")]), Err(()));
+        assert_eq!(range(&[("Range", "bytes=150-200")]), Err(()));
+    }
+
+    #[test]
+    fn multiple_ranges_are_the_whole_object() {
+        assert_eq!(range(&[("Range", "bytes=0-9,20-29")]), Ok(None));
+    }
+
+    #[test]
+    fn if_range_needs_the_current_etag() {
+        assert_eq!(range(&[("Range", "bytes=0-9"), ("If-Range", "\"abc123\"")]), Ok(Some((0, 9))));
+        assert_eq!(range(&[("Range", "bytes=0-9"), ("If-Range", "\"stale\"")]), Ok(None));
+        // Weak tags never match, nor do dates as objects have no modification time here
+        assert_eq!(range(&[("Range", "bytes=0-9"), ("If-Range", "W/\"abc123\"")]), Ok(None));
+        assert_eq!(range(&[("Range", "bytes=0-9"), ("If-Range", "Tue, 15 Nov 1994 08:12:31 GMT")]), Ok(None));
+    }
+
+    fn respond(headers: &[(&str, &str)]) -> HttpResponse {
+        let caching = Caching { public: true, max_age: 60 };
+        bytes_response(&request(headers), caching, ETAG, Bytes::from(vec![7u8; 100]), Some("video/mp4".to_string()))
+    }
+
+    fn content_range(response: &HttpResponse) -> &str {
+        response.headers().get(header::CONTENT_RANGE).unwrap().to_str().unwrap()
+    }
+
+    #[test]
+    fn answers_ranges_with_partial_content() {
+        let response = respond(&[("Range", "bytes=-10")]);
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(content_range(&response), "bytes 90-99/100");
+    }
+
+    #[test]
+    fn answers_unsatisfiable_ranges_with_416() {
+        let response = respond(&[("Range", "bytes=100-")]);
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(content_range(&response), "bytes */100");
+    }
+
+    #[test]
+    fn falls_back_to_the_whole_object() {
+        assert_eq!(respond(&[("Range", "bytes=0-9,20-29")]).status(), StatusCode::OK);
+        assert_eq!(respond(&[("Range", "bytes=0-9"), ("If-Range", "\"stale\"")]).status(), StatusCode::OK);
     }
 }
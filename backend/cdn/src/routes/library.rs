@@ -0,0 +1,133 @@
+use actix_session::Session;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use user::{get_account, Account, Scope};
+use crate::cache::HotCache;
+use crate::cdn::{self, HASH_METADATA};
+use crate::storage::Storage;
+
+#[derive(Debug, Deserialize)]
+pub struct Rename {
+    pub name: String,
+}
+
+async fn owned_object(client: &mongodb::Client, req: &HttpRequest, session: &Session, bucket: &str, name: &str) -> Result<(Account, media::MediaObject), HttpResponse> {
+    let account : Account = match get_account(client, req, session, Scope::MediaUpload).await {
+        Ok(account) => account,
+        Err(_) => return Err(HttpResponse::Unauthorized().body("No account found")),
+    };
+
+    let object = match media::find_object(client, bucket, name).await {
+        Ok(Some(object)) => object,
+        Ok(None) => return Err(HttpResponse::NotFound().body("Could not find media matching")),
+        Err(_) => return Err(HttpResponse::InternalServerError().body("Could not get media")),
+    };
+
+    if !object.owned_by(&account.uuid) {
+        return Err(HttpResponse::Unauthorized().body("Not authorised"));
+    }
+    Ok((account, object))
+}
+
+pub async fn list(req: HttpRequest, session: Session, client: web::Data<mongodb::Client>) -> HttpResponse {
+    let account : Account = match get_account(client.get_ref(), &req, &session, Scope::MediaRead).await {
+        Ok(account) => account,
+        Err(_) => return HttpResponse::Unauthorized().body("No account found"),
+    };
+
+    match media::find_objects_by_owner(client.get_ref(), &account.uuid).await {
+        Ok(objects) => HttpResponse::Ok().json(objects),
+        Err(_) => HttpResponse::InternalServerError().body("Could not get media"),
+    }
+}
+
+/*
+bucket, name
+Renaming changes the URL, so anything a post links to has to stay where it is.
+Content addressed objects are named by their hash and can't be renamed at all.
+ */
+pub async fn rename(req: HttpRequest, session: Session, path: web::Path<(String, String)>, info: web::Json<Rename>, db: web::Data<dyn Storage>, client: web::Data<mongodb::Client>, cache: web::Data<HotCache>) -> HttpResponse {
+    let (bucket, name) = path.into_inner();
+    let new_name = info.name.trim().to_string();
+
+    let (_, object) = match owned_object(client.get_ref(), &req, &session, &bucket, &name).await {
+        Ok(owned) => owned,
+        Err(response) => return response,
+    };
+
+    if object.content_addressed {
+        return HttpResponse::BadRequest().body("Content addressed media can't be renamed");
+    }
+    if !object.posts.is_empty() {
+        return HttpResponse::Conflict().json(object.posts);
+    }
+    if new_name.is_empty() || new_name.contains('/') || new_name.starts_with("variants") {
+        return HttpResponse::BadRequest().body("Invalid name");
+    }
+
+    match db.head(&bucket, &new_name).await {
+        Ok(None) => {},
+        Ok(Some(_)) => return HttpResponse::Conflict().body("An object with that name already exists"),
+        Err(error) => return HttpResponse::InternalServerError().body(error),
+    }
+
+    let bytes = match db.get_bytes(&bucket, &name).await {
+        Ok(bytes) => bytes,
+        Err(error) => return HttpResponse::InternalServerError().body(error),
+    };
+
+    // S3 has no rename, so store a copy under the new name and then remove the old one
+    if let Err(error) = cdn::put_object(db.get_ref(), &bucket, &new_name, &object.content_type, bytes, &object.hash, object.image.as_ref()).await {
+        return HttpResponse::InternalServerError().body(error);
+    }
+    if let Err(error) = cdn::remove_object(db.get_ref(), &bucket, &name).await {
+        eprintln!("Error removing {}/{} after rename: {}", bucket, name, error);
+    }
+    cache.purge(Some(&bucket), Some(&name));
+
+    match media::rename_object(client.get_ref(), &bucket, &name, &new_name).await {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to update media index"),
+    }
+}
+
+// bucket, name
+pub async fn delete(req: HttpRequest, session: Session, path: web::Path<(String, String)>, db: web::Data<dyn Storage>, client: web::Data<mongodb::Client>, cache: web::Data<HotCache>) -> HttpResponse {
+    let (bucket, name) = path.into_inner();
+
+    let (account, object) = match owned_object(client.get_ref(), &req, &session, &bucket, &name).await {
+        Ok(owned) => owned,
+        Err(response) => return response,
+    };
+
+    // Someone else uploaded the same content too, so it stays for them and only leaves this library
+    match media::release_object(client.get_ref(), &bucket, &name, &account.uuid).await {
+        Ok(true) => return HttpResponse::Ok().finish(),
+        Ok(false) => {},
+        Err(_) => return HttpResponse::InternalServerError().body("Failed to update media index"),
+    }
+
+    // Deleting something a post still links to would leave it with a broken image
+    if !object.posts.is_empty() {
+        return HttpResponse::Conflict().json(object.posts);
+    }
+
+    match delete_media(db.get_ref(), client.get_ref(), &cache, &object).await {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(error) => HttpResponse::InternalServerError().body(error),
+    }
+}
+
+pub async fn delete_media(db: &dyn Storage, client: &mongodb::Client, cache: &HotCache, object: &media::MediaObject) -> Result<(), String> {
+    // Only remove the object if it's still the one the index knows about
+    if let Ok(Some(stat)) = db.head(&object.bucket, &object.name).await {
+        if stat.metadata.get(HASH_METADATA) == Some(&object.hash) {
+            cdn::remove_object(db, &object.bucket, &object.name).await?;
+        }
+    }
+    cache.purge(Some(&object.bucket), Some(&object.name));
+
+    media::delete_object(client, &object.bucket, &object.name).await
+        .map_err(|error| format!("Error removing media index entry: {}", error))?;
+    Ok(())
+}
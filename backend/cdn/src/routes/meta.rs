@@ -0,0 +1,80 @@
+use actix_session::Session;
+use actix_web::{web, HttpRequest, HttpResponse};
+use media::ImageInfo;
+use serde::Serialize;
+use crate::cdn::{self, HASH_METADATA};
+use crate::placeholder;
+use crate::routes::get::{authorize, ObjectRef, SignatureQuery};
+use crate::storage::Storage;
+use crate::CdnConfig;
+
+#[derive(Debug, Serialize)]
+pub struct ObjectMetadata {
+    pub bucket: String,
+    pub name: String,
+    pub hash: String,
+    pub size: u64,
+    pub content_type: String,
+    pub image: Option<ImageInfo>,
+}
+
+/*
+bucket, name, hash
+Same access rules as getting the object itself.
+Images stored before placeholders were computed get one now, which is then kept.
+ */
+pub async fn meta(req: HttpRequest, session: Session, path: web::Path<(String, String, String)>, signature: web::Query<SignatureQuery>, db: web::Data<dyn Storage>, client: web::Data<mongodb::Client>, config: web::Data<CdnConfig>) -> HttpResponse {
+    let (bucket, name, hash) = path.into_inner();
+
+    let object = ObjectRef { bucket: &bucket, name: &name, hash: &hash };
+    if let Err(response) = authorize(&req, &session, client.get_ref(), &config, object, &signature).await {
+        return response;
+    }
+
+    let stat = match db.head(&bucket, &name).await {
+        Ok(Some(stat)) => stat,
+        Ok(None) => return HttpResponse::NotFound().body("Object not found"),
+        Err(error) => return HttpResponse::InternalServerError().body(error),
+    };
+
+    // Unhashed objects have nothing to check against until the admin backfill has hashed them
+    if stat.metadata.get(HASH_METADATA) != Some(&hash) {
+        return HttpResponse::BadRequest().body("Invalid hash");
+    }
+
+    let mut image = placeholder::from_metadata(&stat.metadata);
+    if image.is_none() && placeholder::supported(&stat.content_type) {
+        image = match backfill_placeholder(db.get_ref(), client.get_ref(), &bucket, &name, &hash, &stat.content_type).await {
+            Ok(image) => Some(image),
+            Err(error) => {
+                eprintln!("Error computing placeholder for {}/{}: {}", bucket, name, error);
+                None
+            }
+        };
+    }
+
+    HttpResponse::Ok().json(ObjectMetadata {
+        bucket,
+        name,
+        hash,
+        size: stat.size,
+        content_type: stat.content_type,
+        image,
+    })
+}
+
+async fn backfill_placeholder(db: &dyn Storage, client: &mongodb::Client, bucket: &str, name: &str, hash: &str, content_type: &str) -> Result<ImageInfo, String> {
+    let bytes = db.get_bytes(bucket, name).await?;
+
+    let source = bytes.clone();
+    let image = web::block(move || placeholder::compute(&source)).await
+        .map_err(|error| error.to_string())?
+        .map_err(|error| error.to_string())?;
+
+    // Stored the same way as at upload, in the object metadata and the media index
+    cdn::put_object(db, bucket, name, content_type, bytes, hash, Some(&image)).await?;
+    if let Err(error) = media::set_image_info(client, bucket, name, &image).await {
+        eprintln!("Error indexing placeholder for {}/{}: {}", bucket, name, error);
+    }
+    Ok(image)
+}
@@ -1,10 +1,95 @@
-use actix_web::{web, HttpResponse};
+use actix_web::dev::Payload;
+use actix_web::http::Method;
+use actix_web::{web, FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+use crate::cache::HotCache;
+use crate::storage::Storage;
+use crate::CdnConfig;
 
+pub mod admin;
+pub mod cache;
 pub mod get;
+pub mod library;
+pub mod meta;
+pub mod tus;
+pub mod upload;
+
+// The shared parts of the app a handler works with, taken from the app data in one go
+pub struct Services {
+    pub db: web::Data<dyn Storage>,
+    pub client: web::Data<mongodb::Client>,
+    pub config: web::Data<CdnConfig>,
+    pub cache: web::Data<HotCache>,
+}
+
+impl FromRequest for Services {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Services, actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let services = match (req.app_data::<web::Data<dyn Storage>>(), req.app_data::<web::Data<mongodb::Client>>(),
+                              req.app_data::<web::Data<CdnConfig>>(), req.app_data::<web::Data<HotCache>>()) {
+            (Some(db), Some(client), Some(config), Some(cache)) => Ok(Services {
+                db: db.clone(),
+                client: client.clone(),
+                config: config.clone(),
+                cache: cache.clone(),
+            }),
+            _ => Err(actix_web::error::ErrorInternalServerError("App data is missing")),
+        };
+        ready(services)
+    }
+}
 
 pub fn init(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::resource("/get/{bucket}/{name}/{hash}")
             .route(web::get().to(get::get))
+    )
+    .service(
+        web::resource("/meta/{bucket}/{name}/{hash}")
+            .route(web::get().to(meta::meta))
+    )
+    .service(
+        web::resource("/upload/{bucket}")
+            .route(web::post().to(upload::upload))
+    )
+    .service(
+        web::resource("/uploads")
+            .route(web::method(Method::OPTIONS).to(tus::options))
+    )
+    .service(
+        web::resource("/uploads/{bucket}")
+            .route(web::post().to(tus::create))
+            .route(web::method(Method::OPTIONS).to(tus::options))
+    )
+    .service(
+        web::resource("/uploads/{bucket}/{id}")
+            .route(web::head().to(tus::head))
+            .route(web::patch().to(tus::patch))
+            .route(web::delete().to(tus::delete))
+            .route(web::method(Method::OPTIONS).to(tus::options))
+    )
+    .service(
+        web::resource("/media")
+            .route(web::get().to(library::list))
+    )
+    .service(
+        web::resource("/media/{bucket}/{name}")
+            .route(web::patch().to(library::rename))
+            .route(web::delete().to(library::delete))
+    )
+    .service(
+        web::scope("/admin")
+            .service(web::resource("/backfill/{bucket}").route(web::post().to(admin::backfill)))
+            .service(web::resource("/scrub")
+                .route(web::get().to(admin::scrub_report))
+                .route(web::post().to(admin::scrub)))
+            .service(web::resource("/orphans").route(web::get().to(admin::orphan_report)))
+            .service(web::resource("/cache")
+                .route(web::get().to(cache::stats))
+                .route(web::delete().to(cache::purge_all)))
+            .service(web::resource("/cache/{bucket}").route(web::delete().to(cache::purge_bucket)))
+            .service(web::resource("/cache/{bucket}/{name}").route(web::delete().to(cache::purge_object)))
     );
-}
\ No newline at end of file
+}
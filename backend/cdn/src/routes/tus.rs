@@ -0,0 +1,442 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+use actix_session::Session;
+use actix_web::http::header::{HttpDate, CACHE_CONTROL, CONTENT_TYPE, LOCATION};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, HttpResponseBuilder};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures::StreamExt;
+use media::MediaObject;
+use mongodb::bson::DateTime;
+use user::{get_account, Account, Scope};
+use crate::cdn::{generate_name, object_url};
+use crate::storage::Storage;
+use crate::tus::{self, Upload, TUS_EXTENSIONS, TUS_VERSION};
+use crate::CdnConfig;
+
+/*
+Resumable uploads following the tus protocol (https://tus.io/protocols/resumable-upload),
+for files too big to send in one go. POST creates an upload, PATCH sends the next piece,
+HEAD says how much has arrived so an interrupted client knows where to carry on from.
+ */
+
+// Every response says which version it speaks, and every request but OPTIONS has to as well
+fn tus_response(status: StatusCode) -> HttpResponseBuilder {
+    let mut response = HttpResponse::build(status);
+    response.insert_header(("Tus-Resumable", TUS_VERSION));
+    response
+}
+
+// The response to send when the client speaks another version
+fn unsupported_version(req: &HttpRequest) -> Option<HttpResponse> {
+    match req.headers().get("Tus-Resumable").and_then(|version| version.to_str().ok()) {
+        Some(TUS_VERSION) => None,
+        _ => Some(tus_response(StatusCode::PRECONDITION_FAILED)
+            .insert_header(("Tus-Version", TUS_VERSION))
+            .body("Unsupported tus version")),
+    }
+}
+
+fn header_u64(req: &HttpRequest, name: &str) -> Option<u64> {
+    req.headers().get(name)?.to_str().ok()?.parse().ok()
+}
+
+// "key base64value,key base64value", the value can be left out
+fn parse_metadata(header: &str) -> Result<HashMap<String, String>, String> {
+    header.split(',')
+        .map(|pair| pair.trim())
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once(' ').unwrap_or((pair, ""));
+            let value = STANDARD.decode(value.trim()).ok()
+                .and_then(|value| String::from_utf8(value).ok())
+                .ok_or_else(|| format!("Invalid metadata value for {}", key))?;
+            Ok((key.to_string(), value))
+        })
+        .collect()
+}
+
+// Every unfinished upload can hold up to a part in Mongo and any number in storage
+fn upload_limit(unfinished: u64, max: u64) -> Option<HttpResponse> {
+    if unfinished < max {
+        return None;
+    }
+    Some(tus_response(StatusCode::TOO_MANY_REQUESTS).body(format!("At most {} uploads can be in progress at once", max)))
+}
+
+// A PATCH has to carry on exactly where the upload left off
+fn check_offset(req: &HttpRequest, upload: &Upload) -> Option<HttpResponse> {
+    match header_u64(req, "Upload-Offset") {
+        Some(offset) if offset == upload.offset => None,
+        Some(_) => Some(tus_response(StatusCode::CONFLICT)
+            .insert_header(("Upload-Offset", upload.offset.to_string()))
+            .body("Upload-Offset does not match")),
+        None => Some(tus_response(StatusCode::BAD_REQUEST).body("Missing Upload-Offset")),
+    }
+}
+
+// Nothing past the length the upload was created with is taken
+fn check_length(upload: &Upload, chunk: usize) -> Option<HttpResponse> {
+    if upload.offset + chunk as u64 <= upload.length {
+        return None;
+    }
+    Some(tus_response(StatusCode::PAYLOAD_TOO_LARGE)
+        .insert_header(("Upload-Offset", upload.offset.to_string()))
+        .body("Body goes past Upload-Length"))
+}
+
+// Someone else's upload is as unknown as one that doesn't exist
+async fn owned_upload(client: &mongodb::Client, req: &HttpRequest, session: &Session, bucket: &str, id: &str) -> Result<Upload, HttpResponse> {
+    let account : Account = match get_account(client, req, session, Scope::MediaUpload).await {
+        Ok(account) => account,
+        Err(_) => return Err(tus_response(StatusCode::UNAUTHORIZED).body("No account found")),
+    };
+
+    match tus::find_upload(client, id).await {
+        Ok(Some(upload)) if upload.bucket == bucket && account.uuid.eq(&upload.owner) => Ok(upload),
+        Ok(_) => Err(tus_response(StatusCode::NOT_FOUND).body("Unknown upload")),
+        Err(error) => Err(tus_response(StatusCode::INTERNAL_SERVER_ERROR).body(format!("Error finding upload: {}", error))),
+    }
+}
+
+// The upload, held for this request until it is saved without keeping the lease
+async fn leased_upload(client: &mongodb::Client, req: &HttpRequest, session: &Session, bucket: &str, id: &str) -> Result<Upload, HttpResponse> {
+    owned_upload(client, req, session, bucket, id).await?;
+
+    match tus::lease_upload(client, id).await {
+        Ok(Some(upload)) => Ok(upload),
+        Ok(None) => Err(tus_response(StatusCode::LOCKED).body("Upload is busy with another request")),
+        Err(error) => Err(tus_response(StatusCode::INTERNAL_SERVER_ERROR).body(format!("Error finding upload: {}", error))),
+    }
+}
+
+// Gives up on an upload that can't be finished, so its parts don't linger in storage
+async fn discard(db: &dyn Storage, client: &mongodb::Client, upload: &Upload) {
+    if let Err(error) = upload.abort(db).await {
+        eprintln!("Error aborting upload {}/{}: {}", upload.bucket, upload.name, error);
+    }
+    if let Err(error) = tus::remove_upload(client, &upload.id).await {
+        eprintln!("Error removing upload {}: {}", upload.id, error);
+    }
+}
+
+// Hands the upload back for the next request, keeping whatever arrived before the response
+async fn release(client: &mongodb::Client, upload: &mut Upload, response: HttpResponse) -> HttpResponse {
+    match upload.save(client, false).await {
+        Ok(_) => response,
+        Err(error) => tus_response(StatusCode::INTERNAL_SERVER_ERROR).body(error),
+    }
+}
+
+pub async fn options(config: web::Data<CdnConfig>) -> HttpResponse {
+    HttpResponse::NoContent()
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+        .insert_header(("Tus-Max-Size", config.max_resumable_size.to_string()))
+        .finish()
+}
+
+// bucket, Upload-Length and Upload-Metadata (which needs a "filetype") headers
+pub async fn create(session: Session, req: HttpRequest, path: web::Path<String>, db: web::Data<dyn Storage>, client: web::Data<mongodb::Client>, config: web::Data<CdnConfig>) -> HttpResponse {
+    let bucket = path.into_inner();
+
+    if let Some(response) = unsupported_version(&req) {
+        return response;
+    }
+
+    let account : Account = match get_account(client.get_ref(), &req, &session, Scope::MediaUpload).await {
+        Ok(account) => account,
+        Err(_) => {
+            return tus_response(StatusCode::UNAUTHORIZED).body("No account found")
+        }
+    };
+
+    if !account.elevated {
+        return tus_response(StatusCode::FORBIDDEN).finish()
+    }
+
+    if config.buckets.access(&bucket).is_none() {
+        return tus_response(StatusCode::NOT_FOUND).body("Unknown bucket");
+    }
+
+    match tus::count_unfinished(client.get_ref(), &account.uuid).await {
+        Ok(count) => if let Some(response) = upload_limit(count, config.max_resumable_uploads) {
+            return response;
+        },
+        Err(error) => return tus_response(StatusCode::INTERNAL_SERVER_ERROR).body(format!("Error counting uploads: {}", error)),
+    }
+
+    let length = match header_u64(&req, "Upload-Length") {
+        Some(0) => return tus_response(StatusCode::BAD_REQUEST).body("Empty upload"),
+        Some(length) => length,
+        None => return tus_response(StatusCode::BAD_REQUEST).body("Missing Upload-Length"),
+    };
+
+    if length > config.max_resumable_size {
+        return tus_response(StatusCode::PAYLOAD_TOO_LARGE)
+            .body(format!("Uploads are limited to {} bytes", config.max_resumable_size));
+    }
+
+    let metadata = match req.headers().get("Upload-Metadata").map(|header| header.to_str()) {
+        Some(Ok(header)) => match parse_metadata(header) {
+            Ok(metadata) => metadata,
+            Err(error) => return tus_response(StatusCode::BAD_REQUEST).body(error),
+        },
+        Some(Err(_)) => return tus_response(StatusCode::BAD_REQUEST).body("Invalid Upload-Metadata"),
+        None => HashMap::new(),
+    };
+
+    let content_type = match metadata.get("filetype") {
+        Some(content_type) => content_type.clone(),
+        None => return tus_response(StatusCode::BAD_REQUEST).body("Missing filetype metadata"),
+    };
+
+    if !config.allowed_types.contains(&content_type) {
+        return tus_response(StatusCode::UNSUPPORTED_MEDIA_TYPE).body(format!("{} uploads are not allowed", content_type));
+    }
+
+    if !tus::resumable(&content_type) {
+        return tus_response(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+            .body(format!("{} has to be uploaded through /upload/{}", content_type, bucket));
+    }
+
+    let name = generate_name(&content_type);
+    let multipart_id = match db.start_multipart(&bucket, &name, &content_type).await {
+        Ok(multipart_id) => multipart_id,
+        Err(error) => return tus_response(StatusCode::INTERNAL_SERVER_ERROR).body(error),
+    };
+
+    let expires = SystemTime::now() + config.resumable_expiry;
+    let upload = Upload::new(bucket.clone(), name.clone(), account.uuid.clone(), content_type, length, multipart_id, expires);
+    if let Err(error) = tus::insert_upload(client.get_ref(), &upload).await {
+        if let Err(error) = upload.abort(db.get_ref()).await {
+            eprintln!("Error aborting upload {}/{}: {}", bucket, name, error);
+        }
+        return tus_response(StatusCode::INTERNAL_SERVER_ERROR).body(format!("Error saving upload: {}", error));
+    }
+
+    println!("{} started a resumable upload of {}/{} ({} bytes)", account.uuid, bucket, name, length);
+
+    tus_response(StatusCode::CREATED)
+        .insert_header((LOCATION, format!("{}/uploads/{}/{}", config.public_url.trim_end_matches('/'), bucket, upload.id)))
+        .insert_header(("Upload-Expires", HttpDate::from(expires).to_string()))
+        .finish()
+}
+
+// bucket, id
+pub async fn head(session: Session, req: HttpRequest, path: web::Path<(String, String)>, client: web::Data<mongodb::Client>, config: web::Data<CdnConfig>) -> HttpResponse {
+    let (bucket, id) = path.into_inner();
+
+    if let Some(response) = unsupported_version(&req) {
+        return response;
+    }
+
+    let upload = match owned_upload(client.get_ref(), &req, &session, &bucket, &id).await {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+
+    let mut response = tus_response(StatusCode::OK);
+    response
+        .insert_header(("Upload-Offset", upload.offset.to_string()))
+        .insert_header(("Upload-Length", upload.length.to_string()))
+        .insert_header(("Upload-Expires", HttpDate::from(upload.expires.to_system_time()).to_string()))
+        // The offset changes with every PATCH
+        .insert_header((CACHE_CONTROL, "no-store"));
+    if let Some(hash) = &upload.hash {
+        response.insert_header(("Object-Url", object_url(&config.public_url, &bucket, &upload.name, hash)));
+    }
+    response.finish()
+}
+
+// bucket, id, Upload-Offset header and the next piece of the file as the body
+pub async fn patch(session: Session, req: HttpRequest, path: web::Path<(String, String)>, mut payload: web::Payload, db: web::Data<dyn Storage>, client: web::Data<mongodb::Client>, config: web::Data<CdnConfig>) -> HttpResponse {
+    let (bucket, id) = path.into_inner();
+
+    if let Some(response) = unsupported_version(&req) {
+        return response;
+    }
+
+    if req.headers().get(CONTENT_TYPE).and_then(|content_type| content_type.to_str().ok()) != Some("application/offset+octet-stream") {
+        return tus_response(StatusCode::UNSUPPORTED_MEDIA_TYPE).body("Expected application/offset+octet-stream");
+    }
+
+    // Held for the whole request, so a second PATCH is turned away until this one is done
+    let mut upload = match leased_upload(client.get_ref(), &req, &session, &bucket, &id).await {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+
+    if let Some(response) = check_offset(&req, &upload) {
+        return release(client.get_ref(), &mut upload, response).await;
+    }
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                // Whatever arrived before this is kept, the client can carry on from the new offset
+                let response = tus_response(StatusCode::BAD_REQUEST)
+                    .insert_header(("Upload-Offset", upload.offset.to_string()))
+                    .body(format!("Error reading upload: {}", err));
+                return release(client.get_ref(), &mut upload, response).await;
+            }
+        };
+
+        if let Some(response) = check_length(&upload, chunk.len()) {
+            return release(client.get_ref(), &mut upload, response).await;
+        }
+
+        match upload.append(db.get_ref(), &chunk).await {
+            Ok(true) => if let Err(error) = upload.save(client.get_ref(), true).await {
+                // Someone else has the upload now, so it's theirs to finish or throw away
+                return tus_response(StatusCode::CONFLICT).body(error);
+            },
+            Ok(false) => {},
+            Err(error) => {
+                discard(db.get_ref(), client.get_ref(), &upload).await;
+                return tus_response(StatusCode::INTERNAL_SERVER_ERROR).body(error);
+            }
+        }
+
+        if let Err(error) = upload.validate() {
+            discard(db.get_ref(), client.get_ref(), &upload).await;
+            return tus_response(StatusCode::UNSUPPORTED_MEDIA_TYPE).body(error);
+        }
+    }
+
+    upload.expires = DateTime::from_system_time(SystemTime::now() + config.resumable_expiry);
+
+    if upload.finished() && upload.hash.is_none() {
+        let hash = match upload.finish(db.get_ref()).await {
+            Ok(hash) => hash,
+            Err(error) => {
+                discard(db.get_ref(), client.get_ref(), &upload).await;
+                return tus_response(StatusCode::INTERNAL_SERVER_ERROR).body(error);
+            }
+        };
+
+        println!("{} uploaded {}/{} ({} bytes)", upload.owner, upload.bucket, upload.name, upload.length);
+
+        let object = MediaObject {
+            bucket: upload.bucket.clone(),
+            name: upload.name.clone(),
+            hash,
+            size: upload.length,
+            content_type: upload.content_type.clone(),
+            owner: upload.owner.clone(),
+            owners: vec![upload.owner.clone()],
+            uploaded: DateTime::now(),
+            content_addressed: false,
+            posts: vec![],
+            orphaned_since: Some(DateTime::now()),
+            image: None,
+        };
+        if let Err(error) = media::insert_object(client.get_ref(), &object).await {
+            // The object is stored and servable, it just won't show up in the media index
+            eprintln!("Error indexing {}/{}: {}", upload.bucket, upload.name, error);
+        }
+    }
+
+    let mut response = tus_response(StatusCode::NO_CONTENT);
+    response
+        .insert_header(("Upload-Offset", upload.offset.to_string()))
+        .insert_header(("Upload-Expires", HttpDate::from(upload.expires.to_system_time()).to_string()));
+    // Not part of tus, but the client needs the URL to link the object
+    if let Some(hash) = &upload.hash {
+        response.insert_header(("Object-Url", object_url(&config.public_url, &bucket, &upload.name, hash)));
+    }
+    release(client.get_ref(), &mut upload, response.finish()).await
+}
+
+// bucket, id, throws away an unfinished upload
+pub async fn delete(session: Session, req: HttpRequest, path: web::Path<(String, String)>, db: web::Data<dyn Storage>, client: web::Data<mongodb::Client>) -> HttpResponse {
+    let (bucket, id) = path.into_inner();
+
+    if let Some(response) = unsupported_version(&req) {
+        return response;
+    }
+
+    let upload = match leased_upload(client.get_ref(), &req, &session, &bucket, &id).await {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+
+    // A finished upload is a stored object by now, that's deleted through /media instead
+    if upload.hash.is_some() {
+        if let Err(error) = tus::remove_upload(client.get_ref(), &upload.id).await {
+            return tus_response(StatusCode::INTERNAL_SERVER_ERROR).body(format!("Error removing upload: {}", error));
+        }
+    } else {
+        discard(db.get_ref(), client.get_ref(), &upload).await;
+    }
+
+    tus_response(StatusCode::NO_CONTENT).finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn upload(offset: u64, length: u64) -> Upload {
+        let mut upload = Upload::new("files".to_string(), "name".to_string(), "owner".to_string(),
+            "application/pdf".to_string(), length, "multipart".to_string(), SystemTime::now());
+        upload.offset = offset;
+        upload
+    }
+
+    fn offset_header(response: &HttpResponse) -> &str {
+        response.headers().get("Upload-Offset").unwrap().to_str().unwrap()
+    }
+
+    #[test]
+    fn patches_carry_on_from_the_offset() {
+        let req = TestRequest::default().insert_header(("Upload-Offset", "10")).to_http_request();
+        assert!(check_offset(&req, &upload(10, 100)).is_none());
+    }
+
+    #[test]
+    fn rejects_the_wrong_offset() {
+        for offset in ["0", "9", "11"] {
+            let req = TestRequest::default().insert_header(("Upload-Offset", offset)).to_http_request();
+            let response = check_offset(&req, &upload(10, 100)).unwrap();
+            assert_eq!(response.status(), StatusCode::CONFLICT);
+            assert_eq!(offset_header(&response), "10");
+        }
+
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(check_offset(&req, &upload(10, 100)).unwrap().status(), StatusCode::BAD_REQUEST);
+        let req = TestRequest::default().insert_header(("Upload-Offset", "-1")).to_http_request();
+        assert_eq!(check_offset(&req, &upload(10, 100)).unwrap().status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn rejects_growing_past_the_length() {
+        assert!(check_length(&upload(90, 100), 10).is_none());
+        let response = check_length(&upload(90, 100), 11).unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(offset_header(&response), "90");
+        assert!(check_length(&upload(100, 100), 1).is_some());
+    }
+
+    #[test]
+    fn caps_unfinished_uploads() {
+        assert!(upload_limit(0, 3).is_none());
+        assert!(upload_limit(2, 3).is_none());
+        assert_eq!(upload_limit(3, 3).unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(upload_limit(4, 3).unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(upload_limit(0, 0).is_some());
+    }
+
+    #[test]
+    fn parses_metadata() {
+        let metadata = parse_metadata("filename d29ybGRfZG9taW5hdGlvbl9wbGFuLnBkZg==,filetype YXBwbGljYXRpb24vcGRm, is_confidential").unwrap();
+        assert_eq!(metadata["filename"], "world_domination_plan.pdf");
+        assert_eq!(metadata["filetype"], "application/pdf");
+        assert_eq!(metadata["is_confidential"], "");
+        assert!(parse_metadata("filetype not-base64!").is_err());
+    }
+}
@@ -0,0 +1,241 @@
+use crate::cdn::{self, content_name, generate_name, hash_bytes, object_url, StoredObject, HASH_METADATA};
+use crate::{metadata, mime, placeholder, svg};
+use crate::routes::Services;
+use crate::storage::Storage;
+use actix_multipart::Multipart;
+use actix_session::Session;
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures::StreamExt;
+use media::MediaObject;
+use mongodb::bson::DateTime;
+use serde::Deserialize;
+use user::{get_account, Account, Scope};
+
+#[derive(Debug, Deserialize)]
+pub struct UploadQuery {
+    // "content" names the object after its hash and reuses it if it's already stored
+    pub mode: Option<String>,
+    // Metadata tags to keep on images, e.g. "copyright,artist", everything else is stripped
+    pub keep: Option<String>,
+}
+
+// bucket, multipart body with a single "file" field
+pub async fn upload(req: HttpRequest, session: Session, path: web::Path<String>, query: web::Query<UploadQuery>, mut payload: Multipart, services: Services) -> HttpResponse {
+    let bucket = path.into_inner();
+    let Services { db, client, config, .. } = services;
+
+    let account : Account = match get_account(client.get_ref(), &req, &session, Scope::MediaUpload).await {
+        Ok(account) => account,
+        Err(_) => {
+            return HttpResponse::Unauthorized().body("No account found")
+        }
+    };
+
+    if !account.elevated {
+        return HttpResponse::Forbidden().finish()
+    }
+
+    if config.buckets.access(&bucket).is_none() {
+        return HttpResponse::NotFound().body("Unknown bucket");
+    }
+
+    let keep = match metadata::parse_keep(query.keep.as_deref().unwrap_or_default()) {
+        Ok(keep) => keep,
+        Err(error) => return HttpResponse::BadRequest().body(error),
+    };
+
+    let mut file: Option<(String, Vec<u8>)> = None;
+
+    while let Some(item) = payload.next().await {
+        let mut field = match item {
+            Ok(field) => field,
+            Err(err) => {
+                return HttpResponse::BadRequest().body(format!("Invalid multipart body: {}", err));
+            }
+        };
+
+        if field.name() != Some("file") {
+            continue;
+        }
+
+        let content_type = match field.content_type() {
+            Some(content_type) => content_type.essence_str().to_string(),
+            None => return HttpResponse::BadRequest().body("Missing content type"),
+        };
+
+        if !config.allowed_types.contains(&content_type) {
+            return HttpResponse::UnsupportedMediaType().body(format!("{} uploads are not allowed", content_type));
+        }
+
+        let mut bytes: Vec<u8> = Vec::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    return HttpResponse::BadRequest().body(format!("Error reading upload: {}", err));
+                }
+            };
+
+            if bytes.len() + chunk.len() > config.max_upload_size {
+                return HttpResponse::PayloadTooLarge()
+                    .body(format!("Uploads are limited to {} bytes", config.max_upload_size));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+
+        file = Some((content_type, bytes));
+        break;
+    }
+
+    let (content_type, bytes) = match file {
+        Some(file) => file,
+        None => return HttpResponse::BadRequest().body("No file field found"),
+    };
+
+    if bytes.is_empty() {
+        return HttpResponse::BadRequest().body("Empty upload");
+    }
+
+    if let Err(error) = mime::validate(&bytes, &content_type) {
+        return HttpResponse::UnsupportedMediaType().body(error);
+    }
+
+    let bytes = if content_type == "image/svg+xml" {
+        match svg::sanitize(&bytes) {
+            Ok(bytes) => bytes,
+            Err(error) => return HttpResponse::BadRequest().body(format!("Invalid SVG: {}", error)),
+        }
+    } else {
+        bytes
+    };
+
+    // Stripped before hashing, so the hash and size describe what is actually stored.
+    // Rotating means decoding the image, so this stays off the async workers as well
+    let strip_type = content_type.clone();
+    let bytes = match web::block(move || metadata::strip(&bytes, &strip_type, &keep)).await {
+        Ok(Ok(bytes)) => bytes,
+        Ok(Err(error)) => return HttpResponse::BadRequest().body(format!("Invalid image: {}", error)),
+        Err(error) => return HttpResponse::InternalServerError().body(error.to_string()),
+    };
+
+    let hash = hash_bytes(&bytes);
+    let size = bytes.len();
+    let content_addressed = query.mode.as_deref() == Some("content");
+    let name = if content_addressed {
+        content_name(&hash, &content_type)
+    } else {
+        generate_name(&content_type)
+    };
+
+    if content_addressed {
+        match existing_object(db.get_ref(), client.get_ref(), &bucket, &name, &hash, &account.uuid).await {
+            Ok(Some(existing)) => return HttpResponse::Ok().json(StoredObject {
+                url: object_url(&config.public_url, &bucket, &name, &hash),
+                bucket,
+                name,
+                hash,
+                size: existing.size as usize,
+                content_type: existing.content_type,
+                image: existing.image,
+            }),
+            Ok(None) => {},
+            Err(error) => return HttpResponse::InternalServerError().body(error),
+        }
+    }
+
+    // Decoding is CPU heavy so keep it off the async workers
+    let bytes = Bytes::from(bytes);
+    let image = if placeholder::supported(&content_type) {
+        let source = bytes.clone();
+        match web::block(move || placeholder::compute(&source)).await {
+            Ok(Ok(image)) => Some(image),
+            Ok(Err(error)) => {
+                // Still stored, it just gets no placeholder
+                eprintln!("Error computing placeholder for {}/{}: {}", bucket, name, error);
+                None
+            }
+            Err(error) => {
+                eprintln!("Error computing placeholder for {}/{}: {}", bucket, name, error);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    match cdn::put_object(db.get_ref(), &bucket, &name, &content_type, bytes, &hash, image.as_ref()).await {
+        Ok(_) => {},
+        Err(error) => {
+            return HttpResponse::InternalServerError().body(error);
+        }
+    }
+
+    println!("{} uploaded {}/{} ({} bytes)", account.uuid, bucket, name, size);
+
+    let object = MediaObject {
+        bucket: bucket.clone(),
+        name: name.clone(),
+        hash: hash.clone(),
+        size: size as u64,
+        content_type: content_type.clone(),
+        owner: account.uuid.clone(),
+        owners: vec![account.uuid.clone()],
+        uploaded: DateTime::now(),
+        content_addressed,
+        posts: vec![],
+        orphaned_since: Some(DateTime::now()),
+        image: image.clone(),
+    };
+    if let Err(error) = media::insert_object(client.get_ref(), &object).await {
+        // The object is stored and servable, it just won't show up in the media index
+        eprintln!("Error indexing {}/{}: {}", bucket, name, error);
+    }
+
+    HttpResponse::Created().json(StoredObject {
+        url: object_url(&config.public_url, &bucket, &name, &hash),
+        bucket,
+        name,
+        hash,
+        size,
+        content_type,
+        image,
+    })
+}
+
+/*
+Finds a content addressed object that is already stored, making the uploader one of its owners.
+The index is checked first, MinIO covers objects whose index entry never got written (they get one now).
+ */
+async fn existing_object(db: &dyn Storage, client: &mongodb::Client, bucket: &str, name: &str, hash: &str, owner: &str) -> Result<Option<MediaObject>, String> {
+    match media::claim_object(client, bucket, name, hash, owner).await {
+        Ok(Some(object)) => return Ok(Some(object)),
+        Ok(None) => {},
+        Err(error) => return Err(format!("Error checking media index: {}", error)),
+    }
+
+    match db.head(bucket, name).await {
+        Ok(Some(stat)) if stat.metadata.get(HASH_METADATA).map(|stored| stored.as_str()) == Some(hash) => {
+            let object = MediaObject {
+                bucket: bucket.to_string(),
+                name: name.to_string(),
+                hash: hash.to_string(),
+                size: stat.size,
+                content_type: stat.content_type.clone(),
+                owner: owner.to_string(),
+                owners: vec![owner.to_string()],
+                uploaded: DateTime::now(),
+                content_addressed: true,
+                posts: vec![],
+                orphaned_since: Some(DateTime::now()),
+                image: placeholder::from_metadata(&stat.metadata),
+            };
+            if let Err(error) = media::insert_object(client, &object).await {
+                eprintln!("Error indexing {}/{}: {}", bucket, name, error);
+            }
+            Ok(Some(object))
+        }
+        Ok(_) => Ok(None),
+        Err(error) => Err(error),
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use actix_web::web::Bytes;
+use futures::TryStreamExt;
+use super::{Storage, PART_SIZE};
+
+async fn get_range(storage: &dyn Storage, bucket: &str, name: &str, range: (u64, u64)) -> Bytes {
+    let chunks: Vec<Bytes> = storage.get(bucket, name, Some(range)).await.unwrap().try_collect().await.unwrap();
+    Bytes::from(chunks.concat())
+}
+
+// Behaviour every storage backend has to share, run against each of them from their own tests
+pub async fn run(storage: &dyn Storage, bucket: &str) {
+    let name = "conformance/photo.jpg";
+    let variant = "variants/conformance/photo.jpg/abc/320x0-contain.webp";
+    let bytes = Bytes::from_static(b"0123456789abcdef");
+    let mut metadata = HashMap::new();
+    metadata.insert("blake2b".to_string(), "abc".to_string());
+
+    // Missing objects
+    assert_eq!(storage.head(bucket, name).await.unwrap(), None);
+    assert!(storage.get_bytes(bucket, name).await.is_err());
+    storage.delete(bucket, name).await.unwrap();
+
+    // Put, head and get
+    storage.put(bucket, name, "image/jpeg", metadata.clone(), bytes.clone()).await.unwrap();
+    let info = storage.head(bucket, name).await.unwrap().unwrap();
+    assert_eq!(info.size, bytes.len() as u64);
+    assert_eq!(info.content_type, "image/jpeg");
+    assert_eq!(info.metadata.get("blake2b").map(|hash| hash.as_str()), Some("abc"));
+    assert_eq!(storage.get_bytes(bucket, name).await.unwrap(), bytes);
+
+    // Ranges are inclusive on both ends
+    assert_eq!(get_range(storage, bucket, name, (0, 0)).await, Bytes::from_static(b"0"));
+    assert_eq!(get_range(storage, bucket, name, (10, 15)).await, Bytes::from_static(b"abcdef"));
+
+    // Putting again replaces content and metadata
+    storage.put(bucket, name, "image/png", HashMap::new(), Bytes::from_static(b"new")).await.unwrap();
+    let info = storage.head(bucket, name).await.unwrap().unwrap();
+    assert_eq!(info.size, 3);
+    assert_eq!(info.content_type, "image/png");
+    assert!(info.metadata.is_empty());
+    assert_eq!(storage.get_bytes(bucket, name).await.unwrap(), Bytes::from_static(b"new"));
+
+    // Listing by prefix, including nested names
+    storage.put(bucket, variant, "image/webp", HashMap::new(), bytes.clone()).await.unwrap();
+    assert_eq!(storage.list(bucket, "conformance/").await.unwrap(), vec![name.to_string()]);
+    assert_eq!(storage.list(bucket, "variants/conformance/").await.unwrap(), vec![variant.to_string()]);
+    let all = storage.list(bucket, "").await.unwrap();
+    assert!(all.contains(&name.to_string()) && all.contains(&variant.to_string()));
+
+    // Delete
+    storage.delete(bucket, name).await.unwrap();
+    storage.delete(bucket, variant).await.unwrap();
+    assert_eq!(storage.head(bucket, name).await.unwrap(), None);
+    assert!(storage.list(bucket, "conformance/").await.unwrap().is_empty());
+    assert!(storage.list(bucket, "variants/conformance/").await.unwrap().is_empty());
+
+    // Multipart, the metadata is only set once the object is complete
+    let first = Bytes::from(vec![7u8; PART_SIZE]);
+    let upload_id = storage.start_multipart(bucket, name, "video/mp4").await.unwrap();
+    let parts = vec![
+        storage.put_part(bucket, name, &upload_id, 1, first.clone()).await.unwrap(),
+        storage.put_part(bucket, name, &upload_id, 2, Bytes::from_static(b"end")).await.unwrap(),
+    ];
+    assert_eq!(storage.head(bucket, name).await.unwrap(), None);
+    let pending = storage.list_multipart(bucket).await.unwrap();
+    assert!(pending.iter().any(|upload| upload.upload_id == upload_id && upload.name == name));
+    storage.complete_multipart(bucket, name, &upload_id, &parts).await.unwrap();
+    storage.set_metadata(bucket, name, metadata.clone()).await.unwrap();
+    assert!(!storage.list_multipart(bucket).await.unwrap().iter().any(|upload| upload.upload_id == upload_id));
+
+    let info = storage.head(bucket, name).await.unwrap().unwrap();
+    assert_eq!(info.size, PART_SIZE as u64 + 3);
+    assert_eq!(info.content_type, "video/mp4");
+    assert_eq!(info.metadata, metadata);
+    let size = PART_SIZE as u64;
+    assert_eq!(get_range(storage, bucket, name, (size - 1, size + 2)).await, Bytes::from_static(b"\x07end"));
+    assert_eq!(storage.list(bucket, "conformance/").await.unwrap(), vec![name.to_string()]);
+    storage.delete(bucket, name).await.unwrap();
+    assert!(storage.set_metadata(bucket, name, metadata.clone()).await.is_err());
+
+    // Aborted uploads leave nothing behind
+    let upload_id = storage.start_multipart(bucket, name, "video/mp4").await.unwrap();
+    let parts = vec![storage.put_part(bucket, name, &upload_id, 1, first).await.unwrap()];
+    storage.abort_multipart(bucket, name, &upload_id).await.unwrap();
+    assert_eq!(storage.head(bucket, name).await.unwrap(), None);
+    assert!(storage.complete_multipart(bucket, name, &upload_id, &parts).await.is_err());
+    assert!(!storage.list_multipart(bucket).await.unwrap().iter().any(|upload| upload.upload_id == upload_id));
+}
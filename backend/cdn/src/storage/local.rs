@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use actix_web::web::{self, Bytes};
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use super::{ByteStream, ObjectInfo, PendingMultipart, Storage};
+
+// Per bucket directory holding what MinIO would keep as object metadata, plus in progress writes
+const META_DIR: &str = ".meta";
+
+#[derive(Default, Deserialize, Serialize)]
+struct Sidecar {
+    content_type: String,
+    metadata: HashMap<String, String>,
+}
+
+/*
+Keeps objects as plain files under root/bucket/name, so the CDN can run without MinIO.
+Meant for development, it reads whole ranges into memory and has no locking between writers.
+ */
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> LocalStorage {
+        LocalStorage { root: root.into() }
+    }
+
+    // Rejects anything that could point outside the bucket directory, or into the metadata
+    fn bucket_path(&self, bucket: &str) -> Result<PathBuf, String> {
+        if bucket.is_empty() || bucket.contains(['/', '\\']) || bucket.starts_with('.') {
+            return Err(format!("Invalid bucket name {}", bucket));
+        }
+        Ok(self.root.join(bucket))
+    }
+
+    fn paths(&self, bucket: &str, name: &str) -> Result<(PathBuf, PathBuf), String> {
+        let segments: Vec<&str> = name.split('/').collect();
+        if segments.iter().any(|segment| segment.is_empty() || *segment == "." || *segment == ".." || segment.contains('\\'))
+            || segments[0] == META_DIR {
+            return Err(format!("Invalid object name {}", name));
+        }
+
+        let bucket = self.bucket_path(bucket)?;
+        let (last, parents) = segments.split_last().unwrap();
+        let object = segments.iter().fold(bucket.clone(), |path, segment| path.join(segment));
+        let sidecar = parents.iter().fold(bucket.join(META_DIR), |path, segment| path.join(segment))
+            .join(format!("{}.json", last));
+        Ok((object, sidecar))
+    }
+
+    fn multipart_root(&self, bucket: &str) -> Result<PathBuf, String> {
+        Ok(self.bucket_path(bucket)?.join(META_DIR).join("multipart"))
+    }
+
+    // Parts of a multipart upload wait in their own directory until they are joined
+    fn multipart_path(&self, bucket: &str, upload_id: &str) -> Result<PathBuf, String> {
+        if upload_id.is_empty() || !upload_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(format!("Invalid multipart upload {}", upload_id));
+        }
+        Ok(self.multipart_root(bucket)?.join(upload_id))
+    }
+}
+
+// Writes through a temporary file so readers never see half an object
+fn write_atomic(temp_dir: &Path, path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    fs::create_dir_all(temp_dir)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let temp = temp_dir.join(format!(".partial-{}", random_id(12)));
+    fs::write(&temp, bytes)?;
+    fs::rename(&temp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
+
+fn random_id(length: usize) -> String {
+    thread_rng().sample_iter(&Alphanumeric).take(length).map(char::from).collect()
+}
+
+// Concatenates parts 1 to count into a temporary file, then moves it into place
+fn join_parts(temp_dir: &Path, parts_dir: &Path, count: usize, path: &Path) -> std::io::Result<()> {
+    let temp = temp_dir.join(format!(".partial-{}", random_id(12)));
+    let mut output = File::create(&temp)?;
+    for number in 1..=count {
+        let copied = File::open(parts_dir.join(number.to_string()))
+            .and_then(|mut part| std::io::copy(&mut part, &mut output));
+        if let Err(error) = copied {
+            let _ = fs::remove_file(&temp);
+            return Err(error);
+        }
+    }
+    output.sync_all()?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(&temp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
+
+fn list_multipart_dir(root: &Path) -> std::io::Result<Vec<PendingMultipart>> {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error),
+    };
+
+    let mut pending = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        pending.push(PendingMultipart {
+            name: fs::read_to_string(entry.path().join("name")).unwrap_or_default(),
+            upload_id: entry.file_name().to_string_lossy().to_string(),
+            started: entry.metadata()?.modified()?,
+        });
+    }
+    Ok(pending)
+}
+
+fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match fs::remove_file(path) {
+        Err(error) if error.kind() != ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+fn read_range(path: &Path, range: Option<(u64, u64)>) -> std::io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let mut bytes = Vec::new();
+    match range {
+        Some((start, end)) => {
+            file.seek(SeekFrom::Start(start))?;
+            file.take(end.saturating_sub(start) + 1).read_to_end(&mut bytes)?;
+        }
+        None => {
+            file.read_to_end(&mut bytes)?;
+        }
+    }
+    Ok(bytes)
+}
+
+fn list_dir(bucket: &Path, dir: &Path, names: &mut Vec<String>) -> std::io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error),
+    };
+
+    for entry in entries {
+        let path = entry?.path();
+        if path.parent() == Some(bucket) && path.file_name() == Some(META_DIR.as_ref()) {
+            continue;
+        }
+        if path.is_dir() {
+            list_dir(bucket, &path, names)?;
+        } else if let Ok(relative) = path.strip_prefix(bucket) {
+            names.push(relative.components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/"));
+        }
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn get(&self, bucket: &str, name: &str, range: Option<(u64, u64)>) -> Result<ByteStream, String> {
+        let (path, _) = self.paths(bucket, name)?;
+        let bytes = web::block(move || read_range(&path, range)).await
+            .map_err(|error| error.to_string())?
+            .map_err(|error| format!("Error getting object: {}", error))?;
+
+        Ok(stream::once(async move { Ok(Bytes::from(bytes)) }).boxed())
+    }
+
+    async fn put(&self, bucket: &str, name: &str, content_type: &str, metadata: HashMap<String, String>, bytes: Bytes) -> Result<(), String> {
+        let (path, sidecar_path) = self.paths(bucket, name)?;
+        let temp_dir = self.bucket_path(bucket)?.join(META_DIR);
+        let sidecar = serde_json::to_vec(&Sidecar {
+            content_type: content_type.to_string(),
+            metadata,
+        }).map_err(|error| error.to_string())?;
+
+        web::block(move || {
+            write_atomic(&temp_dir, &path, &bytes)?;
+            write_atomic(&temp_dir, &sidecar_path, &sidecar)
+        }).await
+            .map_err(|error| error.to_string())?
+            .map_err(|error| format!("Error storing object: {}", error))
+    }
+
+    async fn head(&self, bucket: &str, name: &str) -> Result<Option<ObjectInfo>, String> {
+        let (path, sidecar_path) = self.paths(bucket, name)?;
+
+        web::block(move || {
+            let size = match fs::metadata(&path) {
+                Ok(metadata) if metadata.is_file() => metadata.len(),
+                Ok(_) => return Ok(None),
+                Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(error) => return Err(error),
+            };
+            // Files copied in by hand have no sidecar, they are treated like objects stored without metadata
+            let sidecar: Sidecar = match fs::read(&sidecar_path) {
+                Ok(sidecar) => serde_json::from_slice(&sidecar).unwrap_or_default(),
+                Err(error) if error.kind() == ErrorKind::NotFound => Sidecar::default(),
+                Err(error) => return Err(error),
+            };
+
+            Ok(Some(ObjectInfo {
+                size,
+                content_type: sidecar.content_type,
+                metadata: sidecar.metadata,
+            }))
+        }).await
+            .map_err(|error| error.to_string())?
+            .map_err(|error| format!("Error getting object info: {}", error))
+    }
+
+    async fn delete(&self, bucket: &str, name: &str) -> Result<(), String> {
+        let (path, sidecar_path) = self.paths(bucket, name)?;
+
+        web::block(move || {
+            remove_if_exists(&path)?;
+            remove_if_exists(&sidecar_path)
+        }).await
+            .map_err(|error| error.to_string())?
+            .map_err(|error| format!("Error removing object: {}", error))
+    }
+
+    async fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<String>, String> {
+        let bucket = self.bucket_path(bucket)?;
+        let prefix = prefix.to_string();
+
+        web::block(move || {
+            let mut names = Vec::new();
+            list_dir(&bucket, &bucket, &mut names)?;
+            names.retain(|name| name.starts_with(&prefix));
+            names.sort();
+            Ok(names)
+        }).await
+            .map_err(|error| error.to_string())?
+            .map_err(|error: std::io::Error| format!("Error listing objects: {}", error))
+    }
+
+    async fn start_multipart(&self, bucket: &str, name: &str, content_type: &str) -> Result<String, String> {
+        self.paths(bucket, name)?;
+        let upload_id = random_id(24);
+        let parts_dir = self.multipart_path(bucket, &upload_id)?;
+        let content_type = content_type.to_string();
+        let name = name.to_string();
+
+        web::block(move || {
+            fs::create_dir_all(&parts_dir)?;
+            fs::write(parts_dir.join("content-type"), content_type)?;
+            fs::write(parts_dir.join("name"), name)
+        }).await
+            .map_err(|error| error.to_string())?
+            .map_err(|error| format!("Error starting multipart upload: {}", error))?;
+        Ok(upload_id)
+    }
+
+    // The files are named by part number, so the number is all the tag there is
+    async fn put_part(&self, bucket: &str, _name: &str, upload_id: &str, number: usize, bytes: Bytes) -> Result<String, String> {
+        let parts_dir = self.multipart_path(bucket, upload_id)?;
+
+        web::block(move || {
+            if !parts_dir.is_dir() {
+                return Err(std::io::Error::new(ErrorKind::NotFound, "unknown multipart upload"));
+            }
+            write_atomic(&parts_dir, &parts_dir.join(number.to_string()), &bytes)
+        }).await
+            .map_err(|error| error.to_string())?
+            .map_err(|error| format!("Error uploading part {}: {}", number, error))?;
+        Ok(number.to_string())
+    }
+
+    async fn complete_multipart(&self, bucket: &str, name: &str, upload_id: &str, parts: &[String]) -> Result<(), String> {
+        let (path, sidecar_path) = self.paths(bucket, name)?;
+        let parts_dir = self.multipart_path(bucket, upload_id)?;
+        let temp_dir = self.bucket_path(bucket)?.join(META_DIR);
+        if parts.iter().enumerate().any(|(index, tag)| *tag != (index + 1).to_string()) {
+            return Err("Error completing multipart upload: parts out of order".to_string());
+        }
+        let count = parts.len();
+
+        web::block(move || {
+            let content_type = fs::read_to_string(parts_dir.join("content-type"))?;
+            join_parts(&temp_dir, &parts_dir, count, &path)?;
+            let sidecar = serde_json::to_vec(&Sidecar { content_type, metadata: HashMap::new() })?;
+            write_atomic(&temp_dir, &sidecar_path, &sidecar)?;
+            fs::remove_dir_all(&parts_dir)
+        }).await
+            .map_err(|error| error.to_string())?
+            .map_err(|error| format!("Error completing multipart upload: {}", error))
+    }
+
+    async fn abort_multipart(&self, bucket: &str, _name: &str, upload_id: &str) -> Result<(), String> {
+        let parts_dir = self.multipart_path(bucket, upload_id)?;
+
+        web::block(move || match fs::remove_dir_all(&parts_dir) {
+            Err(error) if error.kind() != ErrorKind::NotFound => Err(error),
+            _ => Ok(()),
+        }).await
+            .map_err(|error| error.to_string())?
+            .map_err(|error| format!("Error aborting multipart upload: {}", error))
+    }
+
+    async fn list_multipart(&self, bucket: &str) -> Result<Vec<PendingMultipart>, String> {
+        let root = self.multipart_root(bucket)?;
+
+        web::block(move || list_multipart_dir(&root)).await
+            .map_err(|error| error.to_string())?
+            .map_err(|error| format!("Error listing multipart uploads: {}", error))
+    }
+
+    async fn set_metadata(&self, bucket: &str, name: &str, metadata: HashMap<String, String>) -> Result<(), String> {
+        let (path, sidecar_path) = self.paths(bucket, name)?;
+        let temp_dir = self.bucket_path(bucket)?.join(META_DIR);
+
+        web::block(move || {
+            if !path.is_file() {
+                return Err(std::io::Error::new(ErrorKind::NotFound, "no such object"));
+            }
+            let content_type = match fs::read(&sidecar_path) {
+                Ok(sidecar) => serde_json::from_slice::<Sidecar>(&sidecar).unwrap_or_default().content_type,
+                Err(error) if error.kind() == ErrorKind::NotFound => String::new(),
+                Err(error) => return Err(error),
+            };
+            let sidecar = serde_json::to_vec(&Sidecar { content_type, metadata })?;
+            write_atomic(&temp_dir, &sidecar_path, &sidecar)
+        }).await
+            .map_err(|error| error.to_string())?
+            .map_err(|error| format!("Error storing object metadata: {}", error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LocalStorage;
+
+    #[actix_web::test]
+    async fn conformance() {
+        let root = tempfile::tempdir().unwrap();
+        super::super::conformance::run(&LocalStorage::new(root.path()), "conformance").await;
+    }
+
+    #[test]
+    fn rejects_paths_outside_the_bucket() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(root.path());
+
+        assert!(storage.paths("images", "../secret").is_err());
+        assert!(storage.paths("images", ".meta/photo.jpg").is_err());
+        assert!(storage.paths("../images", "photo.jpg").is_err());
+        assert!(storage.paths("images", "variants//photo.jpg").is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use minio_rsc::client::{CopySource, KeyArgs, ListObjectsArgs, MultipartUploadTask};
+use minio_rsc::datatype::Part;
+use minio_rsc::http::Method;
+use minio_rsc::Minio;
+use mongodb::bson::DateTime;
+use serde::Deserialize;
+use super::{ByteStream, ObjectInfo, PendingMultipart, Storage};
+
+/*
+Keeps nothing about multipart uploads between calls, the caller holds on to the upload id
+and the part tags. So an upload can carry on after a restart, or on another instance.
+ */
+pub struct MinioStorage {
+    client: Minio,
+}
+
+/*
+The parts of a ListMultipartUploadsResult needed to find abandoned uploads. minio-rsc has its own,
+but it insists on fields like ChecksumAlgorithm that MinIO leaves out.
+ */
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct MultipartListing {
+    #[serde(default)]
+    is_truncated: bool,
+    #[serde(default)]
+    next_key_marker: String,
+    #[serde(default)]
+    next_upload_id_marker: String,
+    #[serde(default, rename = "Upload")]
+    uploads: Vec<MultipartListingUpload>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct MultipartListingUpload {
+    key: String,
+    upload_id: String,
+    initiated: String,
+}
+
+impl MinioStorage {
+    pub fn new(client: Minio) -> MinioStorage {
+        MinioStorage { client }
+    }
+}
+
+fn task(bucket: &str, name: &str, upload_id: &str) -> MultipartUploadTask {
+    MultipartUploadTask::new(bucket.to_string(), name.to_string(), upload_id.to_string(), None, None, None)
+}
+
+#[async_trait]
+impl Storage for MinioStorage {
+    async fn get(&self, bucket: &str, name: &str, range: Option<(u64, u64)>) -> Result<ByteStream, String> {
+        let mut key = KeyArgs::new(name.to_string());
+        if let Some((start, end)) = range {
+            key = key.offset(start as usize).length((end - start + 1) as usize);
+        }
+
+        let response = self.client.get_object(bucket.to_string(), key).await
+            .map_err(|error| format!("Error getting object: {}", error))?;
+        if !response.status().is_success() {
+            return Err(format!("Error getting object: {}", response.status()));
+        }
+
+        // Stream straight from MinIO instead of buffering the whole object
+        Ok(response.bytes_stream()
+            .map_err(|error| format!("Error fetching response body: {}", error))
+            .boxed())
+    }
+
+    async fn put(&self, bucket: &str, name: &str, content_type: &str, metadata: HashMap<String, String>, bytes: Bytes) -> Result<(), String> {
+        let key = KeyArgs::new(name.to_string())
+            .content_type(Some(content_type.to_string()))
+            .metadata(metadata);
+
+        self.client.put_object(bucket.to_string(), key, bytes).await
+            .map_err(|error| format!("Error storing object: {}", error))
+    }
+
+    async fn head(&self, bucket: &str, name: &str) -> Result<Option<ObjectInfo>, String> {
+        match self.client.stat_object(bucket.to_string(), name.to_string()).await {
+            Ok(Some(stat)) => Ok(Some(ObjectInfo {
+                size: stat.size() as u64,
+                content_type: stat.content_type().to_string(),
+                metadata: stat.metadata().clone(),
+            })),
+            Ok(None) => Ok(None),
+            Err(error) => Err(format!("Error getting object info: {}", error)),
+        }
+    }
+
+    async fn delete(&self, bucket: &str, name: &str) -> Result<(), String> {
+        self.client.remove_object(bucket.to_string(), name.to_string()).await
+            .map_err(|error| format!("Error removing object: {}", error))
+    }
+
+    async fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<String>, String> {
+        let mut names = Vec::new();
+        let mut token: Option<String> = None;
+
+        loop {
+            let mut args = ListObjectsArgs::default().prefix(prefix.to_string());
+            if let Some(token) = &token {
+                args = args.continuation_token(token.clone());
+            }
+
+            let result = self.client.list_objects(bucket.to_string(), args).await
+                .map_err(|error| format!("Error listing objects: {}", error))?;
+            names.extend(result.contents.into_iter().map(|object| object.key));
+
+            if !result.is_truncated || result.next_continuation_token.is_empty() {
+                break;
+            }
+            token = Some(result.next_continuation_token);
+        }
+
+        Ok(names)
+    }
+
+    async fn start_multipart(&self, bucket: &str, name: &str, content_type: &str) -> Result<String, String> {
+        let key = KeyArgs::new(name.to_string()).content_type(Some(content_type.to_string()));
+        let task = self.client.create_multipart_upload(bucket.to_string(), key).await
+            .map_err(|error| format!("Error starting multipart upload: {}", error))?;
+        Ok(task.upload_id().to_string())
+    }
+
+    async fn put_part(&self, bucket: &str, name: &str, upload_id: &str, number: usize, bytes: Bytes) -> Result<String, String> {
+        let part = self.client.upload_part(&task(bucket, name, upload_id), number, bytes).await
+            .map_err(|error| format!("Error uploading part {}: {}", number, error))?;
+        Ok(part.e_tag)
+    }
+
+    async fn complete_multipart(&self, bucket: &str, name: &str, upload_id: &str, parts: &[String]) -> Result<(), String> {
+        let parts = parts.iter().enumerate()
+            .map(|(index, e_tag)| Part { e_tag: e_tag.clone(), part_number: index + 1 })
+            .collect();
+
+        self.client.complete_multipart_upload(&task(bucket, name, upload_id), parts, None).await
+            .map(|_| ())
+            .map_err(|error| format!("Error completing multipart upload: {}", error))
+    }
+
+    async fn abort_multipart(&self, bucket: &str, name: &str, upload_id: &str) -> Result<(), String> {
+        self.client.abort_multipart_upload(&task(bucket, name, upload_id)).await
+            .map_err(|error| format!("Error aborting multipart upload: {}", error))
+    }
+
+    async fn list_multipart(&self, bucket: &str) -> Result<Vec<PendingMultipart>, String> {
+        let mut pending = Vec::new();
+        let mut markers: Option<(String, String)> = None;
+
+        loop {
+            let mut request = self.client.executor(Method::GET)
+                .bucket_name(bucket)
+                .query("uploads", "");
+            if let Some((key, upload_id)) = &markers {
+                request = request.query("key-marker", key.clone()).query("upload-id-marker", upload_id.clone());
+            }
+
+            let body = request.send_text_ok().await
+                .map_err(|error| format!("Error listing multipart uploads: {}", error))?;
+            let listing: MultipartListing = minio_rsc::xml::de::from_str(&body)
+                .map_err(|error| format!("Error listing multipart uploads: {}", error))?;
+
+            for upload in listing.uploads {
+                // An upload with a start time that can't be read is never old enough to throw away
+                let started = match DateTime::parse_rfc3339_str(&upload.initiated) {
+                    Ok(started) => started.to_system_time(),
+                    Err(_) => continue,
+                };
+                pending.push(PendingMultipart { name: upload.key, upload_id: upload.upload_id, started });
+            }
+
+            if !listing.is_truncated || listing.next_key_marker.is_empty() {
+                break;
+            }
+            markers = Some((listing.next_key_marker, listing.next_upload_id_marker));
+        }
+
+        Ok(pending)
+    }
+
+    // S3 can't change metadata in place, the object is copied onto itself, which MinIO does without moving the data through us
+    async fn set_metadata(&self, bucket: &str, name: &str, metadata: HashMap<String, String>) -> Result<(), String> {
+        let content_type = match self.head(bucket, name).await? {
+            Some(info) => info.content_type,
+            None => return Err(format!("Error storing object metadata: no object {}/{}", bucket, name)),
+        };
+        let key = KeyArgs::new(name.to_string())
+            .content_type(Some(content_type))
+            .metadata(metadata);
+        self.client.copy_object(bucket.to_string(), key, CopySource::new(bucket, name)).await
+            .map_err(|error| format!("Error storing object metadata: {}", error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use minio_rsc::provider::StaticProvider;
+    use minio_rsc::Minio;
+    use super::MinioStorage;
+
+    // Needs a running MinIO with an existing BLOG_MINIO_TEST_BUCKET, run with `cargo test -- --ignored`
+    #[actix_web::test]
+    #[ignore]
+    async fn conformance() {
+        let client = Minio::builder()
+            .endpoint(env::var("BLOG_MINIO_ENDPOINT").unwrap_or_else(|_| "127.0.0.1:9000".to_owned()))
+            .provider(StaticProvider::new(
+                env::var("BLOG_MINIO_ACCESS_KEY").unwrap(),
+                env::var("BLOG_MINIO_SECRET_KEY").unwrap(),
+                None
+            ))
+            .secure(false)
+            .build()
+            .unwrap();
+        let bucket = env::var("BLOG_MINIO_TEST_BUCKET").unwrap_or_else(|_| "cdn-test".to_owned());
+
+        super::super::conformance::run(&MinioStorage::new(client), &bucket).await;
+    }
+}
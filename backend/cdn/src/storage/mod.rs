@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
+
+pub mod local;
+pub mod minio;
+#[cfg(test)]
+mod conformance;
+
+pub use local::LocalStorage;
+pub use self::minio::MinioStorage;
+
+// What head knows about a stored object
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ObjectInfo {
+    pub size: u64,
+    pub content_type: String,
+    pub metadata: HashMap<String, String>,
+}
+
+// A multipart upload that was started and never completed or aborted
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingMultipart {
+    pub name: String,
+    pub upload_id: String,
+    pub started: SystemTime,
+}
+
+// Smallest part S3 accepts in a multipart upload (other than the last one)
+pub const PART_SIZE: usize = 5 * 1024 * 1024;
+
+pub type ByteStream = BoxStream<'static, Result<Bytes, String>>;
+
+/*
+Where the CDN keeps its objects. MinIO in production, a local directory for development.
+Names may contain '/' (variants are stored under "variants/..."), buckets may not.
+ */
+#[async_trait]
+pub trait Storage: Send + Sync {
+    // Streams the object, or only the inclusive byte range start..=end of it
+    async fn get(&self, bucket: &str, name: &str, range: Option<(u64, u64)>) -> Result<ByteStream, String>;
+
+    // Replaces any object already stored under the name
+    async fn put(&self, bucket: &str, name: &str, content_type: &str, metadata: HashMap<String, String>, bytes: Bytes) -> Result<(), String>;
+
+    // None if there is no such object
+    async fn head(&self, bucket: &str, name: &str) -> Result<Option<ObjectInfo>, String>;
+
+    // Deleting something that doesn't exist is not an error, same as S3
+    async fn delete(&self, bucket: &str, name: &str) -> Result<(), String>;
+
+    // Every object name starting with prefix, in order
+    async fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<String>, String>;
+
+    // Starts an upload sent in parts, for objects too big to hold in memory. Returns its id
+    async fn start_multipart(&self, bucket: &str, name: &str, content_type: &str) -> Result<String, String>;
+
+    /*
+    Parts are numbered from 1, every part but the last has to be at least PART_SIZE.
+    Returns the tag complete_multipart needs for the part, which the caller keeps.
+     */
+    async fn put_part(&self, bucket: &str, name: &str, upload_id: &str, number: usize, bytes: Bytes) -> Result<String, String>;
+
+    // Joins the parts into the object, given by their tags in order
+    async fn complete_multipart(&self, bucket: &str, name: &str, upload_id: &str, parts: &[String]) -> Result<(), String>;
+
+    async fn abort_multipart(&self, bucket: &str, name: &str, upload_id: &str) -> Result<(), String>;
+
+    // Multipart uploads still waiting to be completed or aborted
+    async fn list_multipart(&self, bucket: &str) -> Result<Vec<PendingMultipart>, String>;
+
+    // Replaces the metadata of a stored object, keeping its content and content type
+    async fn set_metadata(&self, bucket: &str, name: &str, metadata: HashMap<String, String>) -> Result<(), String>;
+
+    async fn get_bytes(&self, bucket: &str, name: &str) -> Result<Bytes, String> {
+        let chunks: Vec<Bytes> = self.get(bucket, name, None).await?.try_collect().await?;
+        Ok(Bytes::from(chunks.concat()))
+    }
+}
@@ -0,0 +1,304 @@
+// Elements kept in uploaded SVGs, anything else is removed along with its children
+const ELEMENTS: [&str; 38] = [
+    "svg", "g", "a", "switch", "defs", "symbol", "use", "title", "desc", "metadata", "style",
+    "path", "rect", "circle", "ellipse", "line", "polyline", "polygon", "text", "tspan", "textPath", "image",
+    "linearGradient", "radialGradient", "stop", "pattern", "clipPath", "mask", "marker", "filter",
+    "feGaussianBlur", "feOffset", "feBlend", "feColorMatrix", "feFlood", "feComposite", "feMerge", "feMergeNode",
+];
+
+// Inline images are the only references to something other than the document itself we keep
+const DATA_IMAGES: [&str; 4] = ["data:image/png;", "data:image/jpeg;", "data:image/gif;", "data:image/webp;"];
+
+enum Token<'a> {
+    Text(&'a str),
+    Start { name: &'a str, attributes: Vec<(&'a str, &'a str)>, empty: bool },
+    End(&'a str),
+    // Comments, processing instructions and doctypes, none of which are kept
+    Skip,
+}
+
+/*
+Removes anything that could run script or load something from elsewhere: scripts, foreignObject (HTML),
+event handlers, and links, styles or images pointing outside the document.
+The doctype goes as well, as custom entities in it are how XML entity expansion attacks work.
+ */
+pub fn sanitize(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let text = std::str::from_utf8(bytes).map_err(|_| "SVG is not valid UTF-8".to_string())?;
+    let text = text.trim_start_matches('\u{feff}');
+
+    let mut output = String::with_capacity(text.len());
+    // Elements still open, an end tag has to close the last one so nothing being removed can be closed early
+    let mut open: Vec<&str> = Vec::new();
+    // How deep we are inside an element being removed
+    let mut removing = 0;
+    let mut style = false;
+    let mut offset = 0;
+
+    while offset < text.len() {
+        let (token, next) = next_token(text, offset)?;
+        offset = next;
+
+        match token {
+            Token::Skip => {},
+            Token::Text(text) => {
+                // Style text may only be kept if it doesn't reference anything
+                if removing == 0 && (!style || safe_value(text)) {
+                    output.push_str(text);
+                }
+            }
+            Token::Start { name, attributes, empty } => {
+                if !empty {
+                    open.push(name);
+                }
+                if removing > 0 || !ELEMENTS.contains(&local_name(name)) {
+                    if !empty {
+                        removing += 1;
+                    }
+                    continue;
+                }
+
+                output.push('<');
+                output.push_str(name);
+                for (attribute, value) in attributes {
+                    if safe_attribute(attribute, value) {
+                        output.push_str(&format!(" {}=\"{}\"", attribute, value.replace('"', "&quot;")));
+                    }
+                }
+                output.push_str(if empty { "/>" } else { ">" });
+                style = !empty && local_name(name) == "style";
+            }
+            Token::End(name) => {
+                match open.pop() {
+                    Some(start) if start == name => {},
+                    Some(start) => return Err(format!("End tag {} does not match {}", name, start)),
+                    None => return Err(format!("End tag {} without a start tag", name)),
+                }
+                if removing > 0 {
+                    removing -= 1;
+                    continue;
+                }
+                style = false;
+                output.push_str("</");
+                output.push_str(name);
+                output.push('>');
+            }
+        }
+    }
+
+    if let Some(name) = open.last() {
+        return Err(format!("Unclosed element {}", name));
+    }
+    if !output.contains("<svg") {
+        return Err("No svg element left after sanitizing".into());
+    }
+    Ok(output.into_bytes())
+}
+
+// Only unprefixed or svg: prefixed elements, anything else is from another namespace (like XHTML)
+fn local_name(name: &str) -> &str {
+    match name.split_once(':') {
+        Some(("svg", name)) => name,
+        Some(_) => "",
+        None => name,
+    }
+}
+
+fn safe_attribute(attribute: &str, value: &str) -> bool {
+    let attribute = attribute.to_ascii_lowercase();
+
+    if attribute.starts_with("on") || attribute == "xml:base" {
+        return false;
+    }
+    // Namespace declarations other than the svg and xlink ones could pull in HTML elements
+    if attribute == "xmlns" || attribute.starts_with("xmlns:") {
+        return value == "http://www.w3.org/2000/svg" || value == "http://www.w3.org/1999/xlink";
+    }
+    if attribute.contains(':') && !attribute.starts_with("xlink:") && !attribute.starts_with("xml:") {
+        return false;
+    }
+    if attribute == "href" || attribute == "xlink:href" {
+        let value = value.trim();
+        return value.starts_with('#') || DATA_IMAGES.iter().any(|prefix| value.to_ascii_lowercase().starts_with(prefix));
+    }
+    safe_value(value)
+}
+
+// Rejects script URLs, CSS imports and url() references to anything but fragments in the document
+fn safe_value(value: &str) -> bool {
+    let value: String = value.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_ascii_lowercase();
+    // Entities and CSS escapes could spell out anything below, so they aren't allowed at all
+    if value.contains("&#") || value.contains('\\') {
+        return false;
+    }
+    if value.contains("javascript:") || value.contains("@import") || value.contains("expression(") {
+        return false;
+    }
+    value.match_indices("url(").all(|(index, _)| {
+        let target = value[index + 4..].trim_start_matches(['"', '\'']);
+        target.starts_with('#') || DATA_IMAGES.iter().any(|prefix| target.starts_with(prefix))
+    })
+}
+
+fn next_token(text: &str, offset: usize) -> Result<(Token<'_>, usize), String> {
+    let rest = &text[offset..];
+    let find = |pattern: &str| rest.find(pattern).map(|end| offset + end + pattern.len())
+        .ok_or_else(|| format!("Unterminated markup at {}", offset));
+
+    if !rest.starts_with('<') {
+        let end = rest.find('<').map(|end| offset + end).unwrap_or(text.len());
+        return Ok((Token::Text(&text[offset..end]), end));
+    }
+    if rest.starts_with("<!--") {
+        return Ok((Token::Skip, find("-->")?));
+    }
+    if rest.starts_with("<![CDATA[") {
+        let end = find("]]>")?;
+        return Ok((Token::Text(&text[offset..end]), end));
+    }
+    if rest.starts_with("<?") {
+        return Ok((Token::Skip, find("?>")?));
+    }
+    if rest.starts_with("<!") {
+        // Doctypes may have an internal subset in brackets, which can contain '>'
+        let end = match (rest.find('['), rest.find('>')) {
+            (Some(open), Some(close)) if open < close => {
+                let subset = rest[open..].find("]").ok_or_else(|| "Unterminated doctype".to_string())? + open;
+                rest[subset..].find('>').ok_or_else(|| "Unterminated doctype".to_string())? + subset + 1
+            }
+            (_, Some(close)) => close + 1,
+            _ => return Err("Unterminated doctype".into()),
+        };
+        return Ok((Token::Skip, offset + end));
+    }
+    if let Some(rest) = rest.strip_prefix("</") {
+        let end = rest.find('>').ok_or_else(|| "Unterminated end tag".to_string())?;
+        return Ok((Token::End(rest[..end].trim()), offset + 2 + end + 1));
+    }
+
+    start_tag(text, offset)
+}
+
+fn start_tag(text: &str, offset: usize) -> Result<(Token<'_>, usize), String> {
+    let bytes = text.as_bytes();
+    let name_end = |from: usize| (from..text.len())
+        .find(|&index| bytes[index].is_ascii_whitespace() || b"=/>".contains(&bytes[index]))
+        .unwrap_or(text.len());
+    let skip_space = |from: usize| (from..text.len())
+        .find(|&index| !bytes[index].is_ascii_whitespace())
+        .unwrap_or(text.len());
+
+    let end = name_end(offset + 1);
+    let name = &text[offset + 1..end];
+    if name.is_empty() {
+        return Err(format!("Invalid tag at {}", offset));
+    }
+
+    let mut attributes = Vec::new();
+    let mut position = skip_space(end);
+    loop {
+        match bytes.get(position) {
+            Some(b'>') => return Ok((Token::Start { name, attributes, empty: false }, position + 1)),
+            Some(b'/') if bytes.get(position + 1) == Some(&b'>') => {
+                return Ok((Token::Start { name, attributes, empty: true }, position + 2));
+            }
+            Some(_) => {},
+            None => return Err(format!("Unterminated tag {}", name)),
+        }
+
+        let attribute_end = name_end(position);
+        let attribute = &text[position..attribute_end];
+        position = skip_space(attribute_end);
+        if attribute.is_empty() || bytes.get(position) != Some(&b'=') {
+            return Err(format!("Invalid attribute in tag {}", name));
+        }
+
+        position = skip_space(position + 1);
+        let quote = match bytes.get(position) {
+            Some(quote @ (b'"' | b'\'')) => *quote as char,
+            _ => return Err(format!("Unquoted attribute {} in tag {}", attribute, name)),
+        };
+        let value_end = text[position + 1..].find(quote)
+            .ok_or_else(|| format!("Unterminated attribute {} in tag {}", attribute, name))? + position + 1;
+        attributes.push((attribute, &text[position + 1..value_end]));
+        position = skip_space(value_end + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clean(svg: &str) -> String {
+        String::from_utf8(sanitize(svg.as_bytes()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn keeps_plain_drawings() {
+        let svg = r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 10 10"><g fill="url(#fade)"><rect width="10" height="10"/></g></svg>"##;
+        assert_eq!(clean(svg), svg);
+    }
+
+    #[test]
+    fn removes_scripts() {
+        let output = clean(r#"<svg><script>alert(1)</script><svg:script>alert(2)</svg:script><circle r="1"/></svg>"#);
+        assert_eq!(output, r#"<svg><circle r="1"/></svg>"#);
+    }
+
+    #[test]
+    fn removes_event_handlers() {
+        let output = clean(r#"<svg onload="alert(1)"><rect ONCLICK="alert(2)" onMouseOver="alert(3)" width="1"/></svg>"#);
+        assert_eq!(output, r#"<svg><rect width="1"/></svg>"#);
+    }
+
+    #[test]
+    fn removes_script_links() {
+        let output = clean(r#"<svg><a href="javascript:alert(1)"><text>a</text></a><a xlink:href=" JavaScript:alert(2)"><text>b</text></a></svg>"#);
+        assert!(!output.to_ascii_lowercase().contains("javascript"));
+        let output = clean(r##"<svg><a href="#top"><text>c</text></a></svg>"##);
+        assert!(output.contains(r##"href="#top""##));
+    }
+
+    #[test]
+    fn removes_entity_encoded_links() {
+        let output = clean(r#"<svg><a href="&#106;avascript:alert(1)"><text>a</text></a><rect fill="&#x75;rl(http://example.com)"/></svg>"#);
+        assert!(!output.contains("&#"));
+    }
+
+    #[test]
+    fn removes_external_references() {
+        let output = clean(r#"<svg><image href="https://example.com/a.png"/><rect style="fill:url(https://example.com/#a)"/><style>@import url(https://example.com/a.css);</style></svg>"#);
+        assert!(!output.contains("example.com"));
+        assert!(clean(r#"<svg><image href="data:image/png;base64,AAAA"/></svg>"#).contains("data:image/png"));
+    }
+
+    #[test]
+    fn removes_foreign_object() {
+        let output = clean(r#"<svg><foreignObject><body xmlns="http://www.w3.org/1999/xhtml"><iframe src="https://example.com"></iframe></body></foreignObject><rect width="1"/></svg>"#);
+        assert_eq!(output, r#"<svg><rect width="1"/></svg>"#);
+        let output = clean(r#"<svg xmlns:h="http://www.w3.org/1999/xhtml"><h:script>alert(1)</h:script></svg>"#);
+        assert_eq!(output, "<svg></svg>");
+    }
+
+    #[test]
+    fn removes_doctype_entities() {
+        let svg = r#"<?xml version="1.0"?><!DOCTYPE svg [<!ENTITY a "aaaaaaaaaa"><!ENTITY b "&a;&a;&a;&a;">]><svg><text>&b;</text></svg>"#;
+        let output = clean(svg);
+        assert!(!output.contains("ENTITY"));
+        assert!(output.starts_with("<svg>"));
+    }
+
+    #[test]
+    fn rejects_unbalanced_end_tags() {
+        // Otherwise the stray end tag would stop the removal and the text after it would be kept
+        assert!(sanitize(br#"<svg><script></g>alert(1)</script></svg>"#).is_err());
+        assert!(sanitize(br#"<svg><g></svg>"#).is_err());
+        assert!(sanitize(br#"<svg></g></svg>"#).is_err());
+        assert!(sanitize(br#"<svg><g>"#).is_err());
+    }
+
+    #[test]
+    fn rejects_documents_without_svg() {
+        assert!(sanitize(br#"<html><body></body></html>"#).is_err());
+        assert!(sanitize(&[0xFF, 0xFE, 0x00]).is_err());
+    }
+}
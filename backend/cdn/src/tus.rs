@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use blake2::{Blake2b512, Digest};
+use futures::stream::TryStreamExt;
+use mongodb::bson::spec::BinarySubtype;
+use mongodb::bson::{doc, Binary, DateTime};
+use mongodb::options::{IndexOptions, ReturnDocument};
+use mongodb::{Client, Collection, IndexModel};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use crate::cdn::HASH_METADATA;
+use crate::mime::{self, POLYGLOT_LENGTH, SNIFF_LENGTH, ZIP_TAIL_LENGTH};
+use crate::placeholder;
+use crate::storage::{Storage, PART_SIZE};
+
+// The tus protocol version spoken, and the extensions on top of the core protocol
+pub const TUS_VERSION: &str = "1.0.0";
+pub const TUS_EXTENSIONS: &str = "creation,expiration,termination";
+
+// How long a request can hold on to an upload before it is given up as dead, renewed with every part
+const LEASE: Duration = Duration::from_secs(15 * 60);
+
+/*
+Images have to go through /upload, which strips their metadata, sanitizes SVGs and computes placeholders.
+None of that can be done on a file that is never whole in memory.
+ */
+pub fn resumable(content_type: &str) -> bool {
+    !placeholder::supported(content_type) && content_type != "image/svg+xml"
+}
+
+/*
+An upload sent in pieces over as many requests as it takes. It lives in Mongo so it survives
+a restart and any instance can carry it on. Bytes are held back until there is a whole part
+for the multipart upload, the stored parts are only known by the tags kept here.
+ */
+#[derive(Deserialize, Serialize)]
+pub struct Upload {
+    pub id: String,
+    pub bucket: String,
+    pub name: String,
+    pub owner: String,
+    pub content_type: String,
+    pub length: u64,
+    pub offset: u64,
+    // Pushed back every time bytes arrive
+    pub expires: DateTime,
+    // Set once every byte has arrived and the object is stored
+    pub hash: Option<String>,
+    multipart_id: String,
+    // Tags of the parts stored so far, in order
+    parts: Vec<String>,
+    // Less than a part, anything more has been sent on
+    buffer: Binary,
+    // Enough of either end of the file for the signature and polyglot checks
+    start: Binary,
+    tail: Binary,
+    // Held by the request working on the upload, so there is only ever one
+    lease: Option<String>,
+    lease_expires: Option<DateTime>,
+}
+
+fn binary(bytes: Vec<u8>) -> Binary {
+    Binary { subtype: BinarySubtype::Generic, bytes }
+}
+
+fn random_id(length: usize) -> String {
+    thread_rng().sample_iter(&Alphanumeric).take(length).map(char::from).collect()
+}
+
+fn from_now(duration: Duration) -> DateTime {
+    DateTime::from_millis(DateTime::now().timestamp_millis() + duration.as_millis() as i64)
+}
+
+impl Upload {
+    pub fn new(bucket: String, name: String, owner: String, content_type: String, length: u64, multipart_id: String, expires: SystemTime) -> Upload {
+        Upload {
+            id: random_id(32),
+            bucket,
+            name,
+            owner,
+            content_type,
+            length,
+            offset: 0,
+            expires: DateTime::from_system_time(expires),
+            hash: None,
+            multipart_id,
+            parts: Vec::new(),
+            buffer: binary(Vec::new()),
+            start: binary(Vec::new()),
+            tail: binary(Vec::new()),
+            lease: None,
+            lease_expires: None,
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.offset == self.length
+    }
+
+    // Returns whether a part was sent on, which has to be saved straight away so a restart never loses track of it
+    pub async fn append(&mut self, db: &dyn Storage, chunk: &[u8]) -> Result<bool, String> {
+        let missing = POLYGLOT_LENGTH.saturating_sub(self.start.bytes.len()).min(chunk.len());
+        self.start.bytes.extend_from_slice(&chunk[..missing]);
+        self.tail.bytes.extend_from_slice(chunk);
+        // Trimmed in bulk rather than on every chunk
+        if self.tail.bytes.len() > 2 * ZIP_TAIL_LENGTH {
+            self.tail.bytes.drain(..self.tail.bytes.len() - ZIP_TAIL_LENGTH);
+        }
+
+        self.buffer.bytes.extend_from_slice(chunk);
+        self.offset += chunk.len() as u64;
+        if self.buffer.bytes.len() < PART_SIZE {
+            return Ok(false);
+        }
+        self.put_part(db).await?;
+        Ok(true)
+    }
+
+    // The signature is checked as soon as there are enough bytes for it, everything else once the file is complete
+    pub fn validate(&self) -> Result<(), String> {
+        if self.finished() {
+            mime::validate_parts(&self.start.bytes, &self.tail.bytes, &self.content_type)
+        } else if self.start.bytes.len() >= SNIFF_LENGTH {
+            mime::check_signature(&self.start.bytes, &self.content_type)
+        } else {
+            Ok(())
+        }
+    }
+
+    /*
+    Sends whatever is left as the last part and assembles the object, returning its hash.
+    The parts may have come through other instances, so the hash is taken from the stored object.
+     */
+    pub async fn finish(&mut self, db: &dyn Storage) -> Result<String, String> {
+        if !self.buffer.bytes.is_empty() {
+            self.put_part(db).await?;
+        }
+        db.complete_multipart(&self.bucket, &self.name, &self.multipart_id, &self.parts).await?;
+
+        let hash = match self.hash_and_tag(db).await {
+            Ok(hash) => hash,
+            Err(error) => {
+                // Without a hash it could never be served, so it's not kept
+                if let Err(error) = db.delete(&self.bucket, &self.name).await {
+                    eprintln!("Error removing unhashed upload {}/{}: {}", self.bucket, self.name, error);
+                }
+                return Err(error);
+            }
+        };
+
+        self.start = binary(Vec::new());
+        self.tail = binary(Vec::new());
+        self.hash = Some(hash.clone());
+        Ok(hash)
+    }
+
+    async fn hash_and_tag(&self, db: &dyn Storage) -> Result<String, String> {
+        let mut hasher = Blake2b512::new();
+        let mut stream = db.get(&self.bucket, &self.name, None).await?;
+        while let Some(chunk) = stream.try_next().await? {
+            hasher.update(&chunk);
+        }
+
+        let hash = format!("{:x}", hasher.finalize());
+        let mut metadata = HashMap::new();
+        metadata.insert(HASH_METADATA.to_string(), hash.clone());
+        db.set_metadata(&self.bucket, &self.name, metadata).await?;
+        Ok(hash)
+    }
+
+    pub async fn abort(&self, db: &dyn Storage) -> Result<(), String> {
+        db.abort_multipart(&self.bucket, &self.name, &self.multipart_id).await
+    }
+
+    async fn put_part(&mut self, db: &dyn Storage) -> Result<(), String> {
+        let bytes = std::mem::take(&mut self.buffer.bytes).into();
+        let tag = db.put_part(&self.bucket, &self.name, &self.multipart_id, self.parts.len() + 1, bytes).await?;
+        self.parts.push(tag);
+        Ok(())
+    }
+
+    /*
+    Writes the upload back, as long as this request still holds the lease. The lease is
+    renewed when kept, otherwise the next request can have the upload straight away.
+     */
+    pub async fn save(&mut self, client: &Client, keep_lease: bool) -> Result<(), String> {
+        let lease = match &self.lease {
+            Some(lease) => lease.clone(),
+            None => return Err("Saving an upload without holding it".to_string()),
+        };
+        if keep_lease {
+            self.lease_expires = Some(from_now(LEASE));
+        } else {
+            self.lease = None;
+            self.lease_expires = None;
+        }
+
+        let result = uploads(client).replace_one(doc! {"id": &self.id, "lease": &lease}, &*self).await
+            .map_err(|error| format!("Error saving upload: {}", error))?;
+        if result.matched_count == 0 {
+            return Err("Upload was taken over by another request".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn uploads(client: &Client) -> Collection<Upload> {
+    client.database("cdn").collection("uploads")
+}
+
+pub async fn create_upload_indexes(client: &Client) -> mongodb::error::Result<()> {
+    let indexes = vec![
+        IndexModel::builder()
+            .keys(doc! {"id": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        IndexModel::builder().keys(doc! {"owner": 1, "hash": 1}).build(),
+        IndexModel::builder().keys(doc! {"multipart_id": 1}).build(),
+        IndexModel::builder().keys(doc! {"expires": 1}).build(),
+    ];
+    uploads(client).create_indexes(indexes).await?;
+    Ok(())
+}
+
+pub async fn insert_upload(client: &Client, upload: &Upload) -> mongodb::error::Result<()> {
+    uploads(client).insert_one(upload).await?;
+    Ok(())
+}
+
+pub async fn find_upload(client: &Client, id: &str) -> mongodb::error::Result<Option<Upload>> {
+    uploads(client).find_one(doc! {"id": id}).await
+}
+
+pub async fn remove_upload(client: &Client, id: &str) -> mongodb::error::Result<()> {
+    uploads(client).delete_one(doc! {"id": id}).await?;
+    Ok(())
+}
+
+// Uploads the account has started and not finished yet
+pub async fn count_unfinished(client: &Client, owner: &str) -> mongodb::error::Result<u64> {
+    uploads(client).count_documents(doc! {"owner": owner, "hash": null}).await
+}
+
+// Takes the upload for a request, None when another request has it
+pub async fn lease_upload(client: &Client, id: &str) -> mongodb::error::Result<Option<Upload>> {
+    let now = DateTime::now();
+    uploads(client).find_one_and_update(
+        doc! {"id": id, "$or": [{"lease": null}, {"lease_expires": {"$lt": now}}]},
+        doc! {"$set": {"lease": random_id(24), "lease_expires": from_now(LEASE)}},
+    )
+        .return_document(ReturnDocument::After)
+        .await
+}
+
+/*
+Drops uploads that have not been touched for a while, aborting their multipart upload so
+the parts don't sit in storage forever. Uploads busy with a request are left for next time.
+ */
+async fn expire_uploads(db: &dyn Storage, client: &Client) -> Result<usize, String> {
+    let expired: Vec<Upload> = uploads(client).find(doc! {"expires": {"$lte": DateTime::now()}}).await
+        .map_err(|error| format!("Error finding expired uploads: {}", error))?
+        .try_collect().await
+        .map_err(|error| format!("Error finding expired uploads: {}", error))?;
+
+    let mut count = 0;
+    for upload in expired {
+        let upload = match lease_upload(client, &upload.id).await {
+            Ok(Some(upload)) if upload.expires <= DateTime::now() => upload,
+            Ok(_) => continue,
+            Err(error) => {
+                eprintln!("Error taking expired upload {}: {}", upload.id, error);
+                continue;
+            }
+        };
+        if upload.hash.is_none() {
+            if let Err(error) = upload.abort(db).await {
+                eprintln!("Error aborting expired upload {}/{}: {}", upload.bucket, upload.name, error);
+            }
+            count += 1;
+        }
+        if let Err(error) = remove_upload(client, &upload.id).await {
+            eprintln!("Error removing expired upload {}: {}", upload.id, error);
+        }
+    }
+    Ok(count)
+}
+
+/*
+Multipart uploads in storage that no upload knows about, left behind by a crash between starting
+one and saving the upload, or by anything else that writes to the buckets. They are aborted once
+they are older than any upload could be without expiring.
+ */
+async fn abort_stale_multipart(db: &dyn Storage, client: &Client, bucket: &str, stale_after: Duration) -> Result<usize, String> {
+    let cutoff = SystemTime::now() - stale_after;
+    let mut count = 0;
+    for pending in db.list_multipart(bucket).await? {
+        if pending.started > cutoff {
+            continue;
+        }
+        let known = uploads(client).count_documents(doc! {"multipart_id": &pending.upload_id}).await
+            .map_err(|error| format!("Error checking multipart upload {}: {}", pending.upload_id, error))?;
+        if known > 0 {
+            continue;
+        }
+        match db.abort_multipart(bucket, &pending.name, &pending.upload_id).await {
+            Ok(_) => count += 1,
+            Err(error) => eprintln!("Error aborting stale multipart upload {}/{}: {}", bucket, pending.name, error),
+        }
+    }
+    Ok(count)
+}
+
+pub fn spawn_expiry(db: Arc<dyn Storage>, client: Client, buckets: Vec<String>, stale_after: Duration, every: Duration) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(every);
+        loop {
+            interval.tick().await;
+            match expire_uploads(db.as_ref(), &client).await {
+                Ok(0) => {},
+                Ok(expired) => println!("Expired {} abandoned uploads", expired),
+                Err(error) => eprintln!("{}", error),
+            }
+            for bucket in &buckets {
+                match abort_stale_multipart(db.as_ref(), &client, bucket, stale_after).await {
+                    Ok(0) => {},
+                    Ok(aborted) => println!("Aborted {} stale multipart uploads in {}", aborted, bucket),
+                    Err(error) => eprintln!("{}", error),
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cdn::hash_bytes;
+    use crate::storage::LocalStorage;
+
+    #[test]
+    fn images_are_not_resumable() {
+        for content_type in ["image/jpeg", "image/png", "image/gif", "image/webp", "image/svg+xml"] {
+            assert!(!resumable(content_type), "{}", content_type);
+        }
+        for content_type in ["video/mp4", "video/webm", "audio/mpeg", "application/pdf", "application/zip"] {
+            assert!(resumable(content_type), "{}", content_type);
+        }
+    }
+
+    async fn start(db: &LocalStorage, content_type: &str, length: usize) -> Upload {
+        let multipart_id = db.start_multipart("files", "upload", content_type).await.unwrap();
+        Upload::new("files".to_string(), "upload".to_string(), "owner".to_string(), content_type.to_string(),
+            length as u64, multipart_id, SystemTime::now())
+    }
+
+    // Bigger than a part, so the file is stored in pieces
+    fn pdf() -> Vec<u8> {
+        let mut bytes = b"%PDF-1.7\n".to_vec();
+        bytes.resize(PART_SIZE + 4096, b' ');
+        bytes.extend_from_slice(b"\n%%EOF\n");
+        bytes
+    }
+
+    #[actix_web::test]
+    async fn finished_uploads_pass_validation() {
+        let root = tempfile::tempdir().unwrap();
+        let db = LocalStorage::new(root.path());
+        let bytes = pdf();
+        let mut upload = start(&db, "application/pdf", bytes.len()).await;
+
+        let mut parts = 0;
+        for chunk in bytes.chunks(1024 * 1024) {
+            if upload.append(&db, chunk).await.unwrap() {
+                parts += 1;
+            }
+            upload.validate().unwrap();
+        }
+        assert_eq!(parts, 1);
+        assert!(upload.finished());
+        mime::validate_parts(&upload.start.bytes, &upload.tail.bytes, "application/pdf").unwrap();
+
+        let hash = upload.finish(&db).await.unwrap();
+        assert_eq!(hash, hash_bytes(&bytes));
+        assert_eq!(db.get_bytes("files", "upload").await.unwrap(), bytes);
+        let stat = db.head("files", "upload").await.unwrap().unwrap();
+        assert_eq!(stat.metadata.get(HASH_METADATA), Some(&hash));
+    }
+
+    #[actix_web::test]
+    async fn rejects_the_wrong_signature_early() {
+        let root = tempfile::tempdir().unwrap();
+        let db = LocalStorage::new(root.path());
+        let mut upload = start(&db, "video/mp4", 4096).await;
+
+        upload.append(&db, &pdf()[..SNIFF_LENGTH]).await.unwrap();
+        assert!(!upload.finished());
+        assert!(upload.validate().is_err());
+    }
+
+    #[actix_web::test]
+    async fn rejects_zips_appended_to_finished_uploads() {
+        let root = tempfile::tempdir().unwrap();
+        let db = LocalStorage::new(root.path());
+        let mut bytes = b"%PDF-1.7\n".to_vec();
+        bytes.resize(SNIFF_LENGTH * 4, b' ');
+        bytes.extend_from_slice(b"PK\x05\x06");
+        bytes.extend_from_slice(&[0; 18]);
+        let mut upload = start(&db, "application/pdf", bytes.len()).await;
+
+        upload.append(&db, &bytes[..SNIFF_LENGTH * 2]).await.unwrap();
+        upload.validate().unwrap();
+        upload.append(&db, &bytes[SNIFF_LENGTH * 2..]).await.unwrap();
+        assert!(upload.validate().is_err());
+    }
+}
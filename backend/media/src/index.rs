@@ -0,0 +1,236 @@
+use futures::stream::TryStreamExt;
+use mongodb::bson::{doc, DateTime};
+use mongodb::results::{DeleteResult, InsertOneResult, UpdateResult};
+use mongodb::options::{IndexOptions, ReturnDocument};
+use mongodb::{Client, Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+use crate::MediaLink;
+
+/*
+Everything that has been uploaded to the CDN, and which posts use it.
+The reference count of an object is the number of posts in `posts`.
+ */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MediaObject {
+    pub bucket: String,
+    pub name: String,
+    pub hash: String,
+    pub size: u64,
+    pub content_type: String,
+    // Whoever uploaded it first
+    pub owner: String,
+    /*
+    Everyone who has uploaded it, owner included, as uploading the same content addressed object again returns this one.
+    Missing on objects indexed before that, only the owner has it there.
+     */
+    #[serde(default)]
+    pub owners: Vec<String>,
+    pub uploaded: DateTime,
+    // The name is the hash, so the same content is only ever stored once
+    pub content_addressed: bool,
+    pub posts: Vec<String>,
+    /*
+    When the last post stopped linking to it, or when it was uploaded if no post ever has.
+    Missing on objects indexed before this was kept, uploaded stands in for it there.
+     */
+    #[serde(default)]
+    pub orphaned_since: Option<DateTime>,
+    // Only for images, missing on objects indexed before placeholders were computed
+    #[serde(default)]
+    pub image: Option<ImageInfo>,
+}
+
+impl MediaObject {
+    pub fn owned_by(&self, uuid: &str) -> bool {
+        self.owner == uuid || self.owners.iter().any(|owner| owner == uuid)
+    }
+}
+
+// What a frontend needs to reserve space for an image and show something while it loads
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    // "#rrggbb"
+    pub color: String,
+    pub blurhash: String,
+}
+
+// An image linked from a post, as sent along with the post
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LinkedImage {
+    pub bucket: String,
+    pub name: String,
+    pub hash: String,
+    #[serde(flatten)]
+    pub image: ImageInfo,
+}
+
+fn objects(client: &Client) -> Collection<MediaObject> {
+    client.database("media").collection("objects")
+}
+
+// One entry per object, so a second upload racing the first can't index it twice
+pub async fn create_media_indexes(client: &Client) -> mongodb::error::Result<()> {
+    let index = IndexModel::builder()
+        .keys(doc! { "bucket": 1, "name": 1 })
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    objects(client).create_index(index).await?;
+    Ok(())
+}
+
+pub async fn insert_object(client: &Client, object: &MediaObject) -> mongodb::error::Result<InsertOneResult> {
+    objects(client).insert_one(object).await
+}
+
+pub async fn find_object(client: &Client, bucket: &str, name: &str) -> mongodb::error::Result<Option<MediaObject>> {
+    objects(client).find_one(doc! { "bucket": bucket, "name": name }).await
+}
+
+pub async fn find_objects_by_owner(client: &Client, owner: &str) -> mongodb::error::Result<Vec<MediaObject>> {
+    objects(client).find(doc! { "$or": [{ "owner": owner }, { "owners": owner }] })
+        .sort(doc! { "uploaded": -1 })
+        .await?
+        .try_collect().await
+}
+
+/*
+Hands an already stored object to someone uploading the same content again, if it is still indexed under that hash.
+It counts as freshly uploaded, so an orphan about to be swept gets the whole grace period again.
+ */
+pub async fn claim_object(client: &Client, bucket: &str, name: &str, hash: &str, owner: &str) -> mongodb::error::Result<Option<MediaObject>> {
+    let pipeline = vec![doc! { "$set": {
+        "owners": { "$setUnion": [{ "$ifNull": ["$owners", ["$owner"]] }, [owner]] },
+        "orphaned_since": { "$cond": [{ "$eq": [{ "$size": "$posts" }, 0] }, "$$NOW", null] },
+    } }];
+    objects(client).find_one_and_update(doc! { "bucket": bucket, "name": name, "hash": hash }, pipeline)
+        .return_document(ReturnDocument::After)
+        .await
+}
+
+/*
+Takes one owner off an object that others have uploaded as well, leaving it to them.
+False if they were its only owner, then the object itself has to go.
+ */
+pub async fn release_object(client: &Client, bucket: &str, name: &str, owner: &str) -> mongodb::error::Result<bool> {
+    let pipeline = vec![
+        doc! { "$set": { "owners": { "$filter": { "input": "$owners", "cond": { "$ne": ["$$this", owner] } } } } },
+        doc! { "$set": { "owner": { "$cond": [{ "$eq": ["$owner", owner] }, { "$first": "$owners" }, "$owner"] } } },
+    ];
+    let filter = doc! { "bucket": bucket, "name": name, "owners": owner, "owners.1": { "$exists": true } };
+    Ok(objects(client).update_one(filter, pipeline).await?.modified_count > 0)
+}
+
+fn orphaned_before(cutoff: DateTime) -> mongodb::bson::Document {
+    doc! {
+        "posts": { "$size": 0 },
+        "$or": [
+            { "orphaned_since": { "$lt": cutoff } },
+            { "orphaned_since": null, "uploaded": { "$lt": cutoff } },
+        ],
+    }
+}
+
+// Objects no post has linked to since before the given time
+pub async fn find_orphans(client: &Client, cutoff: DateTime) -> mongodb::error::Result<Vec<MediaObject>> {
+    objects(client).find(orphaned_before(cutoff))
+        .await?
+        .try_collect().await
+}
+
+// Removes the entry only if it is still an orphan, so a post that has just linked the object keeps it
+pub async fn delete_orphan(client: &Client, bucket: &str, name: &str, cutoff: DateTime) -> mongodb::error::Result<bool> {
+    let mut filter = orphaned_before(cutoff);
+    filter.insert("bucket", bucket);
+    filter.insert("name", name);
+    Ok(objects(client).delete_one(filter).await?.deleted_count > 0)
+}
+
+pub async fn rename_object(client: &Client, bucket: &str, name: &str, new_name: &str) -> mongodb::error::Result<UpdateResult> {
+    objects(client).update_one(
+        doc! { "bucket": bucket, "name": name },
+        doc! { "$set": { "name": new_name } },
+    ).await
+}
+
+pub async fn set_image_info(client: &Client, bucket: &str, name: &str, image: &ImageInfo) -> mongodb::error::Result<UpdateResult> {
+    objects(client).update_one(
+        doc! { "bucket": bucket, "name": name },
+        doc! { "$set": { "image": mongodb::bson::to_bson(image)? } },
+    ).await
+}
+
+// Placeholders for the images among some links, links to anything else (or to a stale hash) are left out
+pub async fn find_images(client: &Client, links: &[MediaLink]) -> mongodb::error::Result<Vec<LinkedImage>> {
+    if links.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let matches: Vec<_> = links.iter()
+        .map(|link| doc! { "bucket": &link.bucket, "name": &link.name, "hash": &link.hash })
+        .collect();
+    let objects: Vec<MediaObject> = objects(client).find(doc! { "$or": matches, "image": { "$ne": null } })
+        .await?
+        .try_collect().await?;
+
+    Ok(objects.into_iter()
+        .filter_map(|object| Some(LinkedImage {
+            image: object.image?,
+            bucket: object.bucket,
+            name: object.name,
+            hash: object.hash,
+        }))
+        .collect())
+}
+
+pub async fn delete_object(client: &Client, bucket: &str, name: &str) -> mongodb::error::Result<DeleteResult> {
+    objects(client).delete_one(doc! { "bucket": bucket, "name": name }).await
+}
+
+/*
+Records which objects a post links to, replacing whatever it linked to before.
+Called whenever a post is created or edited. Objects it still links to keep the post
+throughout, so the orphan sweep never sees them unlinked halfway through an edit.
+ */
+pub async fn set_post_references(client: &Client, post_id: &str, links: &[MediaLink]) -> mongodb::error::Result<()> {
+    // Only links whose hash matches count, anything else would fail to load anyway
+    let matches: Vec<_> = links.iter()
+        .map(|link| doc! { "bucket": &link.bucket, "name": &link.name, "hash": &link.hash })
+        .collect();
+
+    if !matches.is_empty() {
+        objects(client).update_many(
+            doc! { "$or": matches.clone() },
+            doc! { "$addToSet": { "posts": post_id }, "$set": { "orphaned_since": null } },
+        ).await?;
+    }
+
+    let mut unlinked = doc! { "posts": post_id };
+    if !matches.is_empty() {
+        unlinked.insert("$nor", matches);
+    }
+    pull_post(client, post_id, unlinked).await?;
+    Ok(())
+}
+
+pub async fn reference_count(client: &Client, bucket: &str, name: &str) -> mongodb::error::Result<usize> {
+    Ok(find_object(client, bucket, name).await?
+        .map(|object| object.posts.len())
+        .unwrap_or(0))
+}
+
+pub async fn clear_post_references(client: &Client, post_id: &str) -> mongodb::error::Result<UpdateResult> {
+    pull_post(client, post_id, doc! { "posts": post_id }).await
+}
+
+// Takes the post off the objects, marking the ones it was the last link of as orphaned from now
+async fn pull_post(client: &Client, post_id: &str, filter: mongodb::bson::Document) -> mongodb::error::Result<UpdateResult> {
+    let pipeline = vec![
+        doc! { "$set": { "posts": { "$filter": { "input": "$posts", "cond": { "$ne": ["$$this", post_id] } } } } },
+        doc! { "$set": { "orphaned_since": {
+            "$cond": [{ "$eq": [{ "$size": "$posts" }, 0] }, "$$NOW", "$orphaned_since"]
+        } } },
+    ];
+    objects(client).update_many(filter, pipeline).await
+}
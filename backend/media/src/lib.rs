@@ -0,0 +1,72 @@
+mod index;
+mod signing;
+
+pub use index::*;
+pub use signing::*;
+
+#[derive(Clone)]
+pub struct MediaConfig {
+    pub public_url: String,
+    // Without a key links are left as they are
+    pub signing_key: Option<Vec<u8>>,
+    pub signed_ttl: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MediaLink {
+    pub bucket: String,
+    pub name: String,
+    pub hash: String,
+}
+
+// Where a CDN link ends in some text, and where its path ends (a query would start there)
+struct LinkSpan {
+    path_end: usize,
+    end: usize,
+    link: Option<MediaLink>,
+}
+
+fn scan_links(text: &str, public_url: &str) -> Vec<LinkSpan> {
+    let prefix = format!("{}/get/", public_url.trim_end_matches('/'));
+    let mut spans = Vec::new();
+    let mut offset = 0;
+
+    while let Some(found) = text[offset..].find(&prefix) {
+        let start = offset + found;
+        let url = &text[start..];
+
+        // A link ends where markdown or html would end it
+        let end = url.find(|c: char| c.is_whitespace() || "\"'()<>".contains(c)).unwrap_or(url.len());
+        let path_end = url[..end].find('?').unwrap_or(end);
+
+        let segments: Vec<&str> = url[prefix.len()..path_end].split('/').collect();
+        let link = match segments[..] {
+            [bucket, name, hash] if !bucket.is_empty() && !name.is_empty() && !hash.is_empty() => Some(MediaLink {
+                bucket: bucket.to_string(),
+                name: name.to_string(),
+                hash: hash.to_string(),
+            }),
+            _ => None,
+        };
+
+        spans.push(LinkSpan {
+            path_end: start + path_end,
+            end: start + end,
+            link,
+        });
+        offset = start + end.max(prefix.len());
+    }
+
+    spans
+}
+
+// Every CDN object linked from some text (a post body), each only once
+pub fn find_links(text: &str, public_url: &str) -> Vec<MediaLink> {
+    let mut links: Vec<MediaLink> = Vec::new();
+    for link in scan_links(text, public_url).into_iter().filter_map(|span| span.link) {
+        if !links.contains(&link) {
+            links.push(link);
+        }
+    }
+    links
+}
@@ -0,0 +1,220 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use crate::{scan_links, MediaConfig};
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, PartialEq)]
+pub enum SignatureError {
+    Expired,
+    Invalid,
+}
+
+/*
+A signature covers the object, the expiry and the scope, so none of them can be changed without the secret.
+The scope is whatever the link was handed out for (e.g. "post:abc123"), an empty scope is allowed.
+ */
+fn signature_message(bucket: &str, name: &str, hash: &str, expires: u64, scope: &str) -> String {
+    format!("{}/{}/{}\n{}\n{}", bucket, name, hash, expires, scope)
+}
+
+pub fn sign(key: &[u8], bucket: &str, name: &str, hash: &str, expires: u64, scope: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC can take a key of any size");
+    mac.update(signature_message(bucket, name, hash, expires, scope).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+pub fn verify(key: &[u8], bucket: &str, name: &str, hash: &str, expires: u64, scope: &str, signature: &str) -> Result<(), SignatureError> {
+    if expires < now() {
+        return Err(SignatureError::Expired);
+    }
+
+    let signature = hex::decode(signature).map_err(|_| SignatureError::Invalid)?;
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC can take a key of any size");
+    mac.update(signature_message(bucket, name, hash, expires, scope).as_bytes());
+    // verify_slice compares in constant time
+    mac.verify_slice(&signature).map_err(|_| SignatureError::Invalid)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+// The query string to add to a /get/{bucket}/{name}/{hash} URL
+pub fn signed_query(key: &[u8], ttl: u64, bucket: &str, name: &str, hash: &str, scope: &str) -> String {
+    let expires = now() + ttl;
+    let signature = sign(key, bucket, name, hash, expires, scope);
+    let mut query = format!("expires={}&sig={}", expires, signature);
+    if !scope.is_empty() {
+        query.push_str(&format!("&scope={}", encode_query_value(scope)));
+    }
+    query
+}
+
+// Percent encodes everything but the characters RFC 3986 leaves unreserved
+fn encode_query_value(value: &str) -> String {
+    value.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// Query parameters that belong to a signature, dropped from a link before it is signed again
+const SIGNATURE_PARAMS: [&str; 3] = ["expires", "sig", "scope"];
+
+/*
+The parameters of a link's query string (with its leading '?') that aren't part of an old signature,
+joined with the separator the link already used, in html that may be "&amp;".
+ */
+fn unsigned_query(query: &str) -> (String, &'static str) {
+    let query = query.trim_start_matches('?');
+    let separator = if query.contains("&amp;") { "&amp;" } else { "&" };
+    let kept: Vec<&str> = query.split(separator)
+        .filter(|param| !param.is_empty())
+        .filter(|param| !SIGNATURE_PARAMS.contains(&param.split('=').next().unwrap_or_default()))
+        .collect();
+    (kept.join(separator), separator)
+}
+
+/*
+Finds every link to the CDN in some text (a post body) and signs it.
+Links that already carry a query string, like a resize, get the signature added on to it.
+Links that were already signed, e.g. copied from an earlier response, are signed again so they don't run out.
+ */
+pub fn sign_urls(text: &str, config: &MediaConfig, scope: &str) -> String {
+    let key = match &config.signing_key {
+        Some(key) => key,
+        None => return text.to_string(),
+    };
+
+    let mut signed = String::with_capacity(text.len());
+    let mut copied = 0;
+
+    for span in scan_links(text, &config.public_url) {
+        let link = match span.link {
+            Some(link) => link,
+            None => continue,
+        };
+        signed.push_str(&text[copied..span.path_end]);
+        copied = span.end;
+
+        let (query, separator) = unsigned_query(&text[span.path_end..span.end]);
+        signed.push('?');
+        if !query.is_empty() {
+            signed.push_str(&query);
+            signed.push_str(separator);
+        }
+        signed.push_str(&signed_query(key, config.signed_ttl, &link.bucket, &link.name, &link.hash, scope).replace('&', separator));
+    }
+
+    signed.push_str(&text[copied..]);
+    signed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"test signing key";
+
+    fn config() -> MediaConfig {
+        MediaConfig {
+            public_url: "https://cdn.example.com".to_string(),
+            signing_key: Some(KEY.to_vec()),
+            signed_ttl: 600,
+        }
+    }
+
+    // The query parameters of a signed link, decoded the way the CDN would get them
+    fn query_params(url: &str) -> Vec<(String, String)> {
+        let query = &url[url.find('?').unwrap() + 1..];
+        query.split('&')
+            .map(|param| param.split_once('=').unwrap())
+            .map(|(name, value)| (name.to_string(), value.replace("%3A", ":")))
+            .collect()
+    }
+
+    fn param<'a>(params: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        params.iter().find(|(param, _)| param == name).map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn round_trip() {
+        let expires = now() + 60;
+        let signature = sign(KEY, "images", "cat.png", "abc", expires, "post:1");
+        assert_eq!(verify(KEY, "images", "cat.png", "abc", expires, "post:1", &signature), Ok(()));
+    }
+
+    #[test]
+    fn signed_urls_verify() {
+        let signed = sign_urls("![cat](https://cdn.example.com/get/images/cat.png/abc)", &config(), "post:1");
+        let params = query_params(&signed[signed.find("https://").unwrap()..signed.len() - 1]);
+        assert_eq!(param(&params, "scope"), Some("post:1"));
+        let expires = param(&params, "expires").unwrap().parse().unwrap();
+        assert_eq!(verify(KEY, "images", "cat.png", "abc", expires, "post:1", param(&params, "sig").unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn scope_is_encoded() {
+        let signed = sign_urls("https://cdn.example.com/get/images/cat.png/abc", &config(), "post:a&b c");
+        assert!(signed.ends_with("&scope=post%3Aa%26b%20c"));
+    }
+
+    #[test]
+    fn expired() {
+        let expires = now() - 1;
+        let signature = sign(KEY, "images", "cat.png", "abc", expires, "");
+        assert_eq!(verify(KEY, "images", "cat.png", "abc", expires, "", &signature), Err(SignatureError::Expired));
+    }
+
+    #[test]
+    fn tampered() {
+        let expires = now() + 60;
+        let signature = sign(KEY, "images", "cat.png", "abc", expires, "post:1");
+        assert_eq!(verify(KEY, "images", "dog.png", "abc", expires, "post:1", &signature), Err(SignatureError::Invalid));
+        assert_eq!(verify(KEY, "images", "cat.png", "abd", expires, "post:1", &signature), Err(SignatureError::Invalid));
+        assert_eq!(verify(KEY, "images", "cat.png", "abc", expires + 3600, "post:1", &signature), Err(SignatureError::Invalid));
+        assert_eq!(verify(KEY, "images", "cat.png", "abc", expires, "post:2", &signature), Err(SignatureError::Invalid));
+        assert_eq!(verify(KEY, "images", "cat.png", "abc", expires, "", &signature), Err(SignatureError::Invalid));
+        assert_eq!(verify(b"another key", "images", "cat.png", "abc", expires, "post:1", &signature), Err(SignatureError::Invalid));
+        assert_eq!(verify(KEY, "images", "cat.png", "abc", expires, "post:1", "not hex"), Err(SignatureError::Invalid));
+    }
+
+    #[test]
+    fn keeps_resize_query() {
+        let signed = sign_urls("https://cdn.example.com/get/images/cat.png/abc?w=200&h=100", &config(), "");
+        assert!(signed.starts_with("https://cdn.example.com/get/images/cat.png/abc?w=200&h=100&expires="));
+    }
+
+    #[test]
+    fn signs_signed_links_again() {
+        let text = "https://cdn.example.com/get/images/cat.png/abc?w=200&expires=1&sig=00&scope=post%3A0";
+        let signed = sign_urls(text, &config(), "post:1");
+        let params = query_params(&signed);
+        assert_eq!(param(&params, "w"), Some("200"));
+        assert_eq!(params.iter().filter(|(name, _)| name == "sig").count(), 1);
+        assert_eq!(param(&params, "scope"), Some("post:1"));
+        let expires: u64 = param(&params, "expires").unwrap().parse().unwrap();
+        assert!(expires > now());
+        assert_eq!(verify(KEY, "images", "cat.png", "abc", expires, "post:1", param(&params, "sig").unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn keeps_html_separators() {
+        let text = "<img src=\"https://cdn.example.com/get/images/cat.png/abc?expires=1&amp;sig=00&amp;w=200\">";
+        let signed = sign_urls(text, &config(), "");
+        assert!(signed.starts_with("<img src=\"https://cdn.example.com/get/images/cat.png/abc?w=200&amp;expires="));
+        assert!(!signed.contains("sig=00"));
+        assert!(signed.ends_with("\">"));
+    }
+
+    #[test]
+    fn leaves_text_without_key() {
+        let config = MediaConfig { signing_key: None, ..config() };
+        let text = "https://cdn.example.com/get/images/cat.png/abc";
+        assert_eq!(sign_urls(text, &config, "post:1"), text);
+    }
+}
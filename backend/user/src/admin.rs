@@ -0,0 +1,163 @@
+use futures::stream::TryStreamExt;
+use mongodb::bson::{doc, Document, DateTime};
+use mongodb::{Client, Collection};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::{revoke_all_sessions, Account, AccountStatus};
+
+// One change an admin made to an account
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    SetElevated { elevated: bool },
+    SetAdmin { admin: bool },
+    Suspend { until: Option<DateTime>, reason: Option<String> },
+    Ban { reason: Option<String> },
+    Reinstate,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AuditEntry {
+    pub id: String,
+    // The admin who made the change, and the account it was made to
+    pub actor: String,
+    pub target: String,
+    pub action: AuditAction,
+    pub time: DateTime,
+}
+
+fn accounts(client: &Client) -> Collection<Account> {
+    client.database("account").collection("accounts")
+}
+
+fn audit_log(client: &Client) -> Collection<AuditEntry> {
+    client.database("account").collection("audit_log")
+}
+
+// Searches are matched literally, not as a pattern
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\.^$|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Newest first, matching the search against name, provider login, email and uuid
+pub async fn search_accounts(client: &Client, search: Option<&str>, skip: u64, limit: i64) -> mongodb::error::Result<Vec<Account>> {
+    let filter = match search.map(str::trim).filter(|search| !search.is_empty()) {
+        Some(search) => {
+            let pattern = doc! {"$regex": escape_regex(search), "$options": "i"};
+            doc! {"$or": [
+                {"name": pattern.clone()},
+                {"subject": pattern.clone()},
+                {"email": pattern.clone()},
+                {"uuid": pattern},
+            ]}
+        }
+        None => Document::new(),
+    };
+    accounts(client).find(filter)
+        .sort(doc! {"_id": -1})
+        .skip(skip)
+        .limit(limit)
+        .await?
+        .try_collect().await
+}
+
+/*
+Leaves out what isn't given, false when there is no such account.
+Elevation set by hand is kept whatever the login allowlist says.
+ */
+pub async fn set_privileges(client: &Client, uuid: &str, elevated: Option<bool>, admin: Option<bool>) -> mongodb::error::Result<bool> {
+    let mut set = Document::new();
+    if let Some(elevated) = elevated {
+        set.insert("elevated", elevated);
+        set.insert("auto_elevated", false);
+    }
+    if let Some(admin) = admin {
+        set.insert("admin", admin);
+    }
+    if set.is_empty() {
+        return Ok(accounts(client).find_one(doc! {"uuid": uuid}).await?.is_some());
+    }
+    let result = accounts(client).update_one(doc! {"uuid": uuid}, doc! {"$set": set}).await?;
+    Ok(result.matched_count == 1)
+}
+
+// For the login allowlist, which only takes away what it gave
+pub async fn set_auto_elevation(client: &Client, uuid: &str, elevated: bool) -> mongodb::error::Result<()> {
+    accounts(client).update_one(
+        doc! {"uuid": uuid},
+        doc! {"$set": {"elevated": elevated, "auto_elevated": elevated}},
+    ).await?;
+    Ok(())
+}
+
+// Anything but active also ends every session, so it takes effect straight away
+pub async fn set_status(client: &Client, uuid: &str, status: AccountStatus, until: Option<DateTime>, reason: Option<String>) -> mongodb::error::Result<bool> {
+    let result = accounts(client).update_one(
+        doc! {"uuid": uuid},
+        doc! {"$set": {
+            "status": mongodb::bson::to_bson(&status)?,
+            "suspended_until": until,
+            "status_reason": reason,
+        }},
+    ).await?;
+    if result.matched_count == 0 {
+        return Ok(false);
+    }
+    if status != AccountStatus::Active {
+        revoke_all_sessions(client, uuid).await?;
+    }
+    Ok(true)
+}
+
+pub async fn record_audit(client: &Client, actor: &str, target: &str, action: AuditAction) -> mongodb::error::Result<()> {
+    audit_log(client).insert_one(AuditEntry {
+        id: Uuid::new_v4().to_string(),
+        actor: actor.to_string(),
+        target: target.to_string(),
+        action,
+        time: DateTime::now(),
+    }).await?;
+    Ok(())
+}
+
+// Newest first, for one account or all of them
+pub async fn find_audit_entries(client: &Client, target: Option<&str>, skip: u64, limit: i64) -> mongodb::error::Result<Vec<AuditEntry>> {
+    let filter = match target {
+        Some(target) => doc! {"target": target},
+        None => Document::new(),
+    };
+    audit_log(client).find(filter)
+        .sort(doc! {"time": -1})
+        .skip(skip)
+        .limit(limit)
+        .await?
+        .try_collect().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_regex_syntax() {
+        assert_eq!(escape_regex("ann"), "ann");
+        assert_eq!(escape_regex("a.b*c"), "a\\.b\\*c");
+        assert_eq!(escape_regex("^(x|y)+$"), "\\^\\(x\\|y\\)\\+\\$");
+        assert_eq!(escape_regex("[a]{2}?\\"), "\\[a\\]\\{2\\}\\?\\\\");
+    }
+
+    #[test]
+    fn escaped_text_only_matches_itself() {
+        // Anything left unescaped would show up bare here
+        let escaped = escape_regex("\\.^$|?*+()[]{}");
+        assert_eq!(escaped.len(), 28);
+        assert!(escaped.chars().collect::<Vec<_>>().chunks(2).all(|pair| pair[0] == '\\'));
+    }
+}
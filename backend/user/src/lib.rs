@@ -4,33 +4,132 @@ use std::task::{Context, Poll};
 use actix_session::{SessionGetError, SessionInsertError};
 use actix_web::body::{BodySize, MessageBody};
 use actix_web::web::Bytes;
-use mongodb::{Client, Collection};
-use mongodb::bson::{doc, Bson};
+use mongodb::{Client, Collection, IndexModel};
+use mongodb::options::IndexOptions;
+use std::time::Duration;
+use mongodb::bson::{doc, DateTime, Document};
 use mongodb::results::InsertOneResult;
 use serde::{Deserialize, Serialize};
 use rand::{thread_rng, Rng};
 use rand::distributions::Alphanumeric;
 use uuid::uuid;
 
+mod admin;
+mod local;
+mod passkeys;
+mod profile;
+mod tokens;
+mod totp;
+
+pub use admin::{find_audit_entries, record_audit, search_accounts, set_auto_elevation, set_privileges, set_status, AuditAction, AuditEntry};
+pub use local::{change_password, create_local_indexes, register_local_account, verify_local_login, LockoutPolicy, PasswordError, LOCAL_PROVIDER};
+pub use passkeys::{add_passkey, find_account_by_passkey, record_passkey_use, remove_passkey, Passkey};
+pub use profile::{create_profile_indexes, find_account_by_handle, normalise_handle, refresh_profile, update_profile, Profile, ProfileError, ProfileField, ProfileUpdate};
+pub use tokens::{create_api_token, create_token_indexes, find_account_by_token, get_account, list_api_tokens, revoke_api_token, ApiToken, Scope, TOKEN_PREFIX};
+pub use totp::{confirm_totp_enrollment, create_totp_indexes, disable_totp, regenerate_recovery_codes, start_totp_enrollment, totp_enabled, verify_second_factor, TotpEnrollment, TotpError};
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Account {
     pub name: Option<String>,
     pub uuid: String,
-    pub github_id: u64,
+    // Which OAuth provider the account logs in through, and the id that provider knows it by
+    pub provider: String,
+    pub subject: String,
     pub email: Option<String>,
     pub elevated: bool,
+    // Elevated by the login allowlist rather than by hand, so it is taken away again when the account stops matching
+    #[serde(default)]
+    pub auto_elevated: bool,
     pub sessions: Vec<Session>,
+    // Missing on accounts from before passkeys
+    #[serde(default)]
+    pub passkeys: Vec<Passkey>,
+    // Can manage other accounts
+    #[serde(default)]
+    pub admin: bool,
+    #[serde(default)]
+    pub status: AccountStatus,
+    // Only for suspensions, which lift by themselves after it
+    pub suspended_until: Option<DateTime>,
+    pub status_reason: Option<String>,
+    // Missing until the first login or edit after profiles were added
+    #[serde(default)]
+    pub profile: Option<Profile>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    Suspended,
+    Banned,
+}
+
+impl Account {
+    // Whether the account is kept from logging in and using its sessions and tokens
+    pub fn blocked(&self) -> bool {
+        match self.status {
+            AccountStatus::Active => false,
+            AccountStatus::Banned => true,
+            AccountStatus::Suspended => match self.suspended_until {
+                Some(until) => until > DateTime::now(),
+                None => true,
+            },
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Session {
     pub session_id: String,
+    // Safe to show, unlike session_id, so this is what sessions are listed and revoked by
+    #[serde(default)]
+    pub id: String,
+    // All missing on sessions from before they were recorded, which count as expired
+    pub created: Option<DateTime>,
+    pub last_seen: Option<DateTime>,
+    // Both fixed when the session is created, so every service enforces the same limits
+    pub expires: Option<DateTime>,
+    pub idle_timeout_seconds: Option<i64>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
 }
 
+impl Session {
+    pub fn expired(&self, now: DateTime) -> bool {
+        let (expires, last_seen, idle) = match (self.expires, self.last_seen, self.idle_timeout_seconds) {
+            (Some(expires), Some(last_seen), Some(idle)) => (expires, last_seen, idle),
+            _ => return true,
+        };
+        now > expires || now.timestamp_millis() > last_seen.timestamp_millis() + idle * 1000
+    }
+}
+
+// How long a session lasts, set by the authenticate service
+#[derive(Clone, Debug)]
+pub struct SessionPolicy {
+    // Ends after this long without a request
+    pub idle: Duration,
+    // Ends after this long however much it is used
+    pub absolute: Duration,
+}
+
+// Where a session was started from, for telling sessions apart when listing them
+#[derive(Clone, Debug, Default)]
+pub struct SessionOrigin {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+// last_seen is only written when it is at least this old, rather than on every request
+const LAST_SEEN_INTERVAL_MILLIS: i64 = 60 * 1000;
+
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Criteria {
-    pub github_id: u64,
+    pub provider: String,
+    pub subject: String,
 }
 
 
@@ -39,22 +138,68 @@ pub async fn insert_account(client: &Client, account: Account) -> mongodb::error
     collection.insert_one(account).await
 }
 
-pub async fn account_exists(client: &Client, criteria: Criteria) -> Result<bool, mongodb::error::Error> {
-    let account = find_account(client, criteria).await?;
-    Ok(!account.is_none())
+// Inserts the account unless one already exists for its provider and subject, returns whether it was inserted
+pub async fn insert_account_if_missing(client: &Client, account: Account) -> mongodb::error::Result<bool> {
+    match insert_account(client, account).await {
+        Ok(_) => Ok(true),
+        Err(err) if local::is_duplicate_key(&err) => Ok(false),
+        Err(err) => Err(err),
+    }
 }
 
 pub async fn find_account(client: &Client, criteria: Criteria) -> mongodb::error::Result<Option<Account>> {
     let collection: Collection<Account> = client.database("account").collection("accounts");
-    let filter = doc! {"github_id": Bson::Int64(criteria.github_id as i64)};
+    let filter = doc! {"provider": &criteria.provider, "subject": &criteria.subject};
     collection.find_one(filter).await
 }
 
-pub async fn create_session(client: &Client, github_id: u64) -> mongodb::error::Result<String> {
+/*
+Accounts made before there was more than one provider are keyed by github_id alone.
+They are all moved over to provider and subject at startup, before anything reads them as an Account.
+ */
+pub async fn migrate_accounts(client: &Client) -> mongodb::error::Result<u64> {
+    migrate_github_accounts(&client.database("account").collection("accounts")).await
+}
+
+async fn migrate_github_accounts(collection: &Collection<Document>) -> mongodb::error::Result<u64> {
+    let filter = doc! {"github_id": {"$exists": true}, "provider": {"$exists": false}};
+    let update = vec![
+        doc! {"$set": {"provider": "github", "subject": {"$toString": "$github_id"}}},
+        doc! {"$unset": "github_id"},
+    ];
+    let result = collection.update_many(filter, update).await?;
+    Ok(result.modified_count)
+}
+
+// One account per login at each provider, so two callbacks racing can't both create one
+pub async fn create_account_indexes(client: &Client) -> mongodb::error::Result<()> {
+    let collection: Collection<Account> = client.database("account").collection("accounts");
+    let index = IndexModel::builder()
+        .keys(doc! {"provider": 1, "subject": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    collection.create_index(index).await?;
+    Ok(())
+}
+
+pub async fn create_session(client: &Client, uuid: &str, policy: &SessionPolicy, origin: SessionOrigin) -> mongodb::error::Result<String> {
     let session_id = generate_session_id();
     let collection: Collection<Account> = client.database("account").collection("accounts");
-    let filter = doc! {"github_id": Bson::Int64(github_id as i64)};
-    let update = doc! { "$push": { "sessions": { "session_id": &session_id } } };
+    prune_sessions(&collection, uuid).await?;
+
+    let now = DateTime::now();
+    let session = Session {
+        session_id: session_id.clone(),
+        id: uuid::Uuid::new_v4().to_string(),
+        created: Some(now),
+        last_seen: Some(now),
+        expires: Some(DateTime::from_millis(now.timestamp_millis() + policy.absolute.as_millis() as i64)),
+        idle_timeout_seconds: Some(policy.idle.as_secs() as i64),
+        user_agent: origin.user_agent,
+        ip: origin.ip,
+    };
+    let filter = doc! {"uuid": uuid};
+    let update = doc! { "$push": { "sessions": mongodb::bson::to_bson(&session)? } };
     let result = collection.update_one(filter, update).await?;
     if result.modified_count == 0 {
         return Err(mongodb::error::Error::custom("Error creating session".to_string()));
@@ -62,13 +207,62 @@ pub async fn create_session(client: &Client, github_id: u64) -> mongodb::error::
     Ok(session_id)
 }
 
-pub async fn find_account_by_session_id(client: &Client, uuid: String, session_id: String, github_id: u64) -> mongodb::error::Result<Option<Account>> {
-    println!("Trying to find account from accounts collection from id {}, {}", uuid, github_id as i64);
+// Expired sessions are only noticed when used, so the ones that never are get cleared out at the next login
+async fn prune_sessions(collection: &Collection<Account>, uuid: &str) -> mongodb::error::Result<()> {
+    let account = match collection.find_one(doc! {"uuid": uuid}).await? {
+        Some(account) => account,
+        None => return Ok(()),
+    };
+    let now = DateTime::now();
+    let expired: Vec<&str> = account.sessions.iter()
+        .filter(|session| session.expired(now))
+        .map(|session| session.session_id.as_str())
+        .collect();
+    if !expired.is_empty() {
+        collection.update_one(
+            doc! {"uuid": uuid},
+            doc! { "$pull": { "sessions": { "session_id": { "$in": expired } } } },
+        ).await?;
+    }
+    Ok(())
+}
+
+// The sessions that are still usable, newest first
+pub fn active_sessions(account: &Account) -> Vec<&Session> {
+    let now = DateTime::now();
+    let mut sessions: Vec<&Session> = account.sessions.iter()
+        .filter(|session| !session.expired(now))
+        .collect();
+    sessions.sort_by_key(|session| std::cmp::Reverse(session.created));
+    sessions
+}
+
+// By the public id, false when the account has no such session
+pub async fn revoke_session(client: &Client, uuid: &str, id: &str) -> mongodb::error::Result<bool> {
+    let collection: Collection<Account> = client.database("account").collection("accounts");
+    let filter = doc! {"uuid": uuid, "sessions.id": id};
+    let update = doc! { "$pull": { "sessions": { "id": id } } };
+    let result = collection.update_one(filter, update).await?;
+    Ok(result.modified_count == 1)
+}
+
+pub async fn revoke_all_sessions(client: &Client, uuid: &str) -> mongodb::error::Result<()> {
+    let collection: Collection<Account> = client.database("account").collection("accounts");
+    collection.update_one(doc! {"uuid": uuid}, doc! { "$set": { "sessions": [] } }).await?;
+    Ok(())
+}
+
+pub async fn find_account_by_uuid(client: &Client, uuid: &str) -> mongodb::error::Result<Option<Account>> {
+    let collection: Collection<Account> = client.database("account").collection("accounts");
+    collection.find_one(doc! {"uuid": uuid}).await
+}
+
+pub async fn find_account_by_session_id(client: &Client, uuid: String, session_id: String) -> mongodb::error::Result<Option<Account>> {
+    println!("Trying to find account from accounts collection from id {}", uuid);
 
     let collection: Collection<Account> = client.database("account").collection::<Account>("accounts");
     let filter = doc! {
         "uuid": uuid,
-        "github_id": github_id as i64,
         "sessions.session_id": session_id
     };
     collection.find_one(filter).await
@@ -89,13 +283,13 @@ pub enum SessionError {
     SessionInsertError(SessionInsertError),
     NoneFound(String),
     AccountNotFound(String),
+    Expired,
+    Blocked,
+    InvalidToken(String),
+    MissingScope(Scope),
 }
 
 pub async fn get_account_from_session(client: &Client, session: &actix_session::Session) -> Result<Account, SessionError> {
-    session.entries().iter().for_each(|entry| {
-        println!("{:?}", entry);
-    });
-
     let session_id : String = match session.get("session") {
         Ok(session_id) => {
             if session_id.is_none() {
@@ -114,17 +308,8 @@ pub async fn get_account_from_session(client: &Client, session: &actix_session::
         },
         Err(err) => return Err(SessionError::SessionError(err)),
     };
-    let git_id : u64 = match session.get("git_id") {
-        Ok(git_id) => {
-            if git_id.is_none() {
-                return Err(SessionError::NoneFound("git id not found".to_string()))
-            }
-            git_id.unwrap()
-        },
-        Err(err) => return Err(SessionError::SessionError(err)),
-    };
 
-    let account : Account = match find_account_by_session_id(client, account_uuid, session_id, git_id).await {
+    let account : Account = match find_account_by_session_id(client, account_uuid, session_id.clone()).await {
         Ok(acc) => {
             if acc.is_none() {
                 return Err(SessionError::AccountNotFound("Account not found from database".to_string()))
@@ -133,18 +318,40 @@ pub async fn get_account_from_session(client: &Client, session: &actix_session::
         },
         Err(err) => return Err(SessionError::AccountNotFound("Account not found from session id".to_string()))
     };
-    Ok(account)
-}
 
-pub async fn set_account_session(session_id: &String, uuid: &String, git_id: &u64, session: &actix_session::Session) -> Result<(), SessionError> {
-    match session.insert("session", &session_id) {
-        Ok(_) => {},
-        Err(err) => {
-            return Err(SessionError::SessionInsertError(err))
+    let now = DateTime::now();
+    let last_seen = match account.sessions.iter().find(|stored| stored.session_id == session_id) {
+        Some(stored) if !stored.expired(now) => stored.last_seen,
+        _ => {
+            if let Err(err) = delete_session(client, &session_id).await {
+                eprintln!("Error removing expired session of account {}: {}", account.uuid, err);
+            }
+            return Err(SessionError::Expired);
         }
+    };
+    if account.blocked() {
+        return Err(SessionError::Blocked);
     }
 
-    match session.insert("git_id", git_id) {
+    if last_seen.map(|last_seen| now.timestamp_millis() - last_seen.timestamp_millis() >= LAST_SEEN_INTERVAL_MILLIS).unwrap_or(true) {
+        let collection: Collection<Account> = client.database("account").collection("accounts");
+        if let Err(err) = collection.update_one(
+            doc! {"uuid": &account.uuid, "sessions.session_id": &session_id},
+            doc! { "$set": { "sessions.$.last_seen": now } },
+        ).await {
+            eprintln!("Error updating last seen of session of account {}: {}", account.uuid, err);
+        }
+    }
+    Ok(account)
+}
+
+// The session_id behind a cookie session, without checking it is still valid
+pub fn current_session_id(session: &actix_session::Session) -> Option<String> {
+    session.get::<String>("session").ok().flatten()
+}
+
+pub async fn set_account_session(session_id: &String, uuid: &String, session: &actix_session::Session) -> Result<(), SessionError> {
+    match session.insert("session", &session_id) {
         Ok(_) => {},
         Err(err) => {
             return Err(SessionError::SessionInsertError(err))
@@ -170,4 +377,37 @@ fn generate_session_id() -> String {
     ).unwrap()
 }
 
-pub fn main() {}
\ No newline at end of file
+pub fn main() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::Bson;
+
+    // Needs a running MongoDB, run with `cargo test -- --ignored`. Only touches the account_test database
+    #[tokio::test]
+    #[ignore]
+    async fn migrates_github_accounts() {
+        let uri = std::env::var("BLOG_MONGODB_URI").unwrap_or_else(|_| "mongodb://localhost:27017".into());
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let collection: Collection<Document> = client.database("account_test").collection("accounts");
+        collection.drop().await.unwrap();
+
+        collection.insert_many(vec![
+            doc! {"uuid": "legacy", "github_id": Bson::Int64(583231)},
+            doc! {"uuid": "current", "provider": "gitlab", "subject": "42"},
+        ]).await.unwrap();
+
+        assert_eq!(migrate_github_accounts(&collection).await.unwrap(), 1);
+        let legacy = collection.find_one(doc! {"uuid": "legacy"}).await.unwrap().unwrap();
+        assert_eq!(legacy.get_str("provider").unwrap(), "github");
+        assert_eq!(legacy.get_str("subject").unwrap(), "583231");
+        assert!(!legacy.contains_key("github_id"));
+        let current = collection.find_one(doc! {"uuid": "current"}).await.unwrap().unwrap();
+        assert_eq!(current.get_str("provider").unwrap(), "gitlab");
+
+        // Running it again at the next startup changes nothing
+        assert_eq!(migrate_github_accounts(&collection).await.unwrap(), 0);
+        collection.drop().await.unwrap();
+    }
+}
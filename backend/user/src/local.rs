@@ -0,0 +1,273 @@
+use std::sync::OnceLock;
+use std::time::Duration;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::{IndexOptions, ReturnDocument};
+use mongodb::{Client, Collection, IndexModel};
+use rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::{find_account, insert_account, Account, AccountStatus, Criteria};
+
+// Local accounts are keyed like any other, with the username as the subject
+pub const LOCAL_PROVIDER: &str = "local";
+
+pub const MIN_PASSWORD_LENGTH: usize = 12;
+// Hashing is deliberately slow, so there has to be a limit on how much of it one request can ask for
+pub const MAX_PASSWORD_LENGTH: usize = 1024;
+
+/*
+Passwords live in their own collection rather than on the account,
+so the hash can never end up wherever an Account is sent.
+ */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LocalCredentials {
+    pub uuid: String,
+    pub username: String,
+    // Argon2id, as a PHC string so the parameters can change without breaking old hashes
+    pub password_hash: String,
+    pub failed_attempts: u32,
+    pub locked_until: Option<DateTime>,
+}
+
+// Repeated failures lock the username for a while, whoever is trying
+#[derive(Clone, Debug)]
+pub struct LockoutPolicy {
+    pub max_attempts: u32,
+    pub lockout: Duration,
+}
+
+#[derive(Debug)]
+pub enum PasswordError {
+    InvalidUsername(String),
+    WeakPassword(String),
+    UsernameTaken,
+    // Wrong username and wrong password look the same from outside
+    InvalidCredentials,
+    LockedOut(DateTime),
+    NoPassword,
+    HashError(String),
+    MongoError(mongodb::error::Error),
+}
+
+fn credentials(client: &Client) -> Collection<LocalCredentials> {
+    client.database("account").collection("local_credentials")
+}
+
+// Usernames are unique, which the check in register can't promise on its own
+pub async fn create_local_indexes(client: &Client) -> mongodb::error::Result<()> {
+    let index = IndexModel::builder()
+        .keys(doc! {"username": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    credentials(client).create_index(index).await?;
+    Ok(())
+}
+
+// Lowercased so "Danielle" and "danielle" can't be two different people
+pub fn normalise_username(username: &str) -> Result<String, PasswordError> {
+    let username = username.trim().to_lowercase();
+    if username.len() < 3 || username.len() > 32 {
+        return Err(PasswordError::InvalidUsername("Usernames are 3 to 32 characters long".to_string()));
+    }
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.') {
+        return Err(PasswordError::InvalidUsername("Usernames can only use letters, numbers, '_', '-' and '.'".to_string()));
+    }
+    Ok(username)
+}
+
+fn check_password(password: &str) -> Result<(), PasswordError> {
+    let length = password.chars().count();
+    if length < MIN_PASSWORD_LENGTH {
+        return Err(PasswordError::WeakPassword(format!("Passwords need at least {} characters", MIN_PASSWORD_LENGTH)));
+    }
+    if password.len() > MAX_PASSWORD_LENGTH {
+        return Err(PasswordError::WeakPassword(format!("Passwords can be at most {} bytes", MAX_PASSWORD_LENGTH)));
+    }
+    Ok(())
+}
+
+// Argon2 with its default parameters is Argon2id, run off the async workers as it takes a while on purpose
+pub async fn hash_password(password: &str) -> Result<String, PasswordError> {
+    let password = password.to_string();
+    let result = tokio::task::spawn_blocking(move || {
+        let mut salt = [0u8; 16];
+        thread_rng().fill_bytes(&mut salt);
+        let salt = SaltString::encode_b64(&salt).map_err(|err| err.to_string())?;
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|err| err.to_string())
+    }).await;
+
+    match result {
+        Ok(Ok(hash)) => Ok(hash),
+        Ok(Err(err)) => Err(PasswordError::HashError(err)),
+        Err(err) => Err(PasswordError::HashError(err.to_string())),
+    }
+}
+
+pub async fn verify_password(password: &str, hash: &str) -> bool {
+    let password = password.to_string();
+    let hash = hash.to_string();
+    tokio::task::spawn_blocking(move || match PasswordHash::new(&hash) {
+        Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(_) => false,
+    }).await.unwrap_or(false)
+}
+
+// Checked against for unknown usernames, so they take as long to reject as a wrong password
+async fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    if let Some(hash) = DUMMY_HASH.get() {
+        return hash;
+    }
+    let hash = hash_password(&Uuid::new_v4().to_string()).await.unwrap_or_default();
+    DUMMY_HASH.get_or_init(|| hash)
+}
+
+pub async fn register_local_account(client: &Client, username: &str, password: &str, name: Option<String>) -> Result<Account, PasswordError> {
+    let username = normalise_username(username)?;
+    check_password(password)?;
+
+    match credentials(client).find_one(doc! {"username": &username}).await {
+        Ok(Some(_)) => return Err(PasswordError::UsernameTaken),
+        Ok(None) => {},
+        Err(err) => return Err(PasswordError::MongoError(err)),
+    }
+
+    let account = Account {
+        name,
+        uuid: Uuid::new_v4().to_string(),
+        provider: LOCAL_PROVIDER.to_string(),
+        subject: username.clone(),
+        email: None,
+        elevated: false,
+        auto_elevated: false,
+        sessions: vec![],
+        passkeys: vec![],
+        admin: false,
+        status: AccountStatus::Active,
+        suspended_until: None,
+        status_reason: None,
+        profile: None,
+    };
+
+    // Credentials first, the unique index turns a race for the same username into an error here
+    let password_hash = hash_password(password).await?;
+    match credentials(client).insert_one(LocalCredentials {
+        uuid: account.uuid.clone(),
+        username,
+        password_hash,
+        failed_attempts: 0,
+        locked_until: None,
+    }).await {
+        Ok(_) => {},
+        Err(err) if is_duplicate_key(&err) => return Err(PasswordError::UsernameTaken),
+        Err(err) => return Err(PasswordError::MongoError(err)),
+    }
+
+    if let Err(err) = insert_account(client, account.clone()).await {
+        let _ = credentials(client).delete_one(doc! {"uuid": &account.uuid}).await;
+        return Err(PasswordError::MongoError(err));
+    }
+    Ok(account)
+}
+
+pub(crate) fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    matches!(*err.kind, mongodb::error::ErrorKind::Write(mongodb::error::WriteFailure::WriteError(ref error)) if error.code == 11000)
+}
+
+/*
+Checks a username and password, counting failures towards a lockout.
+A locked username is refused without looking at the password, so guessing stops paying off.
+ */
+pub async fn verify_local_login(client: &Client, username: &str, password: &str, policy: &LockoutPolicy) -> Result<Account, PasswordError> {
+    let username = match normalise_username(username) {
+        Ok(username) => username,
+        Err(_) => return Err(PasswordError::InvalidCredentials),
+    };
+    if password.len() > MAX_PASSWORD_LENGTH {
+        return Err(PasswordError::InvalidCredentials);
+    }
+
+    let stored = match credentials(client).find_one(doc! {"username": &username}).await {
+        Ok(Some(stored)) => stored,
+        Ok(None) => {
+            verify_password(password, dummy_hash().await).await;
+            return Err(PasswordError::InvalidCredentials);
+        }
+        Err(err) => return Err(PasswordError::MongoError(err)),
+    };
+
+    if let Some(locked_until) = stored.locked_until {
+        if locked_until > DateTime::now() {
+            return Err(PasswordError::LockedOut(locked_until));
+        }
+    }
+
+    if !verify_password(password, &stored.password_hash).await {
+        record_failure(client, &username, policy).await?;
+        return Err(PasswordError::InvalidCredentials);
+    }
+
+    if let Err(err) = credentials(client).update_one(
+        doc! {"username": &username},
+        doc! {"$set": {"failed_attempts": 0, "locked_until": null}},
+    ).await {
+        return Err(PasswordError::MongoError(err));
+    }
+
+    match find_account(client, Criteria { provider: LOCAL_PROVIDER.to_string(), subject: username }).await {
+        Ok(Some(account)) => Ok(account),
+        Ok(None) => Err(PasswordError::InvalidCredentials),
+        Err(err) => Err(PasswordError::MongoError(err)),
+    }
+}
+
+async fn record_failure(client: &Client, username: &str, policy: &LockoutPolicy) -> Result<(), PasswordError> {
+    let updated = match credentials(client).find_one_and_update(
+        doc! {"username": username},
+        doc! {"$inc": {"failed_attempts": 1}},
+    ).return_document(ReturnDocument::After).await {
+        Ok(updated) => updated,
+        Err(err) => return Err(PasswordError::MongoError(err)),
+    };
+
+    if let Some(updated) = updated {
+        if updated.failed_attempts >= policy.max_attempts {
+            let locked_until = DateTime::from_millis(DateTime::now().timestamp_millis() + policy.lockout.as_millis() as i64);
+            println!("Locking local account {} until {} after {} failed logins", username, locked_until, updated.failed_attempts);
+            if let Err(err) = credentials(client).update_one(
+                doc! {"username": username},
+                doc! {"$set": {"failed_attempts": 0, "locked_until": locked_until}},
+            ).await {
+                return Err(PasswordError::MongoError(err));
+            }
+        }
+    }
+    Ok(())
+}
+
+// The current password is asked for again, so a session left open somewhere isn't enough to take the account over
+pub async fn change_password(client: &Client, uuid: &str, current_password: &str, new_password: &str, policy: &LockoutPolicy) -> Result<(), PasswordError> {
+    let stored = match credentials(client).find_one(doc! {"uuid": uuid}).await {
+        Ok(Some(stored)) => stored,
+        Ok(None) => return Err(PasswordError::NoPassword),
+        Err(err) => return Err(PasswordError::MongoError(err)),
+    };
+
+    // Goes through the same checks and lockout as logging in
+    verify_local_login(client, &stored.username, current_password, policy).await?;
+    check_password(new_password)?;
+
+    let password_hash = hash_password(new_password).await?;
+    match credentials(client).update_one(
+        doc! {"uuid": uuid},
+        doc! {"$set": {"password_hash": password_hash}},
+    ).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(PasswordError::MongoError(err)),
+    }
+}
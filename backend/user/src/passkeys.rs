@@ -0,0 +1,47 @@
+use mongodb::bson::{doc, DateTime};
+use mongodb::results::UpdateResult;
+use mongodb::{Client, Collection};
+use serde::{Deserialize, Serialize};
+use crate::Account;
+
+// A WebAuthn credential, an account can have as many as it has devices
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Passkey {
+    // Both base64url, the key is kept as the COSE_Key the authenticator sent
+    pub credential_id: String,
+    pub public_key: String,
+    // Goes up with every use on authenticators that count, a step backwards means a cloned key
+    pub sign_count: u32,
+    pub name: String,
+    pub created: DateTime,
+    pub last_used: Option<DateTime>,
+}
+
+fn accounts(client: &Client) -> Collection<Account> {
+    client.database("account").collection("accounts")
+}
+
+pub async fn add_passkey(client: &Client, uuid: &str, passkey: &Passkey) -> mongodb::error::Result<UpdateResult> {
+    accounts(client).update_one(
+        doc! {"uuid": uuid},
+        doc! {"$push": {"passkeys": mongodb::bson::to_bson(passkey)?}},
+    ).await
+}
+
+pub async fn find_account_by_passkey(client: &Client, credential_id: &str) -> mongodb::error::Result<Option<Account>> {
+    accounts(client).find_one(doc! {"passkeys.credential_id": credential_id}).await
+}
+
+pub async fn record_passkey_use(client: &Client, credential_id: &str, sign_count: u32) -> mongodb::error::Result<UpdateResult> {
+    accounts(client).update_one(
+        doc! {"passkeys.credential_id": credential_id},
+        doc! {"$set": {"passkeys.$.sign_count": sign_count as i64, "passkeys.$.last_used": DateTime::now()}},
+    ).await
+}
+
+pub async fn remove_passkey(client: &Client, uuid: &str, credential_id: &str) -> mongodb::error::Result<UpdateResult> {
+    accounts(client).update_one(
+        doc! {"uuid": uuid},
+        doc! {"$pull": {"passkeys": {"credential_id": credential_id}}},
+    ).await
+}
@@ -0,0 +1,299 @@
+use mongodb::bson::doc;
+use mongodb::options::IndexOptions;
+use mongodb::{Client, Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+use crate::local::is_duplicate_key;
+use crate::{Account, LOCAL_PROVIDER};
+
+// GitHub's limit, so any GitHub login fits
+pub const MAX_HANDLE_LENGTH: usize = 39;
+const MAX_DISPLAY_NAME_LENGTH: usize = 64;
+const MAX_BIO_LENGTH: usize = 500;
+const MAX_URL_LENGTH: usize = 512;
+const MAX_LINKS: usize = 5;
+
+/*
+What readers see of an author. Filled in from the provider at each login,
+except for the fields the account has edited itself.
+ */
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Profile {
+    // Unique, and what the public author page is found by
+    pub handle: String,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    pub bio: Option<String>,
+    #[serde(default)]
+    pub links: Vec<String>,
+    #[serde(default)]
+    pub edited: Vec<ProfileField>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProfileField {
+    Handle,
+    DisplayName,
+    AvatarUrl,
+    Bio,
+    Links,
+}
+
+/*
+New values for a profile. From the provider a missing value clears the field,
+from the account itself a missing value leaves it as it is and an empty one clears it.
+ */
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ProfileUpdate {
+    pub handle: Option<String>,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    pub bio: Option<String>,
+    pub links: Option<Vec<String>>,
+}
+
+#[derive(Debug)]
+pub enum ProfileError {
+    InvalidInput(String),
+    HandleTaken,
+    MongoError(mongodb::error::Error),
+}
+
+fn accounts(client: &Client) -> Collection<Account> {
+    client.database("account").collection("accounts")
+}
+
+pub async fn create_profile_indexes(client: &Client) -> mongodb::error::Result<()> {
+    let index = IndexModel::builder()
+        .keys(doc! {"profile.handle": 1})
+        .options(IndexOptions::builder()
+            .unique(true)
+            // Accounts from before profiles don't have one
+            .partial_filter_expression(doc! {"profile.handle": {"$type": "string"}})
+            .build())
+        .build();
+    accounts(client).create_index(index).await?;
+    Ok(())
+}
+
+// Lowercased so one author can't pass for another by case alone
+pub fn normalise_handle(handle: &str) -> Result<String, ProfileError> {
+    let handle = handle.trim().to_lowercase();
+    if handle.is_empty() || handle.len() > MAX_HANDLE_LENGTH {
+        return Err(ProfileError::InvalidInput(format!("Handles are 1 to {} characters long", MAX_HANDLE_LENGTH)));
+    }
+    if !handle.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(ProfileError::InvalidInput("Handles can only use letters, numbers, '-' and '_'".to_string()));
+    }
+    Ok(handle)
+}
+
+// The closest valid handle to a provider login or name, which may have anything in it
+fn handle_from(text: &str) -> Option<String> {
+    let handle: String = text.trim().to_lowercase().chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '-' })
+        .collect();
+    let handle: String = handle.trim_matches('-').chars().take(MAX_HANDLE_LENGTH).collect();
+    let handle = handle.trim_end_matches('-').to_string();
+    if handle.is_empty() { None } else { Some(handle) }
+}
+
+// Whether the handle is the base, or the base with a number added to make it unique
+fn derived_from(handle: &str, base: &str) -> bool {
+    match handle.strip_prefix(base) {
+        Some("") => true,
+        Some(rest) => rest.strip_prefix('-').is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit())),
+        None => false,
+    }
+}
+
+async fn handle_taken(client: &Client, uuid: &str, handle: &str) -> mongodb::error::Result<bool> {
+    Ok(accounts(client).find_one(doc! {"profile.handle": handle, "uuid": {"$ne": uuid}}).await?.is_some())
+}
+
+// The base if it is free, otherwise the first of base-2, base-3, ... that is
+async fn free_handle(client: &Client, uuid: &str, base: &str) -> mongodb::error::Result<String> {
+    let mut n = 1;
+    loop {
+        let handle = match n {
+            1 => base.to_string(),
+            _ => {
+                let suffix = format!("-{}", n);
+                format!("{}{}", &base[..base.len().min(MAX_HANDLE_LENGTH - suffix.len())], suffix)
+            }
+        };
+        if !handle_taken(client, uuid, &handle).await? {
+            return Ok(handle);
+        }
+        n += 1;
+    }
+}
+
+fn mark_edited(profile: &mut Profile, field: ProfileField) {
+    if !profile.edited.contains(&field) {
+        profile.edited.push(field);
+    }
+}
+
+fn limit(value: Option<String>, max: usize) -> Option<String> {
+    value.map(|value| value.trim().chars().take(max).collect::<String>())
+        .filter(|value| !value.is_empty())
+}
+
+fn is_web_url(url: &str, https_only: bool) -> bool {
+    url.len() <= MAX_URL_LENGTH
+        && !url.chars().any(char::is_whitespace)
+        && (url.starts_with("https://") || (!https_only && url.starts_with("http://")))
+}
+
+async fn save(client: &Client, uuid: &str, profile: &Profile) -> Result<(), ProfileError> {
+    let profile = mongodb::bson::to_bson(profile)
+        .map_err(|err| ProfileError::MongoError(err.into()))?;
+    match accounts(client).update_one(doc! {"uuid": uuid}, doc! {"$set": {"profile": profile}}).await {
+        Ok(_) => Ok(()),
+        Err(err) if is_duplicate_key(&err) => Err(ProfileError::HandleTaken),
+        Err(err) => Err(ProfileError::MongoError(err)),
+    }
+}
+
+/*
+Brings the profile up to date with what the provider says at login. Values from the provider are
+cut down to size rather than refused, and the handle only changes when the login it came from does.
+ */
+pub async fn refresh_profile(client: &Client, account: &Account, update: ProfileUpdate) -> Result<Profile, ProfileError> {
+    let mut profile = account.profile.clone().unwrap_or_default();
+    let edited = profile.edited.clone();
+    let kept = |field: ProfileField| edited.contains(&field);
+
+    if !kept(ProfileField::Handle) {
+        let base = update.handle.as_deref().and_then(handle_from)
+            .or_else(|| update.display_name.as_deref().and_then(handle_from))
+            .unwrap_or_else(|| "author".to_string());
+        if profile.handle.is_empty() || !derived_from(&profile.handle, &base) {
+            profile.handle = free_handle(client, &account.uuid, &base).await
+                .map_err(ProfileError::MongoError)?;
+        }
+    }
+    if !kept(ProfileField::DisplayName) {
+        profile.display_name = limit(update.display_name, MAX_DISPLAY_NAME_LENGTH);
+    }
+    if !kept(ProfileField::AvatarUrl) {
+        profile.avatar_url = update.avatar_url.filter(|url| is_web_url(url, true));
+    }
+    if !kept(ProfileField::Bio) {
+        profile.bio = limit(update.bio, MAX_BIO_LENGTH);
+    }
+    if !kept(ProfileField::Links) {
+        profile.links = update.links.unwrap_or_default().into_iter()
+            .filter(|url| is_web_url(url, false))
+            .take(MAX_LINKS)
+            .collect();
+    }
+
+    save(client, &account.uuid, &profile).await?;
+    Ok(profile)
+}
+
+// Edits by the account itself, which are kept over what the provider says from then on
+pub async fn update_profile(client: &Client, account: &Account, update: ProfileUpdate) -> Result<Profile, ProfileError> {
+    let mut profile = match &account.profile {
+        Some(profile) => profile.clone(),
+        // Accounts from before profiles start from what is already known about them
+        None => refresh_profile(client, account, ProfileUpdate {
+            handle: Some(account.subject.clone()).filter(|_| account.provider == LOCAL_PROVIDER),
+            display_name: account.name.clone(),
+            ..ProfileUpdate::default()
+        }).await?,
+    };
+
+    if let Some(handle) = update.handle {
+        let handle = normalise_handle(&handle)?;
+        if handle_taken(client, &account.uuid, &handle).await.map_err(ProfileError::MongoError)? {
+            return Err(ProfileError::HandleTaken);
+        }
+        profile.handle = handle;
+        mark_edited(&mut profile, ProfileField::Handle);
+    }
+    if let Some(name) = update.display_name {
+        if name.trim().chars().count() > MAX_DISPLAY_NAME_LENGTH {
+            return Err(ProfileError::InvalidInput(format!("Display names are at most {} characters long", MAX_DISPLAY_NAME_LENGTH)));
+        }
+        profile.display_name = limit(Some(name), MAX_DISPLAY_NAME_LENGTH);
+        mark_edited(&mut profile, ProfileField::DisplayName);
+    }
+    if let Some(url) = update.avatar_url {
+        let url = url.trim().to_string();
+        if !url.is_empty() && !is_web_url(&url, true) {
+            return Err(ProfileError::InvalidInput("Avatars have to be an https:// URL".to_string()));
+        }
+        profile.avatar_url = Some(url).filter(|url| !url.is_empty());
+        mark_edited(&mut profile, ProfileField::AvatarUrl);
+    }
+    if let Some(bio) = update.bio {
+        if bio.trim().chars().count() > MAX_BIO_LENGTH {
+            return Err(ProfileError::InvalidInput(format!("Bios are at most {} characters long", MAX_BIO_LENGTH)));
+        }
+        profile.bio = limit(Some(bio), MAX_BIO_LENGTH);
+        mark_edited(&mut profile, ProfileField::Bio);
+    }
+    if let Some(links) = update.links {
+        let links: Vec<String> = links.iter().map(|url| url.trim().to_string()).filter(|url| !url.is_empty()).collect();
+        if links.len() > MAX_LINKS {
+            return Err(ProfileError::InvalidInput(format!("Profiles have at most {} links", MAX_LINKS)));
+        }
+        if let Some(url) = links.iter().find(|url| !is_web_url(url, false)) {
+            return Err(ProfileError::InvalidInput(format!("{} isn't an http:// or https:// URL", url)));
+        }
+        profile.links = links;
+        mark_edited(&mut profile, ProfileField::Links);
+    }
+
+    save(client, &account.uuid, &profile).await?;
+    Ok(profile)
+}
+
+// Looked up the way handles are stored, and something that could never be a handle finds nothing
+pub async fn find_account_by_handle(client: &Client, handle: &str) -> mongodb::error::Result<Option<Account>> {
+    let handle = match normalise_handle(handle) {
+        Ok(handle) => handle,
+        Err(_) => return Ok(None),
+    };
+    accounts(client).find_one(doc! {"profile.handle": handle}).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalises_handles() {
+        assert_eq!(normalise_handle("  Ann_B-2 ").unwrap(), "ann_b-2");
+        assert_eq!(normalise_handle(&"a".repeat(MAX_HANDLE_LENGTH)).unwrap().len(), MAX_HANDLE_LENGTH);
+    }
+
+    #[test]
+    fn refuses_invalid_handles() {
+        for handle in ["", "   ", "ann b", "ann.b", "ann/../b", "änn", &"a".repeat(MAX_HANDLE_LENGTH + 1)] {
+            assert!(matches!(normalise_handle(handle), Err(ProfileError::InvalidInput(_))), "{:?}", handle);
+        }
+    }
+
+    #[test]
+    fn derives_handles_from_logins() {
+        assert_eq!(handle_from("Ann Smith").as_deref(), Some("ann-smith"));
+        assert_eq!(handle_from("--ann--").as_deref(), Some("ann"));
+        assert_eq!(handle_from("!!!"), None);
+        let long = handle_from(&format!("{}-b", "a".repeat(MAX_HANDLE_LENGTH - 1))).unwrap();
+        assert_eq!(long, "a".repeat(MAX_HANDLE_LENGTH - 1));
+    }
+
+    #[test]
+    fn recognises_numbered_handles() {
+        assert!(derived_from("ann", "ann"));
+        assert!(derived_from("ann-2", "ann"));
+        assert!(!derived_from("ann-", "ann"));
+        assert!(!derived_from("ann-b", "ann"));
+        assert!(!derived_from("anne", "ann"));
+        assert!(!derived_from("bob", "ann"));
+    }
+}
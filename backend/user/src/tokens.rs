@@ -0,0 +1,143 @@
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::HttpRequest;
+use futures::stream::TryStreamExt;
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::IndexOptions;
+use mongodb::{Client, Collection, IndexModel};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use crate::{find_account_by_uuid, get_account_from_session, Account, SessionError};
+
+// Makes tokens easy to spot in logs and secret scanners
+pub const TOKEN_PREFIX: &str = "blog_";
+
+// What a token is allowed to do, on top of whatever its account is allowed to do
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Scope {
+    #[serde(rename = "posts:read")]
+    PostsRead,
+    #[serde(rename = "posts:write")]
+    PostsWrite,
+    #[serde(rename = "media:read")]
+    MediaRead,
+    // Uploading, renaming and deleting your own media
+    #[serde(rename = "media:upload")]
+    MediaUpload,
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+/*
+A personal API token, for CI and command line tools that can't keep a cookie.
+Only a hash is stored, the token itself is shown once when it is made.
+ */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ApiToken {
+    pub id: String,
+    // The account it acts as
+    pub uuid: String,
+    pub name: String,
+    // SHA-256, the tokens are random enough that nothing slower is needed and it can be looked up directly
+    pub token_hash: String,
+    pub scopes: Vec<Scope>,
+    pub created: DateTime,
+    pub expires: Option<DateTime>,
+    pub last_used: Option<DateTime>,
+}
+
+fn tokens(client: &Client) -> Collection<ApiToken> {
+    client.database("account").collection("api_tokens")
+}
+
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub async fn create_token_indexes(client: &Client) -> mongodb::error::Result<()> {
+    let index = IndexModel::builder()
+        .keys(doc! {"token_hash": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    tokens(client).create_index(index).await?;
+    Ok(())
+}
+
+// Returns the stored token along with the token itself, which can't be got back later
+pub async fn create_api_token(client: &Client, uuid: &str, name: &str, scopes: Vec<Scope>, expires: Option<DateTime>) -> mongodb::error::Result<(ApiToken, String)> {
+    let secret: String = thread_rng().sample_iter(&Alphanumeric).take(40).map(char::from).collect();
+    let token = format!("{}{}", TOKEN_PREFIX, secret);
+
+    let api_token = ApiToken {
+        id: Uuid::new_v4().to_string(),
+        uuid: uuid.to_string(),
+        name: name.to_string(),
+        token_hash: hash_token(&token),
+        scopes,
+        created: DateTime::now(),
+        expires,
+        last_used: None,
+    };
+    tokens(client).insert_one(&api_token).await?;
+    Ok((api_token, token))
+}
+
+pub async fn list_api_tokens(client: &Client, uuid: &str) -> mongodb::error::Result<Vec<ApiToken>> {
+    tokens(client).find(doc! {"uuid": uuid})
+        .sort(doc! {"created": -1})
+        .await?
+        .try_collect().await
+}
+
+// False when the account has no token with that id
+pub async fn revoke_api_token(client: &Client, uuid: &str, id: &str) -> mongodb::error::Result<bool> {
+    let result = tokens(client).delete_one(doc! {"uuid": uuid, "id": id}).await?;
+    Ok(result.deleted_count == 1)
+}
+
+pub async fn find_account_by_token(client: &Client, token: &str, scope: Scope) -> Result<Account, SessionError> {
+    let filter = doc! {
+        "token_hash": hash_token(token),
+        "$or": [{"expires": null}, {"expires": {"$gt": DateTime::now()}}],
+    };
+    let api_token = match tokens(client).find_one(filter).await {
+        Ok(Some(api_token)) => api_token,
+        Ok(None) => return Err(SessionError::InvalidToken("Token is unknown or has expired".to_string())),
+        Err(err) => return Err(SessionError::MongoError(err)),
+    };
+    if !api_token.scopes.contains(&scope) {
+        return Err(SessionError::MissingScope(scope));
+    }
+
+    // Only informational, so a failure here doesn't stop the request
+    if let Err(err) = tokens(client).update_one(
+        doc! {"id": &api_token.id},
+        doc! {"$set": {"last_used": DateTime::now()}},
+    ).await {
+        eprintln!("Error recording use of API token {}: {}", api_token.id, err);
+    }
+
+    match find_account_by_uuid(client, &api_token.uuid).await {
+        Ok(Some(account)) if account.blocked() => Err(SessionError::Blocked),
+        Ok(Some(account)) => Ok(account),
+        Ok(None) => Err(SessionError::AccountNotFound("Account of token not found".to_string())),
+        Err(err) => Err(SessionError::MongoError(err)),
+    }
+}
+
+/*
+The account behind a request, from an "Authorization: Bearer" token if there is one and the cookie session otherwise.
+A session can do anything its account can, a token only what its scopes allow.
+ */
+pub async fn get_account(client: &Client, req: &HttpRequest, session: &actix_session::Session, scope: Scope) -> Result<Account, SessionError> {
+    let bearer = req.headers().get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match bearer {
+        Some(token) => find_account_by_token(client, token.trim(), scope).await,
+        None => get_account_from_session(client, session).await,
+    }
+}
@@ -0,0 +1,358 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::{IndexOptions, ReturnDocument};
+use mongodb::{Client, Collection, IndexModel};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use totp_rs::{Algorithm, Secret, TOTP};
+use crate::local::is_duplicate_key;
+use crate::LockoutPolicy;
+
+// What every authenticator app understands: SHA-1, six digits, thirty seconds
+const DIGITS: usize = 6;
+const STEP: u64 = 30;
+// Codes from one step either side still work, for clocks that are a little off
+const SKEW: u8 = 1;
+
+const RECOVERY_CODES: usize = 10;
+const RECOVERY_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/*
+Kept apart from the account for the same reason as passwords,
+anyone with the secret can make codes forever.
+ */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TotpCredentials {
+    pub uuid: String,
+    // Base32, as authenticator apps show it
+    pub secret: String,
+    // Only once a code from the app has been checked, so a scan that went wrong can't lock anyone out
+    pub enabled: bool,
+    // SHA-256 of each unused code, they are random enough that nothing slower is needed
+    pub recovery_codes: Vec<String>,
+    // The last step a code was accepted for, so a code can't be used twice
+    pub last_step: i64,
+    // Six digits don't take long to guess, so wrong codes lock the account like wrong passwords
+    pub failed_attempts: u32,
+    pub locked_until: Option<DateTime>,
+    pub created: DateTime,
+}
+
+#[derive(Debug)]
+pub enum TotpError {
+    AlreadyEnabled,
+    NotEnrolled,
+    InvalidCode,
+    LockedOut(DateTime),
+    SecretError(String),
+    MongoError(mongodb::error::Error),
+}
+
+// What the user needs to set up their app
+pub struct TotpEnrollment {
+    pub secret: String,
+    // otpauth://totp/..., usually shown as a QR code
+    pub uri: String,
+}
+
+fn credentials(client: &Client) -> Collection<TotpCredentials> {
+    client.database("account").collection("totp")
+}
+
+// One secret per account, enrolling again replaces it rather than adding another
+pub async fn create_totp_indexes(client: &Client) -> mongodb::error::Result<()> {
+    let index = IndexModel::builder()
+        .keys(doc! {"uuid": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    credentials(client).create_index(index).await?;
+    Ok(())
+}
+
+fn current_step() -> i64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0);
+    (now / STEP) as i64
+}
+
+fn totp(secret: &str, issuer: &str, account_name: &str) -> Result<TOTP, TotpError> {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes()
+        .map_err(|err| TotpError::SecretError(format!("{:?}", err)))?;
+    // Colons split the issuer from the account in the URI, so neither may have one
+    TOTP::new(Algorithm::SHA1, DIGITS, SKEW, STEP, secret, Some(issuer.replace(':', "")), account_name.replace(':', ""))
+        .map_err(|err| TotpError::SecretError(format!("{:?}", err)))
+}
+
+fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+// Returns the step the code is for, if it is one of the steps currently allowed
+fn matching_step(totp: &TOTP, code: &str) -> Option<i64> {
+    matching_step_at(totp, code, current_step())
+}
+
+fn matching_step_at(totp: &TOTP, code: &str, step: i64) -> Option<i64> {
+    (step - SKEW as i64..=step + SKEW as i64)
+        .find(|step| same(&totp.generate(*step as u64 * STEP), code))
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    // Typed back in by hand, so case and separators don't matter
+    let code: String = code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    Sha256::digest(code.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Returns the codes to show the user once, and their hashes to keep
+fn generate_recovery_codes() -> (Vec<String>, Vec<String>) {
+    let mut rng = thread_rng();
+    let codes: Vec<String> = (0..RECOVERY_CODES)
+        .map(|_| {
+            let code: String = (0..10)
+                .map(|_| RECOVERY_ALPHABET[rng.gen_range(0..RECOVERY_ALPHABET.len())] as char)
+                .collect();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect();
+    let hashes = codes.iter().map(|code| hash_recovery_code(code)).collect();
+    (codes, hashes)
+}
+
+pub async fn totp_enabled(client: &Client, uuid: &str) -> mongodb::error::Result<bool> {
+    Ok(credentials(client).find_one(doc! {"uuid": uuid, "enabled": true}).await?.is_some())
+}
+
+/*
+Starting again replaces a secret that was never confirmed.
+An enabled secret doesn't match the filter, so the upsert runs into the unique index on uuid instead of replacing it.
+ */
+pub async fn start_totp_enrollment(client: &Client, uuid: &str, issuer: &str, account_name: &str) -> Result<TotpEnrollment, TotpError> {
+    let secret = match Secret::generate_secret().to_encoded() {
+        Secret::Encoded(secret) => secret,
+        Secret::Raw(_) => return Err(TotpError::SecretError("Secret was not encoded".to_string())),
+    };
+    let uri = totp(&secret, issuer, account_name)?.get_url();
+
+    let enrollment = TotpCredentials {
+        uuid: uuid.to_string(),
+        secret: secret.clone(),
+        enabled: false,
+        recovery_codes: vec![],
+        last_step: 0,
+        failed_attempts: 0,
+        locked_until: None,
+        created: DateTime::now(),
+    };
+    match credentials(client).replace_one(doc! {"uuid": uuid, "enabled": false}, enrollment).upsert(true).await {
+        Ok(_) => Ok(TotpEnrollment { secret, uri }),
+        Err(err) if is_duplicate_key(&err) => Err(TotpError::AlreadyEnabled),
+        Err(err) => Err(TotpError::MongoError(err)),
+    }
+}
+
+// Turns TOTP on once the app shows the right code, returning the recovery codes
+pub async fn confirm_totp_enrollment(client: &Client, uuid: &str, code: &str) -> Result<Vec<String>, TotpError> {
+    let stored = match credentials(client).find_one(doc! {"uuid": uuid}).await {
+        Ok(Some(stored)) => stored,
+        Ok(None) => return Err(TotpError::NotEnrolled),
+        Err(err) => return Err(TotpError::MongoError(err)),
+    };
+    if stored.enabled {
+        return Err(TotpError::AlreadyEnabled);
+    }
+
+    // The issuer and name only go into the URI, the codes don't depend on them
+    let step = match matching_step(&totp(&stored.secret, "", "")?, code.trim()) {
+        Some(step) => step,
+        None => return Err(TotpError::InvalidCode),
+    };
+
+    let (codes, hashes) = generate_recovery_codes();
+    match credentials(client).update_one(
+        doc! {"uuid": uuid, "enabled": false},
+        doc! {"$set": {"enabled": true, "recovery_codes": hashes, "last_step": step}},
+    ).await {
+        Ok(result) if result.modified_count == 1 => Ok(codes),
+        Ok(_) => Err(TotpError::AlreadyEnabled),
+        Err(err) => Err(TotpError::MongoError(err)),
+    }
+}
+
+/*
+Checks a code from the app, or failing that one of the recovery codes.
+Both are used up in the same update that checks them, so two requests can't spend one code.
+ */
+pub async fn verify_second_factor(client: &Client, uuid: &str, code: &str, policy: &LockoutPolicy) -> Result<(), TotpError> {
+    let stored = match credentials(client).find_one(doc! {"uuid": uuid, "enabled": true}).await {
+        Ok(Some(stored)) => stored,
+        Ok(None) => return Err(TotpError::NotEnrolled),
+        Err(err) => return Err(TotpError::MongoError(err)),
+    };
+
+    if let Some(locked_until) = stored.locked_until {
+        if locked_until > DateTime::now() {
+            return Err(TotpError::LockedOut(locked_until));
+        }
+    }
+
+    let result = check_code(client, &stored, code.trim()).await;
+    match result {
+        Ok(()) => match credentials(client).update_one(
+            doc! {"uuid": uuid, "enabled": true},
+            doc! {"$set": {"failed_attempts": 0, "locked_until": null}},
+        ).await {
+            Ok(_) => Ok(()),
+            Err(err) => Err(TotpError::MongoError(err)),
+        },
+        Err(TotpError::InvalidCode) => {
+            record_failure(client, uuid, policy).await?;
+            Err(TotpError::InvalidCode)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+async fn check_code(client: &Client, stored: &TotpCredentials, code: &str) -> Result<(), TotpError> {
+    if code.len() == DIGITS && code.chars().all(|c| c.is_ascii_digit()) {
+        let step = match matching_step(&totp(&stored.secret, "", "")?, code) {
+            Some(step) => step,
+            None => return Err(TotpError::InvalidCode),
+        };
+        return match credentials(client).update_one(
+            doc! {"uuid": &stored.uuid, "enabled": true, "last_step": {"$lt": step}},
+            doc! {"$set": {"last_step": step}},
+        ).await {
+            Ok(result) if result.modified_count == 1 => Ok(()),
+            Ok(_) => Err(TotpError::InvalidCode),
+            Err(err) => Err(TotpError::MongoError(err)),
+        };
+    }
+
+    let hash = hash_recovery_code(code);
+    match credentials(client).update_one(
+        doc! {"uuid": &stored.uuid, "enabled": true, "recovery_codes": &hash},
+        doc! {"$pull": {"recovery_codes": &hash}},
+    ).await {
+        Ok(result) if result.modified_count == 1 => {
+            println!("Recovery code used for account {}, {} left", stored.uuid, stored.recovery_codes.len() - 1);
+            Ok(())
+        }
+        Ok(_) => Err(TotpError::InvalidCode),
+        Err(err) => Err(TotpError::MongoError(err)),
+    }
+}
+
+async fn record_failure(client: &Client, uuid: &str, policy: &LockoutPolicy) -> Result<(), TotpError> {
+    let updated = match credentials(client).find_one_and_update(
+        doc! {"uuid": uuid, "enabled": true},
+        doc! {"$inc": {"failed_attempts": 1}},
+    ).return_document(ReturnDocument::After).await {
+        Ok(updated) => updated,
+        Err(err) => return Err(TotpError::MongoError(err)),
+    };
+
+    if let Some(updated) = updated {
+        if updated.failed_attempts >= policy.max_attempts {
+            let locked_until = DateTime::from_millis(DateTime::now().timestamp_millis() + policy.lockout.as_millis() as i64);
+            println!("Locking second factor of account {} until {} after {} wrong codes", uuid, locked_until, updated.failed_attempts);
+            if let Err(err) = credentials(client).update_one(
+                doc! {"uuid": uuid, "enabled": true},
+                doc! {"$set": {"failed_attempts": 0, "locked_until": locked_until}},
+            ).await {
+                return Err(TotpError::MongoError(err));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Replaces all recovery codes, for when they have run out or leaked
+pub async fn regenerate_recovery_codes(client: &Client, uuid: &str, code: &str, policy: &LockoutPolicy) -> Result<Vec<String>, TotpError> {
+    verify_second_factor(client, uuid, code, policy).await?;
+
+    let (codes, hashes) = generate_recovery_codes();
+    match credentials(client).update_one(
+        doc! {"uuid": uuid, "enabled": true},
+        doc! {"$set": {"recovery_codes": hashes}},
+    ).await {
+        Ok(_) => Ok(codes),
+        Err(err) => Err(TotpError::MongoError(err)),
+    }
+}
+
+pub async fn disable_totp(client: &Client, uuid: &str, code: &str, policy: &LockoutPolicy) -> Result<(), TotpError> {
+    verify_second_factor(client, uuid, code, policy).await?;
+
+    match credentials(client).delete_one(doc! {"uuid": uuid}).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(TotpError::MongoError(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP";
+
+    fn code_at(totp: &TOTP, step: i64) -> String {
+        totp.generate(step as u64 * STEP)
+    }
+
+    #[test]
+    fn accepts_one_step_either_side() {
+        let totp = totp(SECRET, "", "").unwrap();
+        let step = 1_000_000;
+        for allowed in [step - 1, step, step + 1] {
+            assert_eq!(matching_step_at(&totp, &code_at(&totp, allowed), step), Some(allowed));
+        }
+    }
+
+    #[test]
+    fn rejects_steps_outside_window() {
+        let totp = totp(SECRET, "", "").unwrap();
+        let step = 1_000_000;
+        for outside in [step - 2, step + 2] {
+            let code = code_at(&totp, outside);
+            // Six digits can repeat, only count it if no step in the window happens to have the same code
+            if (step - 1..=step + 1).all(|allowed| code_at(&totp, allowed) != code) {
+                assert_eq!(matching_step_at(&totp, &code, step), None);
+            }
+        }
+    }
+
+    #[test]
+    fn step_boundaries() {
+        // The last second of a step still makes that step's code, the next second makes the next one's
+        let totp = totp(SECRET, "", "").unwrap();
+        assert_eq!(totp.generate(11 * STEP - 1), code_at(&totp, 10));
+        assert_eq!(totp.generate(11 * STEP), code_at(&totp, 11));
+        assert_ne!(code_at(&totp, 10), code_at(&totp, 11));
+    }
+
+    #[test]
+    fn rejects_wrong_length() {
+        let totp = totp(SECRET, "", "").unwrap();
+        let code = code_at(&totp, 1_000_000);
+        assert_eq!(matching_step_at(&totp, &code[1..], 1_000_000), None);
+        assert_eq!(matching_step_at(&totp, &format!("{}0", code), 1_000_000), None);
+    }
+
+    #[test]
+    fn recovery_codes_ignore_case_and_separators() {
+        assert_eq!(hash_recovery_code("abcde-fghjk"), hash_recovery_code("ABCDE FGHJK"));
+        assert_ne!(hash_recovery_code("abcde-fghjk"), hash_recovery_code("abcde-fghjm"));
+    }
+
+    #[test]
+    fn recovery_codes_are_hashed() {
+        let (codes, hashes) = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        for (code, hash) in codes.iter().zip(&hashes) {
+            assert_eq!(&hash_recovery_code(code), hash);
+        }
+    }
+}